@@ -22,6 +22,12 @@ fn main() -> miette::Result<()> {
         "cargo::rustc-env=CLIENT_ID={}",
         std::env::var("CLIENT_ID").unwrap_or_default()
     );
+    // the panel the board has, e.g. "sh1106 128x32 rotate180 0x3d" - see
+    // `DisplayConfig::parse`
+    println!(
+        "cargo::rustc-env=DISPLAY_CONFIG={}",
+        std::env::var("DISPLAY_CONFIG").unwrap_or_default()
+    );
 
     Ok(())
 }
@@ -0,0 +1,136 @@
+use embedded_nal_async::{Dns, TcpConnect};
+use reqwless::{
+    client::{HttpClient, TlsConfig, TlsVerify},
+    request::{Method, RequestBuilder},
+    response::Status,
+};
+
+use crate::{
+    display::thumbnail::Thumbnail,
+    jpeg::{ReadJpegError, TeenyJpegError},
+    net::{StaClient, StaStack, TLS_BUFFER_LEN},
+    playback::{ALBUM_ID_LEN, PLAYBACK_CHANNEL},
+    prelude::*,
+};
+
+/// How many albums' art is kept, so going back to one doesn't download it
+/// again.
+const CACHE_LEN: usize = 8;
+
+#[derive(Debug, Clone)]
+pub enum TeenyAlbumArtError {
+    Http(reqwless::Error),
+    /// Any unsuccessful response.
+    Status(Status),
+    Jpeg(TeenyJpegError),
+}
+
+impl From<reqwless::Error> for TeenyAlbumArtError {
+    fn from(value: reqwless::Error) -> Self {
+        Self::Http(value)
+    }
+}
+
+impl From<ReadJpegError<reqwless::Error>> for TeenyAlbumArtError {
+    fn from(value: ReadJpegError<reqwless::Error>) -> Self {
+        match value {
+            ReadJpegError::Read(e) => Self::Http(e),
+            ReadJpegError::Jpeg(e) => Self::Jpeg(e),
+        }
+    }
+}
+
+/// The art of the album with id `album_id`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlbumArt {
+    pub album_id: String<ALBUM_ID_LEN>,
+    pub thumbnail: Thumbnail,
+}
+
+/// The art of whatever album is playing, once it has been downloaded.
+pub static ALBUM_ART_CHANNEL: PubSubChannel<CriticalSectionRawMutex, AlbumArt, 1, 4, 1> =
+    PubSubChannel::new();
+
+/// Downloads the JPEG at `url`, decoding it as it arrives.
+pub async fn download<T, D>(
+    http: &mut HttpClient<'_, T, D>,
+    url: &str,
+) -> Result<Thumbnail, TeenyAlbumArtError>
+where
+    T: TcpConnect,
+    D: Dns,
+{
+    let headers = [("User-Agent", "teeny/0.1.0"), ("Accept", "image/jpeg")];
+
+    // only needs to fit the response headers, the image is streamed
+    let mut rx_buf = [0; 2048];
+
+    let mut request = http.request(Method::GET, url).await?.headers(&headers);
+    let response = request.send(&mut rx_buf).await?;
+
+    if !response.status.is_successful() {
+        return Err(TeenyAlbumArtError::Status(response.status));
+    }
+
+    Ok(Thumbnail::from_jpeg(&mut response.body().reader()).await?)
+}
+
+/// Downloads the art of every new album that comes up on [`PLAYBACK_CHANNEL`],
+/// publishing it on [`ALBUM_ART_CHANNEL`].
+#[task]
+pub async fn album_art_task(stack: &'static StaStack, mut rng: Rng) {
+    let client = StaClient::new(stack);
+
+    let mut rx_buf = [0; TLS_BUFFER_LEN];
+    let mut tx_buf = [0; TLS_BUFFER_LEN];
+
+    let (tcp_client, dns_socket) = client.ready().await;
+
+    let seed = ((rng.random() as u64) << u32::BITS) + rng.random() as u64;
+    let tls = TlsConfig::new(seed, &mut rx_buf, &mut tx_buf, TlsVerify::None);
+
+    let mut http = HttpClient::new_with_tls(&tcp_client, &dns_socket, tls);
+
+    let mut playback = PLAYBACK_CHANNEL.subscriber().unwrap();
+    let publisher = ALBUM_ART_CHANNEL.immediate_publisher();
+
+    // least recently shown first
+    let mut cache: Vec<AlbumArt, CACHE_LEN> = Vec::new();
+
+    loop {
+        let Some(state) = playback.next_message_pure().await else {
+            continue;
+        };
+
+        if state.art_url.is_empty()
+            || cache
+                .last()
+                .is_some_and(|art| art.album_id == state.album_id)
+        {
+            continue;
+        }
+
+        let art = match cache.iter().position(|art| art.album_id == state.album_id) {
+            Some(i) => cache.remove(i),
+            None => match download(&mut http, &state.art_url).await {
+                Ok(thumbnail) => AlbumArt {
+                    album_id: state.album_id,
+                    thumbnail,
+                },
+                Err(e) => {
+                    warn!("Failed to download album art: {e:?}");
+                    continue;
+                }
+            },
+        };
+
+        if cache.is_full() {
+            cache.remove(0);
+        }
+
+        // can't fail - there was room made for it
+        cache.push(art.clone()).ok();
+
+        publisher.publish_immediate(art);
+    }
+}
@@ -1,4 +1,172 @@
-use crate::prelude::*;
+use core::fmt::Write;
+
+use embedded_nal_async::{Dns, TcpConnect};
+use picoserve::extract::Query;
+use rand_core::RngCore;
+use reqwless::{
+    client::HttpClient,
+    request::{Method, RequestBuilder},
+    response::Status,
+};
+use serde::Deserialize;
+
+use crate::{net::random_utf8, prelude::*};
+
+/// The real spotify accounts service - swap this out in [`AuthConfig`] to run
+/// the flow against a local stand-in token endpoint.
+pub const SPOTIFY_ACCOUNTS_URL: &str = "https://accounts.spotify.com";
+
+/// Where spotify sends the user after they accept (or deny) the authorisation
+/// request - it has to be registered for the app, exactly as written here.
+///
+/// Spotify only allows plain http redirects to loopback addresses, and the
+/// device's own address changes from network to network, so nothing answers
+/// here: the browser fails to load the page, and the user pastes the address
+/// it was sent to into the `/login` page, which hands its query on to
+/// [`callback`].
+pub const REDIRECT_URI: &str = "http://127.0.0.1:8888/callback";
+
+/// Everything the controller needs to read and control playback.
+pub const SCOPE: &str =
+    "user-read-playback-state user-modify-playback-state user-read-currently-playing";
+
+pub const ACCESS_TOKEN_LEN: usize = 512;
+pub const REFRESH_TOKEN_LEN: usize = 256;
+pub const AUTH_CODE_LEN: usize = 512;
+pub const REDIRECT_URI_LEN: usize = 64;
+
+/// [RFC 7636](https://datatracker.ietf.org/doc/html/rfc7636) allows verifiers between 43 and 128 characters long.
+const VERIFIER_LEN: usize = 64;
+const STATE_LEN: usize = 16;
+
+/// The `code` (or `error`) spotify redirected the user to [`callback`] with.
+pub static AUTH_CODE_SIGNAL: Signal<CriticalSectionRawMutex, CallbackParams> = Signal::new();
+
+#[derive(Debug)]
+pub enum TeenyAuthError {
+    Http(reqwless::Error),
+    Status(Status),
+    Json(serde_json_core::de::Error),
+    /// The user (or spotify) refused the authorisation request.
+    Denied(String<64>),
+    /// The `state` returned to [`callback`] wasn't the one we sent - someone
+    /// else's redirect, so it is ignored.
+    StateMismatch,
+    /// A token or request didn't fit in its fixed size buffer.
+    Capacity,
+    /// There is no refresh token to refresh the access token with.
+    NoRefreshToken,
+}
+
+impl TeenyAuthError {
+    /// Whether trying again can't help - the user has to go through the
+    /// [`Authorisation`] flow again.
+    pub fn needs_login(&self) -> bool {
+        matches!(
+            self,
+            Self::Status(Status::BadRequest) | Self::NoRefreshToken
+        )
+    }
+}
+
+impl From<reqwless::Error> for TeenyAuthError {
+    fn from(value: reqwless::Error) -> Self {
+        Self::Http(value)
+    }
+}
+
+impl From<serde_json_core::de::Error> for TeenyAuthError {
+    fn from(value: serde_json_core::de::Error) -> Self {
+        Self::Json(value)
+    }
+}
+
+impl From<core::fmt::Error> for TeenyAuthError {
+    fn from(_: core::fmt::Error) -> Self {
+        Self::Capacity
+    }
+}
+
+/// Anything able to produce a SHA-256 digest - the hardware
+/// [`Sha`](esp_hal::sha::Sha) peripheral on device, or a software
+/// implementation when running the flow on the host.
+pub trait Sha256 {
+    fn digest(&mut self, data: &[u8]) -> [u8; 32];
+}
+
+impl<DM: esp_hal::Mode> Sha256 for esp_hal::sha::Sha<'_, DM> {
+    fn digest(&mut self, mut data: &[u8]) -> [u8; 32] {
+        // SAFETY: All the HW Sha functions are infallible so unwrap is fine to use if
+        // you use block!
+        while !data.is_empty() {
+            data = block!(self.update(data)).unwrap();
+        }
+
+        let mut hash = [0; 32];
+
+        block!(self.finish(hash.as_mut_slice())).unwrap();
+
+        hash
+    }
+}
+
+/// A PKCE code verifier and its S256 challenge.
+#[derive(Debug, Clone)]
+pub struct Pkce {
+    pub verifier: String<VERIFIER_LEN>,
+    pub challenge: String<64>,
+}
+
+impl Pkce {
+    pub fn new(rng: &mut impl RngCore, sha: &mut impl Sha256) -> Self {
+        // SAFETY: the verifier is guaranteed to be valid utf8 as it is base64 encoded
+        let verifier = String::from_utf8(random_utf8::<VERIFIER_LEN>(rng))
+            .expect("Base64 encoding is valid utf8");
+
+        Self::from_verifier(verifier, sha)
+    }
+
+    pub fn from_verifier(verifier: String<VERIFIER_LEN>, sha: &mut impl Sha256) -> Self {
+        let hash = sha.digest(verifier.as_bytes());
+
+        let mut challenge_buf: Vec<u8, 64> = Vec::new();
+        challenge_buf.resize(64, 0).unwrap();
+
+        // S256 is the url safe, unpadded base64 encoding of the verifier's hash -
+        // always 43 bytes long
+        let challenge_len = BASE64_URL_SAFE_NO_PAD
+            .encode_slice(hash, &mut challenge_buf)
+            .expect("a base64 encoded sha256 hash fits in 64 bytes");
+
+        challenge_buf.truncate(challenge_len);
+
+        Self {
+            verifier,
+            challenge: String::from_utf8(challenge_buf).expect("Base64 encoding is valid utf8"),
+        }
+    }
+}
+
+/// Where and as who to authorise - everything which stays the same between
+/// runs of the flow.
+#[derive(Debug, Clone, Copy)]
+pub struct AuthConfig {
+    pub accounts_url: &'static str,
+    pub client_id: &'static str,
+    pub redirect_uri: &'static str,
+    pub scope: &'static str,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            accounts_url: SPOTIFY_ACCOUNTS_URL,
+            client_id: CLIENT_ID,
+            redirect_uri: REDIRECT_URI,
+            scope: SCOPE,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct AuthParams {
@@ -7,25 +175,777 @@ pub struct AuthParams {
     pub scope: &'static str,
     pub code_challenge: String<64>,
     pub code_challenge_method: &'static str,
-    pub redirect_uri: &'static str,
+    pub redirect_uri: String<REDIRECT_URI_LEN>,
+    pub state: String<STATE_LEN>,
 }
 
 impl AuthParams {
-    pub fn to_string(&self) -> String<256> {
+    pub fn to_string(&self) -> String<512> {
         let mut query_string = String::new();
         query_string.push_str("?").unwrap();
-        query_string.push_str("response_type=").unwrap();
-        query_string.push_str(self.response_type).unwrap();
-        query_string.push_str("&client_id=").unwrap();
-        query_string.push_str(self.client_id).unwrap();
-        query_string.push_str("&scope=").unwrap();
-        query_string.push_str(self.scope).unwrap();
-        query_string.push_str("&code_challenge=").unwrap();
-        query_string.push_str(self.code_challenge.as_str()).unwrap();
-        query_string.push_str("&code_challenge_method=").unwrap();
-        query_string.push_str(self.code_challenge_method).unwrap();
-        query_string.push_str("&redirect_uri=").unwrap();
-        query_string.push_str(self.redirect_uri).unwrap();
+        push_param(&mut query_string, "response_type", self.response_type).unwrap();
+        push_param(&mut query_string, "client_id", self.client_id).unwrap();
+        push_param(&mut query_string, "scope", self.scope).unwrap();
+        push_param(&mut query_string, "code_challenge", &self.code_challenge).unwrap();
+        push_param(
+            &mut query_string,
+            "code_challenge_method",
+            self.code_challenge_method,
+        )
+        .unwrap();
+        push_param(&mut query_string, "redirect_uri", &self.redirect_uri).unwrap();
+        push_param(&mut query_string, "state", &self.state).unwrap();
         query_string
     }
 }
+
+/// Appends `key=value` to a query string or form body, percent encoding the
+/// value and adding a `&` separator if needed.
+pub fn push_param<const N: usize>(
+    buf: &mut String<N>,
+    key: &str,
+    value: &str,
+) -> Result<(), TeenyAuthError> {
+    if !buf.is_empty() && !buf.ends_with('?') {
+        buf.push('&').map_err(|_| TeenyAuthError::Capacity)?;
+    }
+
+    buf.push_str(key).map_err(|_| TeenyAuthError::Capacity)?;
+    buf.push('=').map_err(|_| TeenyAuthError::Capacity)?;
+
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => buf
+                .push(byte as char)
+                .map_err(|_| TeenyAuthError::Capacity)?,
+            _ => write!(buf, "%{byte:02X}")?,
+        }
+    }
+
+    Ok(())
+}
+
+/// What spotify appends to [`REDIRECT_URI`] once the user has made a choice -
+/// passed on to [`callback`] by the `/login` page.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CallbackParams {
+    pub code: Option<String<AUTH_CODE_LEN>>,
+    pub error: Option<String<64>>,
+    pub state: Option<String<STATE_LEN>>,
+}
+
+/// The `/callback` route - hands the authorisation code to whoever is waiting
+/// in [`Authorisation::finish`].
+pub async fn callback(Query(params): Query<CallbackParams>) -> &'static str {
+    debug!("Received auth callback");
+
+    let denied = params.error.is_some();
+
+    AUTH_CODE_SIGNAL.signal(params);
+
+    if denied {
+        "Teeny was not authorised - you can close this page."
+    } else {
+        "Teeny is now connected to spotify - you can close this page."
+    }
+}
+
+/// How long spotify's access tokens last, in seconds - assumed if a response
+/// leaves `expires_in` out.
+pub const DEFAULT_EXPIRES_IN: u32 = 60 * 60;
+
+const fn default_expires_in() -> u32 {
+    DEFAULT_EXPIRES_IN
+}
+
+/// An access/refresh token pair, as returned by spotify's `/api/token`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Token {
+    pub access_token: String<ACCESS_TOKEN_LEN>,
+    pub token_type: String<16>,
+    /// Seconds from when the token was issued until it stops being accepted.
+    #[serde(default = "default_expires_in")]
+    pub expires_in: u32,
+    /// Spotify doesn't always rotate the refresh token, so this can be missing
+    /// after a refresh.
+    pub refresh_token: Option<String<REFRESH_TOKEN_LEN>>,
+}
+
+impl Token {
+    /// Reads a token out of the token endpoint's response.
+    pub fn from_json(body: &[u8]) -> Result<Self, TeenyAuthError> {
+        let (token, _) = serde_json_core::from_slice(body)?;
+
+        Ok(token)
+    }
+
+    /// A token with nothing but a (previously saved) refresh token - already
+    /// expired, so a [`TokenManager`] refreshes it straight away.
+    pub fn from_refresh_token(refresh_token: String<REFRESH_TOKEN_LEN>) -> Self {
+        Self {
+            access_token: String::new(),
+            token_type: String::try_from("Bearer").expect("fits in 16 bytes"),
+            expires_in: 0,
+            refresh_token: Some(refresh_token),
+        }
+    }
+}
+
+/// The two ways of getting a [`Token`] out of `/api/token`.
+#[derive(Debug, Clone, Copy)]
+pub enum Grant<'a> {
+    AuthorizationCode {
+        code: &'a str,
+        verifier: &'a str,
+        /// Has to match the one the user was sent to spotify with.
+        redirect_uri: &'a str,
+    },
+    RefreshToken(&'a str),
+}
+
+/// Asks the token endpoint at [`AuthConfig::accounts_url`] for a new
+/// [`Token`].
+pub async fn request_token<T, D>(
+    client: &mut HttpClient<'_, T, D>,
+    config: &AuthConfig,
+    grant: Grant<'_>,
+) -> Result<Token, TeenyAuthError>
+where
+    T: TcpConnect,
+    D: Dns,
+{
+    let mut body: String<1024> = String::new();
+
+    match grant {
+        Grant::AuthorizationCode {
+            code,
+            verifier,
+            redirect_uri,
+        } => {
+            push_param(&mut body, "grant_type", "authorization_code")?;
+            push_param(&mut body, "code", code)?;
+            push_param(&mut body, "redirect_uri", redirect_uri)?;
+            push_param(&mut body, "code_verifier", verifier)?;
+        }
+        Grant::RefreshToken(refresh_token) => {
+            push_param(&mut body, "grant_type", "refresh_token")?;
+            push_param(&mut body, "refresh_token", refresh_token)?;
+        }
+    }
+    push_param(&mut body, "client_id", config.client_id)?;
+
+    let mut url: String<128> = String::new();
+    write!(url, "{}/api/token", config.accounts_url)?;
+
+    let headers = [
+        ("User-Agent", "teeny/0.1.0"),
+        ("Accept", "application/json"),
+        ("Content-Type", "application/x-www-form-urlencoded"),
+    ];
+
+    let mut rx_buf = [0; 4096];
+
+    let mut request = client
+        .request(Method::POST, &url)
+        .await?
+        .headers(&headers)
+        .body(body.as_bytes());
+
+    let response = request.send(&mut rx_buf).await?;
+
+    debug!("Token endpoint responded with {:?}", response.status);
+
+    if !response.status.is_successful() {
+        return Err(TeenyAuthError::Status(response.status.into()));
+    }
+
+    Token::from_json(response.body().read_to_end().await?)
+}
+
+/// One run of the authorisation code flow with PKCE.
+///
+/// Send the user to [`Authorisation::authorize_url`], then wait on
+/// [`Authorisation::finish`] for spotify to redirect them back to
+/// [`callback`].
+pub struct Authorisation {
+    config: AuthConfig,
+    pkce: Pkce,
+    state: String<STATE_LEN>,
+    redirect_uri: String<REDIRECT_URI_LEN>,
+}
+
+impl Authorisation {
+    pub fn new(config: AuthConfig, rng: &mut impl RngCore, sha: &mut impl Sha256) -> Self {
+        Self {
+            config,
+            pkce: Pkce::new(rng, sha),
+            state: String::from_utf8(random_utf8::<STATE_LEN>(rng))
+                .expect("Base64 encoding is valid utf8"),
+            redirect_uri: config
+                .redirect_uri
+                .try_into()
+                .expect("the configured redirect uri should fit"),
+        }
+    }
+
+    pub fn params(&self) -> AuthParams {
+        AuthParams {
+            response_type: "code",
+            client_id: self.config.client_id,
+            scope: self.config.scope,
+            code_challenge: self.pkce.challenge.clone(),
+            code_challenge_method: "S256",
+            redirect_uri: self.redirect_uri.clone(),
+            state: self.state.clone(),
+        }
+    }
+
+    /// The page the user needs to open to grant teeny access.
+    pub fn authorize_url(&self) -> String<640> {
+        let mut url = String::new();
+        url.push_str(self.config.accounts_url).unwrap();
+        url.push_str("/authorize").unwrap();
+        url.push_str(self.params().to_string().as_str()).unwrap();
+        url
+    }
+
+    /// Waits for the redirect to [`callback`] and exchanges its code for a
+    /// [`Token`].
+    pub async fn finish<T, D>(
+        &self,
+        client: &mut HttpClient<'_, T, D>,
+    ) -> Result<Token, TeenyAuthError>
+    where
+        T: TcpConnect,
+        D: Dns,
+    {
+        let code = loop {
+            let params = AUTH_CODE_SIGNAL.wait().await;
+
+            if params.state.as_ref() != Some(&self.state) {
+                warn!(
+                    "Ignoring auth callback: {:?}",
+                    TeenyAuthError::StateMismatch
+                );
+                continue;
+            }
+
+            if let Some(error) = params.error {
+                return Err(TeenyAuthError::Denied(error));
+            }
+
+            if let Some(code) = params.code {
+                break code;
+            }
+        };
+
+        info!("Got authorisation code, exchanging it for a token");
+
+        request_token(
+            client,
+            &self.config,
+            Grant::AuthorizationCode {
+                code: &code,
+                verifier: &self.pkce.verifier,
+                redirect_uri: &self.redirect_uri,
+            },
+        )
+        .await
+    }
+}
+
+/// Refresh this long before the token actually expires, so a request made just
+/// before the deadline still carries a valid token.
+const REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+/// A token is only handed out by [`access_token`] if it is valid for at least
+/// this long.
+const MIN_VALIDITY: Duration = Duration::from_secs(10);
+
+const MIN_BACKOFF: Duration = Duration::from_secs(2);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// The latest access token issued by the [`TokenManager`], or `None` before
+/// the first one arrives (or after it has been [`invalidate`]d).
+pub static ACCESS_TOKEN: Mutex<CriticalSectionRawMutex, Option<AccessToken>> = Mutex::new(None);
+
+/// Signalled whenever [`ACCESS_TOKEN`] is replaced.
+pub static TOKEN_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Signalled with the new refresh token whenever spotify rotates it, so it can
+/// be saved for next time.
+pub static REFRESH_TOKEN_SIGNAL: Signal<CriticalSectionRawMutex, String<REFRESH_TOKEN_LEN>> =
+    Signal::new();
+
+/// Signalled when a request was rejected with the current token, to make the
+/// [`TokenManager`] refresh straight away.
+static REFRESH_NOW_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// A bearer token, and when it stops being accepted.
+#[derive(Debug, Clone)]
+pub struct AccessToken {
+    pub token: String<ACCESS_TOKEN_LEN>,
+    pub expires_at: Instant,
+}
+
+impl AccessToken {
+    pub fn is_valid(&self) -> bool {
+        Instant::now() + MIN_VALIDITY < self.expires_at
+    }
+
+    /// The value of an `Authorization` header using this token.
+    pub fn bearer(&self) -> String<{ ACCESS_TOKEN_LEN + 7 }> {
+        let mut header = String::new();
+        header.push_str("Bearer ").unwrap();
+        header.push_str(&self.token).unwrap();
+        header
+    }
+}
+
+/// Waits until there is a valid access token, and returns it.
+pub async fn access_token() -> AccessToken {
+    loop {
+        if let Some(token) = ACCESS_TOKEN
+            .lock()
+            .await
+            .as_ref()
+            .filter(|token| token.is_valid())
+        {
+            return token.clone();
+        }
+
+        // several tasks can be waiting at once but only one of them gets woken by the
+        // signal, so check back every so often regardless
+        let _ = embassy_time::with_timeout(Duration::from_secs(1), TOKEN_SIGNAL.wait()).await;
+    }
+}
+
+/// Marks the current access token as rejected - [`access_token`] waits for a
+/// new one until the [`TokenManager`] has refreshed it.
+pub async fn invalidate() {
+    *ACCESS_TOKEN.lock().await = None;
+    REFRESH_NOW_SIGNAL.signal(());
+}
+
+/// Owns the refresh token, and keeps [`ACCESS_TOKEN`] valid with it.
+pub struct TokenManager {
+    config: AuthConfig,
+    token: Token,
+    issued_at: Instant,
+}
+
+impl TokenManager {
+    /// Takes over a token which was issued just now.
+    pub fn new(config: AuthConfig, token: Token) -> Self {
+        Self::issued_at(config, token, Instant::now())
+    }
+
+    pub fn issued_at(config: AuthConfig, token: Token, issued_at: Instant) -> Self {
+        Self {
+            config,
+            token,
+            issued_at,
+        }
+    }
+
+    pub fn expires_at(&self) -> Instant {
+        self.issued_at + Duration::from_secs(self.token.expires_in as u64)
+    }
+
+    /// When the token should be refreshed - [`REFRESH_MARGIN`] before it
+    /// expires, or halfway through its life for unusually short lived
+    /// tokens.
+    pub fn refresh_at(&self) -> Instant {
+        let lifetime = Duration::from_secs(self.token.expires_in as u64);
+
+        let margin = if lifetime > REFRESH_MARGIN * 2 {
+            REFRESH_MARGIN
+        } else {
+            lifetime / 2
+        };
+
+        self.expires_at() - margin
+    }
+
+    pub fn needs_refresh(&self, now: Instant) -> bool {
+        now >= self.refresh_at()
+    }
+
+    pub fn refresh_token(&self) -> Option<&str> {
+        self.token.refresh_token.as_deref()
+    }
+
+    /// What to ask the token endpoint for in a [`refresh`](Self::refresh).
+    pub fn refresh_grant(&self) -> Result<Grant<'_>, TeenyAuthError> {
+        self.refresh_token()
+            .map(Grant::RefreshToken)
+            .ok_or(TeenyAuthError::NoRefreshToken)
+    }
+
+    pub fn access_token(&self) -> AccessToken {
+        AccessToken {
+            token: self.token.access_token.clone(),
+            expires_at: self.expires_at(),
+        }
+    }
+
+    /// Makes the current access token available through [`access_token`].
+    pub async fn publish(&self) {
+        *ACCESS_TOKEN.lock().await = Some(self.access_token());
+        TOKEN_SIGNAL.signal(());
+    }
+
+    /// Swaps the refresh token for a new token pair.
+    pub async fn refresh<T, D>(
+        &mut self,
+        client: &mut HttpClient<'_, T, D>,
+    ) -> Result<(), TeenyAuthError>
+    where
+        T: TcpConnect,
+        D: Dns,
+    {
+        let issued_at = Instant::now();
+
+        let mut token = request_token(client, &self.config, self.refresh_grant()?).await?;
+
+        // spotify only sometimes rotates the refresh token - keep the old one if it
+        // didn't send a new one
+        match &token.refresh_token {
+            Some(refresh_token) if token.refresh_token != self.token.refresh_token => {
+                REFRESH_TOKEN_SIGNAL.signal(refresh_token.clone());
+            }
+            Some(_) => {}
+            None => token.refresh_token = self.token.refresh_token.take(),
+        }
+
+        self.token = token;
+        self.issued_at = issued_at;
+
+        debug!(
+            "Access token refreshed, valid for {}s",
+            self.token.expires_in
+        );
+
+        Ok(())
+    }
+
+    /// Keeps [`ACCESS_TOKEN`] fresh forever, refreshing ahead of expiry (or
+    /// straight away after an [`invalidate`]) and backing off exponentially
+    /// while refreshing fails.
+    ///
+    /// Only returns if there is no refresh token or spotify rejects it, in
+    /// which case the user needs to go through the [`Authorisation`] flow
+    /// again - see [`TeenyAuthError::needs_login`].
+    pub async fn maintain<T, D>(&mut self, client: &mut HttpClient<'_, T, D>) -> TeenyAuthError
+    where
+        T: TcpConnect,
+        D: Dns,
+    {
+        use embassy_futures::select::select;
+
+        if !self.needs_refresh(Instant::now()) {
+            self.publish().await;
+        }
+
+        let mut backoff = MIN_BACKOFF;
+
+        loop {
+            if !self.needs_refresh(Instant::now()) {
+                select(Timer::at(self.refresh_at()), REFRESH_NOW_SIGNAL.wait()).await;
+            }
+
+            match self.refresh(client).await {
+                Ok(()) => {
+                    backoff = MIN_BACKOFF;
+                    self.publish().await;
+                }
+                Err(e) if e.needs_login() => {
+                    error!("Can't refresh the access token: {e:?}");
+                    *ACCESS_TOKEN.lock().await = None;
+                    return e;
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to refresh access token, retrying in {}s: {e:?}",
+                        backoff.as_secs()
+                    );
+                    Timer::after(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &[u8] = include_bytes!("../tests/fixtures/token.json");
+    const REFRESHED: &[u8] = include_bytes!("../tests/fixtures/token_refreshed.json");
+
+    /// A software sha in place of the peripheral.
+    struct Software;
+
+    impl Sha256 for Software {
+        fn digest(&mut self, data: &[u8]) -> [u8; 32] {
+            use sha2::Digest;
+
+            sha2::Sha256::digest(data).into()
+        }
+    }
+
+    /// Counts up from `0`, a byte at a time.
+    struct Counting(u8);
+
+    impl RngCore for Counting {
+        fn next_u32(&mut self) -> u32 {
+            rand_core::impls::next_u32_via_fill(self)
+        }
+
+        fn next_u64(&mut self) -> u64 {
+            rand_core::impls::next_u64_via_fill(self)
+        }
+
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            for byte in dest {
+                *byte = self.0;
+                self.0 = self.0.wrapping_add(1);
+            }
+        }
+
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    fn local_config() -> AuthConfig {
+        AuthConfig {
+            accounts_url: "http://127.0.0.1:8080",
+            client_id: "0123456789abcdef",
+            redirect_uri: REDIRECT_URI,
+            scope: SCOPE,
+        }
+    }
+
+    fn encoded(value: &str) -> String<64> {
+        let mut buf = String::new();
+        push_param(&mut buf, "k", value).unwrap();
+        buf
+    }
+
+    #[test]
+    fn challenge_matches_rfc_7636() {
+        // appendix B
+        let verifier = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk";
+
+        let pkce = Pkce::from_verifier(verifier.try_into().unwrap(), &mut Software);
+
+        assert_eq!(pkce.verifier, verifier);
+        assert_eq!(
+            pkce.challenge,
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[test]
+    fn verifiers_are_url_safe_and_long_enough() {
+        let pkce = Pkce::new(&mut Counting(0), &mut Software);
+
+        assert!((43..=128).contains(&pkce.verifier.len()));
+        assert!(pkce
+            .verifier
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_'));
+
+        assert_eq!(
+            Pkce::from_verifier(pkce.verifier.clone(), &mut Software).challenge,
+            pkce.challenge
+        );
+    }
+
+    #[test]
+    fn percent_encodes_everything_but_unreserved_characters() {
+        assert_eq!(encoded("AZaz09-._~"), "k=AZaz09-._~");
+        assert_eq!(encoded("a b&c=d"), "k=a%20b%26c%3Dd");
+        assert_eq!(
+            encoded("http://127.0.0.1:8888/callback"),
+            "k=http%3A%2F%2F127.0.0.1%3A8888%2Fcallback"
+        );
+        assert_eq!(encoded("+/%?"), "k=%2B%2F%25%3F");
+        assert_eq!(encoded("café"), "k=caf%C3%A9");
+        assert_eq!(encoded(""), "k=");
+    }
+
+    #[test]
+    fn separates_params() {
+        let mut query: String<64> = String::try_from("?").unwrap();
+        push_param(&mut query, "a", "1").unwrap();
+        push_param(&mut query, "b", "2").unwrap();
+        assert_eq!(query, "?a=1&b=2");
+
+        let mut body: String<64> = String::new();
+        push_param(&mut body, "a", "1").unwrap();
+        push_param(&mut body, "b", "2").unwrap();
+        assert_eq!(body, "a=1&b=2");
+    }
+
+    #[test]
+    fn params_that_dont_fit_are_capacity_errors() {
+        let mut buf: String<8> = String::new();
+
+        assert!(matches!(
+            push_param(&mut buf, "key", "a b"),
+            Err(TeenyAuthError::Capacity)
+        ));
+    }
+
+    #[test]
+    fn authorize_url_carries_the_challenge_and_state() {
+        let authorisation = Authorisation::new(local_config(), &mut Counting(0), &mut Software);
+
+        let mut expected: String<640> =
+            String::try_from("http://127.0.0.1:8080/authorize?").unwrap();
+        push_param(&mut expected, "response_type", "code").unwrap();
+        push_param(&mut expected, "client_id", "0123456789abcdef").unwrap();
+        push_param(&mut expected, "scope", SCOPE).unwrap();
+        push_param(
+            &mut expected,
+            "code_challenge",
+            &authorisation.pkce.challenge,
+        )
+        .unwrap();
+        push_param(&mut expected, "code_challenge_method", "S256").unwrap();
+        push_param(&mut expected, "redirect_uri", REDIRECT_URI).unwrap();
+        push_param(&mut expected, "state", &authorisation.state).unwrap();
+
+        assert_eq!(authorisation.authorize_url(), expected);
+        assert!(expected.contains(
+            "&scope=user-read-playback-state%20user-modify-playback-state%\
+             20user-read-currently-playing&"
+        ));
+
+        // the state doesn't give away anything about the verifier
+        assert_eq!(authorisation.state.len(), STATE_LEN);
+        assert!(!authorisation
+            .pkce
+            .verifier
+            .contains(authorisation.state.as_str()));
+    }
+
+    #[test]
+    fn reads_tokens() {
+        let token = Token::from_json(TOKEN).unwrap();
+
+        assert!(token.access_token.starts_with("BQC4YqJg"));
+        assert_eq!(token.token_type, "Bearer");
+        assert_eq!(token.expires_in, 3600);
+        assert!(token.refresh_token.unwrap().starts_with("AQDm1Xk4"));
+    }
+
+    #[test]
+    fn refreshed_tokens_can_leave_out_the_refresh_token() {
+        let token = Token::from_json(REFRESHED).unwrap();
+
+        assert!(token.access_token.starts_with("BQDr9Tn2"));
+        assert_eq!(token.expires_in, 3600);
+        assert_eq!(token.refresh_token, None);
+    }
+
+    #[test]
+    fn errors_arent_tokens() {
+        assert!(matches!(
+            Token::from_json(
+                br#"{"error":"invalid_grant","error_description":"Invalid refresh token"}"#
+            ),
+            Err(TeenyAuthError::Json(_))
+        ));
+        assert!(matches!(
+            Token::from_json(&TOKEN[..TOKEN.len() / 2]),
+            Err(TeenyAuthError::Json(_))
+        ));
+    }
+
+    fn manager(token: &[u8], issued_at: u64) -> TokenManager {
+        TokenManager::issued_at(
+            local_config(),
+            Token::from_json(token).unwrap(),
+            Instant::from_secs(issued_at),
+        )
+    }
+
+    #[test]
+    fn refreshes_ahead_of_expiry() {
+        let manager = manager(TOKEN, 100);
+
+        assert_eq!(manager.expires_at(), Instant::from_secs(100 + 3600));
+        assert_eq!(manager.refresh_at(), manager.expires_at() - REFRESH_MARGIN);
+
+        assert!(!manager.needs_refresh(Instant::from_secs(100)));
+        assert!(!manager.needs_refresh(manager.refresh_at() - Duration::from_secs(1)));
+        assert!(manager.needs_refresh(manager.refresh_at()));
+        assert!(manager.needs_refresh(manager.expires_at()));
+    }
+
+    #[test]
+    fn short_lived_tokens_refresh_halfway_through() {
+        let mut token = Token::from_json(TOKEN).unwrap();
+        token.expires_in = 60;
+
+        let manager = TokenManager::issued_at(local_config(), token, Instant::from_secs(100));
+
+        assert_eq!(manager.refresh_at(), Instant::from_secs(130));
+        assert!(!manager.needs_refresh(Instant::from_secs(129)));
+        assert!(manager.needs_refresh(Instant::from_secs(130)));
+    }
+
+    #[test]
+    fn tokens_without_an_expiry_last_as_long_as_usual() {
+        let token =
+            br#"{"access_token":"BQC4YqJg","token_type":"Bearer","refresh_token":"AQDm1Xk4"}"#;
+
+        let manager = manager(token, 100);
+
+        assert_eq!(manager.token.expires_in, DEFAULT_EXPIRES_IN);
+        assert_eq!(
+            manager.refresh_at(),
+            Instant::from_secs(100 + DEFAULT_EXPIRES_IN as u64) - REFRESH_MARGIN
+        );
+    }
+
+    #[test]
+    fn saved_refresh_tokens_are_refreshed_straight_away() {
+        let manager = TokenManager::issued_at(
+            local_config(),
+            Token::from_refresh_token("AQDm1Xk4".try_into().unwrap()),
+            Instant::from_secs(100),
+        );
+
+        assert!(manager.needs_refresh(Instant::from_secs(100)));
+        assert!(matches!(
+            manager.refresh_grant(),
+            Ok(Grant::RefreshToken("AQDm1Xk4"))
+        ));
+    }
+
+    #[test]
+    fn no_refresh_token_means_logging_in_again() {
+        let manager = manager(REFRESHED, 100);
+
+        let e = manager.refresh_grant().unwrap_err();
+
+        assert!(matches!(e, TeenyAuthError::NoRefreshToken));
+        assert!(e.needs_login());
+    }
+
+    #[test]
+    fn only_a_rejected_refresh_token_means_logging_in_again() {
+        assert!(TeenyAuthError::Status(Status::BadRequest).needs_login());
+
+        assert!(!TeenyAuthError::Status(Status::InternalServerError).needs_login());
+        assert!(!TeenyAuthError::Status(Status::TooManyRequests).needs_login());
+        assert!(!TeenyAuthError::Http(reqwless::Error::Dns).needs_login());
+        assert!(!TeenyAuthError::Capacity.needs_login());
+    }
+}
@@ -1,7 +1,6 @@
-use crate::{
-    display::{BoundingBox, TeenyDisplayError},
-    prelude::*,
-};
+use embedded_graphics::{image::ImageRaw, pixelcolor::BinaryColor};
+
+use crate::prelude::*;
 
 #[rustfmt::skip]
 const PLAY_BUTTON_ICON: &[u8] = &[
@@ -51,80 +50,23 @@ const PAUSE_BUTTON_ICON: &[u8] = &[
     0b00000000
 ];
 
-pub static PLAY_SIGNAL: Signal<CriticalSectionRawMutex, bool> = Signal::new();
+pub const PLAY_ICON: ImageRaw<'static, BinaryColor> = ImageRaw::new(PLAY_BUTTON_ICON, 4);
+pub const PAUSE_ICON: ImageRaw<'static, BinaryColor> = ImageRaw::new(PAUSE_BUTTON_ICON, 4);
+pub const SKIP_ICON: ImageRaw<'static, BinaryColor> = ImageRaw::new(SKIP_BUTTON_ICON, 5);
+pub const SKIP_BACK_ICON: ImageRaw<'static, BinaryColor> = ImageRaw::new(SKIP_BACK_BUTTON_ICON, 5);
+
+pub static PLAY_CHANNEL: PubSubChannel<CriticalSectionRawMutex, bool, 2, 4, 1> =
+    PubSubChannel::new();
 
 #[task]
 pub async fn publish_play_pause(mut pin: AnyInput<'static>) {
-    let signal = &PLAY_SIGNAL;
+    let publisher = PLAY_CHANNEL.publisher().unwrap();
 
     loop {
         pin.wait_for_rising_edge().await;
         debug!("PLAY!");
 
-        signal.signal(true);
-    }
-}
-
-#[task]
-pub async fn display_play_pause(mut i2c: SharedI2C) {
-    async fn display_play_pause_internal(i2c: &mut SharedI2C) -> Result<(), TeenyDisplayError> {
-        const BOUNDING_BOX: BoundingBox = BoundingBox::new(Point::new(111, 0), Point::new(118, 7));
-
-        const PLAY_BUTTON: ImageRaw<'static, BinaryColor, BigEndian> =
-            ImageRaw::<BinaryColor>::new(PLAY_BUTTON_ICON, 4);
-
-        const PAUSE_BUTTON: ImageRaw<'static, BinaryColor, BigEndian> =
-            ImageRaw::<BinaryColor>::new(PAUSE_BUTTON_ICON, 4);
-
-        use embedded_graphics::{
-            image::*,
-            pixelcolor::{raw::BigEndian, BinaryColor},
-            prelude::*,
-            primitives::Rectangle,
-        };
-
-        let mut display = Ssd1306::new(
-            ssd1306::I2CDisplayInterface::new(i2c),
-            DisplaySize128x64,
-            DisplayRotation::Rotate0,
-        );
-        display.init().await?;
-        let mut display = display.into_buffered_graphics_mode();
-
-        let signal = &PLAY_SIGNAL;
-
-        loop {
-            display.fill_solid(
-                &Rectangle::with_corners(BOUNDING_BOX.start, BOUNDING_BOX.end),
-                BinaryColor::Off,
-            )?;
-
-            let play = signal.wait().await;
-
-            let raw_image = if play { PLAY_BUTTON } else { PAUSE_BUTTON };
-
-            Image::new(&raw_image, BOUNDING_BOX.start).draw(&mut display)?;
-
-            display.flush().await?;
-
-            Timer::after_millis(250).await;
-
-            display.fill_solid(
-                &Rectangle::with_corners(BOUNDING_BOX.start, BOUNDING_BOX.end),
-                BinaryColor::Off,
-            )?;
-
-            display.flush().await?;
-        }
-    }
-    loop {
-        if let Err(e) = display_play_pause_internal(&mut i2c).await {
-            warn!("Display error: {e:?}");
-        } else {
-            unreachable!()
-        }
-
-        Timer::after_secs(1).await;
+        publisher.publish_immediate(true);
     }
 }
 
@@ -141,18 +83,19 @@ pub async fn publish_raw_skip(mut pin: AnyInput<'static>) {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum SkipType {
     Skip,
     SkipBack,
 }
 
-pub static SKIP_SIGNAL: Signal<CriticalSectionRawMutex, crate::buttons::SkipType> = Signal::new();
+pub static SKIP_CHANNEL: PubSubChannel<CriticalSectionRawMutex, SkipType, 2, 4, 1> =
+    PubSubChannel::new();
 
 #[task]
 pub async fn publish_skip() {
     let raw_signal = &RAW_SKIP_SIGNAL;
-    let signal = &SKIP_SIGNAL;
+    let publisher = SKIP_CHANNEL.publisher().unwrap();
 
     loop {
         raw_signal.wait().await;
@@ -169,71 +112,6 @@ pub async fn publish_skip() {
 
         // raw_signal.reset();
 
-        signal.signal(SkipType::Skip);
-    }
-}
-
-#[task]
-pub async fn display_skip(mut i2c: SharedI2C) {
-    async fn display_skip_internal(i2c: &mut SharedI2C) -> Result<(), TeenyDisplayError> {
-        const BOUNDING_BOX: BoundingBox = BoundingBox::new(Point::new(120, 0), Point::new(127, 7));
-
-        const SKIP_BUTTON: ImageRaw<'static, BinaryColor, BigEndian> =
-            ImageRaw::<BinaryColor>::new(SKIP_BUTTON_ICON, 5);
-
-        const SKIPBACK_BUTTON: ImageRaw<'static, BinaryColor, BigEndian> =
-            ImageRaw::<BinaryColor>::new(SKIP_BACK_BUTTON_ICON, 5);
-
-        use embedded_graphics::{
-            image::*,
-            pixelcolor::{raw::BigEndian, BinaryColor},
-            prelude::*,
-            primitives::Rectangle,
-        };
-
-        let mut display = Ssd1306::new(
-            ssd1306::I2CDisplayInterface::new(i2c),
-            DisplaySize128x64,
-            DisplayRotation::Rotate0,
-        );
-        display.init().await?;
-        let mut display = display.into_buffered_graphics_mode();
-
-        let signal = &SKIP_SIGNAL;
-
-        loop {
-            display.fill_solid(
-                &Rectangle::with_corners(BOUNDING_BOX.start, BOUNDING_BOX.end),
-                BinaryColor::Off,
-            )?;
-
-            let skip = signal.wait().await;
-            error!("SKIP!");
-
-            let raw_image = match skip {
-                SkipType::Skip => SKIP_BUTTON,
-                SkipType::SkipBack => SKIPBACK_BUTTON,
-            };
-
-            Image::new(&raw_image, BOUNDING_BOX.start).draw(&mut display)?;
-            display.flush().await?;
-
-            Timer::after_millis(250).await;
-
-            display.fill_solid(
-                &Rectangle::with_corners(BOUNDING_BOX.start, BOUNDING_BOX.end),
-                BinaryColor::Off,
-            )?;
-
-            display.flush().await?;
-        }
-    }
-
-    loop {
-        if let Err(e) = display_skip_internal(&mut i2c).await {
-            warn!("Display error: {e:?}");
-        }
-
-        Timer::after_secs(1).await;
+        publisher.publish_immediate(SkipType::Skip);
     }
 }
@@ -0,0 +1,659 @@
+use core::{cell::Cell, convert::Infallible, ops::RangeInclusive};
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::{blocking_mutex, channel::Channel};
+use embedded_graphics::{pixelcolor::BinaryColor, prelude::*, primitives::Rectangle};
+use ssd1306::{command::AddrMode, I2CDisplayInterface};
+
+use crate::{
+    display::{
+        driver::{Controller, DisplayConfig, Driver, PanelSize, Sh1106},
+        TeenyDisplayError,
+    },
+    prelude::*,
+};
+
+/// The widest of the panels supported - buffers are sized for it, whichever
+/// panel is fitted.
+pub const MAX_WIDTH: u32 = DisplaySize128x64::WIDTH as u32;
+/// The tallest of the panels supported.
+pub const MAX_HEIGHT: u32 = DisplaySize128x64::HEIGHT as u32;
+
+/// One bit per pixel.
+const FRAME_LEN: usize = (MAX_WIDTH * MAX_HEIGHT / 8) as usize;
+
+/// The display stores pixels in pages - strips 8 rows high, with a byte for
+/// each column - and can only be sent whole bytes of them.
+const PAGES: usize = (MAX_HEIGHT / 8) as usize;
+
+/// The shortest time between two flushes - everything submitted in between is
+/// flushed together.
+pub const FRAME_TIME: Duration = Duration::from_millis(33);
+
+/// How many regions can be waiting for the next frame before
+/// [`Region::submit`] has to wait.
+const MAX_QUEUED_REGIONS: usize = 4;
+
+/// How often how long frames are taking is logged.
+const STATS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Something for the [`display_task`] to draw.
+enum Update {
+    /// A region of the screen - hidden under the overlay, if there is one.
+    Draw(Region),
+    /// A region of the overlay, putting one up over the screen if there isn't
+    /// one already.
+    Overlay(Region),
+    /// Takes the overlay down, uncovering everything drawn underneath it.
+    HideOverlay,
+}
+
+/// Updates waiting to be drawn by the [`display_task`].
+static UPDATE_CHANNEL: Channel<CriticalSectionRawMutex, Update, MAX_QUEUED_REGIONS> =
+    Channel::new();
+
+/// The panel fitted - see [`configure`].
+static CONFIG: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<Option<DisplayConfig>>> =
+    blocking_mutex::Mutex::new(Cell::new(None));
+
+/// How the panel should be set up, for the [`display_task`] - see
+/// [`set_panel`].
+static PANEL_SIGNAL: Signal<CriticalSectionRawMutex, Panel> = Signal::new();
+
+/// How the panel itself is set up, as opposed to what is drawn on it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Panel {
+    pub contrast: u8,
+    pub on: bool,
+    /// How far everything drawn is moved across the panel, so the same pixels
+    /// aren't always the ones lit.
+    pub offset: Point,
+}
+
+impl Default for Panel {
+    fn default() -> Self {
+        Self {
+            contrast: 0xff,
+            on: true,
+            offset: Point::zero(),
+        }
+    }
+}
+
+/// Has the [`display_task`] set the panel up as `panel`, once it has finished
+/// the frame it is on.
+pub fn set_panel(panel: Panel) {
+    PANEL_SIGNAL.signal(panel);
+}
+
+/// Sets which panel is fitted - before anything is laid out on it, as
+/// everything is laid out to fit the [`screen`].
+pub fn configure(config: DisplayConfig) {
+    CONFIG.lock(|cell| cell.set(Some(config)));
+}
+
+/// The panel fitted - the board's, until [`configure`]d otherwise.
+pub fn config() -> DisplayConfig {
+    CONFIG.lock(Cell::get).unwrap_or_default()
+}
+
+/// The whole of the display, the way up it is mounted.
+pub fn screen() -> Rectangle {
+    Rectangle::new(Point::zero(), config().screen_size())
+}
+
+/// A part of the display to draw on, in display coordinates. Once submitted,
+/// it replaces everything that was in its area - including pixels left off.
+#[derive(Clone)]
+pub struct Region {
+    area: Rectangle,
+    pixels: [u8; FRAME_LEN],
+}
+
+impl Region {
+    /// A blank region covering `area` (or as much of it as is on the display).
+    pub fn new(area: Rectangle) -> Self {
+        Self {
+            area: area.intersection(&screen()),
+            pixels: [0; FRAME_LEN],
+        }
+    }
+
+    /// A blank region covering the whole display.
+    pub fn full_screen() -> Self {
+        Self::new(screen())
+    }
+
+    pub fn area(&self) -> Rectangle {
+        self.area
+    }
+
+    /// Which byte of `pixels` `point` is in, and its bit in that byte.
+    fn index(&self, point: Point) -> Option<(usize, u8)> {
+        if !self.area.contains(point) {
+            return None;
+        }
+
+        let x = (point.x - self.area.top_left.x) as usize;
+        let y = (point.y - self.area.top_left.y) as usize;
+        let stride = (self.area.size.width as usize).div_ceil(8);
+
+        Some((y * stride + x / 8, 0x80 >> (x % 8)))
+    }
+
+    /// The colour of the pixel at `point` - off if it is outside the region.
+    pub fn get(&self, point: Point) -> BinaryColor {
+        match self.index(point) {
+            Some((i, bit)) => (self.pixels[i] & bit != 0).into(),
+            None => BinaryColor::Off,
+        }
+    }
+
+    /// Sets the pixel at `point`, if it is inside the region.
+    pub fn set(&mut self, point: Point, color: BinaryColor) {
+        if let Some((i, bit)) = self.index(point) {
+            if color.is_on() {
+                self.pixels[i] |= bit;
+            } else {
+                self.pixels[i] &= !bit;
+            }
+        }
+    }
+
+    /// Copies `other` over this region, marking every pixel which changed in
+    /// `dirty`.
+    fn blit(&mut self, other: &Region, dirty: &mut DirtyPages) {
+        let config = config();
+
+        for point in other.area.points() {
+            let color = other.get(point);
+
+            if self.get(point) != color {
+                self.set(point, color);
+                dirty.include(config.to_panel(point));
+            }
+        }
+    }
+
+    /// Packs `columns` of `page` of the panel the way it stores them - a byte
+    /// per column, top row in the lowest bit - turned to the way the panel is
+    /// mounted and moved by `offset`, returning how many bytes of `bytes` that
+    /// took.
+    fn page(
+        &self,
+        page: u8,
+        columns: RangeInclusive<u8>,
+        offset: Point,
+        bytes: &mut [u8],
+    ) -> usize {
+        let config = config();
+        let top = page as i32 * 8;
+        let mut len = 0;
+
+        for (x, byte) in columns.zip(bytes.iter_mut()) {
+            *byte = (0..8)
+                .map(|row| config.from_panel(Point::new(x as i32, top + row) - offset))
+                .enumerate()
+                .filter(|&(_, point)| self.get(point).is_on())
+                .fold(0, |byte, (row, _)| byte | 1 << row);
+
+            len += 1;
+        }
+
+        len
+    }
+
+    /// Hands the region to the [`display_task`], to be drawn in the next frame.
+    pub async fn submit(self) {
+        UPDATE_CHANNEL.send(Update::Draw(self)).await
+    }
+
+    /// Like [`submit`](Self::submit), but drawn over everything else until
+    /// [`hide_overlay`] - regions submitted in the meantime are kept
+    /// underneath, out of sight.
+    pub async fn submit_overlay(self) {
+        UPDATE_CHANNEL.send(Update::Overlay(self)).await
+    }
+}
+
+/// Takes down whatever was put up with [`Region::submit_overlay`], showing
+/// the screen as it would have been without it.
+pub async fn hide_overlay() {
+    UPDATE_CHANNEL.send(Update::HideOverlay).await
+}
+
+impl Dimensions for Region {
+    fn bounding_box(&self) -> Rectangle {
+        self.area
+    }
+}
+
+impl DrawTarget for Region {
+    type Color = BinaryColor;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            self.set(point, color);
+        }
+
+        Ok(())
+    }
+}
+
+/// Which columns of each page of the panel have changed since they were last
+/// sent to it - in panel coordinates, so after any rotation.
+#[derive(Debug, Clone, Default)]
+struct DirtyPages {
+    columns: [Option<RangeInclusive<u8>>; PAGES],
+}
+
+impl DirtyPages {
+    /// Every pixel of a `panel` sized panel - for when what's on the display
+    /// isn't known.
+    fn all(panel: Size) -> Self {
+        let pages = panel.height.div_ceil(8) as usize;
+
+        Self {
+            columns: core::array::from_fn(|page| (page < pages).then(|| 0..=panel.width as u8 - 1)),
+        }
+    }
+
+    fn include(&mut self, point: Point) {
+        let (x, page) = (point.x as u8, (point.y / 8) as usize);
+
+        self.columns[page] = Some(match self.columns[page].take() {
+            Some(columns) => *columns.start().min(&x)..=*columns.end().max(&x),
+            None => x..=x,
+        });
+    }
+
+    fn is_clean(&self) -> bool {
+        self.columns.iter().all(Option::is_none)
+    }
+
+    /// The dirty columns of each dirty page once everything is moved by
+    /// `offset` (and kept on a `panel` sized panel), leaving them all clean.
+    fn take(
+        &mut self,
+        offset: Point,
+        panel: Size,
+    ) -> impl Iterator<Item = (u8, RangeInclusive<u8>)> {
+        let mut moved = Self::default();
+        let bottom_right = Point::zero() + panel - Point::new(1, 1);
+
+        for (page, columns) in core::mem::take(&mut self.columns).into_iter().enumerate() {
+            let Some(columns) = columns else {
+                continue;
+            };
+
+            // a page moved up or down is spread over two, and whatever moves off the
+            // edge only needs the edge redrawn
+            let top = page as i32 * 8;
+
+            for y in [top, top + 7] {
+                for x in [*columns.start(), *columns.end()] {
+                    moved.include(
+                        (Point::new(x as i32, y) + offset)
+                            .component_max(Point::zero())
+                            .component_min(bottom_right),
+                    );
+                }
+            }
+        }
+
+        moved
+            .columns
+            .into_iter()
+            .enumerate()
+            .filter_map(|(page, columns)| Some((page as u8, columns?)))
+    }
+}
+
+/// What has been drawn on the screen, and anything over it.
+struct Layers {
+    screen: Region,
+    overlay: Option<Region>,
+}
+
+impl Layers {
+    /// Draws `update`, marking every pixel it changed on the display in
+    /// `dirty`.
+    fn apply(&mut self, update: Update, dirty: &mut DirtyPages) {
+        match update {
+            Update::Draw(region) => match self.overlay {
+                // only shows once the overlay comes down
+                Some(_) => self.screen.blit(&region, &mut DirtyPages::default()),
+                None => self.screen.blit(&region, dirty),
+            },
+            Update::Overlay(region) => self
+                .overlay
+                .get_or_insert_with(|| self.screen.clone())
+                .blit(&region, dirty),
+            Update::HideOverlay => {
+                if let Some(mut overlay) = self.overlay.take() {
+                    overlay.blit(&self.screen, dirty);
+                }
+            }
+        }
+    }
+
+    /// What should be on the display.
+    fn shown(&self) -> &Region {
+        self.overlay.as_ref().unwrap_or(&self.screen)
+    }
+}
+
+/// How long flushes have taken, and how much they sent, since they were last
+/// logged.
+struct FrameStats {
+    since: Instant,
+    frames: u32,
+    bytes: usize,
+    total: Duration,
+    worst: Duration,
+}
+
+impl FrameStats {
+    fn new() -> Self {
+        Self {
+            since: Instant::now(),
+            frames: 0,
+            bytes: 0,
+            total: Duration::default(),
+            worst: Duration::default(),
+        }
+    }
+
+    /// Adds a flush which took `time` to send `bytes` bytes, logging (and
+    /// starting again) every [`STATS_INTERVAL`].
+    fn record(&mut self, time: Duration, bytes: usize) {
+        self.frames += 1;
+        self.bytes += bytes;
+        self.total += time;
+        self.worst = self.worst.max(time);
+
+        if self.since.elapsed() < STATS_INTERVAL {
+            return;
+        }
+
+        debug!(
+            "{} frames in {}s - {}us average flush, {}us worst, {} bytes per frame",
+            self.frames,
+            self.since.elapsed().as_secs(),
+            self.total.as_micros() / self.frames as u64,
+            self.worst.as_micros(),
+            self.bytes / self.frames as usize,
+        );
+
+        *self = Self::new();
+    }
+}
+
+/// Keeps `display` showing `layers`, set up as `panel` says, until it fails.
+async fn drive(
+    display: &mut impl Driver,
+    layers: &mut Layers,
+    panel: &mut Panel,
+) -> Result<(), TeenyDisplayError> {
+    display.set_contrast(panel.contrast).await?;
+    display.set_on(panel.on).await?;
+
+    let size = config().panel_size();
+
+    // the panel may have been reset - start from what should be on it
+    let mut dirty = DirtyPages::all(size);
+
+    let mut stats = FrameStats::new();
+    let mut bytes = [0; MAX_WIDTH as usize];
+    let mut last_flush = Instant::now();
+
+    loop {
+        if dirty.is_clean() {
+            match select(UPDATE_CHANNEL.receive(), PANEL_SIGNAL.wait()).await {
+                Either::First(update) => layers.apply(update, &mut dirty),
+                Either::Second(new_panel) => {
+                    if new_panel.contrast != panel.contrast {
+                        display.set_contrast(new_panel.contrast).await?;
+                    }
+
+                    if new_panel.on != panel.on {
+                        display.set_on(new_panel.on).await?;
+                    }
+
+                    if new_panel.offset != panel.offset {
+                        dirty = DirtyPages::all(size);
+                    }
+
+                    *panel = new_panel;
+                    continue;
+                }
+            }
+
+            Timer::at(last_flush + FRAME_TIME).await;
+        }
+
+        // merge in everything else submitted since
+        while let Ok(update) = UPDATE_CHANNEL.try_receive() {
+            layers.apply(update, &mut dirty);
+        }
+
+        // whatever was submitted was already on the display
+        if dirty.is_clean() {
+            continue;
+        }
+
+        let start = Instant::now();
+        let mut sent = 0;
+
+        for (page, columns) in dirty.take(panel.offset, size) {
+            let left = *columns.start();
+            let len = layers.shown().page(page, columns, panel.offset, &mut bytes);
+
+            display.draw_page(page, left, &bytes[..len]).await?;
+
+            sent += len;
+        }
+
+        last_flush = Instant::now();
+        stats.record(last_flush - start, sent);
+    }
+}
+
+/// Sets up an SSD1306 of `size`, and [`drive`]s it.
+async fn drive_ssd1306<SIZE: DisplaySize>(
+    interface: I2CInterface<&mut SharedI2C>,
+    size: SIZE,
+    layers: &mut Layers,
+    panel: &mut Panel,
+) -> Result<(), TeenyDisplayError> {
+    let mut display = Ssd1306::new(interface, size, DisplayRotation::Rotate0);
+    // so a draw area can span pages, not just columns
+    display.init_with_addr_mode(AddrMode::Horizontal).await?;
+
+    drive(&mut display, layers, panel).await
+}
+
+/// Owns the display - the only task which talks to it. Everything else draws
+/// into a [`Region`] and [`submit`](Region::submit)s it.
+///
+/// Only the pages which changed are sent, and only the columns of them which
+/// changed - so flashing an icon costs a few bytes over I2C rather than the
+/// whole kilobyte of the display.
+#[task]
+pub async fn display_task(mut i2c: SharedI2C) {
+    async fn display_task_internal(
+        i2c: &mut SharedI2C,
+        layers: &mut Layers,
+        panel: &mut Panel,
+    ) -> Result<(), TeenyDisplayError> {
+        let config = config();
+        let interface = I2CDisplayInterface::new_custom_address(i2c, config.address);
+
+        match (config.controller, config.size) {
+            (Controller::Ssd1306, PanelSize::Size128x64) => {
+                drive_ssd1306(interface, DisplaySize128x64, layers, panel).await
+            }
+            (Controller::Ssd1306, PanelSize::Size128x32) => {
+                drive_ssd1306(interface, DisplaySize128x32, layers, panel).await
+            }
+            (Controller::Ssd1306, PanelSize::Size72x40) => {
+                drive_ssd1306(interface, DisplaySize72x40, layers, panel).await
+            }
+            (Controller::Sh1106, size) => {
+                let mut display = Sh1106::new(interface, size);
+                display.init().await?;
+
+                drive(&mut display, layers, panel).await
+            }
+        }
+    }
+
+    info!("Driving {:?}", config());
+
+    // kept across errors, so the display can be redrawn once it is back
+    let mut layers = Layers {
+        screen: Region::full_screen(),
+        overlay: None,
+    };
+    let mut panel = Panel::default();
+
+    loop {
+        if let Err(e) = display_task_internal(&mut i2c, &mut layers, &mut panel).await {
+            warn!("Display error: {e:?}");
+        }
+
+        Timer::after_secs(1).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PANEL: Size = Size::new(128, 64);
+
+    fn dirtied(points: &[(i32, i32)]) -> DirtyPages {
+        let mut dirty = DirtyPages::default();
+
+        for &(x, y) in points {
+            dirty.include(Point::new(x, y));
+        }
+
+        dirty
+    }
+
+    fn take(
+        dirty: &mut DirtyPages,
+        offset: (i32, i32),
+        panel: Size,
+    ) -> std::vec::Vec<(u8, RangeInclusive<u8>)> {
+        dirty.take(Point::new(offset.0, offset.1), panel).collect()
+    }
+
+    #[test]
+    fn starts_clean() {
+        let mut dirty = DirtyPages::default();
+
+        assert!(dirty.is_clean());
+        assert_eq!(take(&mut dirty, (0, 0), PANEL), []);
+    }
+
+    #[test]
+    fn a_pixel_dirties_its_column_of_its_page() {
+        let mut dirty = dirtied(&[(5, 10)]);
+
+        assert!(!dirty.is_clean());
+        assert_eq!(take(&mut dirty, (0, 0), PANEL), [(1, 5..=5)]);
+
+        // and leaves it clean
+        assert!(dirty.is_clean());
+        assert_eq!(take(&mut dirty, (0, 0), PANEL), []);
+    }
+
+    #[test]
+    fn pixels_on_a_page_dirty_the_columns_between_them() {
+        let mut dirty = dirtied(&[(9, 8), (5, 15), (7, 11)]);
+
+        assert_eq!(take(&mut dirty, (0, 0), PANEL), [(1, 5..=9)]);
+    }
+
+    #[test]
+    fn each_page_keeps_its_own_columns() {
+        let mut dirty = dirtied(&[(10, 7), (20, 8), (30, 63), (40, 9)]);
+
+        assert_eq!(
+            take(&mut dirty, (0, 0), PANEL),
+            [(0, 10..=10), (1, 20..=40), (7, 30..=30)]
+        );
+    }
+
+    #[test]
+    fn offsets_move_the_columns() {
+        let mut dirty = dirtied(&[(10, 0), (20, 0)]);
+
+        assert_eq!(take(&mut dirty, (2, 0), PANEL), [(0, 12..=22)]);
+
+        let mut dirty = dirtied(&[(10, 0), (20, 0)]);
+
+        assert_eq!(take(&mut dirty, (-2, 0), PANEL), [(0, 8..=18)]);
+    }
+
+    #[test]
+    fn offsets_spread_a_page_over_two() {
+        // anywhere on page 1 might end up on page 1 or 2 once moved down
+        let mut dirty = dirtied(&[(10, 8)]);
+
+        assert_eq!(
+            take(&mut dirty, (1, 3), PANEL),
+            [(1, 11..=11), (2, 11..=11)]
+        );
+
+        // or page 0 or 1 once moved up
+        let mut dirty = dirtied(&[(10, 15)]);
+
+        assert_eq!(
+            take(&mut dirty, (0, -1), PANEL),
+            [(0, 10..=10), (1, 10..=10)]
+        );
+
+        // but whole pages stay whole pages
+        let mut dirty = dirtied(&[(10, 8)]);
+
+        assert_eq!(take(&mut dirty, (0, 8), PANEL), [(2, 10..=10)]);
+    }
+
+    #[test]
+    fn offsets_off_the_edge_dirty_the_edge() {
+        let mut dirty = dirtied(&[(127, 60)]);
+
+        assert_eq!(take(&mut dirty, (2, 2), PANEL), [(7, 127..=127)]);
+
+        let mut dirty = dirtied(&[(0, 0), (3, 0)]);
+
+        assert_eq!(take(&mut dirty, (-3, -3), PANEL), [(0, 0..=0)]);
+
+        let mut dirty = dirtied(&[(100, 30)]);
+
+        // a smaller panel's edge
+        assert_eq!(take(&mut dirty, (0, 20), Size::new(72, 40)), [(4, 71..=71)]);
+    }
+
+    #[test]
+    fn all_covers_the_whole_panel() {
+        for (panel, pages) in [(PANEL, 8), (Size::new(128, 32), 4), (Size::new(72, 40), 5)] {
+            let mut all = DirtyPages::all(panel);
+            let last = panel.width as u8 - 1;
+
+            assert_eq!(
+                take(&mut all, (0, 0), panel),
+                (0..pages)
+                    .map(|page| (page, 0..=last))
+                    .collect::<std::vec::Vec<_>>()
+            );
+        }
+    }
+}
@@ -0,0 +1,336 @@
+use core::future::pending;
+
+use embassy_futures::select::{select, select4, Either, Either4};
+use embassy_sync::channel::Channel;
+use embedded_nal_async::{Dns, TcpConnect};
+use reqwless::client::{HttpClient, TlsConfig, TlsVerify};
+
+use crate::{
+    auth,
+    buttons::{SkipType, PLAY_CHANNEL, SKIP_CHANNEL},
+    idle,
+    menu::{self, Input, COMBO_WINDOW},
+    net::{StaClient, StaStack, TLS_BUFFER_LEN},
+    playback::{self, PLAYBACK_CHANNEL},
+    prelude::*,
+    spotify::{Devices, Spotify, TeenySpotifyError},
+};
+
+/// How many commands can be waiting on the network before new ones get
+/// dropped.
+pub const COMMAND_QUEUE_SIZE: usize = 4;
+
+/// How long the knob has to stay still before its volume is sent.
+const VOLUME_SETTLE: Duration = Duration::from_millis(300);
+
+/// The longest we'll wait for a rate limit to pass before giving up on a
+/// command.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(10);
+
+/// Something for the [`Spotify`] client to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    TogglePlayback,
+    Next,
+    Previous,
+    /// Volume in percent.
+    SetVolume(u8),
+    /// Fetches the devices, for [`DEVICES_SIGNAL`].
+    ListDevices,
+    /// Moves playback to the device at this index of the last
+    /// [`Command::ListDevices`].
+    Transfer(usize),
+}
+
+pub static COMMAND_CHANNEL: Channel<CriticalSectionRawMutex, Command, COMMAND_QUEUE_SIZE> =
+    Channel::new();
+
+/// The devices from the last [`list_devices`], or `None` if they couldn't be
+/// fetched.
+pub static DEVICES_SIGNAL: Signal<CriticalSectionRawMutex, Option<Devices>> = Signal::new();
+
+/// Fetches the devices playback can be moved to - they turn up on
+/// [`DEVICES_SIGNAL`].
+pub fn list_devices() {
+    DEVICES_SIGNAL.reset();
+    enqueue(Command::ListDevices);
+}
+
+/// Moves playback to the device at `index` of the last [`list_devices`].
+pub fn transfer(index: usize) {
+    enqueue(Command::Transfer(index));
+}
+
+/// Queues `command` without ever waiting - if the network can't keep up, it is
+/// better to drop a press than to stop reading the buttons.
+fn enqueue(command: Command) {
+    if COMMAND_CHANNEL.try_send(command).is_err() {
+        warn!("Command queue is full, dropping {command:?}");
+    }
+}
+
+/// A press of one of the buttons.
+#[derive(Debug, Clone, Copy)]
+enum Press {
+    Play,
+    Skip(SkipType),
+}
+
+impl Press {
+    /// What the press does to the player.
+    fn command(self) -> Command {
+        match self {
+            Press::Play => Command::TogglePlayback,
+            Press::Skip(SkipType::Skip) => Command::Next,
+            Press::Skip(SkipType::SkipBack) => Command::Previous,
+        }
+    }
+
+    /// What the press does in the menu.
+    fn input(self) -> Input {
+        match self {
+            Press::Play => Input::Select,
+            Press::Skip(_) => Input::Back,
+        }
+    }
+}
+
+/// Turns button presses and knob movements into [`Command`]s - or, while the
+/// menu is open, into menu [`Input`]s.
+///
+/// Pressing play and skip within [`COMBO_WINDOW`] of each other opens the menu,
+/// so each press waits that long to see if the other button follows.
+#[task]
+pub async fn controller() {
+    let mut play = PLAY_CHANNEL.subscriber().unwrap();
+    let mut skip = SKIP_CHANNEL.subscriber().unwrap();
+    let mut volume = VOLUME_CHANNEL.subscriber().unwrap();
+
+    let mut pending_volume: Option<f32> = None;
+    // a press which may yet be half of the combo, and when it was
+    let mut pending_press: Option<(Press, Instant)> = None;
+
+    loop {
+        // restarted by every new knob reading, so only fires once the knob stops moving
+        let settle = async move {
+            match pending_volume {
+                Some(_) => Timer::after(VOLUME_SETTLE).await,
+                None => pending().await,
+            }
+        };
+
+        let combo_timeout = async move {
+            match pending_press {
+                Some((_, at)) => Timer::at(at + COMBO_WINDOW).await,
+                None => pending().await,
+            }
+        };
+
+        let input = select(
+            select4(
+                play.next_message_pure(),
+                skip.next_message_pure(),
+                volume.next_message_pure(),
+                settle,
+            ),
+            combo_timeout,
+        )
+        .await;
+
+        if let Either::First(Either4::First(_) | Either4::Second(_) | Either4::Third(_)) = input {
+            idle::wake();
+        }
+
+        let press = match input {
+            Either::First(Either4::First(_)) => Press::Play,
+            Either::First(Either4::Second(skip)) => Press::Skip(skip),
+            Either::First(Either4::Third(value)) => {
+                if menu::is_open() {
+                    menu::send(Input::Knob(value));
+                } else {
+                    pending_volume = Some(value);
+                }
+                continue;
+            }
+            Either::First(Either4::Fourth(())) => {
+                if let Some(value) = pending_volume.take() {
+                    enqueue(Command::SetVolume((value.clamp(0., 1.) * 100. + 0.5) as u8));
+                }
+                continue;
+            }
+            // nothing followed it, so it was just a press
+            Either::Second(()) => {
+                if let Some((press, _)) = pending_press.take() {
+                    enqueue(press.command());
+                }
+                continue;
+            }
+        };
+
+        if menu::is_open() {
+            menu::send(press.input());
+            continue;
+        }
+
+        match pending_press.take() {
+            Some((Press::Play, _)) if matches!(press, Press::Skip(_)) => menu::open(),
+            Some((Press::Skip(_), _)) if matches!(press, Press::Play) => menu::open(),
+            other => {
+                // the same button twice is just two presses
+                if let Some((other, _)) = other {
+                    enqueue(other.command());
+                }
+
+                pending_press = Some((press, Instant::now()));
+            }
+        }
+    }
+}
+
+/// Runs queued [`Command`]s against the player api, one at a time.
+pub struct CommandRunner {
+    /// Our best guess at whether something is playing, so play/pause knows
+    /// which one to send.
+    is_playing: bool,
+    /// The last devices listed, which [`Command::Transfer`] picks from.
+    devices: Devices,
+}
+
+impl Default for CommandRunner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CommandRunner {
+    pub const fn new() -> Self {
+        Self {
+            is_playing: false,
+            devices: Devices::new(),
+        }
+    }
+
+    pub fn set_playing(&mut self, is_playing: bool) {
+        self.is_playing = is_playing;
+    }
+
+    /// Takes commands off [`COMMAND_CHANNEL`] forever, keeping track of
+    /// whether something is playing from [`PLAYBACK_CHANNEL`].
+    pub async fn run<T, D>(&mut self, spotify: &mut Spotify<'_, '_, T, D>) -> !
+    where
+        T: TcpConnect,
+        D: Dns,
+    {
+        let mut playback = PLAYBACK_CHANNEL.subscriber().unwrap();
+
+        loop {
+            match select(COMMAND_CHANNEL.receive(), playback.next_message_pure()).await {
+                Either::First(command) => {
+                    let result = self.execute(spotify, command).await;
+
+                    if let Err(e) = &result {
+                        warn!("Failed to run {command:?}: {e:?}");
+                    }
+
+                    if command == Command::ListDevices {
+                        // the menu is waiting for them either way
+                        DEVICES_SIGNAL.signal(result.ok().map(|()| self.devices.clone()));
+                    }
+
+                    // show the effects of the command without waiting for the next poll
+                    playback::poll_now();
+                }
+                Either::Second(state) => {
+                    self.set_playing(state.is_some_and(|state| state.is_playing));
+                }
+            }
+        }
+    }
+
+    /// Runs a single command, retrying once if the token was stale or we were
+    /// briefly rate limited.
+    pub async fn execute<T, D>(
+        &mut self,
+        spotify: &mut Spotify<'_, '_, T, D>,
+        command: Command,
+    ) -> Result<(), TeenySpotifyError>
+    where
+        T: TcpConnect,
+        D: Dns,
+    {
+        debug!("Running {command:?}");
+
+        match self.send(spotify, command).await {
+            Err(TeenySpotifyError::Unauthorized) => {
+                // the token has been invalidated, so this waits for the refreshed one
+                auth::access_token().await;
+                self.send(spotify, command).await
+            }
+            Err(TeenySpotifyError::RateLimited { retry_after })
+                if retry_after <= MAX_RETRY_AFTER =>
+            {
+                Timer::after(retry_after).await;
+                self.send(spotify, command).await
+            }
+            result => result,
+        }
+    }
+
+    async fn send<T, D>(
+        &mut self,
+        spotify: &mut Spotify<'_, '_, T, D>,
+        command: Command,
+    ) -> Result<(), TeenySpotifyError>
+    where
+        T: TcpConnect,
+        D: Dns,
+    {
+        match command {
+            Command::TogglePlayback => {
+                if self.is_playing {
+                    spotify.pause().await?;
+                } else {
+                    spotify.play().await?;
+                }
+                self.is_playing = !self.is_playing;
+                Ok(())
+            }
+            Command::Next => spotify.next().await,
+            Command::Previous => spotify.previous().await,
+            Command::SetVolume(percent) => spotify.set_volume(percent).await,
+            Command::ListDevices => {
+                self.devices = spotify.devices().await?;
+                Ok(())
+            }
+            Command::Transfer(index) => {
+                let Some(device) = self.devices.get(index) else {
+                    warn!("There is no device {index} to move playback to");
+                    return Ok(());
+                };
+
+                // carries on playing, or stays paused, on the new device
+                spotify.transfer_playback(&device.id, self.is_playing).await
+            }
+        }
+    }
+}
+
+/// Sends the [`controller`]'s commands to spotify over the station interface.
+#[task]
+pub async fn command_task(stack: &'static StaStack, mut rng: Rng) {
+    let client = StaClient::new(stack);
+
+    let mut rx_buf = [0; TLS_BUFFER_LEN];
+    let mut tx_buf = [0; TLS_BUFFER_LEN];
+
+    let (tcp_client, dns_socket) = client.ready().await;
+
+    let seed = ((rng.random() as u64) << u32::BITS) + rng.random() as u64;
+    let tls = TlsConfig::new(seed, &mut rx_buf, &mut tx_buf, TlsVerify::None);
+
+    let mut http = HttpClient::new_with_tls(&tcp_client, &dns_socket, tls);
+
+    CommandRunner::default()
+        .run(&mut Spotify::new(&mut http))
+        .await
+}
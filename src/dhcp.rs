@@ -0,0 +1,905 @@
+use embassy_net::{
+    udp::{PacketMetadata, UdpSocket},
+    IpEndpoint, Ipv4Address, Stack,
+};
+use esp_wifi::wifi::{WifiApDevice, WifiDevice};
+
+use crate::prelude::*;
+
+pub const SERVER_PORT: u16 = 67;
+pub const CLIENT_PORT: u16 = 68;
+
+/// The most clients that can have an address at once.
+pub const MAX_LEASES: usize = 16;
+
+/// How long an offered address is kept for a client before it has to ask
+/// again.
+const OFFER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Big enough for any request a client should send us.
+const MAX_PACKET_LEN: usize = 576;
+
+/// Some (older, BOOTP) clients drop anything shorter than this.
+const MIN_PACKET_LEN: usize = 300;
+
+/// Everything up to and including `file`, before the options start.
+const HEADER_LEN: usize = 236;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+
+const OP_REQUEST: u8 = 1;
+const OP_REPLY: u8 = 2;
+const HTYPE_ETHERNET: u8 = 1;
+const FLAG_BROADCAST: u16 = 1 << 15;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS_SERVER: u8 = 6;
+const OPTION_REQUESTED_ADDRESS: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_END: u8 = 255;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum MessageType {
+    Discover = 1,
+    Offer = 2,
+    Request = 3,
+    Decline = 4,
+    Ack = 5,
+    Nak = 6,
+    Release = 7,
+    Inform = 8,
+}
+
+impl MessageType {
+    fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            1 => Self::Discover,
+            2 => Self::Offer,
+            3 => Self::Request,
+            4 => Self::Decline,
+            5 => Self::Ack,
+            6 => Self::Nak,
+            7 => Self::Release,
+            8 => Self::Inform,
+            _ => return None,
+        })
+    }
+}
+
+/// How the access point hands out addresses.
+#[derive(Debug, Clone, Copy)]
+pub struct DhcpConfig {
+    /// Our own address - clients use it as their router and dns server.
+    pub server: [u8; 4],
+    pub netmask: [u8; 4],
+    /// The first address handed out.
+    pub pool_start: [u8; 4],
+    /// How many addresses (from `pool_start`) can be handed out - at most
+    /// [`MAX_LEASES`].
+    pub pool_size: u8,
+    pub lease_time: Duration,
+}
+
+impl Default for DhcpConfig {
+    fn default() -> Self {
+        Self {
+            server: [192, 168, 0, 1],
+            netmask: [255, 255, 255, 0],
+            pool_start: [192, 168, 0, 100],
+            pool_size: MAX_LEASES as u8,
+            lease_time: Duration::from_secs(2 * 60 * 60),
+        }
+    }
+}
+
+impl DhcpConfig {
+    fn pool_size(&self) -> u8 {
+        self.pool_size.min(MAX_LEASES as u8)
+    }
+
+    fn pool_address(&self, index: u8) -> [u8; 4] {
+        (u32::from_be_bytes(self.pool_start) + index as u32).to_be_bytes()
+    }
+
+    fn in_pool(&self, address: [u8; 4]) -> bool {
+        u32::from_be_bytes(address)
+            .checked_sub(u32::from_be_bytes(self.pool_start))
+            .is_some_and(|index| index < self.pool_size() as u32)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeaseState {
+    /// Offered to a client which hasn't accepted it yet.
+    Offered,
+    Bound,
+    /// A client told us someone else is already using it.
+    Declined,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lease {
+    pub mac: [u8; 6],
+    pub address: [u8; 4],
+    pub state: LeaseState,
+    pub expires_at: Instant,
+}
+
+/// The parts of a client's message we care about.
+struct Message<'a> {
+    message_type: MessageType,
+    xid: [u8; 4],
+    flags: u16,
+    ciaddr: [u8; 4],
+    chaddr: &'a [u8; 16],
+    mac: [u8; 6],
+    requested_address: Option<[u8; 4]>,
+    server_id: Option<[u8; 4]>,
+}
+
+impl<'a> Message<'a> {
+    fn parse(packet: &'a [u8]) -> Option<Self> {
+        if packet.len() < HEADER_LEN + MAGIC_COOKIE.len()
+            || packet[0] != OP_REQUEST
+            || packet[1] != HTYPE_ETHERNET
+            || packet[2] != 6
+            || packet[HEADER_LEN..HEADER_LEN + 4] != MAGIC_COOKIE
+        {
+            return None;
+        }
+
+        let mut message_type = None;
+        let mut requested_address = None;
+        let mut server_id = None;
+
+        let mut options = &packet[HEADER_LEN + 4..];
+
+        while let Some((&code, rest)) = options.split_first() {
+            match code {
+                OPTION_PAD => {
+                    options = rest;
+                    continue;
+                }
+                OPTION_END => break,
+                _ => {}
+            }
+
+            let (&len, rest) = rest.split_first()?;
+            let value = rest.get(..len as usize)?;
+
+            match (code, value) {
+                (OPTION_MESSAGE_TYPE, &[value]) => message_type = MessageType::from_u8(value),
+                (OPTION_REQUESTED_ADDRESS, &[a, b, c, d]) => requested_address = Some([a, b, c, d]),
+                (OPTION_SERVER_ID, &[a, b, c, d]) => server_id = Some([a, b, c, d]),
+                _ => {}
+            }
+
+            options = &rest[len as usize..];
+        }
+
+        Some(Self {
+            message_type: message_type?,
+            xid: packet[4..8].try_into().ok()?,
+            flags: u16::from_be_bytes([packet[10], packet[11]]),
+            ciaddr: packet[12..16].try_into().ok()?,
+            chaddr: packet[28..44].try_into().ok()?,
+            mac: packet[28..34].try_into().ok()?,
+            requested_address,
+            server_id,
+        })
+    }
+}
+
+/// Writes options after the header of a reply.
+struct Options<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Options<'_> {
+    fn push(&mut self, code: u8, value: &[u8]) -> Option<&mut Self> {
+        let option = self.buf.get_mut(self.len..self.len + 2 + value.len())?;
+
+        option[0] = code;
+        option[1] = value.len() as u8;
+        option[2..].copy_from_slice(value);
+
+        self.len += 2 + value.len();
+
+        Some(self)
+    }
+}
+
+/// Hands out addresses from [`DhcpConfig`]'s pool, keeping track of who has
+/// which.
+pub struct DhcpServer {
+    config: DhcpConfig,
+    leases: Vec<Lease, MAX_LEASES>,
+}
+
+impl DhcpServer {
+    pub fn new(config: DhcpConfig) -> Self {
+        Self {
+            config,
+            leases: Vec::new(),
+        }
+    }
+
+    pub fn config(&self) -> &DhcpConfig {
+        &self.config
+    }
+
+    pub fn leases(&self) -> &[Lease] {
+        &self.leases
+    }
+
+    fn expire(&mut self, now: Instant) {
+        self.leases.retain(|lease| lease.expires_at > now);
+    }
+
+    fn lease_for(&self, mac: [u8; 6]) -> Option<&Lease> {
+        self.leases
+            .iter()
+            .find(|lease| lease.mac == mac && lease.state != LeaseState::Declined)
+    }
+
+    /// Whether `address` could be given to `mac`.
+    fn is_available(&self, address: [u8; 4], mac: [u8; 6]) -> bool {
+        self.config.in_pool(address)
+            && address != self.config.server
+            && self
+                .leases
+                .iter()
+                .all(|lease| lease.address != address || lease.mac == mac)
+    }
+
+    /// The address `mac` should be offered - the one it already has, the one
+    /// it asked for or the first free one, in that order.
+    fn choose_address(&self, mac: [u8; 6], requested: Option<[u8; 4]>) -> Option<[u8; 4]> {
+        if let Some(lease) = self.lease_for(mac) {
+            return Some(lease.address);
+        }
+
+        if let Some(requested) = requested.filter(|&address| self.is_available(address, mac)) {
+            return Some(requested);
+        }
+
+        (0..self.config.pool_size())
+            .map(|index| self.config.pool_address(index))
+            .find(|&address| self.is_available(address, mac))
+    }
+
+    /// Gives `address` to `mac`, replacing whatever it had before - or returns
+    /// `None` if there is no room left to remember it.
+    fn assign(
+        &mut self,
+        mac: [u8; 6],
+        address: [u8; 4],
+        state: LeaseState,
+        expires_at: Instant,
+    ) -> Option<()> {
+        self.leases
+            .retain(|lease| !(lease.mac == mac && lease.state != LeaseState::Declined));
+
+        let lease = Lease {
+            mac,
+            address,
+            state,
+            expires_at,
+        };
+
+        if self.leases.is_full() {
+            // expired leases are already gone, so only a declined address can make way -
+            // the one which would have been given up soonest
+            let declined = self
+                .leases
+                .iter()
+                .enumerate()
+                .filter(|(_, lease)| lease.state == LeaseState::Declined)
+                .min_by_key(|(_, lease)| lease.expires_at)
+                .map(|(index, _)| index);
+
+            let Some(index) = declined else {
+                warn!("No room left to remember a lease for {mac:02x?}");
+                return None;
+            };
+
+            self.leases.swap_remove(index);
+        }
+
+        self.leases.push(lease).ok()
+    }
+
+    /// Answers the client message in `request`, writing the reply into
+    /// `response`. Returns how long the reply is, or `None` if there is
+    /// nothing to send back.
+    pub fn handle(&mut self, request: &[u8], now: Instant, response: &mut [u8]) -> Option<usize> {
+        let message = Message::parse(request)?;
+
+        self.expire(now);
+
+        let (reply_type, address) = match message.message_type {
+            MessageType::Discover => {
+                let Some(address) = self.choose_address(message.mac, message.requested_address)
+                else {
+                    warn!("No free addresses left to offer");
+                    return None;
+                };
+
+                self.assign(
+                    message.mac,
+                    address,
+                    LeaseState::Offered,
+                    now + OFFER_TIMEOUT,
+                )?;
+
+                (MessageType::Offer, address)
+            }
+            MessageType::Request => {
+                if message
+                    .server_id
+                    .is_some_and(|server_id| server_id != self.config.server)
+                {
+                    // the client went with another server's offer
+                    self.leases.retain(|lease| {
+                        !(lease.mac == message.mac && lease.state == LeaseState::Offered)
+                    });
+                    return None;
+                }
+
+                let address = message.requested_address.unwrap_or(message.ciaddr);
+
+                if self.is_available(address, message.mac) {
+                    self.assign(
+                        message.mac,
+                        address,
+                        LeaseState::Bound,
+                        now + self.config.lease_time,
+                    )?;
+
+                    (MessageType::Ack, address)
+                } else {
+                    (MessageType::Nak, [0; 4])
+                }
+            }
+            MessageType::Decline => {
+                let address = message.requested_address?;
+
+                // only the client we gave the address to can decline it, or anyone could
+                // take addresses out of the pool
+                let is_theirs = self.config.in_pool(address)
+                    && self
+                        .lease_for(message.mac)
+                        .is_some_and(|lease| lease.address == address);
+
+                if !is_theirs {
+                    debug!(
+                        "Ignoring decline of {address:?}, which {:02x?} doesn't have",
+                        message.mac
+                    );
+                    return None;
+                }
+
+                warn!("Address {address:?} is already in use by someone else");
+
+                // there is always room, as this takes the place of the client's lease
+                self.leases.retain(|lease| lease.mac != message.mac);
+                self.assign(
+                    [0; 6],
+                    address,
+                    LeaseState::Declined,
+                    now + self.config.lease_time,
+                );
+
+                return None;
+            }
+            MessageType::Release => {
+                self.leases
+                    .retain(|lease| !(lease.mac == message.mac && lease.address == message.ciaddr));
+
+                return None;
+            }
+            MessageType::Inform => (MessageType::Ack, [0; 4]),
+            MessageType::Offer | MessageType::Ack | MessageType::Nak => return None,
+        };
+
+        debug!(
+            "Answering {:?} from {:02x?} with {reply_type:?} of {address:?}",
+            message.message_type, message.mac
+        );
+
+        self.write_reply(&message, reply_type, address, response)
+    }
+
+    fn write_reply(
+        &self,
+        message: &Message,
+        reply_type: MessageType,
+        address: [u8; 4],
+        response: &mut [u8],
+    ) -> Option<usize> {
+        let header = response.get_mut(..HEADER_LEN + MAGIC_COOKIE.len())?;
+
+        header.fill(0);
+        header[0] = OP_REPLY;
+        header[1] = HTYPE_ETHERNET;
+        header[2] = 6;
+        header[4..8].copy_from_slice(&message.xid);
+        header[10..12].copy_from_slice(&(message.flags & FLAG_BROADCAST).to_be_bytes());
+        if message.message_type == MessageType::Inform {
+            header[12..16].copy_from_slice(&message.ciaddr);
+        }
+        header[16..20].copy_from_slice(&address);
+        header[20..24].copy_from_slice(&self.config.server);
+        header[28..44].copy_from_slice(message.chaddr);
+        header[HEADER_LEN..].copy_from_slice(&MAGIC_COOKIE);
+
+        let mut options = Options {
+            buf: response,
+            len: HEADER_LEN + MAGIC_COOKIE.len(),
+        };
+
+        options
+            .push(OPTION_MESSAGE_TYPE, &[reply_type as u8])?
+            .push(OPTION_SERVER_ID, &self.config.server)?;
+
+        if reply_type != MessageType::Nak {
+            options
+                .push(OPTION_SUBNET_MASK, &self.config.netmask)?
+                .push(OPTION_ROUTER, &self.config.server)?
+                .push(OPTION_DNS_SERVER, &self.config.server)?;
+
+            if message.message_type != MessageType::Inform {
+                let lease_time = self.config.lease_time.as_secs().min(u32::MAX as u64) as u32;
+
+                options.push(OPTION_LEASE_TIME, &lease_time.to_be_bytes())?;
+            }
+        }
+
+        let mut len = options.len;
+
+        *response.get_mut(len)? = OPTION_END;
+        len += 1;
+
+        if len < MIN_PACKET_LEN {
+            response.get_mut(len..MIN_PACKET_LEN)?.fill(OPTION_PAD);
+            len = MIN_PACKET_LEN;
+        }
+
+        Some(len)
+    }
+}
+
+/// Hands out addresses to everyone who joins the access point, pointing them
+/// at the device for their router and dns.
+#[task]
+pub async fn dhcp_task(
+    stack: &'static Stack<WifiDevice<'static, WifiApDevice>>,
+    config: DhcpConfig,
+) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; MAX_PACKET_LEN * 2];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; MAX_PACKET_LEN * 2];
+
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+
+    socket
+        .bind(SERVER_PORT)
+        .expect("nothing else should be bound to the dhcp port");
+
+    let mut server = DhcpServer::new(config);
+
+    let mut request = [0; MAX_PACKET_LEN];
+    let mut response = [0; MAX_PACKET_LEN];
+
+    // clients don't have an address to send to yet, so every reply is broadcast
+    let broadcast = IpEndpoint::new(Ipv4Address::BROADCAST.into(), CLIENT_PORT);
+
+    loop {
+        let len = match socket.recv_from(&mut request).await {
+            Ok((len, _)) => len,
+            Err(e) => {
+                warn!("Failed to receive dhcp request: {e:?}");
+                continue;
+            }
+        };
+
+        let Some(len) = server.handle(&request[..len], Instant::now(), &mut response) else {
+            continue;
+        };
+
+        if let Err(e) = socket.send_to(&response[..len], broadcast).await {
+            warn!("Failed to send dhcp reply: {e:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE: [u8; 6] = [0x02, 0, 0, 0, 0, 0xa1];
+    const BOB: [u8; 6] = [0x02, 0, 0, 0, 0, 0xb0];
+
+    const SERVER: [u8; 4] = [192, 168, 0, 1];
+    const FIRST: [u8; 4] = [192, 168, 0, 100];
+    const SECOND: [u8; 4] = [192, 168, 0, 101];
+
+    /// A client message, as it comes off the wire.
+    fn packet(
+        message_type: MessageType,
+        mac: [u8; 6],
+        ciaddr: [u8; 4],
+        options: &[(u8, &[u8])],
+    ) -> std::vec::Vec<u8> {
+        let mut packet = std::vec![0; HEADER_LEN];
+
+        packet[0] = OP_REQUEST;
+        packet[1] = HTYPE_ETHERNET;
+        packet[2] = 6;
+        packet[4..8].copy_from_slice(&[0xde, 0xad, 0xbe, 0xef]);
+        packet[10..12].copy_from_slice(&FLAG_BROADCAST.to_be_bytes());
+        packet[12..16].copy_from_slice(&ciaddr);
+        packet[28..34].copy_from_slice(&mac);
+        packet.extend_from_slice(&MAGIC_COOKIE);
+        packet.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, message_type as u8]);
+
+        for (code, value) in options {
+            packet.extend_from_slice(&[*code, value.len() as u8]);
+            packet.extend_from_slice(value);
+        }
+
+        packet.push(OPTION_END);
+        packet.resize(MIN_PACKET_LEN, OPTION_PAD);
+
+        packet
+    }
+
+    /// The type of a reply, and the address in it.
+    fn reply(
+        server: &mut DhcpServer,
+        packet: &[u8],
+        now: Instant,
+    ) -> Option<(MessageType, [u8; 4])> {
+        let mut response = [0; MAX_PACKET_LEN];
+        let len = server.handle(packet, now, &mut response)?;
+        let response = &response[..len];
+
+        assert!(len >= MIN_PACKET_LEN);
+        assert_eq!(response[0], OP_REPLY);
+        assert_eq!(response[4..8], [0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(response[HEADER_LEN..HEADER_LEN + 4], MAGIC_COOKIE);
+        assert_eq!(
+            response[HEADER_LEN + 4..HEADER_LEN + 6],
+            [OPTION_MESSAGE_TYPE, 1]
+        );
+
+        let message_type = MessageType::from_u8(response[HEADER_LEN + 6])?;
+
+        Some((message_type, response[16..20].try_into().unwrap()))
+    }
+
+    fn discover(
+        server: &mut DhcpServer,
+        mac: [u8; 6],
+        now: Instant,
+    ) -> Option<(MessageType, [u8; 4])> {
+        reply(
+            server,
+            &packet(MessageType::Discover, mac, [0; 4], &[]),
+            now,
+        )
+    }
+
+    fn request(
+        server: &mut DhcpServer,
+        mac: [u8; 6],
+        address: [u8; 4],
+        now: Instant,
+    ) -> Option<(MessageType, [u8; 4])> {
+        let packet = packet(
+            MessageType::Request,
+            mac,
+            [0; 4],
+            &[
+                (OPTION_REQUESTED_ADDRESS, &address),
+                (OPTION_SERVER_ID, &SERVER),
+            ],
+        );
+
+        reply(server, &packet, now)
+    }
+
+    fn decline(server: &mut DhcpServer, mac: [u8; 6], address: [u8; 4], now: Instant) {
+        let packet = packet(
+            MessageType::Decline,
+            mac,
+            [0; 4],
+            &[(OPTION_REQUESTED_ADDRESS, &address)],
+        );
+
+        assert_eq!(reply(server, &packet, now), None);
+    }
+
+    /// Leases `mac` an address, the whole way through.
+    fn join(server: &mut DhcpServer, mac: [u8; 6], now: Instant) -> [u8; 4] {
+        let (offer, address) = discover(server, mac, now).unwrap();
+        assert_eq!(offer, MessageType::Offer);
+        assert_eq!(
+            request(server, mac, address, now),
+            Some((MessageType::Ack, address))
+        );
+
+        address
+    }
+
+    fn state_of(server: &DhcpServer, address: [u8; 4]) -> Option<(LeaseState, [u8; 6])> {
+        server
+            .leases()
+            .iter()
+            .find(|lease| lease.address == address)
+            .map(|lease| (lease.state, lease.mac))
+    }
+
+    #[test]
+    fn hands_out_addresses() {
+        let mut server = DhcpServer::new(DhcpConfig::default());
+        let now = Instant::from_secs(10);
+
+        assert_eq!(
+            discover(&mut server, ALICE, now),
+            Some((MessageType::Offer, FIRST))
+        );
+        assert_eq!(state_of(&server, FIRST), Some((LeaseState::Offered, ALICE)));
+        // asking again gets the same offer
+        assert_eq!(
+            discover(&mut server, ALICE, now),
+            Some((MessageType::Offer, FIRST))
+        );
+
+        assert_eq!(
+            request(&mut server, ALICE, FIRST, now),
+            Some((MessageType::Ack, FIRST))
+        );
+        assert_eq!(state_of(&server, FIRST), Some((LeaseState::Bound, ALICE)));
+
+        assert_eq!(join(&mut server, BOB, now), SECOND);
+        // nobody else can have a taken address
+        assert_eq!(
+            request(&mut server, BOB, FIRST, now),
+            Some((MessageType::Nak, [0; 4]))
+        );
+        assert_eq!(
+            request(&mut server, BOB, SERVER, now),
+            Some((MessageType::Nak, [0; 4]))
+        );
+        assert_eq!(state_of(&server, FIRST), Some((LeaseState::Bound, ALICE)));
+    }
+
+    #[test]
+    fn replies_carry_the_network_settings() {
+        let mut server = DhcpServer::new(DhcpConfig::default());
+        let mut response = [0; MAX_PACKET_LEN];
+
+        let len = server
+            .handle(
+                &packet(MessageType::Discover, ALICE, [0; 4], &[]),
+                Instant::from_secs(0),
+                &mut response,
+            )
+            .unwrap();
+
+        let options = &response[HEADER_LEN + 4..len];
+        let lease_time = (2u32 * 60 * 60).to_be_bytes();
+
+        for option in [
+            &[OPTION_SERVER_ID, 4, 192, 168, 0, 1][..],
+            &[OPTION_SUBNET_MASK, 4, 255, 255, 255, 0],
+            &[OPTION_ROUTER, 4, 192, 168, 0, 1],
+            &[OPTION_DNS_SERVER, 4, 192, 168, 0, 1],
+            &[
+                OPTION_LEASE_TIME,
+                4,
+                lease_time[0],
+                lease_time[1],
+                lease_time[2],
+                lease_time[3],
+            ],
+        ] {
+            assert!(
+                options.windows(option.len()).any(|window| window == option),
+                "missing {option:?}"
+            );
+        }
+
+        // broadcast, as asked for, to the client's hardware address
+        assert_eq!(response[10..12], FLAG_BROADCAST.to_be_bytes());
+        assert_eq!(response[20..24], SERVER);
+        assert_eq!(response[28..34], ALICE);
+    }
+
+    #[test]
+    fn honours_requested_addresses_when_free() {
+        let mut server = DhcpServer::new(DhcpConfig::default());
+        let now = Instant::from_secs(0);
+        let wanted = [192, 168, 0, 105];
+
+        let discover_wanting = |server: &mut DhcpServer, mac, address: [u8; 4]| {
+            let packet = packet(
+                MessageType::Discover,
+                mac,
+                [0; 4],
+                &[(OPTION_REQUESTED_ADDRESS, &address)],
+            );
+            reply(server, &packet, now)
+        };
+
+        assert_eq!(
+            discover_wanting(&mut server, ALICE, wanted),
+            Some((MessageType::Offer, wanted))
+        );
+        // taken, or not ours to give, so the first free one instead
+        assert_eq!(
+            discover_wanting(&mut server, BOB, wanted),
+            Some((MessageType::Offer, FIRST))
+        );
+        assert_eq!(
+            discover_wanting(&mut server, [0x02, 0, 0, 0, 0, 0xc0], [10, 0, 0, 5]),
+            Some((MessageType::Offer, SECOND))
+        );
+    }
+
+    #[test]
+    fn forgets_offers_taken_elsewhere_and_released_leases() {
+        let mut server = DhcpServer::new(DhcpConfig::default());
+        let now = Instant::from_secs(0);
+
+        discover(&mut server, ALICE, now).unwrap();
+
+        let elsewhere = packet(
+            MessageType::Request,
+            ALICE,
+            [0; 4],
+            &[
+                (OPTION_REQUESTED_ADDRESS, &[10, 0, 0, 7]),
+                (OPTION_SERVER_ID, &[10, 0, 0, 1]),
+            ],
+        );
+        assert_eq!(reply(&mut server, &elsewhere, now), None);
+        assert_eq!(state_of(&server, FIRST), None);
+
+        let address = join(&mut server, BOB, now);
+
+        let release = packet(MessageType::Release, BOB, address, &[]);
+        assert_eq!(reply(&mut server, &release, now), None);
+        assert!(server.leases().is_empty());
+    }
+
+    #[test]
+    fn leases_expire() {
+        let mut server = DhcpServer::new(DhcpConfig::default());
+        let now = Instant::from_secs(0);
+
+        discover(&mut server, ALICE, now).unwrap();
+        assert_eq!(
+            discover(&mut server, BOB, now),
+            Some((MessageType::Offer, SECOND))
+        );
+
+        // alice never took up the offer
+        let later = now + OFFER_TIMEOUT + Duration::from_secs(1);
+        assert_eq!(
+            discover(&mut server, [0x02, 0, 0, 0, 0, 0xc0], later),
+            Some((MessageType::Offer, FIRST))
+        );
+
+        let address = join(&mut server, BOB, later);
+        let expired = later + DhcpConfig::default().lease_time;
+        assert_eq!(
+            request(&mut server, ALICE, address, expired),
+            Some((MessageType::Ack, address))
+        );
+    }
+
+    #[test]
+    fn only_the_leaseholder_can_decline() {
+        let mut server = DhcpServer::new(DhcpConfig::default());
+        let now = Instant::from_secs(0);
+
+        let alices = join(&mut server, ALICE, now);
+
+        // bob doesn't have it, and nobody has the rest
+        decline(&mut server, BOB, alices, now);
+        decline(&mut server, BOB, SECOND, now);
+        decline(&mut server, BOB, [10, 0, 0, 5], now);
+
+        assert_eq!(server.leases().len(), 1);
+        assert_eq!(state_of(&server, alices), Some((LeaseState::Bound, ALICE)));
+
+        decline(&mut server, ALICE, alices, now);
+        assert_eq!(
+            state_of(&server, alices),
+            Some((LeaseState::Declined, [0; 6]))
+        );
+
+        // so nobody is given it again, alice included
+        assert_eq!(join(&mut server, ALICE, now), SECOND);
+        assert_eq!(join(&mut server, BOB, now), [192, 168, 0, 102]);
+    }
+
+    #[test]
+    fn a_full_pool_never_evicts_bound_leases() {
+        let mut server = DhcpServer::new(DhcpConfig::default());
+        let now = Instant::from_secs(0);
+        let client = |index: u8| [0x02, 0, 0, 0, 1, index];
+
+        for index in 0..MAX_LEASES as u8 {
+            join(&mut server, client(index), now);
+        }
+
+        assert_eq!(discover(&mut server, ALICE, now), None);
+
+        // a stranger declining someone's address doesn't push anyone out
+        let someones = server.leases()[3].address;
+        decline(&mut server, ALICE, someones, now);
+        assert!(server
+            .leases()
+            .iter()
+            .all(|lease| lease.state == LeaseState::Bound));
+
+        let declined = server.leases()[0].address;
+        decline(&mut server, client(0), declined, now);
+        // client 0 lost its address, and there isn't another
+        assert_eq!(discover(&mut server, client(0), now), None);
+
+        assert_eq!(server.leases().len(), MAX_LEASES);
+        for index in 1..MAX_LEASES as u8 {
+            assert!(server
+                .lease_for(client(index))
+                .is_some_and(|lease| lease.state == LeaseState::Bound));
+        }
+    }
+
+    #[test]
+    fn ignores_malformed_packets() {
+        let mut server = DhcpServer::new(DhcpConfig::default());
+        let now = Instant::from_secs(0);
+        let valid = packet(MessageType::Discover, ALICE, [0; 4], &[]);
+
+        let mut reply_op = valid.clone();
+        reply_op[0] = OP_REPLY;
+
+        let mut bad_cookie = valid.clone();
+        bad_cookie[HEADER_LEN] = 0;
+
+        let mut no_type = valid.clone();
+        no_type[HEADER_LEN + 4] = OPTION_END;
+
+        // an option running off the end of the packet
+        let mut truncated = valid[..HEADER_LEN + 4].to_vec();
+        truncated.extend_from_slice(&[OPTION_REQUESTED_ADDRESS, 4, 192, 168]);
+
+        for packet in [
+            &valid[..HEADER_LEN],
+            &reply_op,
+            &bad_cookie,
+            &no_type,
+            &truncated,
+        ] {
+            assert_eq!(reply(&mut server, packet, now), None);
+        }
+
+        assert!(server.leases().is_empty());
+    }
+}
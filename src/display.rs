@@ -1,17 +1,40 @@
 use core::fmt::{Debug, Write};
 
 use embedded_graphics::{
+    geometry::AnchorY,
     mono_font::{iso_8859_14::FONT_4X6, MonoTextStyle},
     pixelcolor::BinaryColor,
     prelude::*,
-    primitives::{PrimitiveStyleBuilder, Rectangle},
+    primitives::Rectangle,
     text::Text,
 };
-use ssd1306::{prelude::*, I2CDisplayInterface, Ssd1306};
 
-use crate::prelude::*;
+use crate::{
+    compositor::{Region, MAX_WIDTH},
+    display::widgets::Layout,
+    prelude::*,
+};
 
-const MAX_CHARS: usize = (DisplaySize128x64::WIDTH / 4) as usize;
+pub mod driver;
+pub mod font;
+pub mod menu;
+#[cfg(feature = "net")]
+pub mod now_playing;
+#[cfg(feature = "net")]
+pub mod status;
+pub mod thumbnail;
+pub mod widgets;
+
+const MAX_CHARS: usize = (MAX_WIDTH / 4) as usize;
+
+/// Takes the biggest square that fits down the right of `layout`, leaving at
+/// least half of it - for a QR code beside some text.
+fn qr_area(layout: &mut Layout) -> Rectangle {
+    let size = layout.remaining().size;
+    let side = size.height.min(size.width / 2);
+
+    layout.right(side).resized_height(side, AnchorY::Center)
+}
 
 #[derive(Debug, Clone)]
 #[allow(unused)]
@@ -33,51 +56,19 @@ impl From<core::fmt::Error> for TeenyDisplayError {
     }
 }
 
+impl From<core::convert::Infallible> for TeenyDisplayError {
+    fn from(value: core::convert::Infallible) -> Self {
+        match value {}
+    }
+}
+
 impl From<ssd1306::mode::TerminalModeError> for TeenyDisplayError {
     fn from(value: ssd1306::mode::TerminalModeError) -> Self {
         Self::TerminalModeError(value)
     }
 }
 
-// pub async fn clear(
-//     display: &mut Ssd1306<
-//         I2CInterface<
-//             &mut I2cDevice<'static, NoopRawMutex, I2C<'static,
-// esp_hal::peripherals::I2C0, Async>>,         >,
-//         DisplaySize128x64,
-//         ssd1306::mode::BasicMode,
-//     >,
-// ) -> Result<(), DisplayError> {
-//     use ssd1306::command::AddrMode;
-
-//     let old_addr_mode = self.addr_mode;
-//     if old_addr_mode != AddrMode::Horizontal {
-//         self.set_addr_mode(AddrMode::Horizontal).await?;
-//     }
-
-//     let dim = self.dimensions();
-//     self.set_draw_area((0, 0), dim).await?;
-
-//     let num_pixels = dim.0 as u16 * dim.1 as u16;
-
-//     const BITS_PER_BYTE: u16 = 8;
-//     const BYTES_PER_BATCH: u16 = 64;
-//     const PIXELS_PER_BATCH: u16 = BITS_PER_BYTE * BYTES_PER_BATCH;
-
-//     // Not all screens have number of pixels divisible by 512, so add 1 to
-// cover tail     let num_batches = num_pixels / PIXELS_PER_BATCH + 1;
-
-//     for _ in 0..num_batches {
-//         self.draw(&[0; BYTES_PER_BATCH as usize]).await?;
-//     }
-
-//     if old_addr_mode != AddrMode::Horizontal {
-//         self.set_addr_mode(old_addr_mode).await?;
-//     }
-
-//     Ok(())
-// }
-
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct BoundingBox {
     pub start: Point,
     pub end: Point,
@@ -89,130 +80,141 @@ impl BoundingBox {
     }
 
     pub const fn start(&self) -> (u8, u8) {
-        (self.start.x as u8, self.end.y as u8)
+        (self.start.x as u8, self.start.y as u8)
     }
 
     pub const fn end(&self) -> (u8, u8) {
         (self.end.x as u8, self.end.y as u8)
     }
-}
 
-#[task]
-pub async fn screen_counter(mut i2c: SharedI2C) {
-    async fn screen_counter_internal(i2c: &mut SharedI2C) -> Result<(), TeenyDisplayError> {
-        const BOUNDING_BOX: BoundingBox = BoundingBox::new(Point::new(0, 0), Point::new(64, 6));
+    /// Grows the box to cover `point` too.
+    pub fn include(self, point: Point) -> Self {
+        Self::new(
+            self.start.component_min(point),
+            self.end.component_max(point),
+        )
+    }
 
-        let mut display = Ssd1306::new(
-            I2CDisplayInterface::new(i2c),
-            DisplaySize128x64,
-            DisplayRotation::Rotate0,
-        );
-        display.init().await?;
-        display.clear().await?;
+    /// The smallest box covering both boxes.
+    pub fn union(&self, other: &Self) -> Self {
+        Self::new(
+            self.start.component_min(other.start),
+            self.end.component_max(other.end),
+        )
+    }
 
-        let mut display = display.into_buffered_graphics_mode();
+    /// The box covering `rectangle`, or `None` if it is empty.
+    pub fn from_rectangle(rectangle: &Rectangle) -> Option<Self> {
+        rectangle
+            .bottom_right()
+            .map(|end| Self::new(rectangle.top_left, end))
+    }
 
-        let text_style = MonoTextStyle::new(&FONT_4X6, BinaryColor::On);
+    /// The smallest box covering both boxes, either of which may be missing.
+    pub fn merge(a: Option<Self>, b: Option<Self>) -> Option<Self> {
+        match (a, b) {
+            (Some(a), Some(b)) => Some(a.union(&b)),
+            (a, b) => a.or(b),
+        }
+    }
 
-        let mut counter: u16 = 0;
+    /// The area covered, with both corners included.
+    pub fn rectangle(&self) -> Rectangle {
+        Rectangle::with_corners(self.start, self.end)
+    }
+}
 
-        let mut ticker = Ticker::every(Duration::from_millis(20));
+/// Shows how to join the setup access point - as text on the left, and a QR
+/// code to scan on the right.
+#[cfg(feature = "net")]
+pub async fn show_setup_ap(password: Option<&str>) -> Result<(), TeenyDisplayError> {
+    use embedded_graphics::mono_font::ascii::FONT_6X10;
 
-        loop {
-            let mut string: String<MAX_CHARS> = String::new();
+    use crate::{
+        net::{wifi_qr_payload, AP_SSID},
+        qr::QrCode,
+    };
 
-            string.write_fmt(format_args!("{counter}"))?;
+    let mut region = Region::full_screen();
 
-            //overwrite previous text
-            display.fill_solid(
-                &Rectangle::with_corners(BOUNDING_BOX.start, BOUNDING_BOX.end),
-                BinaryColor::Off,
-            )?;
+    let text_style = MonoTextStyle::new(&FONT_6X10, BinaryColor::On);
 
-            Text::new(&string, Point::new(0, 6), text_style).draw(&mut display)?;
+    // spread out on taller screens, packed together on shorter ones
+    let spacing = (region.area().size.height as i32 / 4).max(10);
 
-            display.flush().await?;
+    let mut line: String<MAX_CHARS> = String::new();
+    line.write_fmt(format_args!("Join {AP_SSID}"))?;
 
-            counter = if let Some(next) = counter.checked_add(1) {
-                next
-            } else {
-                display.clear(BinaryColor::Off)?;
-                1
-            };
+    Text::new(&line, Point::new(0, 10), text_style).draw(&mut region)?;
 
-            ticker.next().await;
+    match password {
+        Some(password) => {
+            Text::new("Password:", Point::new(0, 10 + spacing), text_style).draw(&mut region)?;
+            Text::new(password, Point::new(0, 10 + 2 * spacing), text_style).draw(&mut region)?;
+        }
+        None => {
+            Text::new("No password", Point::new(0, 10 + spacing), text_style).draw(&mut region)?;
         }
     }
 
-    loop {
-        if let Err(e) = screen_counter_internal(&mut i2c).await {
-            warn!("Display error: {e:?}");
-        }
+    let qr_area = qr_area(&mut Layout::screen());
 
-        Timer::after_secs(1).await;
+    match QrCode::fit(wifi_qr_payload(AP_SSID, password).as_bytes(), qr_area.size) {
+        Ok(code) => code.draw(&mut region, qr_area)?,
+        Err(e) => warn!("Couldn't make a QR code for the access point: {e:?}"),
     }
+
+    region.submit().await;
+
+    Ok(())
 }
 
-#[task]
-pub async fn display_shapes(mut i2c: SharedI2C) {
-    async fn display_shapes_internal(i2c: &mut SharedI2C) -> Result<(), TeenyDisplayError> {
-        let mut display = Ssd1306::new(
-            I2CDisplayInterface::new(i2c),
-            DisplaySize128x64,
-            DisplayRotation::Rotate0,
-        );
-        display.init().await?;
-
-        let mut display = display.into_buffered_graphics_mode();
-
-        let y_offset = 20;
-
-        let style = PrimitiveStyleBuilder::new()
-            .stroke_width(1)
-            .stroke_color(BinaryColor::On)
-            .build();
-
-        let mut sub = VOLUME_CHANNEL.subscriber().unwrap();
-
-        loop {
-            let volume = sub.next_message_pure().await;
-
-            // screen outline
-            embedded_graphics::primitives::Rectangle::new(Point::new(0, 10), Size::new(127, 36))
-                .into_styled(style)
-                .draw(&mut display)?;
-
-            // triangle
-            embedded_graphics::primitives::Triangle::new(
-                Point::new((volume * 128.) as i32, 16 + y_offset),
-                Point::new(16 + 16, 16 + y_offset),
-                Point::new(16 + 8, y_offset),
-            )
-            .into_styled(style)
-            .draw(&mut display)?;
-
-            // square
-            embedded_graphics::primitives::Rectangle::new(
-                Point::new(52, y_offset),
-                Size::new_equal(16),
-            )
-            .into_styled(style)
-            .draw(&mut display)?;
-
-            // circle
-            embedded_graphics::primitives::Circle::new(Point::new(88, y_offset), 16)
-                .into_styled(style)
-                .draw(&mut display)?;
-
-            display.flush().await?;
-        }
+/// Shows where to log in to spotify - `url`, as text and a QR code, and the
+/// `code` to enter there (which the QR code carries along for the page to fill
+/// in).
+#[cfg(feature = "net")]
+pub async fn show_login(url: &str, code: &str) -> Result<(), TeenyDisplayError> {
+    use crate::{
+        login::{LOGIN_CODE_LEN, LOGIN_URL_LEN},
+        qr::QrCode,
+    };
+
+    let mut region = Region::full_screen();
+
+    let text_style = MonoTextStyle::new(&FONT_4X6, BinaryColor::On);
+
+    let mut layout = Layout::screen();
+    let qr_area = qr_area(&mut layout);
+
+    // how many characters of the url fit beside the QR code
+    let line_chars = (layout.remaining().size.width / 4).max(1) as usize;
+
+    Text::new("Log in at:", Point::new(0, 6), text_style).draw(&mut region)?;
+
+    let mut y = 6;
+
+    // urls are ascii, so splitting on bytes never splits a character
+    for line in url.as_bytes().chunks(line_chars) {
+        let line = core::str::from_utf8(line).unwrap_or_default();
+
+        y += 7;
+        Text::new(line, Point::new(0, y), text_style).draw(&mut region)?;
     }
 
-    loop {
-        if let Err(e) = display_shapes_internal(&mut i2c).await {
-            warn!("Display error: {e:?}");
-        }
+    let mut line: String<{ LOGIN_CODE_LEN + 6 }> = String::new();
+    write!(line, "Code: {code}")?;
+
+    Text::new(&line, Point::new(0, y + 7), text_style).draw(&mut region)?;
+
+    let mut link: String<{ LOGIN_URL_LEN + LOGIN_CODE_LEN + 1 }> = String::new();
+    write!(link, "{url}#{code}")?;
 
-        Timer::after_secs(1).await;
+    match QrCode::fit(link.as_bytes(), qr_area.size) {
+        Ok(code) => code.draw(&mut region, qr_area)?,
+        Err(e) => warn!("Couldn't make a QR code for the login url: {e:?}"),
     }
+
+    region.submit().await;
+
+    Ok(())
 }
@@ -0,0 +1,247 @@
+//! Which panel is fitted, and how to talk to it - so the same firmware runs in
+//! each of the enclosures, whatever panel is in it.
+//!
+//! The panel is always driven the right way up. Rotation is done by the
+//! [`compositor`](crate::compositor) as it packs pixels into pages, so it works
+//! the same on every controller, and turns by 90 degrees too.
+
+use display_interface::{AsyncWriteOnlyDataCommand, DataFormat, DisplayError};
+use embedded_graphics::prelude::{Point, Size};
+
+use crate::prelude::*;
+
+/// The chip driving the panel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Controller {
+    Ssd1306,
+    /// Nearly an SSD1306, but with 132 columns of memory for a 128 pixel wide
+    /// panel, and no way to send more than a page at a time.
+    Sh1106,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PanelSize {
+    Size128x64,
+    Size128x32,
+    Size72x40,
+}
+
+impl PanelSize {
+    pub const fn width(self) -> u32 {
+        match self {
+            PanelSize::Size128x64 | PanelSize::Size128x32 => 128,
+            PanelSize::Size72x40 => 72,
+        }
+    }
+
+    pub const fn height(self) -> u32 {
+        match self {
+            PanelSize::Size128x64 => 64,
+            PanelSize::Size128x32 => 32,
+            PanelSize::Size72x40 => 40,
+        }
+    }
+}
+
+/// How far the picture is turned clockwise, for a panel mounted sideways or
+/// upside down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    Rotate0,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+/// The panel fitted, and where it is on the I2C bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisplayConfig {
+    pub controller: Controller,
+    pub size: PanelSize,
+    pub rotation: Rotation,
+    pub address: u8,
+}
+
+impl Default for DisplayConfig {
+    /// The panel the board was built with - see [`DisplayConfig::parse`].
+    fn default() -> Self {
+        Self::parse(env!("DISPLAY_CONFIG"))
+    }
+}
+
+impl DisplayConfig {
+    /// What most boards have - an SSD1306, 128x64, at the usual address.
+    pub const STANDARD: Self = Self {
+        controller: Controller::Ssd1306,
+        size: PanelSize::Size128x64,
+        rotation: Rotation::Rotate0,
+        address: 0x3c,
+    };
+
+    /// Reads a config like `sh1106 128x32 rotate180 0x3d` - the words can come
+    /// in any order, and anything left out is as in
+    /// [`STANDARD`](Self::STANDARD). Words which aren't understood are
+    /// ignored, with a warning.
+    pub fn parse(config: &str) -> Self {
+        let mut parsed = Self::STANDARD;
+
+        for word in config.split_whitespace() {
+            match word {
+                "ssd1306" => parsed.controller = Controller::Ssd1306,
+                "sh1106" => parsed.controller = Controller::Sh1106,
+                "128x64" => parsed.size = PanelSize::Size128x64,
+                "128x32" => parsed.size = PanelSize::Size128x32,
+                "72x40" => parsed.size = PanelSize::Size72x40,
+                "rotate0" => parsed.rotation = Rotation::Rotate0,
+                "rotate90" => parsed.rotation = Rotation::Rotate90,
+                "rotate180" => parsed.rotation = Rotation::Rotate180,
+                "rotate270" => parsed.rotation = Rotation::Rotate270,
+                word => match word
+                    .strip_prefix("0x")
+                    .or_else(|| word.strip_prefix("0X"))
+                    .and_then(|address| u8::from_str_radix(address, 16).ok())
+                {
+                    Some(address) => parsed.address = address,
+                    None => warn!("Ignoring unknown display config {word:?}"),
+                },
+            }
+        }
+
+        parsed
+    }
+
+    /// The size of the panel itself.
+    pub const fn panel_size(&self) -> Size {
+        Size::new(self.size.width(), self.size.height())
+    }
+
+    /// The size of the picture - the panel's, turned by the rotation.
+    pub const fn screen_size(&self) -> Size {
+        let panel = self.panel_size();
+
+        match self.rotation {
+            Rotation::Rotate0 | Rotation::Rotate180 => panel,
+            Rotation::Rotate90 | Rotation::Rotate270 => Size::new(panel.height, panel.width),
+        }
+    }
+
+    /// Where `point` in the picture is on the panel.
+    pub const fn to_panel(&self, point: Point) -> Point {
+        let (width, height) = (self.size.width() as i32, self.size.height() as i32);
+
+        match self.rotation {
+            Rotation::Rotate0 => point,
+            Rotation::Rotate90 => Point::new(width - 1 - point.y, point.x),
+            Rotation::Rotate180 => Point::new(width - 1 - point.x, height - 1 - point.y),
+            Rotation::Rotate270 => Point::new(point.y, height - 1 - point.x),
+        }
+    }
+
+    /// Where `point` on the panel is in the picture - the opposite of
+    /// [`to_panel`](Self::to_panel).
+    pub const fn from_panel(&self, point: Point) -> Point {
+        let (width, height) = (self.size.width() as i32, self.size.height() as i32);
+
+        match self.rotation {
+            Rotation::Rotate0 => point,
+            Rotation::Rotate90 => Point::new(point.y, width - 1 - point.x),
+            Rotation::Rotate180 => Point::new(width - 1 - point.x, height - 1 - point.y),
+            Rotation::Rotate270 => Point::new(height - 1 - point.y, point.x),
+        }
+    }
+}
+
+/// What the [`compositor`](crate::compositor) needs from a display controller.
+pub(crate) trait Driver {
+    async fn set_contrast(&mut self, contrast: u8) -> Result<(), DisplayError>;
+
+    async fn set_on(&mut self, on: bool) -> Result<(), DisplayError>;
+
+    /// Sends `bytes` - one per column, top row in the lowest bit - to `page`,
+    /// starting at column `left`.
+    async fn draw_page(&mut self, page: u8, left: u8, bytes: &[u8]) -> Result<(), DisplayError>;
+}
+
+impl<'a, SIZE: DisplaySize> Driver for Ssd1306<I2CInterface<&'a mut SharedI2C>, SIZE, BasicMode> {
+    async fn set_contrast(&mut self, contrast: u8) -> Result<(), DisplayError> {
+        self.set_brightness(Brightness::custom(2, contrast)).await
+    }
+
+    async fn set_on(&mut self, on: bool) -> Result<(), DisplayError> {
+        self.set_display_on(on).await
+    }
+
+    async fn draw_page(&mut self, page: u8, left: u8, bytes: &[u8]) -> Result<(), DisplayError> {
+        // panels narrower than the chip's 128 columns (or shorter than its 64
+        // rows) are wired to the middle of them - and only the buffered mode's
+        // flush adds the offsets itself
+        let left = left + SIZE::OFFSETX;
+        let top = page * 8 + SIZE::OFFSETY;
+
+        // in horizontal addressing mode, so the area is filled a column at a time
+        self.set_draw_area((left, top), (left + bytes.len() as u8, top + 8))
+            .await?;
+        self.draw(bytes).await
+    }
+}
+
+/// An SH1106 controller - just enough of one to set it up and send it pages.
+pub struct Sh1106<DI> {
+    interface: DI,
+    size: PanelSize,
+}
+
+impl<DI: AsyncWriteOnlyDataCommand> Sh1106<DI> {
+    pub fn new(interface: DI, size: PanelSize) -> Self {
+        Self { interface, size }
+    }
+
+    async fn command(&mut self, bytes: &[u8]) -> Result<(), DisplayError> {
+        self.interface.send_commands(DataFormat::U8(bytes)).await
+    }
+
+    /// Sets the panel up, leaving it switched off until [`Driver::set_on`].
+    pub async fn init(&mut self) -> Result<(), DisplayError> {
+        let height = self.size.height() as u8;
+        // sequential rows for panels shorter than the 64 rows the chip drives
+        let com_pins = if height == 64 { 0x12 } else { 0x02 };
+
+        self.command(&[0xae]).await?;
+        self.command(&[0xd5, 0x80]).await?;
+        self.command(&[0xa8, height - 1]).await?;
+        self.command(&[0xd3, 0x00]).await?;
+        self.command(&[0x40]).await?;
+        // the charge pump on
+        self.command(&[0xad, 0x8b]).await?;
+        // column 0 on the left, row 0 at the top
+        self.command(&[0xa1, 0xc8]).await?;
+        self.command(&[0xda, com_pins]).await?;
+        self.command(&[0xd9, 0x22]).await?;
+        self.command(&[0xdb, 0x35]).await?;
+        self.command(&[0xa4, 0xa6]).await
+    }
+
+    /// Where the panel's first column is in the chip's 132 - the panel is
+    /// wired to the middle of them.
+    fn column_offset(&self) -> u8 {
+        ((132 - self.size.width()) / 2) as u8
+    }
+}
+
+impl<DI: AsyncWriteOnlyDataCommand> Driver for Sh1106<DI> {
+    async fn set_contrast(&mut self, contrast: u8) -> Result<(), DisplayError> {
+        self.command(&[0x81, contrast]).await
+    }
+
+    async fn set_on(&mut self, on: bool) -> Result<(), DisplayError> {
+        self.command(&[if on { 0xaf } else { 0xae }]).await
+    }
+
+    async fn draw_page(&mut self, page: u8, left: u8, bytes: &[u8]) -> Result<(), DisplayError> {
+        let column = left + self.column_offset();
+
+        self.command(&[0xb0 | page, column & 0x0f, 0x10 | column >> 4])
+            .await?;
+        self.interface.send_data(DataFormat::U8(bytes)).await
+    }
+}
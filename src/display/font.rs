@@ -0,0 +1,321 @@
+//! Text which isn't just ascii - track and artist names can be in any script.
+//!
+//! A [`Font`] is a stack of same-sized embedded-graphics fonts, one per ISO
+//! 8859 part (plus JIS X 0201 katakana in [`LARGE`]), and each character is
+//! drawn by the first one which has it. Characters none of them have are
+//! swapped for a look-alike where there is one (curly quotes, fullwidth forms,
+//! kana), and drawn as a box otherwise. Widths are counted in glyphs rather
+//! than bytes, so measuring and truncating never splits a character.
+//!
+//! Kana are only drawn in [`LARGE`], as the halfwidth katakana of JIS X 0201
+//! (hiragana included, as their katakana). There are no CJK ideographs or
+//! hangul at all - kanji, hanzi and hangul all draw as boxes, as they aren't
+//! legible at these sizes.
+
+use embedded_graphics::{
+    mono_font::{
+        iso_8859_1, iso_8859_13, iso_8859_14, iso_8859_15, iso_8859_16, iso_8859_2, iso_8859_3,
+        iso_8859_4, iso_8859_5, iso_8859_7, iso_8859_9, jis_x0201,
+        mapping::{self, StrGlyphMapping},
+        MonoFont, MonoTextStyle,
+    },
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::{PrimitiveStyle, Rectangle},
+    text::{Baseline, Text},
+};
+
+use crate::prelude::*;
+
+/// Drawn at the end of text which has been cut short.
+pub const ELLIPSIS: char = '…';
+
+/// One of the fonts in a [`Font`], and the characters it has.
+pub struct Face {
+    font: &'static MonoFont<'static>,
+    mapping: &'static StrGlyphMapping<'static>,
+}
+
+impl Face {
+    pub const fn new(
+        font: &'static MonoFont<'static>,
+        mapping: &'static StrGlyphMapping<'static>,
+    ) -> Self {
+        Self { font, mapping }
+    }
+}
+
+/// The same size of every ISO 8859 font - Latin, Cyrillic and Greek - with the
+/// most common scripts first.
+macro_rules! faces {
+    ($font:ident $(, $extra:expr)*) => {
+        &[
+            Face::new(&iso_8859_1::$font, &mapping::ISO_8859_1),
+            Face::new(&iso_8859_15::$font, &mapping::ISO_8859_15),
+            Face::new(&iso_8859_2::$font, &mapping::ISO_8859_2),
+            Face::new(&iso_8859_9::$font, &mapping::ISO_8859_9),
+            Face::new(&iso_8859_13::$font, &mapping::ISO_8859_13),
+            Face::new(&iso_8859_4::$font, &mapping::ISO_8859_4),
+            Face::new(&iso_8859_3::$font, &mapping::ISO_8859_3),
+            Face::new(&iso_8859_14::$font, &mapping::ISO_8859_14),
+            Face::new(&iso_8859_16::$font, &mapping::ISO_8859_16),
+            Face::new(&iso_8859_5::$font, &mapping::ISO_8859_5),
+            Face::new(&iso_8859_7::$font, &mapping::ISO_8859_7),
+            $($extra,)*
+        ]
+    };
+}
+
+/// 4x6 pixels - for anything which has to fit in a small space.
+pub const SMALL: Font = Font::new(faces!(FONT_4X6));
+
+/// 6x13 pixels, with halfwidth katakana too - for the track title.
+pub const LARGE: Font = Font::new(faces!(
+    FONT_6X13,
+    Face::new(&jis_x0201::FONT_6X13, &mapping::JIS_X0201)
+));
+
+/// Fonts which are all the same size, drawn from together so text can mix
+/// scripts.
+pub struct Font {
+    faces: &'static [Face],
+}
+
+impl Font {
+    /// `faces` must all have the same character size, spacing and baseline.
+    pub const fn new(faces: &'static [Face]) -> Self {
+        Self { faces }
+    }
+
+    fn metrics(&self) -> &'static MonoFont<'static> {
+        self.faces[0].font
+    }
+
+    /// The size of one glyph.
+    pub fn character_size(&self) -> Size {
+        self.metrics().character_size
+    }
+
+    /// How far apart glyphs are, in pixels.
+    pub fn advance(&self) -> u32 {
+        self.metrics().character_size.width + self.metrics().character_spacing
+    }
+
+    /// The first face with a glyph for `c`.
+    fn face(&self, c: char) -> Option<&Face> {
+        self.faces.iter().find(|face| face.mapping.contains(c))
+    }
+
+    /// Whether `c` is drawn as itself, rather than a substitute or a box.
+    pub fn has_glyph(&self, c: char) -> bool {
+        c == ELLIPSIS || self.face(c).is_some()
+    }
+
+    /// What `c` is drawn as - itself if there is a glyph for it, otherwise a
+    /// look-alike if every glyph of that is there, otherwise the box it is
+    /// drawn as when missing.
+    fn glyphs(&self, c: char) -> impl Iterator<Item = char> {
+        let glyphs = match substitute(c) {
+            Some(glyphs) if !self.has_glyph(c) && glyphs.iter().all(|&g| self.has_glyph(g)) => {
+                glyphs
+            }
+            _ => [c].into_iter().collect(),
+        };
+
+        glyphs.into_iter()
+    }
+
+    /// How many glyphs `text` is drawn as.
+    pub fn glyph_count(&self, text: &str) -> u32 {
+        text.chars().map(|c| self.glyphs(c).count() as u32).sum()
+    }
+
+    /// How wide `glyphs` glyphs are, in pixels.
+    fn width_of(&self, glyphs: u32) -> u32 {
+        (glyphs * self.advance()).saturating_sub(self.metrics().character_spacing)
+    }
+
+    /// How wide `text` is when drawn, in pixels.
+    pub fn text_width(&self, text: &str) -> u32 {
+        self.width_of(self.glyph_count(text))
+    }
+
+    /// The longest start of `text` which fits in `width` pixels.
+    pub fn fit<'a>(&self, text: &'a str, width: u32) -> &'a str {
+        let mut glyphs = 0;
+
+        for (i, c) in text.char_indices() {
+            glyphs += self.glyphs(c).count() as u32;
+
+            if self.width_of(glyphs) > width {
+                return &text[..i];
+            }
+        }
+
+        text
+    }
+
+    /// Draws `text` with its top left at `position`, returning where the next
+    /// glyph would go.
+    pub fn draw<D>(
+        &self,
+        text: &str,
+        position: Point,
+        color: BinaryColor,
+        target: &mut D,
+    ) -> Result<Point, D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let mut position = position;
+
+        for glyph in text.chars().flat_map(|c| self.glyphs(c)) {
+            self.draw_glyph(glyph, position, color, target)?;
+            position.x += self.advance() as i32;
+        }
+
+        Ok(position)
+    }
+
+    /// Draws as much of `text` as fits in `width` pixels, ending with an
+    /// [`ELLIPSIS`] if it was cut short.
+    pub fn draw_truncated<D>(
+        &self,
+        text: &str,
+        position: Point,
+        width: u32,
+        color: BinaryColor,
+        target: &mut D,
+    ) -> Result<Point, D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        if self.text_width(text) <= width {
+            return self.draw(text, position, color, target);
+        }
+
+        // leaving room for the ellipsis
+        let start = self.fit(text, width.saturating_sub(self.advance()));
+        let position = self.draw(start, position, color, target)?;
+
+        self.draw(ELLIPSIS.encode_utf8(&mut [0; 4]), position, color, target)
+    }
+
+    fn draw_glyph<D>(
+        &self,
+        c: char,
+        top_left: Point,
+        color: BinaryColor,
+        target: &mut D,
+    ) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        if let Some(face) = self.face(c) {
+            let style = MonoTextStyle::new(face.font, color);
+
+            Text::with_baseline(c.encode_utf8(&mut [0; 4]), top_left, style, Baseline::Top)
+                .draw(target)?;
+
+            return Ok(());
+        }
+
+        let size = self.character_size();
+        let baseline = self.metrics().baseline as i32;
+
+        if c == ELLIPSIS {
+            // dots on every other pixel, leaving the last column as spacing
+            let dots = (0..size.width as i32 - 1)
+                .step_by(2)
+                .map(|x| Pixel(top_left + Point::new(x, baseline), color));
+
+            return target.draw_iter(dots);
+        }
+
+        // a box, for characters there's no glyph for at all
+        Rectangle::with_corners(
+            top_left + Point::new(0, 1),
+            top_left + Point::new(size.width as i32 - 2, baseline),
+        )
+        .into_styled(PrimitiveStyle::with_stroke(color, 1))
+        .draw(target)
+    }
+}
+
+/// Glyphs to draw in place of a character - usually one, but kana with
+/// (han)dakuten take two, and zero width characters take none.
+type Glyphs = Vec<char, 2>;
+
+/// Halfwidth katakana for each of `ァ` to `ヶ`, and the mark after it - `1`
+/// for a dakuten, `2` for a handakuten. Small kana without a halfwidth form
+/// use the full-size one.
+#[rustfmt::skip]
+const KATAKANA: [(u8, u8); 86] = [
+    (0x67, 0), (0x71, 0), (0x68, 0), (0x72, 0), (0x69, 0), (0x73, 0), (0x6a, 0), (0x74, 0),
+    (0x6b, 0), (0x75, 0), (0x76, 0), (0x76, 1), (0x77, 0), (0x77, 1), (0x78, 0), (0x78, 1),
+    (0x79, 0), (0x79, 1), (0x7a, 0), (0x7a, 1), (0x7b, 0), (0x7b, 1), (0x7c, 0), (0x7c, 1),
+    (0x7d, 0), (0x7d, 1), (0x7e, 0), (0x7e, 1), (0x7f, 0), (0x7f, 1), (0x80, 0), (0x80, 1),
+    (0x81, 0), (0x81, 1), (0x6f, 0), (0x82, 0), (0x82, 1), (0x83, 0), (0x83, 1), (0x84, 0),
+    (0x84, 1), (0x85, 0), (0x86, 0), (0x87, 0), (0x88, 0), (0x89, 0), (0x8a, 0), (0x8a, 1),
+    (0x8a, 2), (0x8b, 0), (0x8b, 1), (0x8b, 2), (0x8c, 0), (0x8c, 1), (0x8c, 2), (0x8d, 0),
+    (0x8d, 1), (0x8d, 2), (0x8e, 0), (0x8e, 1), (0x8e, 2), (0x8f, 0), (0x90, 0), (0x91, 0),
+    (0x92, 0), (0x93, 0), (0x6c, 0), (0x94, 0), (0x6d, 0), (0x95, 0), (0x6e, 0), (0x96, 0),
+    (0x97, 0), (0x98, 0), (0x99, 0), (0x9a, 0), (0x9b, 0), (0x9c, 0), (0x9c, 0), (0x72, 0),
+    (0x74, 0), (0x66, 0), (0x9d, 0), (0x73, 1), (0x76, 0), (0x79, 0),
+];
+
+const DAKUTEN: char = '\u{ff9e}';
+const HANDAKUTEN: char = '\u{ff9f}';
+
+/// The halfwidth form of the fullwidth katakana `c`.
+fn halfwidth_katakana(c: char) -> Option<Glyphs> {
+    let (kana, mark) = KATAKANA.get((c as u32).checked_sub('ァ' as u32)? as usize)?;
+    let kana = char::from_u32(0xff00 + *kana as u32)?;
+
+    Some(match mark {
+        1 => [kana, DAKUTEN].into_iter().collect(),
+        2 => [kana, HANDAKUTEN].into_iter().collect(),
+        _ => [kana].into_iter().collect(),
+    })
+}
+
+/// Something close enough to `c` to draw instead, for characters the fonts
+/// don't have.
+fn substitute(c: char) -> Option<Glyphs> {
+    let glyph = match c {
+        // zero width joiners, variation selectors (as in emoji), skin tones and
+        // combining accents
+        '\u{200b}'..='\u{200d}'
+        | '\u{2060}'
+        | '\u{fe00}'..='\u{fe0f}'
+        | '\u{1f3fb}'..='\u{1f3ff}'
+        | '\u{300}'..='\u{36f}' => return Some(Glyphs::new()),
+
+        '\u{2000}'..='\u{200a}' | '\u{202f}' | '\u{205f}' | '\u{3000}' => ' ',
+        '‘' | '’' | '‚' | '‛' | '′' => '\'',
+        '“' | '”' | '„' | '‟' | '″' => '"',
+        '‐'..='―' | '−' => '-',
+        '‹' => '<',
+        '›' => '>',
+        '•' | '‧' => '·',
+
+        // fullwidth ascii
+        '！'..='～' => char::from_u32(c as u32 - 0xfee0)?,
+
+        '、' => '､',
+        '。' => '｡',
+        '「' => '｢',
+        '」' => '｣',
+        '・' => '･',
+        'ー' => 'ｰ',
+        '゛' | '\u{3099}' => DAKUTEN,
+        '゜' | '\u{309a}' => HANDAKUTEN,
+        // hiragana are drawn as katakana - the same sounds, just a different script
+        'ぁ'..='ゖ' => return halfwidth_katakana(char::from_u32(c as u32 + 0x60)?),
+        'ァ'..='ヶ' => return halfwidth_katakana(c),
+
+        _ => return None,
+    };
+
+    Some([glyph].into_iter().collect())
+}
@@ -0,0 +1,376 @@
+use core::fmt::Write;
+
+use embassy_futures::select::{select, Either};
+use embedded_graphics::{
+    geometry::AnchorY, pixelcolor::BinaryColor, prelude::*, primitives::Rectangle, text::Alignment,
+};
+
+use super::{
+    font::{LARGE, SMALL},
+    widgets::{show_overlay, Label, Layout, List, ProgressBar, Widget},
+    BoundingBox,
+};
+use crate::{
+    compositor::hide_overlay,
+    idle,
+    menu::{self, Input, Navigator, Outcome, Screen, MAIN_MENU, MENU_INPUT},
+    prelude::*,
+    storage::{Key, SettingsMutex},
+};
+
+/// How often the screens showing something live (like the uptime) are brought
+/// up to date.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Long enough for any menu title or item.
+const LABEL_LEN: usize = 16;
+
+/// The most items a menu can show.
+const MAX_ITEMS: usize = 8;
+
+/// How many lines the diagnostics screen has.
+const DIAGNOSTICS_LINES: usize = 3;
+
+impl Screen {
+    pub fn title(self) -> &'static str {
+        match self {
+            Screen::Knob => "Knob",
+            Screen::Diagnostics => "Diagnostics",
+            Screen::Devices => "Devices",
+        }
+    }
+}
+
+/// Whatever a [`Navigator`] is on - the items of a menu, or a screen.
+pub struct MenuView {
+    bounds: Rectangle,
+    title: Label<LABEL_LEN>,
+    items: List<LABEL_LEN, MAX_ITEMS>,
+    knob: ProgressBar,
+    knob_value: Label<8>,
+    lines: [Label; DIAGNOSTICS_LINES],
+    devices: List<LABEL_LEN, MAX_ITEMS>,
+    /// Shown on the devices screen in place of the devices, while there are
+    /// none.
+    message: Label<LABEL_LEN>,
+    screen: Option<Screen>,
+    /// Switching between the menu and a screen redraws everything, as they
+    /// share the same space.
+    dirty: bool,
+}
+
+impl MenuView {
+    pub fn new(bounds: Rectangle) -> Self {
+        let mut layout = Layout::new(bounds);
+
+        let title = layout.top(SMALL.character_size().height + 2);
+        layout.top(1);
+        let body = layout.remaining();
+
+        let mut knob = Layout::new(
+            body.resized_height(6 + 2 + SMALL.character_size().height, AnchorY::Center),
+        );
+        let knob_bar = knob.top(6);
+        knob.top(2);
+
+        let mut lines = Layout::new(body);
+
+        let message = body.resized_height(SMALL.character_size().height, AnchorY::Center);
+
+        Self {
+            bounds,
+            title: Label::new(title, &SMALL),
+            items: List::new(body, &LARGE),
+            knob: ProgressBar::new(knob_bar).with_border(),
+            knob_value: Label::new(knob.remaining(), &SMALL).with_alignment(Alignment::Center),
+            lines: core::array::from_fn(|_| {
+                Label::new(lines.top(SMALL.character_size().height + 2), &SMALL)
+            }),
+            devices: List::new(body, &LARGE),
+            message: Label::new(message, &SMALL).with_alignment(Alignment::Center),
+            screen: None,
+            dirty: true,
+        }
+    }
+
+    /// Shows whatever `navigator` is on.
+    pub fn set_navigator(&mut self, navigator: &Navigator) {
+        let screen = navigator.screen();
+
+        if self.screen != screen {
+            self.screen = screen;
+            self.dirty = true;
+        }
+
+        if let Some(screen) = screen {
+            self.title.set_text(screen.title());
+            return;
+        }
+
+        let labels = navigator.labels();
+
+        if self.title.text() != navigator.title()
+            || self
+                .items
+                .items()
+                .iter()
+                .map(String::as_str)
+                .ne(labels.clone())
+        {
+            self.title.set_text(navigator.title());
+            self.items.set_items(labels);
+        }
+
+        self.items.select(navigator.selected());
+    }
+
+    /// Where the knob is, from `0` to `1`.
+    pub fn set_knob(&mut self, value: f32) {
+        let value = value.clamp(0., 1.);
+
+        let mut percent: String<8> = String::new();
+        write!(percent, "{}%", (value * 100. + 0.5) as u8).ok();
+
+        self.knob.set_value(value);
+        self.knob_value.set_text(&percent);
+    }
+
+    /// Says `message` on the devices screen, instead of listing any.
+    pub fn set_message(&mut self, message: &str) {
+        self.devices.set_items([]);
+        self.message.set_text(message);
+    }
+
+    /// Lists the devices by name on the devices screen, with the one at
+    /// `selected` selected.
+    pub fn set_devices<'a>(&mut self, names: impl IntoIterator<Item = &'a str>, selected: usize) {
+        self.devices.set_items(names);
+        self.devices.select(selected);
+
+        if self.devices.items().is_empty() {
+            self.message.set_text("No devices");
+        }
+    }
+
+    pub fn select_device(&mut self, index: usize) {
+        self.devices.select(index);
+    }
+
+    /// Brings the diagnostics up to `now`.
+    pub fn update(&mut self, now: Instant) {
+        let secs = now.as_secs();
+        let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+
+        let mut uptime: String<32> = String::new();
+        write!(uptime, "Up {hours}:{minutes:02}:{secs:02}").ok();
+
+        let chip = if cfg!(feature = "esp32") {
+            "ESP32"
+        } else {
+            "ESP32-C3"
+        };
+
+        self.lines[0].set_text(concat!("teeny v", env!("CARGO_PKG_VERSION")));
+        self.lines[1].set_text(chip);
+        self.lines[2].set_text(&uptime);
+    }
+}
+
+impl Widget for MenuView {
+    fn bounds(&self) -> Rectangle {
+        self.bounds
+    }
+
+    fn is_dirty(&self) -> bool {
+        self.dirty
+            || self.title.is_dirty()
+            || match self.screen {
+                None => self.items.is_dirty(),
+                Some(Screen::Knob) => self.knob.is_dirty() || self.knob_value.is_dirty(),
+                Some(Screen::Diagnostics) => self.lines.iter().any(Label::is_dirty),
+                Some(Screen::Devices) => self.devices.is_dirty() || self.message.is_dirty(),
+            }
+    }
+
+    fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    fn mark_clean(&mut self) {
+        self.dirty = false;
+        self.title.mark_clean();
+        self.items.mark_clean();
+        self.knob.mark_clean();
+        self.knob_value.mark_clean();
+        self.lines.iter_mut().for_each(Label::mark_clean);
+        self.devices.mark_clean();
+        self.message.mark_clean();
+    }
+
+    fn render<D>(&self, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        target.fill_solid(&self.bounds, BinaryColor::Off)?;
+
+        self.title.render(target)?;
+
+        match self.screen {
+            None => self.items.render(target),
+            Some(Screen::Knob) => {
+                self.knob.render(target)?;
+                self.knob_value.render(target)
+            }
+            Some(Screen::Diagnostics) => self.lines.iter().try_for_each(|line| line.render(target)),
+            Some(Screen::Devices) if self.devices.items().is_empty() => self.message.render(target),
+            Some(Screen::Devices) => self.devices.render(target),
+        }
+    }
+
+    fn dirty_box(&self) -> Option<BoundingBox> {
+        if self.dirty {
+            return BoundingBox::from_rectangle(&self.bounds);
+        }
+
+        let title = self.title.dirty_box();
+
+        match self.screen {
+            None => BoundingBox::merge(title, self.items.dirty_box()),
+            Some(Screen::Knob) => [self.knob.dirty_box(), self.knob_value.dirty_box()]
+                .into_iter()
+                .fold(title, BoundingBox::merge),
+            Some(Screen::Diagnostics) => self
+                .lines
+                .iter()
+                .map(Label::dirty_box)
+                .fold(title, BoundingBox::merge),
+            Some(Screen::Devices) => [self.devices.dirty_box(), self.message.dirty_box()]
+                .into_iter()
+                .fold(title, BoundingBox::merge),
+        }
+    }
+}
+
+/// Shows the menu over everything else whenever it is [`open`](menu::open),
+/// working it with the [`Input`]s from [`MENU_INPUT`] until it closes - and
+/// saves any settings chosen in it to `settings`.
+#[task]
+pub async fn display_menu(settings: &'static SettingsMutex) {
+    let mut ticker = Ticker::every(REFRESH_INTERVAL);
+
+    loop {
+        menu::opened().await;
+
+        let mut navigator = Navigator::new(&MAIN_MENU, idle::preferences());
+
+        let mut view = MenuView::new(crate::compositor::screen());
+        view.set_navigator(&navigator);
+
+        loop {
+            view.update(Instant::now());
+            show_overlay(&mut view).await;
+
+            let input = match select(MENU_INPUT.receive(), ticker.next()).await {
+                Either::First(input) => input,
+                Either::Second(()) => continue,
+            };
+
+            if let Input::Knob(value) = input {
+                view.set_knob(value);
+            }
+
+            match navigator.handle(input) {
+                Outcome::Changed => {
+                    view.set_navigator(&navigator);
+
+                    #[cfg(feature = "net")]
+                    if navigator.screen() == Some(Screen::Devices)
+                        && pick_device(&mut navigator, &mut view).await
+                    {
+                        break;
+                    }
+                }
+                Outcome::Chosen => {
+                    let preferences = navigator.preferences();
+
+                    idle::set_preferences(preferences);
+
+                    if let Err(e) = settings.lock().await.save(Key::Preferences, &preferences) {
+                        warn!("Failed to save preferences: {e:?}");
+                    }
+
+                    view.set_navigator(&navigator);
+                }
+                Outcome::Closed => break,
+                // the devices screen is worked by `pick_device`, and none of the others
+                // do anything with the buttons
+                Outcome::Unchanged | Outcome::Screen(..) => {}
+            }
+        }
+
+        hide_overlay().await;
+        menu::close();
+    }
+}
+
+/// Works the devices screen until a device is picked - returning `true`, as
+/// there is nothing left to do in the menu - or back is pressed.
+#[cfg(feature = "net")]
+async fn pick_device(navigator: &mut Navigator, view: &mut MenuView) -> bool {
+    use crate::{
+        controller::{self, DEVICES_SIGNAL},
+        menu::Picker,
+    };
+
+    view.set_message("Looking...");
+    controller::list_devices();
+
+    // nothing to pick from until the devices turn up
+    let mut picker: Option<Picker> = None;
+
+    loop {
+        show_overlay(view).await;
+
+        let input = match select(MENU_INPUT.receive(), DEVICES_SIGNAL.wait()).await {
+            Either::First(input) => input,
+            Either::Second(Some(devices)) => {
+                // starting from the one playing now
+                let active = devices.iter().position(|device| device.is_active);
+                let listed = Picker::new(devices.len(), active.unwrap_or_default());
+
+                view.set_devices(
+                    devices.iter().map(|device| device.name.as_str()),
+                    listed.selected(),
+                );
+                picker = Some(listed);
+
+                continue;
+            }
+            Either::Second(None) => {
+                view.set_message("Couldn't list");
+                continue;
+            }
+        };
+
+        match navigator.handle(input) {
+            Outcome::Screen(_, input) => {
+                let Some(picker) = &mut picker else {
+                    continue;
+                };
+
+                match picker.handle(input) {
+                    Some(index) => {
+                        controller::transfer(index);
+                        return true;
+                    }
+                    None => view.select_device(picker.selected()),
+                }
+            }
+            // back out to the menu
+            _ => {
+                view.set_navigator(navigator);
+                return false;
+            }
+        }
+    }
+}
@@ -0,0 +1,362 @@
+use core::fmt::Write;
+
+use embassy_futures::select::{select, select4, Either, Either4};
+use embedded_graphics::{
+    geometry::AnchorY, pixelcolor::BinaryColor, prelude::*, primitives::Rectangle, text::Alignment,
+};
+
+use super::{
+    font::{LARGE, SMALL},
+    thumbnail::{Artwork, THUMBNAIL_SIZE},
+    widgets::{show, Icon, Label, Layout, ProgressBar, ScrollingText, Widget},
+    BoundingBox,
+};
+use crate::{
+    album_art::{AlbumArt, ALBUM_ART_CHANNEL},
+    buttons::{SkipType, PAUSE_ICON, PLAY_ICON, SKIP_BACK_ICON, SKIP_CHANNEL, SKIP_ICON},
+    playback::{PlaybackState, ARTIST_LEN, PLAYBACK_CHANNEL, TRACK_LEN},
+    prelude::*,
+    status::{SystemState, SYSTEM_STATE_CHANNEL},
+};
+
+/// How often the title and artist move along by a pixel, and the progress bar
+/// and times are brought up to date.
+const SCROLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long the skip icon stays up after a skip.
+const SKIP_ICON_TIME: Duration = Duration::from_millis(250);
+
+/// Long enough for `-99:59:59`.
+const TIME_LEN: usize = 9;
+
+/// Formats `duration` as `m:ss`, or `h:mm:ss` for anything an hour or longer.
+fn format_time(duration: Duration) -> String<TIME_LEN> {
+    let secs = duration.as_secs();
+    let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+
+    let mut time = String::new();
+
+    if hours > 0 {
+        write!(time, "{hours}:{minutes:02}:{secs:02}").ok();
+    } else {
+        write!(time, "{minutes}:{secs:02}").ok();
+    }
+
+    time
+}
+
+/// The album art, track title and artist (scrolling when they don't fit),
+/// whether it is playing, and how far through it playback is.
+pub struct NowPlaying {
+    bounds: Rectangle,
+    volume: Label<8>,
+    status: Icon,
+    skip: Icon,
+    artwork: Artwork,
+    title: ScrollingText<TRACK_LEN>,
+    artist: ScrollingText<ARTIST_LEN>,
+    progress: ProgressBar,
+    elapsed: Label<TIME_LEN>,
+    remaining: Label<TIME_LEN>,
+    playback: Option<PlaybackState>,
+    /// The last album art downloaded - only shown while that album is playing.
+    art: Option<AlbumArt>,
+}
+
+impl NowPlaying {
+    pub fn new(bounds: Rectangle) -> Self {
+        let mut layout = Layout::new(bounds);
+
+        let mut status_bar = Layout::new(layout.top(8));
+        let skip = status_bar.right(8);
+        let status = status_bar.right(9);
+        let volume = status_bar.remaining();
+
+        let mut times = Layout::new(layout.bottom(SMALL.character_size().height));
+        layout.bottom(2);
+        let progress = layout.bottom(6);
+
+        // the album art, with the title and artist beside it - both centred in
+        // between the status bar and progress bar. Screens without room for the
+        // art beside the text just have the text
+        let mut middle = Layout::new(layout.remaining());
+        let has_room = middle.remaining().size.height >= THUMBNAIL_SIZE
+            && middle.remaining().size.width >= 2 * THUMBNAIL_SIZE;
+
+        let artwork = if has_room {
+            let artwork = middle
+                .left(THUMBNAIL_SIZE)
+                .resized_height(THUMBNAIL_SIZE, AnchorY::Center);
+            middle.left(3);
+            artwork
+        } else {
+            middle.left(0)
+        };
+
+        // as much of the title and artist as fits - just the title, on the shortest
+        // screens
+        let text_height = (LARGE.character_size().height + 3 + SMALL.character_size().height)
+            .min(middle.remaining().size.height);
+        let mut text = Layout::new(
+            middle
+                .remaining()
+                .resized_height(text_height, AnchorY::Center),
+        );
+        let title = text.top(LARGE.character_size().height);
+        text.top(3);
+        let artist = text.top(SMALL.character_size().height);
+
+        let time_width = times.remaining().size.width / 2;
+        let elapsed = times.left(time_width);
+
+        let mut screen = Self {
+            bounds,
+            volume: Label::new(volume, &SMALL),
+            status: Icon::new(status),
+            skip: Icon::new(skip),
+            artwork: Artwork::new(artwork),
+            title: ScrollingText::new(title, &LARGE),
+            artist: ScrollingText::new(artist, &SMALL),
+            progress: ProgressBar::new(progress).with_border(),
+            elapsed: Label::new(elapsed, &SMALL),
+            remaining: Label::new(times.remaining(), &SMALL).with_alignment(Alignment::Right),
+            playback: None,
+            art: None,
+        };
+
+        screen.set_playback(None);
+        screen
+    }
+
+    /// Shows `playback` - or that nothing is playing, if it is `None`.
+    pub fn set_playback(&mut self, playback: Option<PlaybackState>) {
+        match &playback {
+            Some(playback) => {
+                self.title.set_text(&playback.track);
+                self.artist.set_text(&playback.artist);
+                self.status.set_image(Some(if playback.is_playing {
+                    PLAY_ICON
+                } else {
+                    PAUSE_ICON
+                }));
+
+                let mut volume: String<8> = String::new();
+
+                if let Some(percent) = playback.volume {
+                    write!(volume, "{percent}%").ok();
+                }
+
+                self.volume.set_text(&volume);
+            }
+            None => {
+                self.title.set_text("Nothing playing");
+                self.artist.set_text("");
+                self.status.set_image(None);
+                self.volume.set_text("");
+            }
+        }
+
+        self.playback = playback;
+        self.update_artwork();
+        self.update(Instant::now());
+    }
+
+    /// Shows `art` whenever its album is playing.
+    pub fn set_album_art(&mut self, art: AlbumArt) {
+        self.art = Some(art);
+        self.update_artwork();
+    }
+
+    fn update_artwork(&mut self) {
+        let thumbnail = match (&self.playback, &self.art) {
+            (Some(playback), Some(art)) if playback.album_id == art.album_id => {
+                Some(art.thumbnail.clone())
+            }
+            _ => None,
+        };
+
+        self.artwork.set_thumbnail(thumbnail);
+    }
+
+    /// Flashes the icon for `skip` - or clears it, if it is `None`.
+    pub fn set_skip(&mut self, skip: Option<SkipType>) {
+        self.skip.set_image(skip.map(|skip| match skip {
+            SkipType::Skip => SKIP_ICON,
+            SkipType::SkipBack => SKIP_BACK_ICON,
+        }));
+    }
+
+    /// Brings the progress bar and times up to `now`, carrying on from the last
+    /// poll while something is playing.
+    pub fn update(&mut self, now: Instant) {
+        match &self.playback {
+            Some(playback) => {
+                let progress = playback.progress_at(now);
+                let duration = playback.duration.as_millis().max(1);
+
+                self.progress
+                    .set_value(progress.as_millis() as f32 / duration as f32);
+                self.elapsed.set_text(&format_time(progress));
+
+                let mut remaining: String<TIME_LEN> = String::new();
+                write!(remaining, "-{}", format_time(playback.remaining_at(now))).ok();
+                self.remaining.set_text(&remaining);
+            }
+            None => {
+                self.progress.set_value(0.);
+                self.elapsed.set_text("");
+                self.remaining.set_text("");
+            }
+        }
+    }
+
+    /// Moves long titles and artists along by `pixels`.
+    pub fn scroll(&mut self, pixels: u32) {
+        self.title.scroll(pixels);
+        self.artist.scroll(pixels);
+    }
+}
+
+impl Widget for NowPlaying {
+    fn bounds(&self) -> Rectangle {
+        self.bounds
+    }
+
+    fn is_dirty(&self) -> bool {
+        self.volume.is_dirty()
+            || self.status.is_dirty()
+            || self.skip.is_dirty()
+            || self.artwork.is_dirty()
+            || self.title.is_dirty()
+            || self.artist.is_dirty()
+            || self.progress.is_dirty()
+            || self.elapsed.is_dirty()
+            || self.remaining.is_dirty()
+    }
+
+    fn mark_dirty(&mut self) {
+        // together the widgets span the whole screen, so the gaps get redrawn too
+        self.volume.mark_dirty();
+        self.status.mark_dirty();
+        self.skip.mark_dirty();
+        self.artwork.mark_dirty();
+        self.title.mark_dirty();
+        self.artist.mark_dirty();
+        self.progress.mark_dirty();
+        self.elapsed.mark_dirty();
+        self.remaining.mark_dirty();
+    }
+
+    fn mark_clean(&mut self) {
+        self.volume.mark_clean();
+        self.status.mark_clean();
+        self.skip.mark_clean();
+        self.artwork.mark_clean();
+        self.title.mark_clean();
+        self.artist.mark_clean();
+        self.progress.mark_clean();
+        self.elapsed.mark_clean();
+        self.remaining.mark_clean();
+    }
+
+    fn render<D>(&self, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        target.fill_solid(&self.bounds, BinaryColor::Off)?;
+
+        self.volume.render(target)?;
+        self.status.render(target)?;
+        self.skip.render(target)?;
+        self.artwork.render(target)?;
+        self.title.render(target)?;
+        self.artist.render(target)?;
+        self.progress.render(target)?;
+        self.elapsed.render(target)?;
+        self.remaining.render(target)
+    }
+
+    fn dirty_box(&self) -> Option<BoundingBox> {
+        [
+            self.volume.dirty_box(),
+            self.status.dirty_box(),
+            self.skip.dirty_box(),
+            self.artwork.dirty_box(),
+            self.title.dirty_box(),
+            self.artist.dirty_box(),
+            self.progress.dirty_box(),
+            self.elapsed.dirty_box(),
+            self.remaining.dirty_box(),
+        ]
+        .into_iter()
+        .fold(None, BoundingBox::merge)
+    }
+}
+
+/// Shows what is playing while teeny is [`Ready`](SystemState::Ready) -
+/// until then, and whenever it stops being ready, the
+/// [status screens](super::status) are up instead.
+#[task]
+pub async fn display_now_playing() {
+    let mut playback = PLAYBACK_CHANNEL.subscriber().unwrap();
+    let mut skip = SKIP_CHANNEL.subscriber().unwrap();
+    let mut art = ALBUM_ART_CHANNEL.subscriber().unwrap();
+    let mut status = SYSTEM_STATE_CHANNEL.subscriber().unwrap();
+
+    let mut screen = NowPlaying::new(crate::compositor::screen());
+
+    screen.set_playback(None);
+
+    let mut ticker = Ticker::every(SCROLL_INTERVAL);
+    let mut skip_until: Option<Instant> = None;
+    let mut ready = false;
+
+    loop {
+        let now = Instant::now();
+
+        if skip_until.is_some_and(|until| until <= now) {
+            skip_until = None;
+            screen.set_skip(None);
+        }
+
+        screen.update(now);
+
+        if ready {
+            show(&mut screen).await;
+        }
+
+        match select(
+            select4(
+                playback.next_message_pure(),
+                skip.next_message_pure(),
+                art.next_message_pure(),
+                ticker.next(),
+            ),
+            status.next_message_pure(),
+        )
+        .await
+        {
+            Either::First(Either4::First(state)) => {
+                screen.set_playback(state);
+                // something else may have drawn over the screen since it was last shown
+                screen.mark_dirty();
+            }
+            Either::First(Either4::Second(skip)) => {
+                screen.set_skip(Some(skip));
+                skip_until = Some(Instant::now() + SKIP_ICON_TIME);
+            }
+            Either::First(Either4::Third(art)) => screen.set_album_art(art),
+            Either::First(Either4::Fourth(())) => screen.scroll(1),
+            Either::Second(state) => {
+                let now_ready = state == SystemState::Ready;
+
+                // taking over from the status screen
+                if now_ready && !ready {
+                    screen.mark_dirty();
+                }
+
+                ready = now_ready;
+            }
+        }
+    }
+}
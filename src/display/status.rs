@@ -0,0 +1,273 @@
+use core::fmt::Write;
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::pubsub::Subscriber;
+use embedded_graphics::{
+    geometry::AnchorY, pixelcolor::BinaryColor, prelude::*, primitives::Rectangle,
+};
+
+use super::{
+    font::{LARGE, SMALL},
+    show_login, show_setup_ap,
+    widgets::{show, Label, Layout, Spinner, Widget},
+    BoundingBox, TeenyDisplayError,
+};
+use crate::{
+    net::ApPassword,
+    prelude::*,
+    status::{SystemState, SYSTEM_STATE_CHANNEL},
+};
+
+/// How often the spinner moves on, and the retry countdown is brought up to
+/// date.
+const SPIN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The most the spinner takes up, on screens tall enough.
+const SPINNER_SIZE: u32 = 24;
+
+/// Long enough for any detail - an SSID is up to 32 bytes.
+const DETAIL_LEN: usize = 32;
+
+type StateSubscriber = Subscriber<'static, CriticalSectionRawMutex, SystemState, 1, 4, 1>;
+
+/// What teeny is up to, under a spinner - for every [`SystemState`] without a
+/// screen of its own.
+pub struct StatusView {
+    bounds: Rectangle,
+    spinner: Spinner,
+    headline: Label<16>,
+    detail: Label<DETAIL_LEN>,
+    note: Label<DETAIL_LEN>,
+    /// When whatever went wrong is tried again, counted down to in the note.
+    retry_at: Option<Instant>,
+    /// Everything is redrawn the first time, as something else was on the
+    /// display.
+    dirty: bool,
+}
+
+impl StatusView {
+    pub fn new(bounds: Rectangle) -> Self {
+        let mut layout = Layout::new(bounds);
+
+        let spinner_size = SPINNER_SIZE.min(bounds.size.height);
+        let spinner = layout
+            .left(spinner_size)
+            .resized_height(spinner_size, AnchorY::Center);
+        layout.left(4);
+
+        let text_height = LARGE.character_size().height + 2 + 2 * SMALL.character_size().height + 1;
+        let mut text = Layout::new(layout.remaining().resized_height(
+            text_height.min(layout.remaining().size.height),
+            AnchorY::Center,
+        ));
+        let headline = text.top(LARGE.character_size().height);
+        text.top(2);
+        let detail = text.top(SMALL.character_size().height);
+        text.top(1);
+        let note = text.top(SMALL.character_size().height);
+
+        Self {
+            bounds,
+            spinner: Spinner::new(spinner),
+            headline: Label::new(headline, &LARGE),
+            detail: Label::new(detail, &SMALL),
+            note: Label::new(note, &SMALL),
+            retry_at: None,
+            dirty: true,
+        }
+    }
+
+    /// Shows `state` - for the states with their own screen, just what they
+    /// are.
+    pub fn set_state(&mut self, state: &SystemState) {
+        let mut detail: String<DETAIL_LEN> = String::new();
+        let mut note: String<DETAIL_LEN> = String::new();
+
+        let headline = match state {
+            SystemState::Booting => {
+                detail
+                    .push_str(concat!("teeny v", env!("CARGO_PKG_VERSION")))
+                    .ok();
+                "Starting up"
+            }
+            SystemState::StartingAp => {
+                detail.push_str("Setup access point").ok();
+                "Starting up"
+            }
+            SystemState::SettingUp => "Set up teeny",
+            SystemState::Connecting { ssid } => {
+                detail
+                    .push_str(ssid.as_deref().unwrap_or("Looking for wifi"))
+                    .ok();
+                "Connecting"
+            }
+            SystemState::GotIp { ssid, address } => {
+                detail.push_str(ssid).ok();
+                write!(note, "{address}").ok();
+                "Connected"
+            }
+            SystemState::Authorising { .. } => {
+                detail.push_str("to Spotify").ok();
+                "Logging in"
+            }
+            SystemState::Ready => "Ready",
+            SystemState::Error { reason, .. } => {
+                detail.push_str(reason).ok();
+                "Error"
+            }
+        };
+
+        self.headline.set_text(headline);
+        self.detail.set_text(&detail);
+        self.note.set_text(&note);
+
+        self.retry_at = match state {
+            SystemState::Error { retry_at, .. } => *retry_at,
+            _ => None,
+        };
+    }
+
+    /// Moves the spinner on, and brings the retry countdown up to `now`.
+    pub fn update(&mut self, now: Instant) {
+        self.spinner.step();
+
+        if let Some(retry_at) = self.retry_at {
+            // rounded up, so it never says 0 while still waiting
+            let secs = retry_at
+                .saturating_duration_since(now)
+                .as_millis()
+                .div_ceil(1000);
+
+            let mut note: String<DETAIL_LEN> = String::new();
+            write!(note, "Retrying in {secs}s").ok();
+            self.note.set_text(&note);
+        }
+    }
+}
+
+impl Widget for StatusView {
+    fn bounds(&self) -> Rectangle {
+        self.bounds
+    }
+
+    fn is_dirty(&self) -> bool {
+        self.dirty
+            || self.spinner.is_dirty()
+            || self.headline.is_dirty()
+            || self.detail.is_dirty()
+            || self.note.is_dirty()
+    }
+
+    fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    fn mark_clean(&mut self) {
+        self.dirty = false;
+        self.spinner.mark_clean();
+        self.headline.mark_clean();
+        self.detail.mark_clean();
+        self.note.mark_clean();
+    }
+
+    fn render<D>(&self, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        target.fill_solid(&self.bounds, BinaryColor::Off)?;
+
+        self.spinner.render(target)?;
+        self.headline.render(target)?;
+        self.detail.render(target)?;
+        self.note.render(target)
+    }
+
+    fn dirty_box(&self) -> Option<BoundingBox> {
+        if self.dirty {
+            return BoundingBox::from_rectangle(&self.bounds);
+        }
+
+        [
+            self.spinner.dirty_box(),
+            self.headline.dirty_box(),
+            self.detail.dirty_box(),
+            self.note.dirty_box(),
+        ]
+        .into_iter()
+        .fold(None, BoundingBox::merge)
+    }
+}
+
+/// Shows what teeny is up to until it is [`Ready`](SystemState::Ready) - how
+/// to join the setup access point (with `ap_password`) while it is waiting to
+/// be set up, where to log in to spotify while it is waiting for a login, and
+/// a [`StatusView`] the rest of the time. Once it is ready the now playing
+/// screen takes over.
+#[task]
+pub async fn display_status(ap_password: Option<ApPassword>) {
+    async fn display_status_internal(
+        ap_password: Option<&str>,
+        subscriber: &mut StateSubscriber,
+        state: &mut SystemState,
+    ) -> Result<(), TeenyDisplayError> {
+        let mut ticker = Ticker::every(SPIN_INTERVAL);
+        let mut view = StatusView::new(Layout::screen().remaining());
+
+        loop {
+            let current = state.clone();
+
+            match &current {
+                // screens of their own, which stay put until the state changes
+                SystemState::SettingUp => show_setup_ap(ap_password).await?,
+                SystemState::Authorising {
+                    login: Some((url, code)),
+                } => show_login(url, code).await?,
+                SystemState::Ready => {}
+                current => {
+                    view.set_state(current);
+                    // whatever was on the display before is drawn over
+                    view.mark_dirty();
+                    show(&mut view).await;
+
+                    loop {
+                        match select(subscriber.next_message_pure(), ticker.next()).await {
+                            Either::First(new_state) if new_state != *current => {
+                                *state = new_state;
+                                break;
+                            }
+                            Either::First(_) => {}
+                            Either::Second(()) => {
+                                view.update(Instant::now());
+                                show(&mut view).await;
+                            }
+                        }
+                    }
+
+                    continue;
+                }
+            }
+
+            loop {
+                let new_state = subscriber.next_message_pure().await;
+
+                if new_state != *state {
+                    *state = new_state;
+                    break;
+                }
+            }
+        }
+    }
+
+    let mut subscriber = SYSTEM_STATE_CHANNEL.subscriber().unwrap();
+    let mut state = SystemState::Booting;
+
+    loop {
+        if let Err(e) =
+            display_status_internal(ap_password.as_deref(), &mut subscriber, &mut state).await
+        {
+            warn!("Display error: {e:?}");
+        }
+
+        Timer::after_secs(1).await;
+    }
+}
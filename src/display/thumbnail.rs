@@ -0,0 +1,296 @@
+//! Album art, shrunk down and dithered to 1 bit.
+
+use embedded_graphics::{
+    image::{Image, ImageRaw},
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::Rectangle,
+};
+
+use super::widgets::{dirty_flag, Widget};
+use crate::jpeg::{read_jpeg, Block, Frame, ReadJpegError};
+
+/// How many pixels across (and down) a thumbnail is.
+pub const THUMBNAIL_SIZE: u32 = 32;
+
+const PIXELS: usize = (THUMBNAIL_SIZE * THUMBNAIL_SIZE) as usize;
+
+/// One bit per pixel.
+const THUMBNAIL_LEN: usize = PIXELS / 8;
+
+/// Averages an image down to [`THUMBNAIL_SIZE`] greyscale pixels, a block at
+/// a time.
+struct Shrinker {
+    sums: [u32; PIXELS],
+    counts: [u16; PIXELS],
+}
+
+impl Shrinker {
+    fn new() -> Self {
+        Self {
+            sums: [0; PIXELS],
+            counts: [0; PIXELS],
+        }
+    }
+
+    /// The thumbnail pixels which pixel `x` of `len` covers, along one side.
+    fn covers(x: u32, len: u32) -> core::ops::Range<u32> {
+        let start = x * THUMBNAIL_SIZE / len;
+        let end = ((x + 1) * THUMBNAIL_SIZE).div_ceil(len).max(start + 1);
+
+        start..end.min(THUMBNAIL_SIZE)
+    }
+
+    fn add(&mut self, frame: &Frame, block: &Block) {
+        for (i, &pixel) in block.pixels.iter().enumerate() {
+            let x = block.x + i as u32 % 8;
+            let y = block.y + i as u32 / 8;
+
+            if x >= frame.width || y >= frame.height {
+                continue;
+            }
+
+            for thumbnail_y in Self::covers(y, frame.height) {
+                for thumbnail_x in Self::covers(x, frame.width) {
+                    let index = (thumbnail_y * THUMBNAIL_SIZE + thumbnail_x) as usize;
+
+                    self.sums[index] += pixel as u32;
+                    self.counts[index] += 1;
+                }
+            }
+        }
+    }
+
+    fn finish(&self) -> [u8; PIXELS] {
+        let mut pixels = [0; PIXELS];
+
+        for ((pixel, &sum), &count) in pixels.iter_mut().zip(&self.sums).zip(&self.counts) {
+            *pixel = (sum / count.max(1) as u32) as u8;
+        }
+
+        pixels
+    }
+}
+
+/// A [`THUMBNAIL_SIZE`] square 1 bit image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Thumbnail {
+    bits: [u8; THUMBNAIL_LEN],
+}
+
+impl Thumbnail {
+    /// Decodes the JPEG `reader` returns, squashing it into a square if it
+    /// isn't one.
+    pub async fn from_jpeg<R>(reader: &mut R) -> Result<Self, ReadJpegError<R::Error>>
+    where
+        R: embedded_io_async::Read,
+    {
+        let mut shrinker = Shrinker::new();
+
+        read_jpeg(reader, |frame, block| shrinker.add(frame, block)).await?;
+
+        Ok(Self::dithered(&shrinker.finish()))
+    }
+
+    /// Dithers greyscale `pixels` (row by row, `0` is black) with
+    /// Floyd-Steinberg, after stretching them out to use the whole range from
+    /// black to white - album art on a 1 bit display needs all the contrast it
+    /// can get.
+    pub fn dithered(pixels: &[u8; PIXELS]) -> Self {
+        const WIDTH: usize = THUMBNAIL_SIZE as usize;
+
+        let darkest = pixels.iter().copied().min().unwrap_or_default() as i32;
+        let lightest = pixels.iter().copied().max().unwrap_or_default() as i32;
+        let range = (lightest - darkest).max(1);
+
+        let mut bits = [0; THUMBNAIL_LEN];
+
+        // the error carried to this row and the next, offset by one so there is room
+        // either side
+        let mut errors = [0i16; WIDTH + 2];
+        let mut next_errors = [0i16; WIDTH + 2];
+
+        for y in 0..WIDTH {
+            for x in 0..WIDTH {
+                let stretched = (pixels[y * WIDTH + x] as i32 - darkest) * 255 / range;
+                let value = stretched as i16 + errors[x + 1];
+
+                let on = value >= 128;
+                let error = value - if on { 255 } else { 0 };
+
+                errors[x + 2] += error * 7 / 16;
+                next_errors[x] += error * 3 / 16;
+                next_errors[x + 1] += error * 5 / 16;
+                next_errors[x + 2] += error / 16;
+
+                if on {
+                    bits[(y * WIDTH + x) / 8] |= 0x80 >> (x % 8);
+                }
+            }
+
+            errors = next_errors;
+            next_errors = [0; WIDTH + 2];
+        }
+
+        Self { bits }
+    }
+
+    pub fn image(&self) -> ImageRaw<'_, BinaryColor> {
+        ImageRaw::new(&self.bits, THUMBNAIL_SIZE)
+    }
+}
+
+/// A [`Thumbnail`] in the top left of its bounds, or nothing at all.
+pub struct Artwork {
+    bounds: Rectangle,
+    thumbnail: Option<Thumbnail>,
+    dirty: bool,
+}
+
+impl Artwork {
+    pub fn new(bounds: Rectangle) -> Self {
+        Self {
+            bounds,
+            thumbnail: None,
+            dirty: true,
+        }
+    }
+
+    pub fn set_thumbnail(&mut self, thumbnail: Option<Thumbnail>) {
+        if self.thumbnail != thumbnail {
+            self.thumbnail = thumbnail;
+            self.dirty = true;
+        }
+    }
+}
+
+impl Widget for Artwork {
+    fn bounds(&self) -> Rectangle {
+        self.bounds
+    }
+
+    dirty_flag!();
+
+    fn render<D>(&self, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let mut target = target.clipped(&self.bounds);
+        target.fill_solid(&self.bounds, BinaryColor::Off)?;
+
+        if let Some(thumbnail) = &self.thumbnail {
+            Image::new(&thumbnail.image(), self.bounds.top_left).draw(&mut target)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+    use embedded_graphics::image::GetPixel;
+
+    use super::*;
+    use crate::jpeg::TeenyJpegError;
+
+    const SIZE: usize = THUMBNAIL_SIZE as usize;
+
+    fn lit(thumbnail: &Thumbnail, x: usize, y: usize) -> bool {
+        thumbnail.image().pixel(Point::new(x as i32, y as i32)) == Some(BinaryColor::On)
+    }
+
+    /// How many pixels are lit in the columns `columns`.
+    fn lit_in(thumbnail: &Thumbnail, columns: core::ops::Range<usize>) -> usize {
+        (0..SIZE)
+            .flat_map(|y| columns.clone().map(move |x| (x, y)))
+            .filter(|&(x, y)| lit(thumbnail, x, y))
+            .count()
+    }
+
+    fn from_jpeg(data: &[u8]) -> Result<Thumbnail, ReadJpegError<core::convert::Infallible>> {
+        block_on(Thumbnail::from_jpeg(&mut &data[..]))
+    }
+
+    #[test]
+    fn black_and_white_stay_put() {
+        let pixels = core::array::from_fn(|i| if i % SIZE < SIZE / 2 { 0 } else { 255 });
+        let thumbnail = Thumbnail::dithered(&pixels);
+
+        assert_eq!(lit_in(&thumbnail, 0..SIZE / 2), 0);
+        assert_eq!(lit_in(&thumbnail, SIZE / 2..SIZE), SIZE * SIZE / 2);
+    }
+
+    #[test]
+    fn contrast_is_stretched_to_black_and_white() {
+        // two greys a shade apart still come out black and white
+        let pixels = core::array::from_fn(|i| if i % SIZE < SIZE / 2 { 100 } else { 110 });
+
+        assert_eq!(
+            Thumbnail::dithered(&pixels),
+            Thumbnail::dithered(&core::array::from_fn(|i| pixels[i] / 110 * 255))
+        );
+    }
+
+    #[test]
+    fn greys_dither_to_as_many_lit_pixels() {
+        // black to white, left to right
+        let pixels = core::array::from_fn(|i| ((i % SIZE) * 255 / (SIZE - 1)) as u8);
+        let thumbnail = Thumbnail::dithered(&pixels);
+
+        // a quarter of the way along is about a quarter lit, and so on
+        for quarter in 0..4 {
+            let columns = quarter * SIZE / 4..(quarter + 1) * SIZE / 4;
+            let lit = lit_in(&thumbnail, columns.clone()) as f32 / (SIZE * SIZE / 4) as f32;
+            let brightness = (columns.start + columns.end - 1) as f32 / 2. / (SIZE - 1) as f32;
+
+            assert!(
+                (lit - brightness).abs() < 0.05,
+                "{lit} of quarter {quarter} is lit, for {brightness}"
+            );
+        }
+
+        // with the lit pixels spread out, not bunched up at the end of each row
+        for y in 0..SIZE {
+            let row_lit = (SIZE / 2..SIZE).filter(|&x| lit(&thumbnail, x, y)).count();
+            assert!((8..=16).contains(&row_lit), "{row_lit} lit in row {y}");
+        }
+    }
+
+    #[test]
+    fn shrinks_jpegs() {
+        // 16x16, getting lighter to the right and down
+        let thumbnail = from_jpeg(include_bytes!("../../tests/fixtures/grey.jpg")).unwrap();
+
+        assert!(!lit(&thumbnail, 0, 0));
+        assert!(lit(&thumbnail, SIZE - 1, SIZE - 1));
+        assert!(lit_in(&thumbnail, 0..SIZE / 2) < lit_in(&thumbnail, SIZE / 2..SIZE));
+    }
+
+    #[test]
+    fn squashes_jpegs_into_a_square() {
+        // 30x20, getting lighter down and (a little) to the right
+        let thumbnail = from_jpeg(include_bytes!("../../tests/fixtures/colour_420.jpg")).unwrap();
+
+        let top = (0..SIZE / 4).flat_map(|y| (0..SIZE).map(move |x| (x, y)));
+        let bottom = (SIZE * 3 / 4..SIZE).flat_map(|y| (0..SIZE).map(move |x| (x, y)));
+
+        let top_lit = top.filter(|&(x, y)| lit(&thumbnail, x, y)).count();
+        let bottom_lit = bottom.filter(|&(x, y)| lit(&thumbnail, x, y)).count();
+
+        // the whole of the thumbnail is covered, top to bottom
+        assert!(top_lit < SIZE * SIZE / 16, "{top_lit} lit at the top");
+        assert!(
+            bottom_lit > SIZE * SIZE * 3 / 16,
+            "{bottom_lit} lit at the bottom"
+        );
+    }
+
+    #[test]
+    fn passes_on_decoding_errors() {
+        assert_eq!(
+            from_jpeg(include_bytes!("../../tests/fixtures/progressive.jpg")),
+            Err(ReadJpegError::Jpeg(TeenyJpegError::Unsupported))
+        );
+    }
+}
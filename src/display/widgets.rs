@@ -0,0 +1,1214 @@
+//! A small retained-mode widget layer - widgets remember what they show, only
+//! redraw once it changes, and render into any [`DrawTarget`] so the same code
+//! draws to the display (through a [`Region`]) or to a buffer on the host.
+
+use embedded_graphics::{
+    geometry::{AnchorPoint, AnchorX, AnchorY},
+    image::{Image, ImageRaw},
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::{Circle, PrimitiveStyle, Rectangle},
+    text::Alignment,
+};
+
+use super::{font::Font, BoundingBox};
+use crate::{compositor::Region, prelude::*};
+
+/// Something on screen which knows its area, whether it needs redrawing, and
+/// how to draw itself.
+pub trait Widget {
+    /// Everything the widget draws is inside this area.
+    fn bounds(&self) -> Rectangle;
+
+    /// Whether the widget changed since it was last drawn.
+    fn is_dirty(&self) -> bool;
+
+    /// Forces a redraw, e.g. after something else was drawn over the widget.
+    fn mark_dirty(&mut self);
+
+    fn mark_clean(&mut self);
+
+    /// Draws the whole widget - background included - clipped to its bounds.
+    fn render<D>(&self, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>;
+
+    /// The area which needs redrawing, if any.
+    fn dirty_box(&self) -> Option<BoundingBox> {
+        if self.is_dirty() {
+            BoundingBox::from_rectangle(&self.bounds())
+        } else {
+            None
+        }
+    }
+}
+
+/// Implements [`Widget::is_dirty`], [`Widget::mark_dirty`] and
+/// [`Widget::mark_clean`] for a widget which keeps track of changes in nothing
+/// but its own `dirty` field.
+macro_rules! dirty_flag {
+    () => {
+        fn is_dirty(&self) -> bool {
+            self.dirty
+        }
+
+        fn mark_dirty(&mut self) {
+            self.dirty = true;
+        }
+
+        fn mark_clean(&mut self) {
+            self.dirty = false;
+        }
+    };
+}
+
+pub(crate) use dirty_flag;
+
+/// Renders whatever changed in `widget`, if anything did.
+fn render_dirty(widget: &mut impl Widget) -> Option<Region> {
+    let dirty = widget.dirty_box()?;
+
+    let mut region = Region::new(dirty.rectangle());
+    widget.render(&mut region).unwrap_or_else(|e| match e {});
+    widget.mark_clean();
+
+    Some(region)
+}
+
+/// Sends whatever changed in `widget` to the display.
+pub async fn show(widget: &mut impl Widget) {
+    if let Some(region) = render_dirty(widget) {
+        region.submit().await;
+    }
+}
+
+/// Like [`show`], but over everything else on the display - see
+/// [`Region::submit_overlay`].
+pub async fn show_overlay(widget: &mut impl Widget) {
+    if let Some(region) = render_dirty(widget) {
+        region.submit_overlay().await;
+    }
+}
+
+/// As much of `text` as fits in `N` bytes, without splitting a character.
+fn truncated<const N: usize>(text: &str) -> String<N> {
+    let mut end = text.len().min(N);
+
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    String::try_from(&text[..end]).expect("the text was cut down to fit")
+}
+
+/// Where text in `font` starts, to be vertically centred on `point`.
+fn centred_on(point: Point, font: &Font) -> Point {
+    point
+        - Point::new(
+            0,
+            (font.character_size().height.saturating_sub(1) / 2) as i32,
+        )
+}
+
+/// Carves an area up into the areas its widgets live in, by taking strips off
+/// its edges.
+#[derive(Debug, Clone, Copy)]
+pub struct Layout {
+    area: Rectangle,
+}
+
+impl Layout {
+    pub const fn new(area: Rectangle) -> Self {
+        Self { area }
+    }
+
+    pub fn screen() -> Self {
+        Self::new(crate::compositor::screen())
+    }
+
+    /// Takes a strip `height` pixels high off the top.
+    pub fn top(&mut self, height: u32) -> Rectangle {
+        let height = height.min(self.area.size.height);
+        let strip = self.area.resized_height(height, AnchorY::Top);
+
+        self.area = self
+            .area
+            .resized_height(self.area.size.height - height, AnchorY::Bottom);
+
+        strip
+    }
+
+    /// Takes a strip `height` pixels high off the bottom.
+    pub fn bottom(&mut self, height: u32) -> Rectangle {
+        let height = height.min(self.area.size.height);
+        let strip = self.area.resized_height(height, AnchorY::Bottom);
+
+        self.area = self
+            .area
+            .resized_height(self.area.size.height - height, AnchorY::Top);
+
+        strip
+    }
+
+    /// Takes a strip `width` pixels wide off the left.
+    pub fn left(&mut self, width: u32) -> Rectangle {
+        let width = width.min(self.area.size.width);
+        let strip = self.area.resized_width(width, AnchorX::Left);
+
+        self.area = self
+            .area
+            .resized_width(self.area.size.width - width, AnchorX::Right);
+
+        strip
+    }
+
+    /// Takes a strip `width` pixels wide off the right.
+    pub fn right(&mut self, width: u32) -> Rectangle {
+        let width = width.min(self.area.size.width);
+        let strip = self.area.resized_width(width, AnchorX::Right);
+
+        self.area = self
+            .area
+            .resized_width(self.area.size.width - width, AnchorX::Left);
+
+        strip
+    }
+
+    /// Whatever is left.
+    pub fn remaining(self) -> Rectangle {
+        self.area
+    }
+}
+
+/// A single line of text, vertically centred in its bounds.
+pub struct Label<const N: usize = 32> {
+    bounds: Rectangle,
+    text: String<N>,
+    font: &'static Font,
+    alignment: Alignment,
+    dirty: bool,
+}
+
+impl<const N: usize> Label<N> {
+    pub fn new(bounds: Rectangle, font: &'static Font) -> Self {
+        Self {
+            bounds,
+            text: String::new(),
+            font,
+            alignment: Alignment::Left,
+            dirty: true,
+        }
+    }
+
+    pub fn with_alignment(mut self, alignment: Alignment) -> Self {
+        self.alignment = alignment;
+        self
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Replaces the text - cut short if it is longer than `N` bytes.
+    pub fn set_text(&mut self, text: &str) {
+        let text = truncated(text);
+
+        if self.text != text {
+            self.text = text;
+            self.dirty = true;
+        }
+    }
+}
+
+impl<const N: usize> Widget for Label<N> {
+    fn bounds(&self) -> Rectangle {
+        self.bounds
+    }
+
+    dirty_flag!();
+
+    fn render<D>(&self, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let mut target = target.clipped(&self.bounds);
+        target.fill_solid(&self.bounds, BinaryColor::Off)?;
+
+        // cut short with an ellipsis if it doesn't fit
+        let width = self.bounds.size.width;
+        let text_width = self.font.text_width(&self.text).min(width);
+
+        let x = match self.alignment {
+            Alignment::Left => 0,
+            Alignment::Center => (width - text_width) / 2,
+            Alignment::Right => width - text_width,
+        };
+
+        let start = centred_on(self.bounds.anchor_point(AnchorPoint::CenterLeft), self.font);
+
+        self.font.draw_truncated(
+            &self.text,
+            start + Point::new(x as i32, 0),
+            width,
+            BinaryColor::On,
+            &mut target,
+        )?;
+
+        Ok(())
+    }
+}
+
+/// A 1 bit image in the top left of its bounds, or nothing at all.
+pub struct Icon {
+    bounds: Rectangle,
+    image: Option<ImageRaw<'static, BinaryColor>>,
+    dirty: bool,
+}
+
+impl Icon {
+    pub fn new(bounds: Rectangle) -> Self {
+        Self {
+            bounds,
+            image: None,
+            dirty: true,
+        }
+    }
+
+    pub fn set_image(&mut self, image: Option<ImageRaw<'static, BinaryColor>>) {
+        if self.image != image {
+            self.image = image;
+            self.dirty = true;
+        }
+    }
+}
+
+impl Widget for Icon {
+    fn bounds(&self) -> Rectangle {
+        self.bounds
+    }
+
+    dirty_flag!();
+
+    fn render<D>(&self, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let mut target = target.clipped(&self.bounds);
+        target.fill_solid(&self.bounds, BinaryColor::Off)?;
+
+        if let Some(image) = &self.image {
+            Image::new(image, self.bounds.top_left).draw(&mut target)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Dots round a circle, one of them bigger than the rest and moving round a
+/// step at a time - to show something is still going on.
+pub struct Spinner {
+    bounds: Rectangle,
+    /// Which dot is the big one.
+    step: u32,
+    dirty: bool,
+}
+
+impl Spinner {
+    /// How many dots there are round the circle.
+    const DOTS: u32 = 8;
+
+    pub fn new(bounds: Rectangle) -> Self {
+        Self {
+            bounds,
+            step: 0,
+            dirty: true,
+        }
+    }
+
+    /// Moves the big dot on to the next one round.
+    pub fn step(&mut self) {
+        self.step = (self.step + 1) % Self::DOTS;
+        self.dirty = true;
+    }
+}
+
+impl Widget for Spinner {
+    fn bounds(&self) -> Rectangle {
+        self.bounds
+    }
+
+    dirty_flag!();
+
+    fn render<D>(&self, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let mut target = target.clipped(&self.bounds);
+        target.fill_solid(&self.bounds, BinaryColor::Off)?;
+
+        let centre = self.bounds.center();
+        // leaving room for the big dot at the edge
+        let radius = (self.bounds.size.width.min(self.bounds.size.height) / 2).saturating_sub(2);
+
+        for dot in 0..Self::DOTS {
+            // clockwise from the top
+            let angle = dot as f32 * 2. * core::f32::consts::PI / Self::DOTS as f32;
+            let position = centre
+                + Point::new(
+                    libm::roundf(libm::sinf(angle) * radius as f32) as i32,
+                    libm::roundf(-libm::cosf(angle) * radius as f32) as i32,
+                );
+
+            let diameter = if dot == self.step { 3 } else { 1 };
+
+            Circle::with_center(position, diameter)
+                .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+                .draw(&mut target)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A bar filled from the left, e.g. for volume or how far through a track is.
+pub struct ProgressBar {
+    bounds: Rectangle,
+    /// How many pixels are filled in.
+    filled: u32,
+    border: bool,
+    dirty: bool,
+}
+
+impl ProgressBar {
+    pub fn new(bounds: Rectangle) -> Self {
+        Self {
+            bounds,
+            filled: 0,
+            border: false,
+            dirty: true,
+        }
+    }
+
+    /// Draws an outline around the bar, and fills inside it.
+    pub fn with_border(mut self) -> Self {
+        self.border = true;
+        self
+    }
+
+    /// The area which gets filled in.
+    fn track(&self) -> Rectangle {
+        if self.border {
+            self.bounds.offset(-2)
+        } else {
+            self.bounds
+        }
+    }
+
+    /// Sets how full the bar is, from `0.0` to `1.0` - only marking it dirty if
+    /// that changes how many pixels are filled in.
+    pub fn set_value(&mut self, value: f32) {
+        let width = self.track().size.width;
+        let filled = ((value.clamp(0., 1.) * width as f32) as u32).min(width);
+
+        if self.filled != filled {
+            self.filled = filled;
+            self.dirty = true;
+        }
+    }
+}
+
+impl Widget for ProgressBar {
+    fn bounds(&self) -> Rectangle {
+        self.bounds
+    }
+
+    dirty_flag!();
+
+    fn render<D>(&self, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let mut target = target.clipped(&self.bounds);
+        target.fill_solid(&self.bounds, BinaryColor::Off)?;
+
+        if self.border {
+            self.bounds
+                .into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 1))
+                .draw(&mut target)?;
+        }
+
+        let track = self.track();
+        let fill = track.resized_width(self.filled, AnchorX::Left);
+
+        target.fill_solid(&fill, BinaryColor::On)
+    }
+}
+
+/// How many pixels of space come between the end of a [`ScrollingText`] and
+/// its start coming round again.
+const SCROLL_GAP: u32 = 16;
+
+/// A single line of text which scrolls sideways when it doesn't fit.
+pub struct ScrollingText<const N: usize = 64> {
+    bounds: Rectangle,
+    text: String<N>,
+    font: &'static Font,
+    /// How many pixels the text has scrolled left by.
+    offset: u32,
+    dirty: bool,
+}
+
+impl<const N: usize> ScrollingText<N> {
+    pub fn new(bounds: Rectangle, font: &'static Font) -> Self {
+        Self {
+            bounds,
+            text: String::new(),
+            font,
+            offset: 0,
+            dirty: true,
+        }
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Replaces the text (cut short if it is longer than `N` bytes), scrolling
+    /// back to its start.
+    pub fn set_text(&mut self, text: &str) {
+        let text = truncated(text);
+
+        if self.text != text {
+            self.text = text;
+            self.offset = 0;
+            self.dirty = true;
+        }
+    }
+
+    /// Whether the text is too wide to fit without scrolling.
+    pub fn overflows(&self) -> bool {
+        self.font.text_width(&self.text) > self.bounds.size.width
+    }
+
+    /// Moves the text `pixels` further left, wrapping round to its start. Does
+    /// nothing if the text already fits.
+    pub fn scroll(&mut self, pixels: u32) {
+        if !self.overflows() || pixels == 0 {
+            return;
+        }
+
+        let period = self.font.text_width(&self.text) + SCROLL_GAP;
+
+        self.offset = (self.offset + pixels) % period;
+        self.dirty = true;
+    }
+}
+
+impl<const N: usize> Widget for ScrollingText<N> {
+    fn bounds(&self) -> Rectangle {
+        self.bounds
+    }
+
+    dirty_flag!();
+
+    fn render<D>(&self, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let mut target = target.clipped(&self.bounds);
+        target.fill_solid(&self.bounds, BinaryColor::Off)?;
+
+        let start = centred_on(self.bounds.anchor_point(AnchorPoint::CenterLeft), self.font)
+            - Point::new(self.offset as i32, 0);
+
+        self.font
+            .draw(&self.text, start, BinaryColor::On, &mut target)?;
+
+        // the start of the text, coming round again after the end
+        if self.overflows() {
+            let period = self.font.text_width(&self.text) + SCROLL_GAP;
+
+            self.font.draw(
+                &self.text,
+                start + Point::new(period as i32, 0),
+                BinaryColor::On,
+                &mut target,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A column of `M` items, `N` bytes each, with one of them selected - scrolled
+/// so the selected item is always in view.
+pub struct List<const N: usize, const M: usize> {
+    bounds: Rectangle,
+    items: Vec<String<N>, M>,
+    font: &'static Font,
+    selected: usize,
+    /// The first item in view.
+    top: usize,
+    dirty: bool,
+}
+
+impl<const N: usize, const M: usize> List<N, M> {
+    pub fn new(bounds: Rectangle, font: &'static Font) -> Self {
+        Self {
+            bounds,
+            items: Vec::new(),
+            font,
+            selected: 0,
+            top: 0,
+            dirty: true,
+        }
+    }
+
+    fn row_height(&self) -> u32 {
+        self.font.character_size().height + 1
+    }
+
+    /// How many items fit in the list at once.
+    pub fn visible_rows(&self) -> usize {
+        (self.bounds.size.height / self.row_height()).max(1) as usize
+    }
+
+    pub fn items(&self) -> &[String<N>] {
+        &self.items
+    }
+
+    /// Replaces the items (keeping the first `M`, each cut short to `N`
+    /// bytes), selecting the first.
+    pub fn set_items<'a>(&mut self, items: impl IntoIterator<Item = &'a str>) {
+        self.items.clear();
+
+        for item in items.into_iter().take(M) {
+            // can't fail - there are at most `M` items
+            self.items.push(truncated(item)).ok();
+        }
+
+        self.selected = 0;
+        self.top = 0;
+        self.dirty = true;
+    }
+
+    /// The index of the selected item, if there are any items.
+    pub fn selected(&self) -> Option<usize> {
+        (!self.items.is_empty()).then_some(self.selected)
+    }
+
+    /// Selects the item at `index` (or the last one, if it's past the end).
+    pub fn select(&mut self, index: usize) {
+        let index = index.min(self.items.len().saturating_sub(1));
+
+        if self.selected == index {
+            return;
+        }
+
+        self.selected = index;
+
+        let visible = self.visible_rows();
+
+        if self.selected < self.top {
+            self.top = self.selected;
+        } else if self.selected >= self.top + visible {
+            self.top = self.selected + 1 - visible;
+        }
+
+        self.dirty = true;
+    }
+
+    /// Selects the next item, wrapping round to the first.
+    pub fn select_next(&mut self) {
+        if self.items.is_empty() {
+            return;
+        }
+
+        self.select((self.selected + 1) % self.items.len());
+    }
+
+    /// Selects the previous item, wrapping round to the last.
+    pub fn select_previous(&mut self) {
+        if self.items.is_empty() {
+            return;
+        }
+
+        self.select(self.selected.checked_sub(1).unwrap_or(self.items.len() - 1));
+    }
+}
+
+impl<const N: usize, const M: usize> Widget for List<N, M> {
+    fn bounds(&self) -> Rectangle {
+        self.bounds
+    }
+
+    dirty_flag!();
+
+    fn render<D>(&self, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let mut target = target.clipped(&self.bounds);
+        target.fill_solid(&self.bounds, BinaryColor::Off)?;
+
+        let row_height = self.row_height();
+
+        let rows = self
+            .items
+            .iter()
+            .enumerate()
+            .skip(self.top)
+            .take(self.visible_rows());
+
+        for (row, (i, item)) in rows.enumerate() {
+            let area = Rectangle::new(
+                self.bounds.top_left + Point::new(0, (row as u32 * row_height) as i32),
+                Size::new(self.bounds.size.width, row_height),
+            );
+
+            // the selected item is drawn inverted
+            let text_color = if i == self.selected {
+                target.fill_solid(&area, BinaryColor::On)?;
+                BinaryColor::Off
+            } else {
+                BinaryColor::On
+            };
+
+            self.font.draw_truncated(
+                item,
+                area.top_left + Point::new(1, 1),
+                area.size.width.saturating_sub(2),
+                text_color,
+                &mut target,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A bordered box with a message in it, drawn over whatever is underneath
+/// while it is visible.
+pub struct Modal<const N: usize = 64> {
+    bounds: Rectangle,
+    message: String<N>,
+    font: &'static Font,
+    visible: bool,
+    dirty: bool,
+}
+
+impl<const N: usize> Modal<N> {
+    pub fn new(bounds: Rectangle, font: &'static Font) -> Self {
+        Self {
+            bounds,
+            message: String::new(),
+            font,
+            visible: false,
+            dirty: false,
+        }
+    }
+
+    pub fn is_visible(&self) -> bool {
+        self.visible
+    }
+
+    /// Shows `message` (cut short if it is longer than `N` bytes).
+    pub fn show(&mut self, message: &str) {
+        self.message = truncated(message);
+        self.visible = true;
+        self.dirty = true;
+    }
+
+    /// Hides the modal - whatever is underneath has to be redrawn.
+    pub fn hide(&mut self) {
+        if self.visible {
+            self.visible = false;
+            self.dirty = true;
+        }
+    }
+}
+
+impl<const N: usize> Widget for Modal<N> {
+    fn bounds(&self) -> Rectangle {
+        self.bounds
+    }
+
+    dirty_flag!();
+
+    fn render<D>(&self, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        if !self.visible {
+            return Ok(());
+        }
+
+        let mut target = target.clipped(&self.bounds);
+        target.fill_solid(&self.bounds, BinaryColor::Off)?;
+
+        self.bounds
+            .into_styled(PrimitiveStyle::with_stroke(BinaryColor::On, 1))
+            .draw(&mut target)?;
+
+        let inner = self.bounds.offset(-2);
+        let line_height = self.font.character_size().height as i32 + 1;
+        let max_glyphs = (inner.size.width / self.font.advance()).max(1);
+
+        // greedily wrap the message at spaces, splitting words too long for a line
+        let mut line: String<N> = String::new();
+        let mut y = inner.top_left.y;
+
+        let mut draw_line = |line: &str, y: &mut i32| {
+            let result = self
+                .font
+                .draw(
+                    line,
+                    Point::new(inner.top_left.x, *y),
+                    BinaryColor::On,
+                    &mut target,
+                )
+                .map(|_| ());
+
+            *y += line_height;
+            result
+        };
+
+        for word in self.message.split(' ') {
+            let mut word = word;
+
+            loop {
+                let line_glyphs = self.font.glyph_count(&line);
+                let word_glyphs = self.font.glyph_count(word);
+                let space = u32::from(!line.is_empty());
+
+                if line_glyphs + space + word_glyphs <= max_glyphs {
+                    if space == 1 {
+                        line.push(' ').ok();
+                    }
+                    line.push_str(word).ok();
+                    break;
+                }
+
+                if !line.is_empty() {
+                    draw_line(&line, &mut y)?;
+                    line.clear();
+                    continue;
+                }
+
+                // the word doesn't fit on a line of its own
+                let split = match self.font.fit(word, inner.size.width).len() {
+                    // at least one character, even if it's too wide on its own
+                    0 => word.chars().next().map_or(word.len(), char::len_utf8),
+                    split => split,
+                };
+
+                draw_line(&word[..split], &mut y)?;
+                word = &word[split..];
+
+                if word.is_empty() {
+                    break;
+                }
+            }
+        }
+
+        if !line.is_empty() {
+            draw_line(&line, &mut y)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_graphics::{geometry::OriginDimensions, Pixel};
+
+    use super::*;
+    use crate::display::font::SMALL;
+
+    const WIDTH: usize = 32;
+    const HEIGHT: usize = 32;
+
+    /// A framebuffer for widgets to render into.
+    struct Canvas {
+        pixels: [[BinaryColor; WIDTH]; HEIGHT],
+    }
+
+    impl Canvas {
+        /// Lit inside `bounds` and dark all round them - so anything a widget
+        /// leaves undrawn in its bounds, or draws outside them, shows up.
+        fn around(bounds: Rectangle) -> Self {
+            let mut canvas = Self {
+                pixels: [[BinaryColor::Off; WIDTH]; HEIGHT],
+            };
+
+            canvas.fill_solid(&bounds, BinaryColor::On).unwrap();
+            canvas
+        }
+
+        /// `area` as text, a line per row - `#` for lit pixels, `.` for the
+        /// rest.
+        fn snapshot(&self, area: Rectangle) -> std::string::String {
+            let mut snapshot = std::string::String::new();
+
+            for y in area.rows() {
+                for x in area.columns() {
+                    snapshot.push(match self.pixels[y as usize][x as usize] {
+                        BinaryColor::On => '#',
+                        BinaryColor::Off => '.',
+                    });
+                }
+                snapshot.push('\n');
+            }
+
+            snapshot
+        }
+    }
+
+    impl OriginDimensions for Canvas {
+        fn size(&self) -> Size {
+            Size::new(WIDTH as u32, HEIGHT as u32)
+        }
+    }
+
+    impl DrawTarget for Canvas {
+        type Color = BinaryColor;
+        type Error = core::convert::Infallible;
+
+        fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+        where
+            I: IntoIterator<Item = Pixel<Self::Color>>,
+        {
+            for Pixel(point, color) in pixels {
+                let x = usize::try_from(point.x).unwrap();
+                let y = usize::try_from(point.y).unwrap();
+
+                self.pixels[y][x] = color;
+            }
+
+            Ok(())
+        }
+    }
+
+    /// Renders `widget`, then snapshots its bounds with a pixel more on every
+    /// side.
+    fn snapshot(widget: &impl Widget) -> std::string::String {
+        let mut canvas = Canvas::around(widget.bounds());
+        widget.render(&mut canvas).unwrap();
+
+        canvas.snapshot(widget.bounds().offset(1))
+    }
+
+    /// A snapshot written out in the test, without its indentation.
+    fn expected(snapshot: &str) -> std::string::String {
+        snapshot
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .flat_map(|line| [line, "\n"])
+            .collect()
+    }
+
+    fn area(x: i32, y: i32, width: u32, height: u32) -> Rectangle {
+        Rectangle::new(Point::new(x, y), Size::new(width, height))
+    }
+
+    #[test]
+    fn label_aligns_its_text() {
+        let mut label: Label<8> = Label::new(area(1, 1, 14, 7), &SMALL);
+        label.set_text("Hi");
+
+        assert_eq!(
+            snapshot(&label),
+            expected(
+                "
+                ................
+                ................
+                .#.#..#.........
+                .#.#............
+                .###.##.........
+                .#.#..#.........
+                .#.#.###........
+                ................
+                ................
+                "
+            )
+        );
+
+        let label = label.with_alignment(Alignment::Center);
+
+        assert_eq!(
+            snapshot(&label),
+            expected(
+                "
+                ................
+                ................
+                ....#.#..#......
+                ....#.#.........
+                ....###.##......
+                ....#.#..#......
+                ....#.#.###.....
+                ................
+                ................
+                "
+            )
+        );
+
+        let label = label.with_alignment(Alignment::Right);
+
+        assert_eq!(
+            snapshot(&label),
+            expected(
+                "
+                ................
+                ................
+                .......#.#..#...
+                .......#.#......
+                .......###.##...
+                .......#.#..#...
+                .......#.#.###..
+                ................
+                ................
+                "
+            )
+        );
+    }
+
+    #[test]
+    fn label_cuts_its_text_short() {
+        let mut label: Label<8> = Label::new(area(1, 1, 18, 7), &SMALL);
+        label.set_text("abcdefghijk");
+        assert_eq!(label.text(), "abcdefgh");
+
+        // as many characters as fit with an ellipsis after them
+        assert_eq!(
+            snapshot(&label),
+            expected(
+                "
+                ....................
+                ....................
+                .....#..............
+                ..##.##...##........
+                .#.#.#.#.#..........
+                .#.#.#.#.#..........
+                ..##.##...##.#.#....
+                ....................
+                ....................
+                "
+            )
+        );
+    }
+
+    #[test]
+    fn labels_only_redraw_when_their_text_changes() {
+        let mut label: Label<8> = Label::new(area(1, 1, 18, 7), &SMALL);
+        assert!(label.is_dirty());
+
+        label.set_text("abc");
+        label.mark_clean();
+        assert_eq!(label.dirty_box(), None);
+
+        label.set_text("abc");
+        assert!(!label.is_dirty());
+
+        label.set_text("abd");
+        assert_eq!(
+            label.dirty_box().map(|dirty| dirty.rectangle()),
+            Some(label.bounds())
+        );
+
+        label.mark_clean();
+        label.mark_dirty();
+        assert!(label.is_dirty());
+    }
+
+    #[test]
+    fn scrolling_text_comes_round_again() {
+        let mut text: ScrollingText<8> = ScrollingText::new(area(1, 1, 16, 7), &SMALL);
+        text.set_text("abcde");
+        assert!(text.overflows());
+
+        let start = expected(
+            "
+            ..................
+            ..................
+            .....#.........#..
+            ..##.##...##..##..
+            .#.#.#.#.#...#.#..
+            .#.#.#.#.#...#.#..
+            ..##.##...##..##..
+            ..................
+            ..................
+            ",
+        );
+
+        assert_eq!(snapshot(&text), start);
+
+        // the end of the text, then the gap before it starts again
+        text.scroll(14);
+
+        assert_eq!(
+            snapshot(&text),
+            expected(
+                "
+                ..................
+                ..................
+                .#................
+                .#..#.............
+                .#.#.#............
+                .#.##.............
+                .#..##............
+                ..................
+                ..................
+                "
+            )
+        );
+
+        // all the way round - the text is 20 pixels wide, plus the gap
+        text.scroll(22);
+        assert_eq!(snapshot(&text), start);
+
+        // text which fits stays put
+        text.set_text("ab");
+        text.mark_clean();
+        text.scroll(1);
+        assert!(!text.is_dirty());
+    }
+
+    #[test]
+    fn list_scrolls_to_the_selection() {
+        let mut list: List<4, 4> = List::new(area(1, 1, 14, 21), &SMALL);
+        list.set_items(["a", "bb", "ccc", "dddd"]);
+
+        assert_eq!(list.visible_rows(), 3);
+        assert_eq!(list.selected(), Some(0));
+
+        // the selected item is inverted
+        assert_eq!(
+            snapshot(&list),
+            expected(
+                "
+                ................
+                .##############.
+                .##############.
+                .##..##########.
+                .#.#.##########.
+                .#.#.##########.
+                .##..##########.
+                .##############.
+                ................
+                ..#...#.........
+                ..##..##........
+                ..#.#.#.#.......
+                ..#.#.#.#.......
+                ..##..##........
+                ................
+                ................
+                ................
+                ...##..##..##...
+                ..#...#...#.....
+                ..#...#...#.....
+                ...##..##..##...
+                ................
+                ................
+                "
+            )
+        );
+
+        // wrapping round to the last item, which scrolls the list down by one
+        list.select_previous();
+        assert_eq!(list.selected(), Some(3));
+
+        assert_eq!(
+            snapshot(&list),
+            expected(
+                "
+                ................
+                ................
+                ..#...#.........
+                ..##..##........
+                ..#.#.#.#.......
+                ..#.#.#.#.......
+                ..##..##........
+                ................
+                ................
+                ................
+                ...##..##..##...
+                ..#...#...#.....
+                ..#...#...#.....
+                ...##..##..##...
+                ................
+                .##############.
+                .###.###.######.
+                .##..##..######.
+                .#.#.#.#.######.
+                .#.#.#.#.######.
+                .##..##..#.#.##.
+                .##############.
+                ................
+                "
+            )
+        );
+
+        list.select_next();
+        assert_eq!(list.selected(), Some(0));
+    }
+
+    #[test]
+    fn modal_wraps_its_message() {
+        let mut modal: Modal<32> = Modal::new(area(1, 1, 24, 26), &SMALL);
+
+        // nothing is drawn until it is shown
+        assert!(!modal.is_dirty());
+        assert_eq!(
+            snapshot(&modal),
+            Canvas::around(modal.bounds()).snapshot(modal.bounds().offset(1))
+        );
+
+        // words are kept together where they fit, and split where they don't
+        modal.show("ab cd efghijklm");
+        assert!(modal.is_dirty());
+
+        assert_eq!(
+            snapshot(&modal),
+            expected(
+                "
+                ..........................
+                .########################.
+                .#......................#.
+                .#.....#.............#..#.
+                .#..##.##.......##..##..#.
+                .#.#.#.#.#.....#...#.#..#.
+                .#.#.#.#.#.....#...#.#..#.
+                .#..##.##.......##..##..#.
+                .#......................#.
+                .#......................#.
+                .#.......#.....#....#...#.
+                .#..#...#...##.##.......#.
+                .#.#.#.###.#.#.#.#.##...#.
+                .#.##...#...##.#.#..#...#.
+                .#..##..#....#.#.#.###..#.
+                .#.........##...........#.
+                .#......................#.
+                .#...#.#...##...........#.
+                .#.....#.#..#..#.#......#.
+                .#...#.##...#..###......#.
+                .#...#.#.#..#..#.#......#.
+                .#...#.#.#.###.#.#......#.
+                .#.##...................#.
+                .#......................#.
+                .#......................#.
+                .#......................#.
+                .########################.
+                ..........................
+                "
+            )
+        );
+
+        // hiding it leaves whatever is underneath to be redrawn
+        modal.mark_clean();
+        modal.hide();
+        assert!(modal.is_dirty());
+        assert!(!modal.is_visible());
+    }
+}
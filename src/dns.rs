@@ -0,0 +1,389 @@
+use embassy_net::{
+    udp::{PacketMetadata, UdpSocket},
+    Stack,
+};
+use esp_wifi::wifi::{WifiApDevice, WifiDevice};
+
+use crate::prelude::*;
+
+pub const DNS_PORT: u16 = 53;
+
+/// Plain DNS over UDP is limited to 512 bytes.
+const MAX_PACKET_LEN: usize = 512;
+
+const HEADER_LEN: usize = 12;
+
+/// The answer we append - a pointer to the question's name, then type, class,
+/// ttl, length and the address itself.
+const ANSWER_LEN: usize = 16;
+
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+
+/// How long clients should cache our (made up) answers, in seconds.
+const TTL: u32 = 60;
+
+const FLAG_RESPONSE: u16 = 1 << 15;
+const FLAG_AUTHORITATIVE: u16 = 1 << 10;
+const FLAG_RECURSION_DESIRED: u16 = 1 << 8;
+const FLAG_RECURSION_AVAILABLE: u16 = 1 << 7;
+const OPCODE_MASK: u16 = 0b1111 << 11;
+
+const RCODE_FORMAT_ERROR: u16 = 1;
+const RCODE_NOT_IMPLEMENTED: u16 = 4;
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([buf[offset], buf[offset + 1]])
+}
+
+/// Where the (single) question in `query` ends, if it is well formed.
+fn question_end(query: &[u8]) -> Option<usize> {
+    let mut offset = HEADER_LEN;
+
+    loop {
+        let len = *query.get(offset)? as usize;
+
+        match len {
+            0 => break,
+            // queries never compress names
+            len if len & 0xc0 != 0 => return None,
+            len => offset += 1 + len,
+        }
+    }
+
+    // the terminating 0, then type and class
+    let end = offset + 1 + 4;
+
+    (end <= query.len()).then_some(end)
+}
+
+/// Writes the answer to `query` into `response`, pointing every `A` question
+/// at `address`. Returns how long the answer is, or `None` if `query` should
+/// be ignored.
+pub fn answer(query: &[u8], address: [u8; 4], response: &mut [u8]) -> Option<usize> {
+    if query.len() < HEADER_LEN || response.len() < HEADER_LEN {
+        return None;
+    }
+
+    let flags = read_u16(query, 2);
+
+    // never answer answers
+    if flags & FLAG_RESPONSE != 0 {
+        return None;
+    }
+
+    let question_count = read_u16(query, 4);
+
+    let reply_flags = FLAG_RESPONSE
+        | FLAG_AUTHORITATIVE
+        | FLAG_RECURSION_AVAILABLE
+        | (flags & (OPCODE_MASK | FLAG_RECURSION_DESIRED));
+
+    // only standard queries with a single question are worth answering properly
+    let end = match question_end(query) {
+        Some(end) if flags & OPCODE_MASK == 0 && question_count == 1 => end,
+        _ => {
+            let rcode = if flags & OPCODE_MASK == 0 {
+                RCODE_FORMAT_ERROR
+            } else {
+                RCODE_NOT_IMPLEMENTED
+            };
+
+            response[..2].copy_from_slice(&query[..2]);
+            response[2..4].copy_from_slice(&(reply_flags | rcode).to_be_bytes());
+            response[4..HEADER_LEN].fill(0);
+
+            return Some(HEADER_LEN);
+        }
+    };
+
+    if response.len() < end + ANSWER_LEN {
+        return None;
+    }
+
+    let question_type = read_u16(query, end - 4);
+    let question_class = read_u16(query, end - 2);
+
+    let answer_count: u16 = (question_type == TYPE_A && question_class == CLASS_IN).into();
+
+    response[..end].copy_from_slice(&query[..end]);
+    response[2..4].copy_from_slice(&reply_flags.to_be_bytes());
+    response[6..8].copy_from_slice(&answer_count.to_be_bytes());
+    // drop any authority or additional records (e.g. EDNS) the query came with
+    response[8..HEADER_LEN].fill(0);
+
+    if answer_count == 0 {
+        return Some(end);
+    }
+
+    let answer = &mut response[end..end + ANSWER_LEN];
+
+    // the name is a pointer back to the one in the question
+    answer[..2].copy_from_slice(&(0xc000 | HEADER_LEN as u16).to_be_bytes());
+    answer[2..4].copy_from_slice(&TYPE_A.to_be_bytes());
+    answer[4..6].copy_from_slice(&CLASS_IN.to_be_bytes());
+    answer[6..10].copy_from_slice(&TTL.to_be_bytes());
+    answer[10..12].copy_from_slice(&4u16.to_be_bytes());
+    answer[12..].copy_from_slice(&address);
+
+    Some(end + ANSWER_LEN)
+}
+
+/// Answers every DNS query on the access point with the device's own address,
+/// so any page a client asks for leads to the setup page - which is what makes
+/// phones show their "sign in to network" prompt.
+#[task]
+pub async fn dns_task(stack: &'static Stack<WifiDevice<'static, WifiApDevice>>) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; MAX_PACKET_LEN * 2];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; MAX_PACKET_LEN * 2];
+
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+
+    socket
+        .bind(DNS_PORT)
+        .expect("nothing else should be bound to the dns port");
+
+    let mut query = [0; MAX_PACKET_LEN];
+    let mut response = [0; MAX_PACKET_LEN];
+
+    loop {
+        let (len, endpoint) = match socket.recv_from(&mut query).await {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("Failed to receive dns query: {e:?}");
+                continue;
+            }
+        };
+
+        let Some(config) = stack.config_v4() else {
+            continue;
+        };
+
+        let address = config.address.address().0;
+
+        let Some(len) = answer(&query[..len], address, &mut response) else {
+            trace!("Ignoring dns packet from {endpoint}");
+            continue;
+        };
+
+        trace!("Answering dns query from {endpoint}");
+
+        if let Err(e) = socket.send_to(&response[..len], endpoint).await {
+            warn!("Failed to send dns answer: {e:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: [u8; 4] = [192, 168, 0, 1];
+
+    const TYPE_AAAA: u16 = 28;
+    const TYPE_OPT: u16 = 41;
+
+    /// `name` as labels, e.g. `connectivitycheck.gstatic.com`.
+    fn labels(name: &str) -> std::vec::Vec<u8> {
+        let mut labels = std::vec::Vec::new();
+
+        for label in name.split('.') {
+            labels.push(label.len() as u8);
+            labels.extend_from_slice(label.as_bytes());
+        }
+
+        labels.push(0);
+        labels
+    }
+
+    /// A standard query with one question for `name`, as it comes off the
+    /// wire.
+    fn query(name: &str, question_type: u16) -> std::vec::Vec<u8> {
+        let mut query = std::vec![0x12, 0x34];
+        query.extend_from_slice(&FLAG_RECURSION_DESIRED.to_be_bytes());
+        query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+        query.extend_from_slice(&labels(name));
+        query.extend_from_slice(&question_type.to_be_bytes());
+        query.extend_from_slice(&CLASS_IN.to_be_bytes());
+        query
+    }
+
+    fn respond(query: &[u8]) -> Option<std::vec::Vec<u8>> {
+        let mut response = [0; MAX_PACKET_LEN];
+        let len = answer(query, ADDRESS, &mut response)?;
+
+        Some(response[..len].to_vec())
+    }
+
+    /// The header of `response` - its id, flags and record counts.
+    fn header(response: &[u8]) -> [u16; 6] {
+        core::array::from_fn(|field| read_u16(response, field * 2))
+    }
+
+    const REPLY_FLAGS: u16 =
+        FLAG_RESPONSE | FLAG_AUTHORITATIVE | FLAG_RECURSION_DESIRED | FLAG_RECURSION_AVAILABLE;
+
+    #[test]
+    fn answers_a_queries_with_the_address() {
+        let query = query("connectivitycheck.gstatic.com", TYPE_A);
+
+        let response = respond(&query).unwrap();
+
+        assert_eq!(response.len(), query.len() + ANSWER_LEN);
+        assert_eq!(header(&response), [0x1234, REPLY_FLAGS, 1, 1, 0, 0]);
+        assert_eq!(response[HEADER_LEN..query.len()], query[HEADER_LEN..]);
+
+        let mut record = std::vec![0xc0, HEADER_LEN as u8, 0, 1, 0, 1];
+        record.extend_from_slice(&TTL.to_be_bytes());
+        record.extend_from_slice(&[0, 4]);
+        record.extend_from_slice(&ADDRESS);
+
+        assert_eq!(response[query.len()..], record);
+    }
+
+    #[test]
+    fn other_types_get_an_empty_answer() {
+        for question_type in [TYPE_AAAA, 16, 65] {
+            let query = query("captive.apple.com", question_type);
+
+            let response = respond(&query).unwrap();
+
+            assert_eq!(response.len(), query.len());
+            assert_eq!(header(&response), [0x1234, REPLY_FLAGS, 1, 0, 0, 0]);
+            assert_eq!(response[HEADER_LEN..], query[HEADER_LEN..]);
+        }
+    }
+
+    #[test]
+    fn other_classes_get_an_empty_answer() {
+        let mut query = query("example.com", TYPE_A);
+        let len = query.len();
+        query[len - 2..].copy_from_slice(&3u16.to_be_bytes());
+
+        assert_eq!(header(&respond(&query).unwrap())[3], 0);
+    }
+
+    #[test]
+    fn drops_additional_records() {
+        let mut query = query("example.com", TYPE_A);
+        let end = query.len();
+
+        // an EDNS OPT record
+        query[10..12].copy_from_slice(&1u16.to_be_bytes());
+        query.push(0);
+        query.extend_from_slice(&TYPE_OPT.to_be_bytes());
+        query.extend_from_slice(&[0x04, 0xd0, 0, 0, 0, 0, 0, 0]);
+
+        let response = respond(&query).unwrap();
+
+        assert_eq!(response.len(), end + ANSWER_LEN);
+        assert_eq!(header(&response), [0x1234, REPLY_FLAGS, 1, 1, 0, 0]);
+    }
+
+    #[test]
+    fn ignores_truncated_headers_and_answers() {
+        let query = query("example.com", TYPE_A);
+
+        assert_eq!(respond(&[]), None);
+        assert_eq!(respond(&query[..HEADER_LEN - 1]), None);
+
+        let mut response = respond(&query).unwrap();
+        assert_eq!(respond(&response), None);
+
+        // even an answer with nothing in it
+        response.truncate(HEADER_LEN);
+        assert_eq!(respond(&response), None);
+    }
+
+    #[test]
+    fn malformed_questions_are_format_errors() {
+        let query = query("example.com", TYPE_A);
+
+        let mut several = query.clone();
+        several[4..6].copy_from_slice(&2u16.to_be_bytes());
+
+        let mut none = query.clone();
+        none[4..6].fill(0);
+
+        let mut compressed = query[..HEADER_LEN].to_vec();
+        compressed.extend_from_slice(&[0xc0, HEADER_LEN as u8, 0, 1, 0, 1]);
+
+        for malformed in [
+            &query[..HEADER_LEN],
+            // the name running off the end
+            &query[..HEADER_LEN + 4],
+            // without its type and class
+            &query[..query.len() - 4],
+            &several,
+            &none,
+            &compressed,
+        ] {
+            assert_eq!(
+                respond(malformed),
+                Some(
+                    [0x12, 0x34]
+                        .into_iter()
+                        .chain((REPLY_FLAGS | RCODE_FORMAT_ERROR).to_be_bytes())
+                        .chain([0; 8])
+                        .collect()
+                ),
+                "{malformed:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn other_opcodes_are_not_implemented() {
+        // a status request
+        let mut query = query("example.com", TYPE_A);
+        query[2] |= 2 << 3;
+
+        let response = respond(&query).unwrap();
+
+        assert_eq!(response.len(), HEADER_LEN);
+        assert_eq!(
+            header(&response),
+            [
+                0x1234,
+                REPLY_FLAGS | 2 << 11 | RCODE_NOT_IMPLEMENTED,
+                0,
+                0,
+                0,
+                0
+            ]
+        );
+    }
+
+    #[test]
+    fn needs_room_for_the_whole_answer() {
+        let query = query("connectivitycheck.gstatic.com", TYPE_A);
+
+        let mut response = std::vec![0; query.len() + ANSWER_LEN];
+
+        assert_eq!(
+            answer(&query, ADDRESS, &mut response[..HEADER_LEN - 1]),
+            None
+        );
+        assert_eq!(answer(&query, ADDRESS, &mut response[..query.len()]), None);
+        assert_eq!(
+            answer(
+                &query,
+                ADDRESS,
+                &mut response[..query.len() + ANSWER_LEN - 1]
+            ),
+            None
+        );
+        assert_eq!(
+            answer(&query, ADDRESS, &mut response),
+            Some(query.len() + ANSWER_LEN)
+        );
+    }
+}
@@ -0,0 +1,125 @@
+//! Looks after the display while nobody is looking at it - dimming it after a
+//! while without any input, switching it off after a while longer, and moving
+//! everything around by a pixel every so often, as OLEDs burn in wherever the
+//! same pixels stay lit.
+
+use core::cell::Cell;
+
+use embassy_futures::select::select;
+use embassy_sync::blocking_mutex;
+use embedded_graphics::prelude::Point;
+
+use crate::{
+    compositor::{self, Panel},
+    prelude::*,
+    storage::Preferences,
+};
+
+/// How often everything moves to the next of [`SHIFT_OFFSETS`].
+const SHIFT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Where everything is moved to, in turn - round a square a pixel across, so
+/// nothing moves far enough to notice.
+const SHIFT_OFFSETS: [Point; 4] = [
+    Point::new(0, 0),
+    Point::new(1, 0),
+    Point::new(1, 1),
+    Point::new(0, 1),
+];
+
+/// How many times dimmer than usual the display is while idle.
+const DIM_DIVISOR: u8 = 8;
+
+static ACTIVITY_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// The preferences in use - `None` until the [`idle_task`] starts.
+static PREFERENCES: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<Option<Preferences>>> =
+    blocking_mutex::Mutex::new(Cell::new(None));
+
+/// Brings the display back up, and starts the timeouts again - for any input.
+pub fn wake() {
+    ACTIVITY_SIGNAL.signal(());
+}
+
+pub fn preferences() -> Preferences {
+    PREFERENCES.lock(Cell::get).unwrap_or_default()
+}
+
+/// Starts using `preferences` straight away - changing them counts as
+/// activity, so the display wakes to show the difference.
+pub fn set_preferences(preferences: Preferences) {
+    PREFERENCES.lock(|cell| cell.set(Some(preferences)));
+    wake();
+}
+
+/// A timeout of `secs` seconds, where `0` is never.
+fn timeout(secs: u16) -> Option<Duration> {
+    (secs != 0).then(|| Duration::from_secs(secs as u64))
+}
+
+/// Dims and switches off the display once there has been no input for as long
+/// as the [`Preferences`] say, waking it on any input (see [`wake`]) or change
+/// in playback, and keeps the picture moving while pixel shifting is on.
+#[task]
+pub async fn idle_task(preferences: Preferences) {
+    PREFERENCES.lock(|cell| cell.set(Some(preferences)));
+
+    #[cfg(feature = "net")]
+    let mut playback = crate::playback::PLAYBACK_CHANNEL.subscriber().unwrap();
+
+    let mut last_activity = Instant::now();
+    let mut shifts: usize = 0;
+    let mut next_shift = Instant::now() + SHIFT_INTERVAL;
+
+    loop {
+        let preferences = self::preferences();
+        let now = Instant::now();
+
+        if now >= next_shift {
+            shifts = shifts.wrapping_add(1);
+            next_shift = now + SHIFT_INTERVAL;
+        }
+
+        let dim_at = timeout(preferences.dim_after_secs).map(|after| last_activity + after);
+        let off_at = timeout(preferences.off_after_secs).map(|after| last_activity + after);
+
+        let is_past = |at: Option<Instant>| at.is_some_and(|at| at <= now);
+
+        compositor::set_panel(Panel {
+            contrast: if is_past(dim_at) {
+                preferences.brightness / DIM_DIVISOR
+            } else {
+                preferences.brightness
+            },
+            on: !is_past(off_at),
+            offset: if preferences.pixel_shift {
+                SHIFT_OFFSETS[shifts % SHIFT_OFFSETS.len()]
+            } else {
+                Point::zero()
+            },
+        });
+
+        // whichever comes next of dimming, switching off and shifting
+        let next_change = [
+            dim_at,
+            off_at,
+            preferences.pixel_shift.then_some(next_shift),
+        ]
+        .into_iter()
+        .flatten()
+        .filter(|&at| at > now)
+        .min()
+        .unwrap_or(Instant::MAX);
+
+        let activity = async {
+            #[cfg(feature = "net")]
+            select(ACTIVITY_SIGNAL.wait(), playback.next_message_pure()).await;
+            #[cfg(not(feature = "net"))]
+            ACTIVITY_SIGNAL.wait().await;
+
+            last_activity = Instant::now();
+        };
+
+        select(activity, Timer::at(next_change)).await;
+    }
+}
@@ -0,0 +1,958 @@
+//! A streaming, allocation free decoder for baseline JPEGs, which only decodes
+//! their brightness - all a 1 bit display can show.
+//!
+//! The image is read a small chunk at a time and handed out one 8x8 block at a
+//! time, so it never has to fit in ram (neither compressed nor decoded). Colour
+//! is still read, to get past it, but never turned into pixels.
+
+use heapless::Vec;
+
+/// Luma (or the only component, in a greyscale image) and two chroma
+/// components - CMYK isn't supported.
+const MAX_COMPONENTS: usize = 3;
+
+/// How many bytes are read from the reader at a time.
+const CHUNK_LEN: usize = 256;
+
+/// Where each coefficient read from the image goes in an 8x8 block, by the
+/// order they are read in.
+#[rustfmt::skip]
+const ZIGZAG: [u8; 64] = [
+     0,  1,  8, 16,  9,  2,  3, 10,
+    17, 24, 32, 25, 18, 11,  4,  5,
+    12, 19, 26, 33, 40, 48, 41, 34,
+    27, 20, 13,  6,  7, 14, 21, 28,
+    35, 42, 49, 56, 57, 50, 43, 36,
+    29, 22, 15, 23, 30, 37, 44, 51,
+    58, 59, 52, 45, 38, 31, 39, 46,
+    53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TeenyJpegError {
+    /// Not a JPEG, or a header which doesn't make sense.
+    Invalid,
+    /// Progressive, arithmetic coded, lossless, 12 bit or CMYK - only baseline
+    /// (and extended huffman coded 8 bit) JPEGs can be decoded.
+    Unsupported,
+    /// Image data which doesn't match its huffman tables.
+    BadCode,
+    /// The image ended before all of it had been read.
+    Incomplete,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReadJpegError<E> {
+    Read(E),
+    Jpeg(TeenyJpegError),
+}
+
+impl<E> From<TeenyJpegError> for ReadJpegError<E> {
+    fn from(value: TeenyJpegError) -> Self {
+        Self::Jpeg(value)
+    }
+}
+
+/// The size of the decoded image - the luma, which is usually the whole image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+}
+
+/// 8x8 brightness values, with their top left `x`, `y` pixels into the image.
+/// Blocks on the right and bottom edges can hang off the edge of the image.
+#[derive(Debug, Clone)]
+pub struct Block {
+    pub x: u32,
+    pub y: u32,
+    /// Row by row, `0` is black and `255` white.
+    pub pixels: [u8; 64],
+}
+
+/// A huffman table, as the number of codes of each length and the values
+/// they decode to.
+#[derive(Clone)]
+struct Huffman {
+    /// The values, in order of code length then code.
+    values: [u8; 256],
+    /// The first code of each length (indexed from 1).
+    first_code: [u16; 17],
+    /// How many codes there are of each length (indexed from 1).
+    counts: [u8; 17],
+    /// Where the values of each length of code start in `values`.
+    first_index: [u16; 17],
+}
+
+impl Huffman {
+    const fn empty() -> Self {
+        Self {
+            values: [0; 256],
+            first_code: [0; 17],
+            counts: [0; 17],
+            first_index: [0; 17],
+        }
+    }
+
+    fn new(counts: &[u8; 16], values: &[u8]) -> Self {
+        let mut table = Self::empty();
+        table.values[..values.len()].copy_from_slice(values);
+
+        let mut code = 0u32;
+        let mut index = 0u32;
+
+        for (len, &count) in counts.iter().enumerate() {
+            let len = len + 1;
+
+            table.first_code[len] = code as u16;
+            table.counts[len] = count;
+            table.first_index[len] = index as u16;
+
+            code = (code + count as u32) << 1;
+            index += count as u32;
+        }
+
+        table
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Component {
+    id: u8,
+    /// Horizontal and vertical sampling factors.
+    h: u8,
+    v: u8,
+    quant_table: u8,
+    dc_table: u8,
+    ac_table: u8,
+    /// The DC coefficient of the last block - each one is relative to it.
+    prediction: i32,
+}
+
+/// Bytes (and bits, in image data) from a reader, a chunk at a time.
+struct Reader<'r, R> {
+    reader: &'r mut R,
+    chunk: [u8; CHUNK_LEN],
+    start: usize,
+    end: usize,
+    bits: u8,
+    bit_count: u8,
+    /// A marker found in the middle of image data.
+    marker: Option<u8>,
+}
+
+impl<'r, R> Reader<'r, R>
+where
+    R: embedded_io_async::Read,
+{
+    fn new(reader: &'r mut R) -> Self {
+        Self {
+            reader,
+            chunk: [0; CHUNK_LEN],
+            start: 0,
+            end: 0,
+            bits: 0,
+            bit_count: 0,
+            marker: None,
+        }
+    }
+
+    async fn byte(&mut self) -> Result<u8, ReadJpegError<R::Error>> {
+        if self.start == self.end {
+            self.start = 0;
+            self.end = self
+                .reader
+                .read(&mut self.chunk)
+                .await
+                .map_err(ReadJpegError::Read)?;
+
+            if self.end == 0 {
+                return Err(TeenyJpegError::Incomplete.into());
+            }
+        }
+
+        self.start += 1;
+
+        Ok(self.chunk[self.start - 1])
+    }
+
+    async fn u16(&mut self) -> Result<u16, ReadJpegError<R::Error>> {
+        Ok(u16::from_be_bytes([self.byte().await?, self.byte().await?]))
+    }
+
+    async fn skip(&mut self, len: usize) -> Result<(), ReadJpegError<R::Error>> {
+        for _ in 0..len {
+            self.byte().await?;
+        }
+
+        Ok(())
+    }
+
+    /// The next marker - either one already found in image data, or the next
+    /// one in the stream (skipping anything before it).
+    async fn marker(&mut self) -> Result<u8, ReadJpegError<R::Error>> {
+        self.bit_count = 0;
+
+        if let Some(marker) = self.marker.take() {
+            return Ok(marker);
+        }
+
+        loop {
+            if self.byte().await? != 0xff {
+                continue;
+            }
+
+            // any number of 0xff can pad out a marker
+            let mut marker = self.byte().await?;
+
+            while marker == 0xff {
+                marker = self.byte().await?;
+            }
+
+            // a stuffed zero is image data, not a marker
+            if marker != 0 {
+                return Ok(marker);
+            }
+        }
+    }
+
+    async fn bit(&mut self) -> Result<u16, ReadJpegError<R::Error>> {
+        if self.bit_count == 0 {
+            // past a marker there are no more bits in this segment - pretend the rest are
+            // zeroes, and let the missing marker show up as corrupt data (or not at all)
+            self.bits = match self.marker {
+                Some(_) => 0,
+                None => match self.byte().await? {
+                    0xff => {
+                        let mut next = self.byte().await?;
+
+                        while next == 0xff {
+                            next = self.byte().await?;
+                        }
+
+                        if next == 0 {
+                            0xff
+                        } else {
+                            self.marker = Some(next);
+                            0
+                        }
+                    }
+                    byte => byte,
+                },
+            };
+            self.bit_count = 8;
+        }
+
+        self.bit_count -= 1;
+
+        Ok((self.bits >> self.bit_count) as u16 & 1)
+    }
+
+    async fn bits(&mut self, count: u8) -> Result<u16, ReadJpegError<R::Error>> {
+        let mut bits = 0;
+
+        for _ in 0..count {
+            bits = (bits << 1) | self.bit().await?;
+        }
+
+        Ok(bits)
+    }
+
+    async fn decode(&mut self, table: &Huffman) -> Result<u8, ReadJpegError<R::Error>> {
+        let mut code = 0;
+
+        for len in 1..=16 {
+            code = (code << 1) | self.bit().await?;
+
+            let offset = code.wrapping_sub(table.first_code[len]);
+
+            if code >= table.first_code[len] && offset < table.counts[len] as u16 {
+                return Ok(table.values[table.first_index[len] as usize + offset as usize]);
+            }
+        }
+
+        Err(TeenyJpegError::BadCode.into())
+    }
+
+    /// A `size` bit coefficient, which is negative if its top bit is clear.
+    async fn coefficient(&mut self, size: u8) -> Result<i32, ReadJpegError<R::Error>> {
+        match size {
+            0 => return Ok(0),
+            // no coefficient is longer - anything more is a corrupt huffman table
+            16.. => return Err(TeenyJpegError::BadCode.into()),
+            _ => {}
+        }
+
+        let value = self.bits(size).await? as i32;
+
+        if value < 1 << (size - 1) {
+            Ok(value - (1 << size) + 1)
+        } else {
+            Ok(value)
+        }
+    }
+}
+
+struct Decoder {
+    quant_tables: [[u16; 64]; 4],
+    dc_tables: [Huffman; 2],
+    ac_tables: [Huffman; 2],
+    components: Vec<Component, MAX_COMPONENTS>,
+    frame: Option<Frame>,
+    /// The image's size, rather than the luma's.
+    size: (u32, u32),
+    /// How many MCUs come between restart markers, if there are any.
+    restart_interval: u16,
+    /// `cos((2x + 1)uπ/16)`, scaled for the inverse DCT - by `x` then `u`.
+    cosines: [[f32; 8]; 8],
+}
+
+impl Decoder {
+    fn new() -> Self {
+        let mut cosines = [[0.; 8]; 8];
+
+        for (x, row) in cosines.iter_mut().enumerate() {
+            for (u, cosine) in row.iter_mut().enumerate() {
+                let scale = if u == 0 {
+                    core::f32::consts::FRAC_1_SQRT_2
+                } else {
+                    1.
+                };
+
+                *cosine = scale
+                    * libm::cosf((2 * x + 1) as f32 * u as f32 * core::f32::consts::PI / 16.)
+                    / 2.;
+            }
+        }
+
+        Self {
+            quant_tables: [[0; 64]; 4],
+            dc_tables: [Huffman::empty(), Huffman::empty()],
+            ac_tables: [Huffman::empty(), Huffman::empty()],
+            components: Vec::new(),
+            frame: None,
+            size: (0, 0),
+            restart_interval: 0,
+            cosines,
+        }
+    }
+
+    fn max_sampling(&self) -> (u8, u8) {
+        self.components
+            .iter()
+            .fold((1, 1), |(h, v), c| (h.max(c.h), v.max(c.v)))
+    }
+
+    /// How many blocks across and down `component` is.
+    fn blocks(&self, component: &Component) -> (u32, u32) {
+        let (h_max, v_max) = self.max_sampling();
+        let width = (self.size.0 * component.h as u32).div_ceil(h_max as u32);
+        let height = (self.size.1 * component.v as u32).div_ceil(v_max as u32);
+
+        (width.div_ceil(8), height.div_ceil(8))
+    }
+
+    async fn read_frame<R>(
+        &mut self,
+        reader: &mut Reader<'_, R>,
+    ) -> Result<(), ReadJpegError<R::Error>>
+    where
+        R: embedded_io_async::Read,
+    {
+        let _len = reader.u16().await?;
+
+        if reader.byte().await? != 8 {
+            return Err(TeenyJpegError::Unsupported.into());
+        }
+
+        let height = reader.u16().await? as u32;
+        let width = reader.u16().await? as u32;
+        let count = reader.byte().await?;
+
+        // a height of 0 means it comes after the image data, in a DNL marker
+        if height == 0 || width == 0 {
+            return Err(TeenyJpegError::Unsupported.into());
+        }
+
+        self.components.clear();
+
+        for _ in 0..count {
+            let id = reader.byte().await?;
+            let sampling = reader.byte().await?;
+            let quant_table = reader.byte().await?;
+
+            let component = Component {
+                id,
+                h: sampling >> 4,
+                v: sampling & 0xf,
+                quant_table,
+                ..Default::default()
+            };
+
+            if !(1..=4).contains(&component.h) || !(1..=4).contains(&component.v) || quant_table > 3
+            {
+                return Err(TeenyJpegError::Invalid.into());
+            }
+
+            self.components
+                .push(component)
+                .map_err(|_| TeenyJpegError::Unsupported)?;
+        }
+
+        self.size = (width, height);
+
+        let (h_max, v_max) = self.max_sampling();
+        let luma = self.components.first().ok_or(TeenyJpegError::Invalid)?;
+
+        self.frame = Some(Frame {
+            width: (width * luma.h as u32).div_ceil(h_max as u32),
+            height: (height * luma.v as u32).div_ceil(v_max as u32),
+        });
+
+        Ok(())
+    }
+
+    async fn read_huffman_tables<R>(
+        &mut self,
+        reader: &mut Reader<'_, R>,
+    ) -> Result<(), ReadJpegError<R::Error>>
+    where
+        R: embedded_io_async::Read,
+    {
+        let mut len = reader.u16().await?.saturating_sub(2);
+
+        while len > 0 {
+            let table = reader.byte().await?;
+
+            let mut counts = [0; 16];
+
+            for count in &mut counts {
+                *count = reader.byte().await?;
+            }
+
+            let total: usize = counts.iter().map(|&count| count as usize).sum();
+
+            if total > 256 {
+                return Err(TeenyJpegError::Invalid.into());
+            }
+
+            let mut values = [0; 256];
+
+            for value in &mut values[..total] {
+                *value = reader.byte().await?;
+            }
+
+            let huffman = Huffman::new(&counts, &values[..total]);
+
+            match (table >> 4, table & 0xf) {
+                (0, id @ 0..=1) => self.dc_tables[id as usize] = huffman,
+                (1, id @ 0..=1) => self.ac_tables[id as usize] = huffman,
+                _ => return Err(TeenyJpegError::Unsupported.into()),
+            }
+
+            len = len.saturating_sub(17 + total as u16);
+        }
+
+        Ok(())
+    }
+
+    async fn read_quant_tables<R>(
+        &mut self,
+        reader: &mut Reader<'_, R>,
+    ) -> Result<(), ReadJpegError<R::Error>>
+    where
+        R: embedded_io_async::Read,
+    {
+        let mut len = reader.u16().await?.saturating_sub(2);
+
+        while len > 0 {
+            let table = reader.byte().await?;
+            let wide = table >> 4 == 1;
+
+            let quant_table = self
+                .quant_tables
+                .get_mut((table & 0xf) as usize)
+                .ok_or(TeenyJpegError::Invalid)?;
+
+            // kept in the order they're read in, like the coefficients they scale
+            for value in quant_table.iter_mut() {
+                *value = match wide {
+                    true => reader.u16().await?,
+                    false => reader.byte().await? as u16,
+                };
+            }
+
+            len = len.saturating_sub(if wide { 129 } else { 65 });
+        }
+
+        Ok(())
+    }
+
+    /// Reads one block of `component`, returning its pixels if `decode` (and
+    /// just getting past it otherwise).
+    async fn read_block<R>(
+        &mut self,
+        reader: &mut Reader<'_, R>,
+        component: usize,
+        decode: bool,
+    ) -> Result<Option<[u8; 64]>, ReadJpegError<R::Error>>
+    where
+        R: embedded_io_async::Read,
+    {
+        let Component {
+            quant_table,
+            dc_table,
+            ac_table,
+            ..
+        } = self.components[component];
+
+        let dc_table = self
+            .dc_tables
+            .get(dc_table as usize)
+            .ok_or(TeenyJpegError::Invalid)?;
+        let ac_table = self
+            .ac_tables
+            .get(ac_table as usize)
+            .ok_or(TeenyJpegError::Invalid)?;
+        let quant_table = &self.quant_tables[quant_table as usize];
+
+        let mut coefficients = [0i32; 64];
+
+        let size = reader.decode(dc_table).await?;
+        let prediction = &mut self.components[component].prediction;
+        *prediction += reader.coefficient(size).await?;
+        coefficients[0] = *prediction * quant_table[0] as i32;
+
+        let mut k = 1;
+
+        while k < 64 {
+            let symbol = reader.decode(ac_table).await?;
+            let (zeroes, size) = (symbol >> 4, symbol & 0xf);
+
+            if size == 0 {
+                // sixteen zeroes, or the rest of the block is
+                if zeroes == 15 {
+                    k += 16;
+                    continue;
+                }
+                break;
+            }
+
+            k += zeroes as usize;
+
+            if k > 63 {
+                return Err(TeenyJpegError::BadCode.into());
+            }
+
+            coefficients[ZIGZAG[k] as usize] =
+                reader.coefficient(size).await? * quant_table[k] as i32;
+            k += 1;
+        }
+
+        Ok(decode.then(|| self.inverse_dct(&coefficients)))
+    }
+
+    /// Turns coefficients back into pixels - the rows, then the columns.
+    fn inverse_dct(&self, coefficients: &[i32; 64]) -> [u8; 64] {
+        let mut rows = [0f32; 64];
+
+        for v in 0..8 {
+            for x in 0..8 {
+                rows[v * 8 + x] = (0..8)
+                    .map(|u| coefficients[v * 8 + u] as f32 * self.cosines[x][u])
+                    .sum();
+            }
+        }
+
+        let mut pixels = [0; 64];
+
+        for y in 0..8 {
+            for x in 0..8 {
+                let value: f32 = (0..8).map(|v| rows[v * 8 + x] * self.cosines[y][v]).sum();
+
+                pixels[y * 8 + x] = (value + 128.5).clamp(0., 255.) as u8;
+            }
+        }
+
+        pixels
+    }
+
+    async fn read_scan<R>(
+        &mut self,
+        reader: &mut Reader<'_, R>,
+        on_block: &mut impl FnMut(&Frame, &Block),
+    ) -> Result<(), ReadJpegError<R::Error>>
+    where
+        R: embedded_io_async::Read,
+    {
+        let frame = self.frame.ok_or(TeenyJpegError::Invalid)?;
+
+        let _len = reader.u16().await?;
+        let count = reader.byte().await?;
+
+        // indices into `components`, in the order the scan has them
+        let mut scan: Vec<usize, MAX_COMPONENTS> = Vec::new();
+
+        for _ in 0..count {
+            let id = reader.byte().await?;
+            let tables = reader.byte().await?;
+
+            let index = self
+                .components
+                .iter()
+                .position(|component| component.id == id)
+                .ok_or(TeenyJpegError::Invalid)?;
+
+            self.components[index].dc_table = tables >> 4;
+            self.components[index].ac_table = tables & 0xf;
+
+            scan.push(index).map_err(|_| TeenyJpegError::Invalid)?;
+        }
+
+        // spectral selection and successive approximation - only for progressive
+        reader.skip(3).await?;
+
+        for component in &mut self.components {
+            component.prediction = 0;
+        }
+
+        // one component is stored block by block, more interleaved a few blocks of
+        // each at a time
+        let (h_max, v_max) = self.max_sampling();
+
+        let (mcus_across, mcus_down) = match scan.as_slice() {
+            [component] => self.blocks(&self.components[*component]),
+            _ => (
+                self.size.0.div_ceil(8 * h_max as u32),
+                self.size.1.div_ceil(8 * v_max as u32),
+            ),
+        };
+
+        let mut mcus_until_restart = self.restart_interval;
+
+        for mcu_y in 0..mcus_down {
+            for mcu_x in 0..mcus_across {
+                if self.restart_interval > 0 {
+                    if mcus_until_restart == 0 {
+                        match reader.marker().await? {
+                            0xd0..=0xd7 => {}
+                            _ => return Err(TeenyJpegError::Invalid.into()),
+                        }
+
+                        for component in &mut self.components {
+                            component.prediction = 0;
+                        }
+
+                        mcus_until_restart = self.restart_interval;
+                    }
+
+                    mcus_until_restart -= 1;
+                }
+
+                for &component in &scan {
+                    let (h, v) = match scan.len() {
+                        1 => (1, 1),
+                        _ => (self.components[component].h, self.components[component].v),
+                    };
+
+                    for block_y in 0..v as u32 {
+                        for block_x in 0..h as u32 {
+                            // only the first component is the luma
+                            let luma = component == 0;
+
+                            let Some(pixels) = self.read_block(reader, component, luma).await?
+                            else {
+                                continue;
+                            };
+
+                            let block = Block {
+                                x: (mcu_x * h as u32 + block_x) * 8,
+                                y: (mcu_y * v as u32 + block_y) * 8,
+                                pixels,
+                            };
+
+                            if block.x < frame.width && block.y < frame.height {
+                                on_block(&frame, &block);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Decodes the JPEG `reader` returns, handing each block of its brightness to
+/// `on_block` as it's read - and returning the size of the image once it
+/// has all been read.
+pub async fn read_jpeg<R>(
+    reader: &mut R,
+    mut on_block: impl FnMut(&Frame, &Block),
+) -> Result<Frame, ReadJpegError<R::Error>>
+where
+    R: embedded_io_async::Read,
+{
+    let mut reader = Reader::new(reader);
+    let mut decoder = Decoder::new();
+
+    if reader.u16().await? != 0xffd8 {
+        return Err(TeenyJpegError::Invalid.into());
+    }
+
+    loop {
+        match reader.marker().await? {
+            // baseline and extended sequential, huffman coded
+            0xc0 | 0xc1 => decoder.read_frame(&mut reader).await?,
+            // every other kind of frame
+            0xc2 | 0xc3 | 0xc5..=0xc7 | 0xc9..=0xcb | 0xcd..=0xcf => {
+                return Err(TeenyJpegError::Unsupported.into())
+            }
+            0xc4 => decoder.read_huffman_tables(&mut reader).await?,
+            0xdb => decoder.read_quant_tables(&mut reader).await?,
+            0xdd => {
+                let _len = reader.u16().await?;
+                decoder.restart_interval = reader.u16().await?;
+            }
+            0xda => decoder.read_scan(&mut reader, &mut on_block).await?,
+            0xd9 => break,
+            // stray restart markers have nothing after them
+            0xd0..=0xd7 | 0x01 => {}
+            // app data, comments and anything else with a length
+            _ => {
+                let len = reader.u16().await?;
+                reader.skip(len.saturating_sub(2) as usize).await?;
+            }
+        }
+    }
+
+    decoder.frame.ok_or(TeenyJpegError::Invalid.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_futures::block_on;
+
+    use super::*;
+
+    // made with the `jpeg-encoder` crate at quality 95, from `grey` and `colour`
+    // below
+    const GREY: &[u8] = include_bytes!("../tests/fixtures/grey.jpg");
+    const COLOUR_420: &[u8] = include_bytes!("../tests/fixtures/colour_420.jpg");
+    const RESTARTS: &[u8] = include_bytes!("../tests/fixtures/restarts.jpg");
+    const PROGRESSIVE: &[u8] = include_bytes!("../tests/fixtures/progressive.jpg");
+
+    /// How far the decoded brightness can be from the original - quantisation
+    /// (and, in colour, rounding the chroma away) loses a little.
+    const TOLERANCE: u8 = 6;
+
+    /// What `grey.jpg` and `progressive.jpg` were made from.
+    fn grey(x: u32, y: u32) -> u8 {
+        (x * 8 + y * 7) as u8
+    }
+
+    /// The brightness of what `colour_420.jpg` and `restarts.jpg` were made
+    /// from.
+    fn colour(x: u32, y: u32) -> u8 {
+        let [r, g, b] = [x * 8, y * 8, 255 - x * 8].map(|c| c as f32);
+
+        (0.299 * r + 0.587 * g + 0.114 * b).round() as u8
+    }
+
+    /// Hands out at most `chunk` bytes a read, as a slow connection would.
+    struct Trickle<'a> {
+        data: &'a [u8],
+        chunk: usize,
+    }
+
+    impl embedded_io_async::ErrorType for Trickle<'_> {
+        type Error = core::convert::Infallible;
+    }
+
+    impl embedded_io_async::Read for Trickle<'_> {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            let len = buf.len().min(self.chunk).min(self.data.len());
+
+            buf[..len].copy_from_slice(&self.data[..len]);
+            self.data = &self.data[len..];
+
+            Ok(len)
+        }
+    }
+
+    /// Decodes `data` a `chunk` at a time, into its brightness row by row.
+    fn decode(
+        data: &[u8],
+        chunk: usize,
+    ) -> Result<(Frame, std::vec::Vec<u8>), ReadJpegError<core::convert::Infallible>> {
+        let mut pixels = std::vec::Vec::new();
+
+        let frame = block_on(read_jpeg(&mut Trickle { data, chunk }, |frame, block| {
+            pixels.resize((frame.width * frame.height) as usize, 0);
+
+            for (i, &pixel) in block.pixels.iter().enumerate() {
+                let x = block.x + i as u32 % 8;
+                let y = block.y + i as u32 / 8;
+
+                if x < frame.width && y < frame.height {
+                    pixels[(y * frame.width + x) as usize] = pixel;
+                }
+            }
+        }))?;
+
+        Ok((frame, pixels))
+    }
+
+    /// Checks every pixel of `pixels` is close to `expected`.
+    fn assert_close(frame: Frame, pixels: &[u8], expected: impl Fn(u32, u32) -> u8) {
+        assert_eq!(pixels.len(), (frame.width * frame.height) as usize);
+
+        for y in 0..frame.height {
+            for x in 0..frame.width {
+                let pixel = pixels[(y * frame.width + x) as usize];
+
+                assert!(
+                    pixel.abs_diff(expected(x, y)) <= TOLERANCE,
+                    "{pixel} at {x}, {y} should be about {}",
+                    expected(x, y)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn decodes_greyscale() {
+        let (frame, pixels) = decode(GREY, usize::MAX).unwrap();
+
+        assert_eq!(
+            frame,
+            Frame {
+                width: 16,
+                height: 16
+            }
+        );
+        assert_close(frame, &pixels, grey);
+    }
+
+    #[test]
+    fn decodes_the_brightness_of_subsampled_colour() {
+        // 4:2:0, and not a whole number of 16x16 blocks across or down
+        let (frame, pixels) = decode(COLOUR_420, usize::MAX).unwrap();
+
+        assert_eq!(
+            frame,
+            Frame {
+                width: 30,
+                height: 20
+            }
+        );
+        assert_close(frame, &pixels, colour);
+    }
+
+    #[test]
+    fn decodes_restart_intervals() {
+        assert!(RESTARTS.windows(2).any(|marker| marker == [0xff, 0xdd]));
+
+        let (frame, pixels) = decode(RESTARTS, usize::MAX).unwrap();
+
+        assert_eq!(
+            frame,
+            Frame {
+                width: 32,
+                height: 32
+            }
+        );
+        assert_close(frame, &pixels, colour);
+    }
+
+    #[test]
+    fn chunk_size_makes_no_difference() {
+        for fixture in [GREY, COLOUR_420, RESTARTS] {
+            let whole = decode(fixture, usize::MAX).unwrap();
+
+            for chunk in [1, 2, 3, 7, 64] {
+                assert_eq!(
+                    decode(fixture, chunk).unwrap(),
+                    whole,
+                    "{chunk} bytes a read"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn blocks_hanging_off_the_edge_are_still_handed_out() {
+        let mut blocks = std::vec::Vec::new();
+
+        block_on(read_jpeg(
+            &mut Trickle {
+                data: COLOUR_420,
+                chunk: usize::MAX,
+            },
+            |_, block| blocks.push((block.x, block.y)),
+        ))
+        .unwrap();
+
+        blocks.sort();
+        let expected: std::vec::Vec<_> = (0..4)
+            .flat_map(|x| (0..3).map(move |y| (x * 8, y * 8)))
+            .collect();
+
+        assert_eq!(blocks, expected);
+    }
+
+    #[test]
+    fn rejects_progressive() {
+        assert_eq!(
+            decode(PROGRESSIVE, usize::MAX),
+            Err(ReadJpegError::Jpeg(TeenyJpegError::Unsupported))
+        );
+    }
+
+    #[test]
+    fn rejects_what_isnt_a_jpeg() {
+        assert_eq!(
+            decode(b"GIF89a\x01\x00\x01\x00", usize::MAX),
+            Err(ReadJpegError::Jpeg(TeenyJpegError::Invalid))
+        );
+    }
+
+    #[test]
+    fn truncated_images_are_incomplete() {
+        for len in [0, 1, 2, 20, GREY.len() / 2, GREY.len() - 2] {
+            assert_eq!(
+                decode(&GREY[..len], usize::MAX),
+                Err(ReadJpegError::Jpeg(TeenyJpegError::Incomplete)),
+                "{len} bytes"
+            );
+        }
+    }
+
+    #[test]
+    fn oversized_coefficients_are_bad_codes() {
+        // the dc table's values are the sizes of the dc coefficients
+        let table = GREY
+            .windows(5)
+            .position(|segment| segment[..2] == [0xff, 0xc4] && segment[4] == 0x00)
+            .expect("there should be a dc table");
+
+        let counts = table + 5;
+        let total: usize = GREY[counts..counts + 16]
+            .iter()
+            .map(|&count| count as usize)
+            .sum();
+
+        // one bit too long, and long enough to overflow the sign extension
+        for size in [16, 40, 255] {
+            let mut data = GREY.to_vec();
+            data[counts + 16..counts + 16 + total].fill(size);
+
+            assert_eq!(
+                decode(&data, usize::MAX),
+                Err(ReadJpegError::Jpeg(TeenyJpegError::BadCode)),
+                "{size} bit coefficients"
+            );
+        }
+    }
+}
@@ -0,0 +1,739 @@
+//! A streaming, allocation free json reader which only keeps hold of the value
+//! it is currently reading - enough to pick a handful of fields out of
+//! responses far bigger than our ram.
+
+use heapless::Vec;
+
+/// Keys longer than this never match a pattern.
+pub const KEY_LEN: usize = 32;
+
+/// How many levels of the path are tracked - values nested deeper than this
+/// never match a pattern, but are still parsed.
+pub const MAX_PATH: usize = 8;
+
+/// The deepest nesting the parser can handle at all.
+pub const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TeenyJsonError {
+    /// Unexpected byte at `offset` bytes into the document.
+    Syntax { offset: usize },
+    /// The document nests deeper than [`MAX_DEPTH`].
+    TooDeep,
+    /// The document ended before its root value did.
+    Incomplete,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment {
+    Key(heapless::String<KEY_LEN>),
+    Index(usize),
+    /// A key too long to be stored, or an object whose first key hasn't been
+    /// read yet.
+    Skipped,
+}
+
+/// Where in the document a value is.
+#[derive(Debug, Clone, Default)]
+pub struct JsonPath {
+    segments: Vec<Segment, MAX_PATH>,
+    depth: usize,
+}
+
+impl JsonPath {
+    pub const fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Whether this path matches `pattern` - keys and array indices separated
+    /// by `.`, with `*` matching any single key or index, e.g.
+    /// `item.artists.*.name`. The empty pattern matches the root value.
+    pub fn matches(&self, pattern: &str) -> bool {
+        if self.depth != self.segments.len() {
+            return false;
+        }
+
+        let mut parts = pattern.split('.').filter(|part| !part.is_empty());
+
+        for segment in &self.segments {
+            let Some(part) = parts.next() else {
+                return false;
+            };
+
+            let matched = match segment {
+                _ if part == "*" => true,
+                Segment::Key(key) => key == part,
+                Segment::Index(index) => part.parse() == Ok(*index),
+                Segment::Skipped => false,
+            };
+
+            if !matched {
+                return false;
+            }
+        }
+
+        parts.next().is_none()
+    }
+
+    /// The index of the array element at `level` in the path, if that level is
+    /// an array.
+    pub fn index(&self, level: usize) -> Option<usize> {
+        match self.segments.get(level) {
+            Some(Segment::Index(index)) => Some(*index),
+            _ => None,
+        }
+    }
+
+    fn push(&mut self, segment: Segment) -> Result<(), TeenyJsonError> {
+        if self.depth == MAX_DEPTH {
+            return Err(TeenyJsonError::TooDeep);
+        }
+
+        if self.depth == self.segments.len() {
+            // past `MAX_PATH` deep the segment just isn't tracked
+            let _ = self.segments.push(segment);
+        }
+
+        self.depth += 1;
+
+        Ok(())
+    }
+
+    fn pop(&mut self) {
+        if self.depth == self.segments.len() {
+            self.segments.pop();
+        }
+
+        self.depth -= 1;
+    }
+
+    /// Replaces the innermost segment, if it is tracked.
+    fn set_last(&mut self, segment: Segment) {
+        if self.depth == self.segments.len() {
+            if let Some(last) = self.segments.last_mut() {
+                *last = segment;
+            }
+        }
+    }
+
+    fn next_index(&mut self) {
+        if self.depth == self.segments.len() {
+            if let Some(Segment::Index(index)) = self.segments.last_mut() {
+                *index += 1;
+            }
+        }
+    }
+}
+
+/// A scalar value, and the path it was found at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JsonValue<'a> {
+    /// A string with its escapes decoded - cut short (at a character boundary)
+    /// if longer than the parser's `VALUE_LEN`.
+    String(&'a str),
+    /// A number, exactly as written in the document.
+    Number(&'a str),
+    Bool(bool),
+    Null,
+}
+
+impl<'a> JsonValue<'a> {
+    pub fn as_str(&self) -> Option<&'a str> {
+        match self {
+            Self::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Self::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// The value as a whole number - anything after a decimal point is
+    /// dropped.
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Self::Number(value) => value.split('.').next()?.parse().ok(),
+            _ => None,
+        }
+    }
+
+    /// The value as a string, cut short at the last character which fits in
+    /// `N` bytes - empty if it isn't a string.
+    pub fn truncated<const N: usize>(&self) -> heapless::String<N> {
+        let mut string = heapless::String::new();
+
+        for c in self.as_str().unwrap_or_default().chars() {
+            if string.push(c).is_err() {
+                break;
+            }
+        }
+
+        string
+    }
+
+    pub const fn is_null(&self) -> bool {
+        matches!(self, Self::Null)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Expecting a value - or the end of the array if it would be its first.
+    Value {
+        first: bool,
+    },
+    /// Expecting a key - or the end of the object if it would be its first.
+    Key {
+        first: bool,
+    },
+    Colon,
+    /// Expecting a `,` or the end of the enclosing object or array.
+    AfterValue,
+    String {
+        key: bool,
+    },
+    Escape {
+        key: bool,
+    },
+    Unicode {
+        key: bool,
+        digits: u8,
+        code: u16,
+    },
+    Number,
+    Literal,
+    Done,
+}
+
+/// Reads a json document fed to it in chunks of any size, calling back with
+/// every scalar value it finds.
+pub struct JsonParser<const VALUE_LEN: usize = 256> {
+    state: State,
+    path: JsonPath,
+    /// One bit per level of nesting - set for arrays, clear for objects.
+    containers: u64,
+    value: Vec<u8, VALUE_LEN>,
+    high_surrogate: Option<u16>,
+    offset: usize,
+}
+
+impl<const VALUE_LEN: usize> Default for JsonParser<VALUE_LEN> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const VALUE_LEN: usize> JsonParser<VALUE_LEN> {
+    pub const fn new() -> Self {
+        Self {
+            state: State::Value { first: false },
+            path: JsonPath {
+                segments: Vec::new(),
+                depth: 0,
+            },
+            containers: 0,
+            value: Vec::new(),
+            high_surrogate: None,
+            offset: 0,
+        }
+    }
+
+    /// Whether the whole document has been read.
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    /// Checks the document ended where it should have, after the last
+    /// [`JsonParser::feed`] - a number on its own is never complete, as there
+    /// is nothing after it to end it.
+    pub fn finish(&self) -> Result<(), TeenyJsonError> {
+        if self.is_done() {
+            Ok(())
+        } else {
+            Err(TeenyJsonError::Incomplete)
+        }
+    }
+
+    /// Parses the next chunk of the document.
+    pub fn feed(
+        &mut self,
+        chunk: &[u8],
+        mut on_value: impl FnMut(&JsonPath, JsonValue<'_>),
+    ) -> Result<(), TeenyJsonError> {
+        let mut i = 0;
+
+        while i < chunk.len() {
+            let byte = chunk[i];
+
+            match self.state {
+                State::Value { first } => match byte {
+                    b' ' | b'\t' | b'\n' | b'\r' => {}
+                    b'{' => {
+                        self.open(false, Segment::Skipped)?;
+                        self.state = State::Key { first: true };
+                    }
+                    b'[' => {
+                        self.open(true, Segment::Index(0))?;
+                        self.state = State::Value { first: true };
+                    }
+                    b']' if first => self.close(true)?,
+                    b'"' => {
+                        self.start_value();
+                        self.state = State::String { key: false };
+                    }
+                    b'-' | b'0'..=b'9' => {
+                        self.start_value();
+                        self.push_byte(byte);
+                        self.state = State::Number;
+                    }
+                    b't' | b'f' | b'n' => {
+                        self.start_value();
+                        self.push_byte(byte);
+                        self.state = State::Literal;
+                    }
+                    _ => return Err(self.syntax_error()),
+                },
+                State::Key { first } => match byte {
+                    b' ' | b'\t' | b'\n' | b'\r' => {}
+                    b'"' => {
+                        self.start_value();
+                        self.state = State::String { key: true };
+                    }
+                    b'}' if first => self.close(false)?,
+                    _ => return Err(self.syntax_error()),
+                },
+                State::Colon => match byte {
+                    b' ' | b'\t' | b'\n' | b'\r' => {}
+                    b':' => self.state = State::Value { first: false },
+                    _ => return Err(self.syntax_error()),
+                },
+                State::AfterValue => match byte {
+                    b' ' | b'\t' | b'\n' | b'\r' => {}
+                    b',' if self.in_array() => {
+                        self.path.next_index();
+                        self.state = State::Value { first: false };
+                    }
+                    b',' => self.state = State::Key { first: false },
+                    b']' => self.close(true)?,
+                    b'}' => self.close(false)?,
+                    _ => return Err(self.syntax_error()),
+                },
+                State::String { key } => match byte {
+                    b'\\' => self.state = State::Escape { key },
+                    _ if self.high_surrogate.take().is_some() => {
+                        // half a surrogate pair, with nothing to pair it with
+                        self.push_char(char::REPLACEMENT_CHARACTER);
+                        continue;
+                    }
+                    b'"' if key => {
+                        let segment = match core::str::from_utf8(&self.value) {
+                            Ok(key) if self.value.len() <= KEY_LEN => {
+                                Segment::Key(key.try_into().expect("checked the length above"))
+                            }
+                            _ => Segment::Skipped,
+                        };
+
+                        self.path.set_last(segment);
+                        self.state = State::Colon;
+                    }
+                    b'"' => {
+                        let value = JsonValue::String(self.value_str());
+                        on_value(&self.path, value);
+                        self.end_value();
+                    }
+                    _ => self.push_byte(byte),
+                },
+                State::Escape { key } => {
+                    self.state = State::String { key };
+
+                    if byte != b'u' && self.high_surrogate.take().is_some() {
+                        self.push_char(char::REPLACEMENT_CHARACTER);
+                    }
+
+                    match byte {
+                        b'"' => self.push_byte(b'"'),
+                        b'\\' => self.push_byte(b'\\'),
+                        b'/' => self.push_byte(b'/'),
+                        b'b' => self.push_byte(0x08),
+                        b'f' => self.push_byte(0x0c),
+                        b'n' => self.push_byte(b'\n'),
+                        b'r' => self.push_byte(b'\r'),
+                        b't' => self.push_byte(b'\t'),
+                        b'u' => {
+                            self.state = State::Unicode {
+                                key,
+                                digits: 0,
+                                code: 0,
+                            }
+                        }
+                        _ => return Err(self.syntax_error()),
+                    }
+                }
+                State::Unicode { key, digits, code } => {
+                    let digit = match byte {
+                        b'0'..=b'9' => byte - b'0',
+                        b'a'..=b'f' => byte - b'a' + 10,
+                        b'A'..=b'F' => byte - b'A' + 10,
+                        _ => return Err(self.syntax_error()),
+                    };
+
+                    let code = (code << 4) | digit as u16;
+
+                    if digits == 3 {
+                        self.push_code_unit(code);
+                        self.state = State::String { key };
+                    } else {
+                        self.state = State::Unicode {
+                            key,
+                            digits: digits + 1,
+                            code,
+                        };
+                    }
+                }
+                State::Number => match byte {
+                    b'0'..=b'9' | b'-' | b'+' | b'.' | b'e' | b'E' => self.push_byte(byte),
+                    _ => {
+                        let value = JsonValue::Number(self.value_str());
+                        on_value(&self.path, value);
+                        self.end_value();
+
+                        // this byte belongs to whatever comes after the number
+                        continue;
+                    }
+                },
+                State::Literal => match byte {
+                    b'a'..=b'z' => self.push_byte(byte),
+                    _ => {
+                        let value = match self.value.as_slice() {
+                            b"true" => JsonValue::Bool(true),
+                            b"false" => JsonValue::Bool(false),
+                            b"null" => JsonValue::Null,
+                            _ => return Err(self.syntax_error()),
+                        };
+                        on_value(&self.path, value);
+                        self.end_value();
+
+                        continue;
+                    }
+                },
+                State::Done => match byte {
+                    b' ' | b'\t' | b'\n' | b'\r' => {}
+                    _ => return Err(self.syntax_error()),
+                },
+            }
+
+            i += 1;
+            self.offset += 1;
+        }
+
+        Ok(())
+    }
+
+    fn syntax_error(&self) -> TeenyJsonError {
+        TeenyJsonError::Syntax {
+            offset: self.offset,
+        }
+    }
+
+    fn in_array(&self) -> bool {
+        self.path.depth > 0 && self.containers & (1 << (self.path.depth - 1)) != 0
+    }
+
+    fn open(&mut self, array: bool, segment: Segment) -> Result<(), TeenyJsonError> {
+        let level = self.path.depth;
+
+        self.path.push(segment)?;
+
+        if array {
+            self.containers |= 1 << level;
+        } else {
+            self.containers &= !(1 << level);
+        }
+
+        Ok(())
+    }
+
+    fn close(&mut self, array: bool) -> Result<(), TeenyJsonError> {
+        if self.path.depth == 0 || self.in_array() != array {
+            return Err(self.syntax_error());
+        }
+
+        self.path.pop();
+        self.end_value();
+
+        Ok(())
+    }
+
+    fn start_value(&mut self) {
+        self.value.clear();
+        self.high_surrogate = None;
+    }
+
+    fn end_value(&mut self) {
+        self.state = if self.path.depth == 0 {
+            State::Done
+        } else {
+            State::AfterValue
+        };
+    }
+
+    /// Stores a byte of the current value, silently dropping it if the value
+    /// is already full.
+    fn push_byte(&mut self, byte: u8) {
+        let _ = self.value.push(byte);
+    }
+
+    fn push_char(&mut self, c: char) {
+        let mut buf = [0; 4];
+
+        for &byte in c.encode_utf8(&mut buf).as_bytes() {
+            self.push_byte(byte);
+        }
+    }
+
+    /// Decodes a `\uXXXX` escape - characters outside the basic multilingual
+    /// plane are written as two of them, a surrogate pair.
+    fn push_code_unit(&mut self, code: u16) {
+        match (self.high_surrogate.take(), code) {
+            (None, 0xd800..=0xdbff) => self.high_surrogate = Some(code),
+            (Some(high), 0xdc00..=0xdfff) => {
+                let c = 0x10000 + (((high as u32) - 0xd800) << 10) + ((code as u32) - 0xdc00);
+                self.push_char(char::from_u32(c).unwrap_or(char::REPLACEMENT_CHARACTER));
+            }
+            (high, code) => {
+                if high.is_some() {
+                    self.push_char(char::REPLACEMENT_CHARACTER);
+                }
+                self.push_char(char::from_u32(code as u32).unwrap_or(char::REPLACEMENT_CHARACTER));
+            }
+        }
+    }
+
+    /// The current value, cut at the last whole character if it was
+    /// truncated.
+    fn value_str(&self) -> &str {
+        match core::str::from_utf8(&self.value) {
+            Ok(value) => value,
+            Err(e) => core::str::from_utf8(&self.value[..e.valid_up_to()])
+                .expect("valid up to this point"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReadJsonError<E> {
+    Read(E),
+    Json(TeenyJsonError),
+}
+
+impl<E> From<TeenyJsonError> for ReadJsonError<E> {
+    fn from(value: TeenyJsonError) -> Self {
+        Self::Json(value)
+    }
+}
+
+/// Feeds everything `reader` returns through `parser`, a small chunk at a
+/// time.
+pub async fn read_json<R, const VALUE_LEN: usize>(
+    reader: &mut R,
+    parser: &mut JsonParser<VALUE_LEN>,
+    mut on_value: impl FnMut(&JsonPath, JsonValue<'_>),
+) -> Result<(), ReadJsonError<R::Error>>
+where
+    R: embedded_io_async::Read,
+{
+    let mut chunk = [0; 256];
+
+    loop {
+        let len = reader.read(&mut chunk).await.map_err(ReadJsonError::Read)?;
+
+        if len == 0 {
+            break;
+        }
+
+        parser.feed(&chunk[..len], &mut on_value)?;
+    }
+
+    parser.finish()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::string::String;
+
+    use super::*;
+    use crate::playback::TRACK_LEN;
+
+    const PAUSED: &[u8] = include_bytes!("../tests/fixtures/player_paused.json");
+    const LOCAL_FILE: &[u8] = include_bytes!("../tests/fixtures/player_local_file.json");
+
+    /// The fields [`Spotify::playback_state`](crate::spotify::Spotify) picks
+    /// out of `/v1/me/player`, as they were read.
+    #[derive(Debug, Default, PartialEq)]
+    struct Player {
+        track: String,
+        artists: std::vec::Vec<String>,
+        art_url: String,
+        album_id: String,
+        device: String,
+        progress_ms: Option<u64>,
+        duration_ms: Option<u64>,
+        /// `Some(None)` for a `null` volume.
+        volume: Option<Option<u64>>,
+        is_playing: Option<bool>,
+    }
+
+    /// Reads `document` a `chunk` bytes at a time, the way it comes off the
+    /// network.
+    fn read(document: &[u8], chunk: usize) -> Result<Player, TeenyJsonError> {
+        let mut parser: JsonParser<TRACK_LEN> = JsonParser::new();
+        let mut player = Player::default();
+
+        for chunk in document.chunks(chunk) {
+            parser.feed(chunk, |path, value| {
+                if path.matches("item.name") {
+                    player.track = value.as_str().unwrap().into();
+                } else if path.matches("item.artists.*.name") {
+                    player.artists.push(value.as_str().unwrap().into());
+                } else if path.matches("item.album.images.*.url") {
+                    player.art_url = value.as_str().unwrap().into();
+                } else if path.matches("item.album.id") {
+                    player.album_id = value.as_str().unwrap_or_default().into();
+                } else if path.matches("device.name") {
+                    player.device = value.as_str().unwrap().into();
+                } else if path.matches("progress_ms") {
+                    player.progress_ms = value.as_u64();
+                } else if path.matches("item.duration_ms") {
+                    player.duration_ms = value.as_u64();
+                } else if path.matches("device.volume_percent") {
+                    player.volume = Some(value.as_u64());
+                } else if path.matches("is_playing") {
+                    player.is_playing = value.as_bool();
+                }
+            })?;
+        }
+
+        parser.finish()?;
+
+        Ok(player)
+    }
+
+    #[test]
+    fn reads_recorded_player() {
+        let player = read(PAUSED, PAUSED.len()).unwrap();
+
+        assert_eq!(player.track, "Don’t Stop Me Now - Remastered 2011");
+        assert_eq!(player.artists, ["Queen"]);
+        // the last (smallest) image, with its escaped slashes undone
+        assert_eq!(
+            player.art_url,
+            "https://i.scdn.co/image/ab67616d000048517c39dd133836c2c1c87e34d6"
+        );
+        assert_eq!(player.album_id, "2yuTRGIackbcReLUXOYBqU");
+        assert_eq!(player.device, "Kitchen");
+        assert_eq!(player.progress_ms, Some(74213));
+        assert_eq!(player.duration_ms, Some(209413));
+        assert_eq!(player.volume, Some(Some(38)));
+        assert_eq!(player.is_playing, Some(false));
+    }
+
+    #[test]
+    fn chunk_size_makes_no_difference() {
+        for document in [PAUSED, LOCAL_FILE] {
+            let whole = read(document, document.len()).unwrap();
+
+            for chunk in [1, 2, 3, 7, 64, 256] {
+                assert_eq!(read(document, chunk).unwrap(), whole, "{chunk} byte chunks");
+            }
+        }
+    }
+
+    #[test]
+    fn decodes_escapes() {
+        let player = read(LOCAL_FILE, 5).unwrap();
+
+        assert_eq!(player.device, "Laptop \"Study\"");
+        assert_eq!(player.artists, ["YOASOBI", "Ayase\\Ikura"]);
+        // a surrogate pair, for a character outside the basic multilingual plane
+        assert!(player.track.contains("Night\") \u{1f319} —"));
+        // no volume, and a local file has no album art or album id
+        assert_eq!(player.volume, Some(None));
+        assert_eq!(player.art_url, "");
+        assert_eq!(player.album_id, "");
+        assert_eq!(player.is_playing, Some(true));
+
+        let mut parser: JsonParser = JsonParser::new();
+        let mut strings = std::vec::Vec::<String>::new();
+
+        parser
+            .feed(
+                r#"["\b\f\n\r\t\/", "\u00e9é", "\ud83d", "\udc00x", "\ud83dx\n"]"#.as_bytes(),
+                |_, value| strings.push(value.as_str().unwrap().into()),
+            )
+            .unwrap();
+
+        assert_eq!(
+            strings,
+            [
+                "\u{8}\u{c}\n\r\t/",
+                "éé",
+                "\u{fffd}",
+                "\u{fffd}x",
+                "\u{fffd}x\n"
+            ]
+        );
+    }
+
+    #[test]
+    fn truncates_at_track_len() {
+        const FULL: &str = "夜に駆ける (\"Racing into the Night\") 🌙 — an extended mix, long \
+                            enough to run past the name buffer — 夜に駆ける・アンコール";
+
+        let player = read(LOCAL_FILE, 16).unwrap();
+
+        assert!(FULL.len() > TRACK_LEN);
+        // `・` straddles the limit, so it is dropped whole
+        assert_eq!(player.track, FULL.split_once('・').unwrap().0);
+        assert!(player.track.len() <= TRACK_LEN);
+
+        // the rest of the name is skipped, rather than read as the values after it
+        assert_eq!(player.duration_ms, Some(261000));
+    }
+
+    #[test]
+    fn truncated_document_is_incomplete() {
+        for end in [1, PAUSED.len() / 2, PAUSED.len() - 2] {
+            assert_eq!(
+                read(&PAUSED[..end], 64),
+                Err(TeenyJsonError::Incomplete),
+                "cut at {end}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_documents() {
+        for document in [
+            &br#"{"a":1,}"#[..],
+            br#"[1,]"#,
+            br#"[1]]"#,
+            br#"{"a" 1}"#,
+            br#"[tru]"#,
+            br#"["\x"]"#,
+        ] {
+            let result = JsonParser::<16>::new().feed(document, |_, _| {});
+
+            assert!(
+                matches!(result, Err(TeenyJsonError::Syntax { .. })),
+                "{:?}",
+                core::str::from_utf8(document)
+            );
+        }
+    }
+}
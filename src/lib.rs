@@ -1,5 +1,5 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 #![feature(impl_trait_in_assoc_type)]
 #![feature(type_alias_impl_trait)]
 #![feature(error_in_core)]
@@ -10,16 +10,42 @@
 #[cfg(feature = "alloc")]
 pub mod alloc;
 pub mod blink;
+#[cfg(feature = "net")]
+pub mod dhcp;
 pub mod display;
+#[cfg(feature = "net")]
+pub mod dns;
 pub mod errors;
+pub mod idle;
+pub mod jpeg;
+pub mod json;
 
+#[cfg(feature = "net")]
+pub mod album_art;
+#[cfg(feature = "net")]
 pub mod auth;
 pub mod ble;
 pub mod buttons;
+pub mod compositor;
+#[cfg(feature = "net")]
+pub mod controller;
 pub mod logger;
 #[cfg(feature = "net")]
+pub mod login;
+pub mod menu;
+#[cfg(feature = "net")]
 pub mod net;
+#[cfg(feature = "net")]
+pub mod playback;
 pub mod potentiometer;
+pub mod qr;
+#[cfg(feature = "net")]
+pub mod spotify;
+#[cfg(feature = "net")]
+pub mod station;
+#[cfg(feature = "net")]
+pub mod status;
+pub mod storage;
 pub mod volume;
 
 /// A simplified version of [`make_static`](`static_cell::make_static`), while [rust-analyzer#13824](https://github.com/rust-lang/rust-analyzer/issues/13824) exists (due to TAIT not being implimented yet: [rust#120700](https://github.com/rust-lang/rust/pull/120700)).
@@ -37,9 +63,9 @@ pub mod prelude {
 
     pub use super::*;
 
-    // pub const SSID: &str = env!("SSID");
+    pub const SSID: &str = env!("SSID");
 
-    // pub const PASSWORD: &str = env!("PASSWORD");
+    pub const PASSWORD: &str = env!("PASSWORD");
     pub const CLIENT_ID: &str = env!("CLIENT_ID");
 
     pub use core::f64::consts::PI;
@@ -60,7 +86,7 @@ pub mod prelude {
         I2C<'static, esp_hal::peripherals::I2C0, Async>,
     >;
 
-    pub static VOLUME_CHANNEL: PubSubChannel<CriticalSectionRawMutex, f32, 2, 2, 1> =
+    pub static VOLUME_CHANNEL: PubSubChannel<CriticalSectionRawMutex, f32, 2, 4, 1> =
         PubSubChannel::new();
 
     pub static I2C_BUS: StaticCell<I2cBusMutex> = StaticCell::new();
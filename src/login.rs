@@ -0,0 +1,268 @@
+use core::fmt::Write;
+
+use embassy_futures::select::{select, Either};
+use embedded_nal_async::{Dns, TcpConnect};
+use esp_hal::{sha::Sha, Blocking};
+use picoserve::{extract::Query, response::Json};
+use reqwless::client::{HttpClient, TlsConfig, TlsVerify};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::{
+        AuthConfig, Authorisation, TeenyAuthError, Token, TokenManager, REFRESH_TOKEN_LEN,
+        REFRESH_TOKEN_SIGNAL,
+    },
+    net::{random_password, StaClient, StaStack, TLS_BUFFER_LEN},
+    prelude::*,
+    storage::{Key, SettingsMutex},
+};
+
+/// Long enough for `http://255.255.255.255/login`.
+pub const LOGIN_URL_LEN: usize = 32;
+
+pub const LOGIN_CODE_LEN: usize = 6;
+
+/// Shown on the display while a login is pending, and asked for by the
+/// `/login` page - so only someone who can see the device can log it in.
+///
+/// Left out of its `Debug` output, so it never ends up in the logs.
+#[derive(Clone, PartialEq, Eq)]
+pub struct LoginCode(pub String<LOGIN_CODE_LEN>);
+
+impl LoginCode {
+    pub fn new(rng: Rng) -> Self {
+        Self(random_password(rng))
+    }
+
+    /// Whether `given` is this code - ignoring case, as it is hard to tell on
+    /// the display.
+    pub fn matches(&self, given: &str) -> bool {
+        self.0.eq_ignore_ascii_case(given)
+    }
+}
+
+impl core::ops::Deref for LoginCode {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl core::fmt::Debug for LoginCode {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("LoginCode(..)")
+    }
+}
+
+/// Where the user is in logging in to spotify.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoginState {
+    /// Picking up the saved login, or starting a new one.
+    Authorising,
+    /// Waiting for the user to open `url` (the device's own `/login` page),
+    /// enter `code`, log in to spotify from there, and paste back where spotify
+    /// sent them.
+    Required {
+        url: String<LOGIN_URL_LEN>,
+        code: LoginCode,
+    },
+    LoggedIn,
+    /// The last attempt went wrong - another is on its way.
+    Failed,
+}
+
+/// Every change in [`LoginState`], e.g. for showing the login code on the
+/// display.
+pub static LOGIN_CHANNEL: PubSubChannel<CriticalSectionRawMutex, LoginState, 1, 4, 1> =
+    PubSubChannel::new();
+
+/// The spotify page `/login` sends the user on to while a login is pending,
+/// and the code it is given out for.
+static AUTHORIZE_URL: Mutex<CriticalSectionRawMutex, Option<(LoginCode, String<640>)>> =
+    Mutex::new(None);
+
+fn set_state(state: LoginState) {
+    debug!("Login state: {state:?}");
+
+    LOGIN_CHANNEL.immediate_publisher().publish_immediate(state);
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginUrlParams {
+    code: Option<String<LOGIN_CODE_LEN>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LoginUrl {
+    /// Whether a login is pending.
+    waiting: bool,
+    url: Option<String<640>>,
+}
+
+/// The `/login/url` route - whether a login is pending, and where the `/login`
+/// page should send the user to log in once they have entered the code on the
+/// display.
+///
+/// The url carries the `state` the redirect back is checked against, so handing
+/// it to anyone on the network would let them link teeny to their own account.
+pub async fn login_url(Query(params): Query<LoginUrlParams>) -> Json<LoginUrl> {
+    let pending = AUTHORIZE_URL.lock().await;
+
+    let url = pending.as_ref().and_then(|(code, url)| {
+        params
+            .code
+            .filter(|given| code.matches(given))
+            .map(|_| url.clone())
+    });
+
+    Json(LoginUrl {
+        waiting: pending.is_some(),
+        url,
+    })
+}
+
+/// Runs the authorisation flow, with the user coming in through the device's
+/// `/login` page at `address` with the code on the display - spotify sends
+/// them on to
+/// [`REDIRECT_URI`](crate::auth::REDIRECT_URI), which they paste back into the
+/// same page. The refresh token is saved for next time.
+async fn login<T, D>(
+    client: &mut HttpClient<'_, T, D>,
+    config: AuthConfig,
+    rng: &mut Rng,
+    sha: &mut Sha<'static, Blocking>,
+    address: impl core::fmt::Display,
+    settings: &'static SettingsMutex,
+) -> Result<Token, TeenyAuthError>
+where
+    T: TcpConnect,
+    D: Dns,
+{
+    let mut url: String<LOGIN_URL_LEN> = String::new();
+    write!(url, "http://{address}/login")?;
+
+    let authorisation = Authorisation::new(config, rng, sha);
+    let code = LoginCode::new(*rng);
+
+    *AUTHORIZE_URL.lock().await = Some((code.clone(), authorisation.authorize_url()));
+
+    info!("Log in to spotify at {url}");
+    set_state(LoginState::Required { url, code });
+
+    let token = authorisation.finish(client).await;
+
+    *AUTHORIZE_URL.lock().await = None;
+
+    let token = token?;
+
+    if let Some(refresh_token) = &token.refresh_token {
+        save_refresh_token(settings, refresh_token).await;
+    }
+
+    Ok(token)
+}
+
+async fn save_refresh_token(
+    settings: &'static SettingsMutex,
+    refresh_token: &String<REFRESH_TOKEN_LEN>,
+) {
+    if let Err(e) = settings.lock().await.save(Key::RefreshToken, refresh_token) {
+        error!("Failed to save the refresh token: {e:?}");
+    }
+}
+
+/// Saves every refresh token spotify swaps the old one for.
+async fn save_rotated_tokens(settings: &'static SettingsMutex) -> ! {
+    loop {
+        let refresh_token = REFRESH_TOKEN_SIGNAL.wait().await;
+        save_refresh_token(settings, &refresh_token).await;
+    }
+}
+
+/// Logs in to spotify (or picks up the saved login), then keeps the access
+/// token fresh for everyone else - logging in again whenever spotify stops
+/// accepting the refresh token.
+#[task]
+pub async fn auth_task(
+    stack: &'static StaStack,
+    mut rng: Rng,
+    mut sha: Sha<'static, Blocking>,
+    settings: &'static SettingsMutex,
+) {
+    async fn auth_task_internal(
+        client: &StaClient,
+        rng: &mut Rng,
+        sha: &mut Sha<'static, Blocking>,
+        settings: &'static SettingsMutex,
+    ) -> Result<(), TeenyAuthError> {
+        let mut rx_buf = [0; TLS_BUFFER_LEN];
+        let mut tx_buf = [0; TLS_BUFFER_LEN];
+
+        let (tcp_client, dns_socket) = client.ready().await;
+
+        set_state(LoginState::Authorising);
+
+        let seed = ((rng.random() as u64) << u32::BITS) + rng.random() as u64;
+        let tls = TlsConfig::new(seed, &mut rx_buf, &mut tx_buf, TlsVerify::None);
+
+        let mut http = HttpClient::new_with_tls(&tcp_client, &dns_socket, tls);
+
+        let config = AuthConfig::default();
+
+        let saved: Option<String<REFRESH_TOKEN_LEN>> = settings
+            .lock()
+            .await
+            .load(Key::RefreshToken)
+            .unwrap_or_else(|e| {
+                warn!("Failed to load the refresh token: {e:?}");
+                None
+            });
+
+        // looked up every time, in case the network has changed since
+        let address = || {
+            client
+                .stack()
+                .config_v4()
+                .expect("the network is up once the client is ready")
+                .address
+                .address()
+        };
+
+        let mut token = match saved {
+            Some(refresh_token) => Token::from_refresh_token(refresh_token),
+            None => login(&mut http, config, rng, sha, address(), settings).await?,
+        };
+
+        loop {
+            set_state(LoginState::LoggedIn);
+
+            let mut manager = TokenManager::new(config, token);
+
+            let e = match select(manager.maintain(&mut http), save_rotated_tokens(settings)).await {
+                Either::First(e) => e,
+                Either::Second(never) => never,
+            };
+
+            warn!("Spotify needs logging in to again: {e:?}");
+
+            // the saved login is no good any more - straight on to asking the user
+            if let Err(e) = settings.lock().await.remove(Key::RefreshToken) {
+                error!("Failed to forget the refresh token: {e:?}");
+            }
+
+            token = login(&mut http, config, rng, sha, address(), settings).await?;
+        }
+    }
+
+    let client = StaClient::new(stack);
+
+    loop {
+        if let Err(e) = auth_task_internal(&client, &mut rng, &mut sha, settings).await {
+            warn!("Spotify login failed: {e:?}");
+            set_state(LoginState::Failed);
+        }
+
+        Timer::after_secs(1).await;
+    }
+}
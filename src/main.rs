@@ -4,14 +4,7 @@
 #![feature(impl_trait_in_assoc_type)]
 
 use embassy_executor::Spawner;
-use embassy_net::{
-    dns::DnsSocket,
-    tcp::{
-        client::{TcpClient, TcpClientState},
-        TcpSocket,
-    },
-    Config, Ipv4Address, Ipv4Cidr, Stack, StackResources, StaticConfigV4,
-};
+use embassy_net::{Config, Ipv4Address, Ipv4Cidr, Stack, StackResources, StaticConfigV4};
 use embassy_time::Ticker;
 use esp_hal::{
     analog::adc::{Adc, AdcConfig, Attenuation},
@@ -23,21 +16,30 @@ use esp_hal::{
     timer::{OneShotTimer, PeriodicTimer},
 };
 use esp_println::println;
-use esp_wifi::wifi::WifiApDevice;
-use reqwless::{
-    client::{HttpClient, TlsConfig, TlsVerify},
-    request::{Method, RequestBuilder},
-};
+use esp_wifi::wifi::{WifiApDevice, WifiStaDevice};
 use teeny::{
-    auth::AuthParams,
+    album_art::album_art_task,
     blink::blink,
-    buttons::{
-        display_play_pause, display_skip, publish_play_pause, publish_raw_skip, publish_skip,
+    buttons::{publish_play_pause, publish_raw_skip, publish_skip},
+    compositor::{self, display_task},
+    controller::{command_task, controller},
+    dhcp::{dhcp_task, DhcpConfig},
+    display::{
+        driver::DisplayConfig, menu::display_menu, now_playing::display_now_playing,
+        status::display_status,
     },
-    display::{display_shapes, screen_counter},
-    net::{self, ap_task, connection, random_utf8, wifi_task, AppRouter, GlobalState, WifiConfig},
+    dns::dns_task,
+    idle::idle_task,
+    login::auth_task,
+    net::{
+        self, ap_task, connection, wifi_task, AppRouter, GlobalState, LoginRouter, SettingsState,
+        WifiConfig, WifiConfigState,
+    },
+    playback::playback_task,
     prelude::*,
-    volume::{display_volume, publish_volume},
+    status::{self, status_task, ApState},
+    storage::{self, Key, PotCalibration, Preferences},
+    volume::publish_volume,
 };
 
 #[main]
@@ -88,15 +90,36 @@ async fn main(spawner: Spawner) -> ! {
         );
     }
 
+    let settings = storage::init_settings();
+
+    let (pot_calibration, preferences, display_config): (
+        PotCalibration,
+        Preferences,
+        DisplayConfig,
+    ) = {
+        let mut settings = settings
+            .try_lock()
+            .expect("nothing else has the settings yet");
+
+        (
+            settings.load_or_default(Key::PotCalibration),
+            settings.load_or_default(Key::Preferences),
+            settings.load_or_default(Key::Display),
+        )
+    };
+
+    // before anything is laid out to fit it
+    compositor::configure(display_config);
+
     let mut adc1_config = AdcConfig::new();
 
     let rng = Rng::new(peripherals.RNG);
     let mut rng = *RNG.init(rng);
 
     #[cfg(target_arch = "riscv32")]
-    let mut sha = Sha::new(peripherals.SHA, ShaMode::SHA256, None);
+    let sha = Sha::new(peripherals.SHA, ShaMode::SHA256, None);
     #[cfg(target_arch = "xtensa")]
-    let mut sha = Sha::new(peripherals.SHA, ShaMode::SHA256);
+    let sha = Sha::new(peripherals.SHA, ShaMode::SHA256);
 
     #[cfg(target_arch = "xtensa")]
     let pot_pin = adc1_config.enable_pin(io.pins.gpio32, Attenuation::Attenuation11dB);
@@ -155,8 +178,8 @@ async fn main(spawner: Spawner) -> ! {
     });
 
     let wifi = peripherals.WIFI;
-    let (ap_interface, controller) =
-        esp_wifi::wifi::new_with_mode(&init, wifi, WifiApDevice).unwrap();
+    let (ap_interface, sta_interface, controller) =
+        esp_wifi::wifi::new_ap_sta(&init, wifi).unwrap();
 
     let ap_config = Config::ipv4_static(StaticConfigV4 {
         address: Ipv4Cidr::new(Ipv4Address::new(192, 168, 0, 1), 24),
@@ -181,39 +204,59 @@ async fn main(spawner: Spawner) -> ! {
     );
 
     // Init wifi networking stack
-    // let wifi_stack = make_static!(Stack::new(
-    //     wifi_interface,
-    //     wifi_config,
-    //     make_static!(StackResources::<3>::new()),
-    //     seed
-    // ));
+    let wifi_stack = mk_static!(
+        Stack<esp_wifi::wifi::WifiDevice<'_, WifiStaDevice>>,
+        Stack::new(
+            sta_interface,
+            wifi_config,
+            // dhcp, the web server, and a tcp and dns socket each for logging in, polling
+            // playback, sending commands and downloading album art
+            mk_static!(StackResources::<11>, StackResources::<11>::new()),
+            seed
+        )
+    );
 
     spawner.spawn(blink(internal_led)).ok();
-    // spawner.must_spawn(publish_play_pause(play_pause_button));
-    // spawner.must_spawn(publish_raw_skip(skip_button));
-    // spawner.must_spawn(publish_skip());
-    // spawner.must_spawn(publish_volume(adc1, pot_pin));
-
-    // spawner.spawn(screen_counter(I2cDevice::new(i2c_bus))).ok();
-    // spawner.spawn(display_shapes(I2cDevice::new(i2c_bus))).ok();
-    // spawner.spawn(display_volume(I2cDevice::new(i2c_bus))).ok();
-    // spawner.spawn(display_skip(I2cDevice::new(i2c_bus))).ok();
-    // spawner
-    //     .spawn(display_play_pause(I2cDevice::new(i2c_bus)))
-    //     .ok();
-
-    spawner.must_spawn(connection(controller, rng));
+    spawner.must_spawn(publish_play_pause(play_pause_button));
+    spawner.must_spawn(publish_raw_skip(skip_button));
+    spawner.must_spawn(publish_skip());
+    spawner.must_spawn(publish_volume(adc1, pot_pin, pot_calibration));
+    spawner.must_spawn(controller());
+
+    spawner.must_spawn(display_task(I2cDevice::new(i2c_bus)));
+    spawner.must_spawn(display_menu(settings));
+    spawner.must_spawn(idle_task(preferences));
+
+    let wifi_config_state = WifiConfigState(mk_static!(
+        Mutex<CriticalSectionRawMutex, WifiConfig>,
+        Mutex::new(WifiConfig::default())
+    ));
+
+    // only ever shown on the display, never logged
+    let ap_password = net::ap_password(settings, rng).await;
+
+    spawner.must_spawn(display_status(ap_password.clone()));
+    spawner.must_spawn(status_task());
+
+    spawner.must_spawn(connection(
+        controller,
+        rng,
+        wifi_stack,
+        wifi_config_state,
+        settings,
+        ap_password,
+    ));
     spawner.must_spawn(ap_task(ap_stack));
-    // spawner.must_spawn(wifi_task(wifi_stack));
+    spawner.must_spawn(dhcp_task(ap_stack, DhcpConfig::default()));
+    spawner.must_spawn(dns_task(ap_stack));
+    spawner.must_spawn(wifi_task(wifi_stack));
+    spawner.must_spawn(auth_task(wifi_stack, rng, sha, settings));
+    spawner.must_spawn(playback_task(wifi_stack, rng));
+    spawner.must_spawn(command_task(wifi_stack, rng));
+    spawner.must_spawn(album_art_task(wifi_stack, rng));
+    spawner.must_spawn(display_now_playing());
 
-    // loop {
-    //     trace!("Checking stack state...");
-    //     if wifi_stack.is_link_up() {
-    //         debug!("Link is up!");
-    //         break;
-    //     }
-    //     Timer::after(Duration::from_millis(500)).await;
-    // }
+    status::set_ap(ApState::Starting);
 
     loop {
         if ap_stack.is_link_up() {
@@ -223,11 +266,18 @@ async fn main(spawner: Spawner) -> ! {
         Timer::after(Duration::from_millis(500)).await;
     }
 
-    // println!("Connect to the AP `esp-wifi` and point your browser to http://192.168.2.1:8080/");
-    // println!("Use a static IP in the range 192.168.2.2 .. 192.168.2.255, use
-    // gateway 192.168.2.1");
+    status::set_ap(ApState::Up);
 
-    let app = mk_static!(picoserve::Router<AppRouter,GlobalState>, net::app_router());
+    info!(
+        "Connect to the `Teeny` access point and point your browser to {}",
+        net::PORTAL_URL
+    );
+
+    let app = mk_static!(picoserve::Router<AppRouter, GlobalState>, net::app_router());
+    let login_app = mk_static!(
+        picoserve::Router<LoginRouter, GlobalState>,
+        net::login_router()
+    );
 
     let config = mk_static!(
         picoserve::Config<Duration>,
@@ -239,193 +289,29 @@ async fn main(spawner: Spawner) -> ! {
         .keep_connection_alive()
     );
 
-    let wifi_config = net::WifiConfigState(
-        mk_static!(Mutex<CriticalSectionRawMutex, WifiConfig>, Mutex::new(WifiConfig::default())),
-    );
-
     for id in 0..net::WEB_TASK_POOL_SIZE {
         spawner.must_spawn(net::site_task(
             id,
             ap_stack,
             app,
             config,
-            GlobalState { wifi_config },
+            GlobalState {
+                wifi_config: wifi_config_state,
+                settings: SettingsState(settings),
+            },
+        ));
+        spawner.must_spawn(net::sta_site_task(
+            id,
+            wifi_stack,
+            login_app,
+            config,
+            GlobalState {
+                wifi_config: wifi_config_state,
+                settings: SettingsState(settings),
+            },
         ));
     }
 
-    // info!("Waiting to get IP address... ");
-    // loop {
-    //     if let Some(config) = wifi_stack.config_v4() {
-    //         info!("Got IP: {}", config.address);
-    //         break;
-    //     }
-    //     Timer::after(Duration::from_millis(500)).await;
-    // }
-
-    // let mut ap_rx_buffer = [0; 1536];
-    // let mut ap_tx_buffer = [0; 1536];
-
-    // let mut ap_socket = TcpSocket::new(ap_stack, &mut ap_rx_buffer, &mut
-    // ap_tx_buffer);
-
-    // ap_socket.set_timeout(Some(embassy_time::Duration::from_secs(10)));
-
-    // let mut wifi_rx_buf = [0; 16640];
-    // let mut wifi_tx_buf = [0; 16640];
-
-    // let state = TcpClientState::<1, 4096, 4096>::new();
-
-    // let tcp_client = TcpClient::new(wifi_stack, &state);
-
-    // let dns_socket = DnsSocket::new(wifi_stack);
-
-    // let config = TlsConfig::new(seed, &mut wifi_rx_buf, &mut wifi_tx_buf,
-    // TlsVerify::None);
-
-    // let mut client = HttpClient::new_with_tls(&tcp_client, &dns_socket, config);
-
-    // debug!("Http Client created");
-
-    // loop {
-    //     info!("Starting wifi loop...");
-
-    //     let code_challenge_raw = random_utf8::<64>(rng);
-
-    //     // convert the vec to a string
-    //     // SAFETY: the verifier is guaranteed to be valid utf8 as it is base64
-    // encoded     let code_challenge: String<64> = String::from_utf8(
-    //         Vec::from_slice(&code_challenge_raw)
-    //             .expect("should be enough bytes for cloning into the vec"),
-    //     )
-    //     .expect("Base64 encoding is valid utf8");
-
-    //     let token = "TOKEN_GOES_HERE";
-
-    //     let mut string: String<64> = String::new();
-
-    //     string.push_str("Bearer ").unwrap();
-    //     string.push_str(token).unwrap();
-    //     let mut code_challenge_raw = code_challenge_raw.as_slice();
-
-    //     // SHA256 Hashing the verifier
-    //     while !code_challenge_raw.is_empty() {
-    //         // SAFETY: All the HW Sha functions are infallible so unwrap is fine
-    // to use if         // you use block!
-    //         code_challenge_raw = block!(sha.update(code_challenge_raw)).unwrap();
-    //     }
-
-    //     let mut hash_buffer: [u8; 32] = [0; 32];
-
-    //     block!(sha.finish(hash_buffer.as_mut_slice())).unwrap();
-
-    //     let mut base64_buf: Vec<u8, 47> = Vec::new();
-    //     base64_buf.resize(32 * 4 / 3 + 4, 0).unwrap();
-
-    //     // Encode the hash to base64
-    //     let real_b64_len = BASE64_STANDARD_NO_PAD
-    //         .encode_slice(hash_buffer, &mut base64_buf)
-    //         .unwrap();
-
-    //     base64_buf.truncate(real_b64_len);
-
-    //     let _verifier_hash = String::from_utf8(base64_buf).unwrap();
-
-    //     let auth_params = AuthParams {
-    //         response_type: "code",
-    //         client_id: CLIENT_ID,
-    //         scope: "user-read-private%20user-read-email",
-    //         code_challenge,
-    //         code_challenge_method: "S256",
-    //         redirect_uri: "http://192.168.2.1/callback",
-    //     };
-
-    //     let headers = [
-    //         ("User-Agent", "teeny/0.1.0"),
-    //         ("Accept", "*/*"),
-    //         ("Connection", "close"),
-    //     ];
-
-    //     let mut header_buf = [0; 1024 * 8];
-
-    //     let mut auth_path: String<512> = String::new();
-
-    //     auth_path.push_str("/authorize").unwrap();
-    //     auth_path
-    //         .push_str(auth_params.to_string().as_str())
-    //         .inspect_err(|e| println!("{e:?}"))
-    //         .unwrap();
-
-    //     println!("{:#?}", &auth_path);
-
-    //     let mut request = client
-    //         .request(Method::GET, "https://accounts.spotify.com")
-    //         .await
-    //         .unwrap()
-    //         .path(&auth_path)
-    //         .headers(&headers);
-
-    //     // println!("{:#?}", &request.build());
-
-    //     let response = request.send(&mut header_buf).await.unwrap();
-
-    //     debug!("Request sent");
-
-    //     let content_len = response.content_length.unwrap();
-
-    //     debug!("Response Recieved");
-
-    //     let mut buf = [0; 32 * 1024];
-
-    //     if let Err(e) = response.body().reader().read_to_end(&mut buf).await {
-    //         error!("Error: {e:?}");
-    //         break;
-    //     }
-
-    //     println!("{:#?}", core::str::from_utf8(&buf[..content_len]).unwrap());
-
-    //     {
-
-    //         // let token = "TOKEN_GOES_HERE";
-    //         // let mut string: String<64> = String::new();
-    //         // string.push_str("Bearer ").unwrap();
-    //         // string.push_str(token).unwrap();
-
-    //         // let headers = [
-    //         //     ("User-Agent", "teeny/0.1.0"),
-    //         //     ("Accept", "*/*"),
-    //         //     ("Connection", "close"),
-    //         //     ("Authorization", string.as_str()),
-    //         // ];
-
-    //         // let mut request = client
-    //         //     .request(Method::GET, "https://accounts.spotify.com")
-    //         //     .await
-    //         //     .unwrap()
-    //         //     .path("/authorise")
-    //         //     .headers(&headers);
-
-    //         // let response = request.send(&mut header_buf).await.unwrap();
-
-    //         // debug!("Request sent");
-
-    //         // let content_len = response.content_length.unwrap();
-
-    //         // debug!("Response Recieved");
-
-    //         // let mut buf = [0; 50 * 1024];
-
-    //         // if let Err(e) = response.body().reader().read_to_end(&mut
-    //         // buf).await {     error!("Error: {e:?}");
-    //         //     break;
-    //         // }
-
-    //         // println!("{:#?}",
-    //         // core::str::from_utf8(&buf[..content_len]).unwrap());
-    //     }
-
-    //     Timer::after(Duration::from_secs(3)).await;
-    // }
-
     let mut ticker = Ticker::every(Duration::from_millis(1000));
 
     loop {
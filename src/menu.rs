@@ -0,0 +1,716 @@
+//! Menus, opened by pressing play and skip together - the knob moves through
+//! the items, play chooses one and skip goes back.
+//!
+//! Menus are declared as `static`s, and a [`Navigator`] only ever sees
+//! [`Input`]s, so walking through them needs no hardware.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_sync::channel::Channel;
+
+use crate::{prelude::*, storage::Preferences};
+
+/// How close together play and skip have to be pressed to open the menu.
+pub const COMBO_WINDOW: Duration = Duration::from_millis(200);
+
+/// How far the knob has to turn to move the selection by one item.
+const KNOB_STEP: f32 = 0.08;
+
+/// How many menus deep [`MAIN_MENU`] can go.
+const MAX_DEPTH: usize = 4;
+
+/// How many inputs can be waiting for the menu before new ones get dropped.
+const INPUT_QUEUE_SIZE: usize = 4;
+
+/// Something done with the knob or buttons while the menu is open.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Input {
+    /// The knob moved, to somewhere from `0` to `1`.
+    Knob(f32),
+    /// Play was pressed.
+    Select,
+    /// Skip was pressed.
+    Back,
+}
+
+/// Something with a screen of its own, which gets the inputs while it is up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Screen {
+    /// Where the knob is, to check it reaches both ends.
+    Knob,
+    /// How long the device has been up, and what it is running.
+    Diagnostics,
+    /// The spotify devices - choosing one moves playback to it.
+    Devices,
+}
+
+/// One of the [`Preferences`], picked from a few choices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Setting {
+    DimAfter,
+    OffAfter,
+    PixelShift,
+}
+
+impl Setting {
+    pub fn title(self) -> &'static str {
+        match self {
+            Setting::DimAfter => "Dim after",
+            Setting::OffAfter => "Screen off after",
+            Setting::PixelShift => "Pixel shift",
+        }
+    }
+
+    /// What the setting can be set to, and what each choice is called.
+    pub fn choices(self) -> &'static [(&'static str, u16)] {
+        match self {
+            Setting::DimAfter => &[
+                ("10 seconds", 10),
+                ("30 seconds", 30),
+                ("1 minute", 60),
+                ("5 minutes", 300),
+                ("Never", 0),
+            ],
+            Setting::OffAfter => &[
+                ("1 minute", 60),
+                ("5 minutes", 300),
+                ("15 minutes", 900),
+                ("1 hour", 3600),
+                ("Never", 0),
+            ],
+            Setting::PixelShift => &[("On", 1), ("Off", 0)],
+        }
+    }
+
+    pub fn get(self, preferences: &Preferences) -> u16 {
+        match self {
+            Setting::DimAfter => preferences.dim_after_secs,
+            Setting::OffAfter => preferences.off_after_secs,
+            Setting::PixelShift => preferences.pixel_shift as u16,
+        }
+    }
+
+    pub fn set(self, preferences: &mut Preferences, value: u16) {
+        match self {
+            Setting::DimAfter => preferences.dim_after_secs = value,
+            Setting::OffAfter => preferences.off_after_secs = value,
+            Setting::PixelShift => preferences.pixel_shift = value != 0,
+        }
+    }
+}
+
+/// What choosing an item does.
+#[derive(Debug, Clone, Copy)]
+pub enum Action {
+    /// Opens another menu, which back returns from.
+    Open(&'static Menu),
+    /// Lists the choices for a setting - choosing one sets it, and back leaves
+    /// it as it was.
+    Choose(Setting),
+    /// Hands over to a screen, which back returns from.
+    Show(Screen),
+    /// Closes the menu altogether.
+    Close,
+}
+
+#[derive(Debug)]
+pub struct Item {
+    pub label: &'static str,
+    pub action: Action,
+}
+
+impl Item {
+    pub const fn new(label: &'static str, action: Action) -> Self {
+        Self { label, action }
+    }
+}
+
+#[derive(Debug)]
+pub struct Menu {
+    pub title: &'static str,
+    pub items: &'static [Item],
+}
+
+/// What the button combo opens.
+pub static MAIN_MENU: Menu = Menu {
+    title: "Menu",
+    items: &[
+        Item::new("Settings", Action::Open(&SETTINGS_MENU)),
+        Item::new("Diagnostics", Action::Show(Screen::Diagnostics)),
+        Item::new("Devices", Action::Show(Screen::Devices)),
+        Item::new("Close", Action::Close),
+    ],
+};
+
+pub static SETTINGS_MENU: Menu = Menu {
+    title: "Settings",
+    items: &[
+        Item::new("Dim after", Action::Choose(Setting::DimAfter)),
+        Item::new("Screen off after", Action::Choose(Setting::OffAfter)),
+        Item::new("Pixel shift", Action::Choose(Setting::PixelShift)),
+        Item::new("Knob", Action::Show(Screen::Knob)),
+    ],
+};
+
+/// What a [`Navigator`] did with an [`Input`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    /// Nothing - e.g. the knob didn't turn far enough to move the selection.
+    Unchanged,
+    /// A different menu, item or screen is showing.
+    Changed,
+    /// An input for the screen that is up.
+    Screen(Screen, Input),
+    /// A setting was chosen - see [`Navigator::preferences`].
+    Chosen,
+    Closed,
+}
+
+/// A list of things to pick from.
+#[derive(Debug, Clone, Copy)]
+enum Level {
+    Menu(&'static Menu),
+    Choices(Setting),
+}
+
+impl Level {
+    fn title(self) -> &'static str {
+        match self {
+            Level::Menu(menu) => menu.title,
+            Level::Choices(setting) => setting.title(),
+        }
+    }
+
+    fn labels(self) -> impl Iterator<Item = &'static str> + Clone {
+        let (items, choices) = match self {
+            Level::Menu(menu) => (menu.items, [].as_slice()),
+            Level::Choices(setting) => ([].as_slice(), setting.choices()),
+        };
+
+        items
+            .iter()
+            .map(|item| item.label)
+            .chain(choices.iter().map(|(label, _)| *label))
+    }
+
+    fn len(self) -> usize {
+        match self {
+            Level::Menu(menu) => menu.items.len(),
+            Level::Choices(setting) => setting.choices().len(),
+        }
+    }
+}
+
+/// Where in the menus we are - which menus are open, the item selected in
+/// each, and whether a screen is up over them.
+#[derive(Debug, Clone)]
+pub struct Navigator {
+    /// Innermost last, never empty.
+    levels: Vec<(Level, usize), MAX_DEPTH>,
+    screen: Option<Screen>,
+    /// What the settings are, with any chosen since the menu opened.
+    preferences: Preferences,
+    /// Where the knob was when the selection last moved, once it has moved
+    /// since the menu (or screen) came up.
+    knob: Option<f32>,
+}
+
+impl Navigator {
+    pub fn new(root: &'static Menu, preferences: Preferences) -> Self {
+        let mut levels = Vec::new();
+        // can't fail - there is room for at least one
+        levels.push((Level::Menu(root), 0)).ok();
+
+        Self {
+            levels,
+            screen: None,
+            preferences,
+            knob: None,
+        }
+    }
+
+    fn level(&self) -> (Level, usize) {
+        self.levels[self.levels.len() - 1]
+    }
+
+    /// The title of the innermost open menu (or setting).
+    pub fn title(&self) -> &'static str {
+        self.level().0.title()
+    }
+
+    /// The items of the innermost open menu, or the choices of the setting
+    /// being chosen.
+    pub fn labels(&self) -> impl Iterator<Item = &'static str> + Clone {
+        self.level().0.labels()
+    }
+
+    /// The index of the selected item (or choice).
+    pub fn selected(&self) -> usize {
+        self.level().1
+    }
+
+    pub fn preferences(&self) -> Preferences {
+        self.preferences
+    }
+
+    /// The screen up over the menus, if there is one.
+    pub fn screen(&self) -> Option<Screen> {
+        self.screen
+    }
+
+    pub fn handle(&mut self, input: Input) -> Outcome {
+        if let Some(screen) = self.screen {
+            if input != Input::Back {
+                return Outcome::Screen(screen, input);
+            }
+
+            self.screen = None;
+            self.knob = None;
+
+            return Outcome::Changed;
+        }
+
+        match input {
+            Input::Knob(value) => self.turn(value),
+            Input::Select => self.select(),
+            Input::Back => {
+                if self.levels.len() == 1 {
+                    return Outcome::Closed;
+                }
+
+                self.levels.pop();
+                self.knob = None;
+
+                Outcome::Changed
+            }
+        }
+    }
+
+    /// Moves the selection an item for every [`KNOB_STEP`] the knob turned.
+    fn turn(&mut self, value: f32) -> Outcome {
+        let last = self.levels.len() - 1;
+        let (level, selected) = &mut self.levels[last];
+
+        if turn(&mut self.knob, selected, level.len(), value) {
+            Outcome::Changed
+        } else {
+            Outcome::Unchanged
+        }
+    }
+
+    fn select(&mut self) -> Outcome {
+        let menu = match self.level() {
+            (Level::Menu(menu), _) => menu,
+            (Level::Choices(setting), selected) => {
+                let Some(&(_, value)) = setting.choices().get(selected) else {
+                    return Outcome::Unchanged;
+                };
+
+                setting.set(&mut self.preferences, value);
+
+                self.levels.pop();
+                self.knob = None;
+
+                return Outcome::Chosen;
+            }
+        };
+
+        let Some(item) = menu.items.get(self.selected()) else {
+            return Outcome::Unchanged;
+        };
+
+        let level = match item.action {
+            Action::Open(menu) => (Level::Menu(menu), 0),
+            Action::Choose(setting) => {
+                // starting from what it is now
+                let current = setting.get(&self.preferences);
+                let selected = setting
+                    .choices()
+                    .iter()
+                    .position(|&(_, value)| value == current)
+                    .unwrap_or_default();
+
+                (Level::Choices(setting), selected)
+            }
+            Action::Show(screen) => {
+                self.screen = Some(screen);
+                self.knob = None;
+
+                return Outcome::Changed;
+            }
+            Action::Close => return Outcome::Closed,
+        };
+
+        if self.levels.push(level).is_err() {
+            warn!("Menus nested more than {MAX_DEPTH} deep");
+            return Outcome::Unchanged;
+        }
+
+        self.knob = None;
+
+        Outcome::Changed
+    }
+}
+
+/// Moves `selected` (one of `len` items) an item for every [`KNOB_STEP`] the
+/// knob turned - relative to `knob`, where it was when the selection last
+/// moved, so opening a menu never jumps to wherever the knob happens to be.
+///
+/// Returns whether the selection moved.
+fn turn(knob: &mut Option<f32>, selected: &mut usize, len: usize, value: f32) -> bool {
+    let reference = *knob.get_or_insert(value);
+    let steps = ((value - reference) / KNOB_STEP) as i32;
+
+    if steps == 0 {
+        return false;
+    }
+
+    *knob = Some(reference + steps as f32 * KNOB_STEP);
+
+    let moved = (*selected as i32 + steps).min(len as i32 - 1).max(0) as usize;
+
+    if moved == *selected {
+        return false;
+    }
+
+    *selected = moved;
+
+    true
+}
+
+/// Picks one of a list which isn't known until its screen is up (like the
+/// devices), with the same knob steps as the menus.
+#[derive(Debug, Clone)]
+pub struct Picker {
+    len: usize,
+    selected: usize,
+    knob: Option<f32>,
+}
+
+impl Picker {
+    /// Picks from `len` things, starting from the one at `selected`.
+    pub fn new(len: usize, selected: usize) -> Self {
+        Self {
+            len,
+            selected: selected.min(len.saturating_sub(1)),
+            knob: None,
+        }
+    }
+
+    /// The index of the selected thing.
+    pub fn selected(&self) -> usize {
+        self.selected
+    }
+
+    /// Moves the selection with the knob, and returns the selected index once
+    /// it is chosen - screens get [`Input::Back`] before this does.
+    pub fn handle(&mut self, input: Input) -> Option<usize> {
+        match input {
+            Input::Knob(value) => {
+                turn(&mut self.knob, &mut self.selected, self.len, value);
+                None
+            }
+            Input::Select => (self.len > 0).then_some(self.selected),
+            Input::Back => None,
+        }
+    }
+}
+
+static OPEN: AtomicBool = AtomicBool::new(false);
+
+static OPEN_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Inputs for the open menu - see [`send`].
+pub static MENU_INPUT: Channel<CriticalSectionRawMutex, Input, INPUT_QUEUE_SIZE> = Channel::new();
+
+/// Whether the knob and buttons are working the menu, rather than the player.
+pub fn is_open() -> bool {
+    OPEN.load(Ordering::Relaxed)
+}
+
+/// Opens [`MAIN_MENU`] - everything from the knob and buttons goes to the menu
+/// until it closes.
+pub fn open() {
+    // anything left over was meant for the last time it was open
+    while MENU_INPUT.try_receive().is_ok() {}
+
+    OPEN.store(true, Ordering::Relaxed);
+    OPEN_SIGNAL.signal(());
+}
+
+/// Waits for the menu to be [`open`]ed.
+pub async fn opened() {
+    OPEN_SIGNAL.wait().await
+}
+
+/// Hands the knob and buttons back to the player.
+pub fn close() {
+    OPEN.store(false, Ordering::Relaxed);
+}
+
+/// Queues `input` for the open menu without ever waiting - like commands, it is
+/// better to drop one than to stop reading the buttons.
+pub fn send(input: Input) {
+    if MENU_INPUT.try_send(input).is_err() {
+        trace!("Menu input queue is full, dropping {input:?}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use Input::{Back, Knob, Select};
+    use Outcome::{Changed, Chosen, Closed, Unchanged};
+
+    use super::*;
+
+    /// Feeds `inputs` to `navigator` one at a time, as the menu task would.
+    fn run(navigator: &mut Navigator, inputs: &[Input]) -> std::vec::Vec<Outcome> {
+        inputs
+            .iter()
+            .map(|&input| navigator.handle(input))
+            .collect()
+    }
+
+    fn main_menu() -> Navigator {
+        Navigator::new(&MAIN_MENU, Preferences::default())
+    }
+
+    #[test]
+    fn the_knob_steps_relative_to_where_it_started() {
+        let mut navigator = main_menu();
+
+        // wherever the knob happens to be when the menu opens is the reference,
+        // and jitter smaller than a step is ignored
+        assert_eq!(
+            run(&mut navigator, &[Knob(0.9), Knob(0.95), Knob(0.85)]),
+            [Unchanged, Unchanged, Unchanged]
+        );
+        assert_eq!(navigator.selected(), 0);
+
+        // a step (and a bit) down moves down one item
+        assert_eq!(
+            run(&mut navigator, &[Knob(0.9 + KNOB_STEP * 1.5)]),
+            [Changed]
+        );
+        assert_eq!(navigator.selected(), 1);
+
+        // the reference moved along by whole steps, so half a step more makes up
+        // the second one
+        assert_eq!(
+            run(&mut navigator, &[Knob(0.9 + KNOB_STEP * 2.1)]),
+            [Changed]
+        );
+        assert_eq!(navigator.selected(), 2);
+
+        // turning back moves straight back, rather than after the slack is taken up
+        assert_eq!(
+            run(&mut navigator, &[Knob(0.9 + KNOB_STEP * 0.9)]),
+            [Changed]
+        );
+        assert_eq!(navigator.selected(), 1);
+    }
+
+    #[test]
+    fn the_knob_stops_at_either_end() {
+        let mut navigator = main_menu();
+
+        assert_eq!(
+            run(&mut navigator, &[Knob(0.), Knob(1.)]),
+            [Unchanged, Changed]
+        );
+        assert_eq!(navigator.selected(), MAIN_MENU.items.len() - 1);
+
+        assert_eq!(
+            run(&mut navigator, &[Knob(0.), Knob(-1.)]),
+            [Changed, Unchanged]
+        );
+        assert_eq!(navigator.selected(), 0);
+    }
+
+    #[test]
+    fn the_knob_starts_afresh_in_every_menu() {
+        let mut navigator = main_menu();
+
+        assert_eq!(
+            run(&mut navigator, &[Knob(0.2), Select]),
+            [Unchanged, Changed]
+        );
+        assert_eq!(navigator.title(), "Settings");
+
+        // nowhere near where it was in the last menu, but nothing moves
+        assert_eq!(run(&mut navigator, &[Knob(0.9)]), [Unchanged]);
+        assert_eq!(navigator.selected(), 0);
+
+        assert_eq!(run(&mut navigator, &[Back, Knob(0.)]), [Changed, Unchanged]);
+        assert_eq!(navigator.title(), "Menu");
+    }
+
+    #[test]
+    fn choosing_sets_a_preference() {
+        let mut navigator = main_menu();
+
+        // settings, then screen off after - which starts on what it is now
+        run(&mut navigator, &[Select, Knob(0.), Knob(KNOB_STEP), Select]);
+        assert_eq!(navigator.title(), Setting::OffAfter.title());
+        assert_eq!(
+            Setting::OffAfter.choices()[navigator.selected()].1,
+            Preferences::default().off_after_secs
+        );
+
+        // down to "Never"
+        assert_eq!(
+            run(&mut navigator, &[Knob(0.), Knob(1.), Select]),
+            [Unchanged, Changed, Chosen]
+        );
+        assert_eq!(navigator.title(), "Settings");
+
+        assert_eq!(
+            navigator.preferences(),
+            Preferences {
+                off_after_secs: 0,
+                ..Preferences::default()
+            }
+        );
+    }
+
+    #[test]
+    fn backing_out_of_a_choice_changes_nothing() {
+        let mut navigator = main_menu();
+
+        for setting in 0..3 {
+            let mut inputs = std::vec![Select, Knob(0.)];
+            inputs.extend((1..=setting).map(|step| Knob(step as f32 * KNOB_STEP)));
+            inputs.extend([Select, Knob(0.5), Knob(0.)]);
+
+            run(&mut navigator, &inputs);
+            assert!(navigator.title() != "Settings");
+
+            assert_eq!(run(&mut navigator, &[Back, Back]), [Changed, Changed]);
+            assert_eq!(navigator.title(), "Menu");
+            assert_eq!(navigator.preferences(), Preferences::default());
+        }
+    }
+
+    #[test]
+    fn screens_get_everything_but_back() {
+        let mut navigator = main_menu();
+
+        assert_eq!(
+            run(&mut navigator, &[Knob(0.), Knob(KNOB_STEP), Select]),
+            [Unchanged, Changed, Changed]
+        );
+        assert_eq!(navigator.screen(), Some(Screen::Diagnostics));
+
+        assert_eq!(
+            run(&mut navigator, &[Knob(0.7), Select, Knob(0.1)]),
+            [
+                Outcome::Screen(Screen::Diagnostics, Knob(0.7)),
+                Outcome::Screen(Screen::Diagnostics, Select),
+                Outcome::Screen(Screen::Diagnostics, Knob(0.1)),
+            ]
+        );
+
+        // back to the menu, with the same item selected and the knob starting
+        // afresh
+        assert_eq!(
+            run(&mut navigator, &[Back, Knob(0.9)]),
+            [Changed, Unchanged]
+        );
+        assert_eq!(navigator.screen(), None);
+        assert_eq!(navigator.selected(), 1);
+    }
+
+    #[test]
+    fn closing() {
+        // back from the top
+        assert_eq!(run(&mut main_menu(), &[Back]), [Closed]);
+        assert_eq!(
+            run(&mut main_menu(), &[Select, Back, Back]),
+            [Changed, Changed, Closed]
+        );
+
+        // or the close item
+        assert_eq!(
+            run(&mut main_menu(), &[Knob(0.), Knob(1.), Select]),
+            [Unchanged, Changed, Closed]
+        );
+    }
+
+    #[test]
+    fn the_devices_screen_picks_with_the_knob() {
+        let mut navigator = main_menu();
+
+        assert_eq!(
+            run(&mut navigator, &[Knob(0.), Knob(2. * KNOB_STEP), Select]),
+            [Unchanged, Changed, Changed]
+        );
+        assert_eq!(navigator.screen(), Some(Screen::Devices));
+
+        // the devices turn up with the active one selected
+        let mut picker = Picker::new(3, 1);
+
+        let picked: std::vec::Vec<_> = [Knob(0.5), Knob(0.5 + KNOB_STEP), Knob(1.), Select]
+            .into_iter()
+            .map(|input| match navigator.handle(input) {
+                Outcome::Screen(Screen::Devices, input) => picker.handle(input),
+                outcome => panic!("{outcome:?} from the devices screen"),
+            })
+            .collect();
+
+        // the knob starts afresh, and stops at the last device
+        assert_eq!(picked, [None, None, None, Some(2)]);
+
+        assert_eq!(run(&mut navigator, &[Back]), [Changed]);
+        assert_eq!(navigator.screen(), None);
+    }
+
+    #[test]
+    fn nothing_to_pick_from_no_devices() {
+        let mut picker = Picker::new(0, 0);
+
+        assert_eq!(picker.handle(Knob(0.)), None);
+        assert_eq!(picker.handle(Knob(1.)), None);
+        assert_eq!(picker.selected(), 0);
+        assert_eq!(picker.handle(Select), None);
+    }
+
+    static DEEPEST: Menu = Menu {
+        title: "5",
+        items: &[Item::new("Close", Action::Close)],
+    };
+
+    static DEEPER: [Menu; 4] = [
+        Menu {
+            title: "1",
+            items: &[Item::new("Deeper", Action::Open(&DEEPER[1]))],
+        },
+        Menu {
+            title: "2",
+            items: &[Item::new("Deeper", Action::Open(&DEEPER[2]))],
+        },
+        Menu {
+            title: "3",
+            items: &[Item::new("Deeper", Action::Open(&DEEPER[3]))],
+        },
+        Menu {
+            title: "4",
+            items: &[Item::new("Deeper", Action::Open(&DEEPEST))],
+        },
+    ];
+
+    #[test]
+    fn menus_nest_up_to_max_depth() {
+        let mut navigator = Navigator::new(&DEEPER[0], Preferences::default());
+
+        assert_eq!(
+            run(&mut navigator, &[Select; MAX_DEPTH]),
+            [Changed, Changed, Changed, Unchanged]
+        );
+        assert_eq!(navigator.title(), "4");
+
+        assert_eq!(
+            run(&mut navigator, &[Back; MAX_DEPTH]),
+            [Changed, Changed, Changed, Closed]
+        );
+    }
+}
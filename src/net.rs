@@ -1,15 +1,50 @@
 use base64::prelude::*;
-use embassy_net::Stack;
+use embassy_net::{
+    dns::DnsSocket,
+    tcp::client::{TcpClient, TcpClientState},
+    Stack,
+};
 use esp_wifi::wifi::{
-    AccessPointConfiguration, AuthMethod, ClientConfiguration, Configuration, WifiApDevice,
-    WifiController, WifiDevice, WifiEvent, WifiStaDevice, WifiState,
+    AuthMethod, ClientConfiguration, WifiApDevice, WifiController, WifiDevice, WifiStaDevice,
+    WifiState,
+};
+use picoserve::{
+    extract::{Form, FromRef, State},
+    response::{File, Json, Redirect},
+    routing::{get, get_service, post},
+};
+use rand_core::RngCore;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth,
+    display::driver::DisplayConfig,
+    login,
+    prelude::*,
+    station::StationManager,
+    storage::{Key, SettingsMutex, WifiNetwork},
 };
 
-use crate::prelude::*;
+/// Where the setup page lives on the access point.
+pub const PORTAL_URL: &str = "http://192.168.0.1/";
+
+/// The name of the setup access point.
+pub const AP_SSID: &str = "Teeny";
+
+/// How long the setup access point's password is - WPA2 needs at least 8.
+pub const AP_PASSWORD_LEN: usize = 10;
+
+pub type ApPassword = String<AP_PASSWORD_LEN>;
+
+/// Characters which are hard to mix up when reading them off the display.
+const PASSWORD_CHARSET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+/// How many connections the on-device web server can handle at once.
+pub const WEB_TASK_POOL_SIZE: usize = 2;
 
 #[inline]
 ///Uses base64 to generate a valid random utf8 string from a random buffer
-pub fn random_utf8<const LEN: usize>(mut rng: Rng) -> Vec<u8, LEN> {
+pub fn random_utf8<const LEN: usize>(rng: &mut impl RngCore) -> Vec<u8, LEN> {
     let mut input_buf: Vec<u8, LEN> = Vec::new();
 
     input_buf
@@ -18,15 +53,13 @@ pub fn random_utf8<const LEN: usize>(mut rng: Rng) -> Vec<u8, LEN> {
 
     //read the number of bytes required such that the base64 output is `LEN` bytes
     // long.
-    rng.read(&mut input_buf[..{ (LEN / 4) * 3 }]);
+    rng.fill_bytes(&mut input_buf[..{ (LEN / 4) * 3 }]);
 
     let mut output_buf: Vec<u8, LEN> = Vec::new();
 
     //resize the output buffer to be able to hold the base64 encoded output
     output_buf.resize(LEN, 0).unwrap();
 
-    println!("{}", input_buf.len());
-
     // write the base64 encoded verifier to a vec and get the length of the written
     // bytes - will be 4 less than the buffer length
     let base64_encoded_length = BASE64_URL_SAFE_NO_PAD
@@ -38,82 +71,406 @@ pub fn random_utf8<const LEN: usize>(mut rng: Rng) -> Vec<u8, LEN> {
     output_buf
 }
 
-#[task]
-pub async fn connection(mut controller: WifiController<'static>, _rng: Rng) {
-    debug!("Start connection task");
-    debug!("Device capabilities: {:?}", controller.get_capabilities());
+///Generates a random password made up of [`PASSWORD_CHARSET`]
+pub fn random_password<const LEN: usize>(mut rng: Rng) -> String<LEN> {
+    let mut password = String::new();
 
-    if !matches!(controller.is_started(), Ok(true)) {
-        let config = Configuration::Mixed(
-            ClientConfiguration {
-                ssid: SSID.try_into().unwrap_or_default(),
-                password: PASSWORD.try_into().unwrap_or_default(),
-                auth_method: {
-                    #[allow(clippy::const_is_empty)]
-                    if PASSWORD.is_empty() {
-                        AuthMethod::None
-                    } else {
-                        AuthMethod::default()
-                    }
-                },
-                ..Default::default()
-            },
-            AccessPointConfiguration {
-                ssid: String::try_from("Teeny").expect("should be a valid access point SSID"),
-                auth_method: AuthMethod::None,
-                // password: {
-
-                // let password: String<64> = String::from_utf8(
-                //     Vec::from_slice(&random_utf8::<12>(rng)).expect("64 is larger than 10"),
-                // )
-                // .expect("10 is less than 64");
-
-                // info!("Wifi Password: {:?}", &password);
-
-                // info!("Wifi Password: {:?}", &password.as_bytes());
-                // password
-                // },
-                ..Default::default()
-            },
-        );
-        controller.set_configuration(&config).unwrap();
-        info!("Starting wifi");
-        controller.start().await.unwrap();
-        // controller.connect()
-        info!("Wifi started!");
+    while password.len() < LEN {
+        let byte = rng.random() as u8;
+
+        // keep every character equally likely
+        if (byte as usize) < 256 - 256 % PASSWORD_CHARSET.len() {
+            let c = PASSWORD_CHARSET[byte as usize % PASSWORD_CHARSET.len()];
+            password.push(c as char).expect("checked the length above");
+        }
+    }
+
+    password
+}
+
+/// The setup access point's password - generated once and then kept in flash,
+/// so it can be written down. `None` leaves the access point open.
+pub async fn ap_password(settings: &'static SettingsMutex, rng: Rng) -> Option<ApPassword> {
+    if !cfg!(feature = "secure-ap") {
+        return None;
+    }
+
+    let mut settings = settings.lock().await;
+
+    match settings.load(Key::ApPassword) {
+        Ok(Some(password)) => return Some(password),
+        Ok(None) => {}
+        Err(e) => warn!("Failed to load the access point password: {e:?}"),
     }
 
-    loop {
-        match esp_wifi::wifi::get_ap_state() {
-            WifiState::ApStarted => {
-                println!("About to start access point...");
-
-                if esp_wifi::wifi::get_wifi_state() == WifiState::StaConnected {
-                    // wait until we're no longer connected
-                    controller.wait_for_event(WifiEvent::StaDisconnected).await;
-                    Timer::after(Duration::from_millis(5000)).await;
-                }
-
-                debug!("About to connect...");
-                match controller.connect().await {
-                    Ok(()) => info!("Wifi connected!"),
-                    Err(e) => {
-                        error!("Failed to connect to wifi: {e:?}");
-                        Timer::after(Duration::from_millis(5000)).await;
-                    }
-                }
+    let password = random_password(rng);
+
+    if let Err(e) = settings.save(Key::ApPassword, &password) {
+        error!("Failed to save the access point password: {e:?}");
+    }
+
+    Some(password)
+}
+
+/// The text of a QR code which joins the setup access point when scanned.
+pub fn wifi_qr_payload(ssid: &str, password: Option<&str>) -> String<96> {
+    fn push_escaped(payload: &mut String<96>, value: &str) {
+        for c in value.chars() {
+            if matches!(c, '\\' | ';' | ',' | ':' | '"') {
+                payload.push('\\').ok();
             }
-            _ => return,
+            payload.push(c).ok();
+        }
+    }
+
+    let mut payload = String::new();
+
+    match password {
+        Some(_) => payload.push_str("WIFI:T:WPA;S:").ok(),
+        None => payload.push_str("WIFI:T:nopass;S:").ok(),
+    };
+    push_escaped(&mut payload, ssid);
+
+    if let Some(password) = password {
+        payload.push_str(";P:").ok();
+        push_escaped(&mut payload, password);
+    }
+
+    payload.push_str(";;").ok();
+
+    payload
+}
+
+/// How many connections a [`StaClient`] can have open at once.
+pub const MAX_TCP_CONNECTIONS: usize = 1;
+
+/// The size of each connection's send and receive buffers.
+pub const TCP_BUFFER_LEN: usize = 4096;
+
+/// The size of each tls record buffer - big enough for the largest record a
+/// server may send.
+pub const TLS_BUFFER_LEN: usize = 16640;
+
+/// The station (internet facing) network stack.
+pub type StaStack = Stack<WifiDevice<'static, WifiStaDevice>>;
+
+pub type StaTcpClient<'a> = TcpClient<
+    'a,
+    WifiDevice<'static, WifiStaDevice>,
+    MAX_TCP_CONNECTIONS,
+    TCP_BUFFER_LEN,
+    TCP_BUFFER_LEN,
+>;
+
+pub type StaDnsSocket<'a> = DnsSocket<'a, WifiDevice<'static, WifiStaDevice>>;
+
+/// How many networks a scan reports.
+pub const MAX_SCAN_RESULTS: usize = 16;
+
+/// The network the station interface should connect to, as entered on the
+/// setup page.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct WifiConfig {
+    pub ssid: String<32>,
+    #[serde(default)]
+    pub password: String<64>,
+}
+
+impl From<WifiNetwork> for WifiConfig {
+    fn from(network: WifiNetwork) -> Self {
+        Self {
+            ssid: network.ssid,
+            password: network.password,
+        }
+    }
+}
+
+impl WifiConfig {
+    pub(crate) fn client_configuration(&self) -> ClientConfiguration {
+        ClientConfiguration {
+            ssid: self.ssid.clone(),
+            password: self.password.clone(),
+            auth_method: if self.password.is_empty() {
+                AuthMethod::None
+            } else {
+                AuthMethod::default()
+            },
+            ..Default::default()
         }
     }
 }
 
+/// The [`WifiConfig`] currently in use, shared with the web server.
+#[derive(Clone, Copy)]
+pub struct WifiConfigState(pub &'static Mutex<CriticalSectionRawMutex, WifiConfig>);
+
+#[derive(Clone, Copy)]
+pub struct SettingsState(pub &'static SettingsMutex);
+
+/// Everything the web server's handlers can get at.
+#[derive(Clone, Copy)]
+pub struct GlobalState {
+    pub wifi_config: WifiConfigState,
+    pub settings: SettingsState,
+}
+
+impl FromRef<GlobalState> for WifiConfigState {
+    fn from_ref(state: &GlobalState) -> Self {
+        state.wifi_config
+    }
+}
+
+impl FromRef<GlobalState> for SettingsState {
+    fn from_ref(state: &GlobalState) -> Self {
+        state.settings
+    }
+}
+
+/// The panel fitted, as entered on the setup page - see
+/// [`DisplayConfig::parse`].
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DisplayForm {
+    #[serde(default)]
+    pub config: String<48>,
+}
+
+/// A network seen by a scan.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScannedNetwork {
+    pub ssid: String<32>,
+    /// Signal strength in dBm.
+    pub strength: i8,
+    pub secured: bool,
+}
+
+/// Asks the [`StationManager`] to connect to a new network.
+pub(crate) static WIFI_CONFIG_SIGNAL: Signal<CriticalSectionRawMutex, WifiConfig> = Signal::new();
+
+/// Asks the [`StationManager`] to scan for networks.
+pub(crate) static SCAN_REQUEST_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// The results of the last scan asked for with [`SCAN_REQUEST_SIGNAL`].
+pub(crate) static SCAN_RESULTS_SIGNAL: Signal<
+    CriticalSectionRawMutex,
+    Vec<ScannedNetwork, MAX_SCAN_RESULTS>,
+> = Signal::new();
+
+/// Whether the station interface is connected and has an address.
+pub fn is_network_up(stack: &StaStack) -> bool {
+    stack.is_link_up() && stack.is_config_up()
+}
+
+/// Waits until the station interface is connected and has an address.
+pub async fn wait_for_network(stack: &StaStack) {
+    while !is_network_up(stack) {
+        Timer::after(Duration::from_millis(500)).await;
+    }
+}
+
+/// Makes connections to the internet over the station interface.
+pub struct StaClient {
+    stack: &'static StaStack,
+    tcp_state: TcpClientState<MAX_TCP_CONNECTIONS, TCP_BUFFER_LEN, TCP_BUFFER_LEN>,
+}
+
+impl StaClient {
+    pub fn new(stack: &'static StaStack) -> Self {
+        Self {
+            stack,
+            tcp_state: TcpClientState::new(),
+        }
+    }
+
+    pub fn stack(&self) -> &'static StaStack {
+        self.stack
+    }
+
+    /// Waits for the network to be up, then hands out a tcp client and dns
+    /// socket to build an [`HttpClient`](reqwless::client::HttpClient) from.
+    pub async fn ready(&self) -> (StaTcpClient<'_>, StaDnsSocket<'static>) {
+        wait_for_network(self.stack).await;
+
+        (
+            TcpClient::new(self.stack, &self.tcp_state),
+            DnsSocket::new(self.stack),
+        )
+    }
+}
+
+#[task]
+pub async fn connection(
+    controller: WifiController<'static>,
+    rng: Rng,
+    stack: &'static StaStack,
+    wifi_config: WifiConfigState,
+    settings: &'static SettingsMutex,
+    ap_password: Option<ApPassword>,
+) {
+    debug!("Start connection task");
+    debug!("Device capabilities: {:?}", controller.get_capabilities());
+
+    StationManager::new(controller, rng, stack, wifi_config, settings, ap_password)
+        .await
+        .run()
+        .await
+}
+
 #[task]
 pub async fn ap_task(stack: &'static Stack<WifiDevice<'static, WifiApDevice>>) {
     stack.run().await
 }
 
 #[task]
-pub async fn wifi_task(stack: &'static Stack<WifiDevice<'static, WifiStaDevice>>) {
+pub async fn wifi_task(stack: &'static StaStack) {
     stack.run().await
 }
+
+#[derive(Debug, Serialize)]
+struct WifiStatus {
+    connected: bool,
+    ssid: String<32>,
+}
+
+async fn scan_networks() -> Json<Vec<ScannedNetwork, MAX_SCAN_RESULTS>> {
+    SCAN_REQUEST_SIGNAL.signal(());
+
+    let networks = embassy_time::with_timeout(Duration::from_secs(10), SCAN_RESULTS_SIGNAL.wait())
+        .await
+        .unwrap_or_default();
+
+    Json(networks)
+}
+
+async fn connect(Form(config): Form<WifiConfig>) -> &'static str {
+    WIFI_CONFIG_SIGNAL.signal(config);
+
+    "Connecting..."
+}
+
+/// Saves the panel fitted, for next time teeny starts - or goes back to the
+/// one the board was built with, if the config is left empty.
+async fn display(
+    State(SettingsState(settings)): State<SettingsState>,
+    Form(form): Form<DisplayForm>,
+) -> &'static str {
+    let mut settings = settings.lock().await;
+
+    let saved = if form.config.trim().is_empty() {
+        settings.remove(Key::Display)
+    } else {
+        settings.save(Key::Display, &DisplayConfig::parse(&form.config))
+    };
+
+    match saved {
+        Ok(()) => "Saved - restart teeny to use it",
+        Err(e) => {
+            warn!("Failed to save the display config: {e:?}");
+            "Couldn't save it"
+        }
+    }
+}
+
+async fn status(State(WifiConfigState(wifi_config)): State<WifiConfigState>) -> Json<WifiStatus> {
+    Json(WifiStatus {
+        connected: esp_wifi::wifi::get_wifi_state() == WifiState::StaConnected,
+        ssid: wifi_config.lock().await.ssid.clone(),
+    })
+}
+
+pub type AppRouter = impl picoserve::routing::PathRouter<GlobalState>;
+
+pub fn app_router() -> picoserve::Router<AppRouter, GlobalState> {
+    picoserve::Router::new()
+        .route("/", get_service(File::html(include_str!("index.html"))))
+        .route("/scan", get(scan_networks))
+        .route("/connect", post(connect))
+        .route("/status", get(status))
+        .route("/display", post(display))
+        .route("/callback", get(auth::callback))
+        .route(
+            "/login",
+            get_service(File::html(include_str!("login.html"))),
+        )
+        .route("/login/url", get(login::login_url))
+        // the urls phones and laptops check to see if they are behind a captive portal -
+        // anything but the answer they expect makes them open the setup page
+        .route("/generate_204", get(|| async { Redirect::to(PORTAL_URL) }))
+        .route(
+            "/hotspot-detect.html",
+            get(|| async { Redirect::to(PORTAL_URL) }),
+        )
+        .route(
+            "/connecttest.txt",
+            get(|| async { Redirect::to(PORTAL_URL) }),
+        )
+}
+
+pub type LoginRouter = impl picoserve::routing::PathRouter<GlobalState>;
+
+/// Just the pages for logging in to spotify - all the station interface
+/// serves, as anyone on that network can reach it.
+pub fn login_router() -> picoserve::Router<LoginRouter, GlobalState> {
+    picoserve::Router::new()
+        .route(
+            "/login",
+            get_service(File::html(include_str!("login.html"))),
+        )
+        .route("/login/url", get(login::login_url))
+        .route("/callback", get(auth::callback))
+}
+
+#[task(pool_size = WEB_TASK_POOL_SIZE)]
+pub async fn site_task(
+    id: usize,
+    stack: &'static Stack<WifiDevice<'static, WifiApDevice>>,
+    app: &'static picoserve::Router<AppRouter, GlobalState>,
+    config: &'static picoserve::Config<Duration>,
+    state: GlobalState,
+) -> ! {
+    let port = 80;
+    let mut tcp_rx_buffer = [0; 1024];
+    let mut tcp_tx_buffer = [0; 1024];
+    let mut http_buffer = [0; 2048];
+
+    picoserve::listen_and_serve_with_state(
+        id,
+        app,
+        config,
+        stack,
+        port,
+        &mut tcp_rx_buffer,
+        &mut tcp_tx_buffer,
+        &mut http_buffer,
+        &state,
+    )
+    .await
+}
+
+/// Serves the [`login_router`] on the station interface, so teeny can be
+/// logged in to from the local network.
+#[task(pool_size = WEB_TASK_POOL_SIZE)]
+pub async fn sta_site_task(
+    id: usize,
+    stack: &'static StaStack,
+    app: &'static picoserve::Router<LoginRouter, GlobalState>,
+    config: &'static picoserve::Config<Duration>,
+    state: GlobalState,
+) -> ! {
+    let port = 80;
+    let mut tcp_rx_buffer = [0; 1024];
+    let mut tcp_tx_buffer = [0; 1024];
+    let mut http_buffer = [0; 2048];
+
+    picoserve::listen_and_serve_with_state(
+        id,
+        app,
+        config,
+        stack,
+        port,
+        &mut tcp_rx_buffer,
+        &mut tcp_tx_buffer,
+        &mut http_buffer,
+        &state,
+    )
+    .await
+}
@@ -0,0 +1,169 @@
+use embassy_futures::select::select;
+use embedded_nal_async::{Dns, TcpConnect};
+use reqwless::client::{HttpClient, TlsConfig, TlsVerify};
+
+use crate::{
+    net::{StaClient, StaStack, TLS_BUFFER_LEN},
+    prelude::*,
+    spotify::{Spotify, TeenySpotifyError},
+};
+
+pub const TRACK_LEN: usize = 128;
+pub const ARTIST_LEN: usize = 64;
+pub const ALBUM_LEN: usize = 128;
+/// Spotify ids are 22 base62 characters.
+pub const ALBUM_ID_LEN: usize = 22;
+/// Long enough for `https://i.scdn.co/image/` and a 40 character image id.
+pub const ART_URL_LEN: usize = 96;
+
+/// Poll often while something is playing, so skips and track changes show up
+/// quickly.
+const PLAYING_INTERVAL: Duration = Duration::from_secs(2);
+/// Nothing changes by itself while paused, so there is only the other devices
+/// to keep up with.
+const PAUSED_INTERVAL: Duration = Duration::from_secs(10);
+/// No active device (or an error) - check back occasionally.
+const IDLE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// What is currently playing, as of [`PlaybackState::fetched_at`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlaybackState {
+    pub track: String<TRACK_LEN>,
+    /// The first (main) artist of the track.
+    pub artist: String<ARTIST_LEN>,
+    pub album: String<ALBUM_LEN>,
+    pub album_id: String<ALBUM_ID_LEN>,
+    /// The smallest of the album's images, or empty if it has none (e.g. for
+    /// local files).
+    pub art_url: String<ART_URL_LEN>,
+    pub progress: Duration,
+    pub duration: Duration,
+    pub is_playing: bool,
+    /// The active device's volume in percent, if it has one.
+    pub volume: Option<u8>,
+    pub fetched_at: Instant,
+}
+
+impl PlaybackState {
+    /// How far through the track playback is at `now`, assuming it carried on
+    /// (or stayed paused) since it was fetched.
+    pub fn progress_at(&self, now: Instant) -> Duration {
+        if !self.is_playing {
+            return self.progress;
+        }
+
+        (self.progress + now.saturating_duration_since(self.fetched_at)).min(self.duration)
+    }
+
+    /// How much of the track is left at `now`.
+    pub fn remaining_at(&self, now: Instant) -> Duration {
+        self.duration
+            .checked_sub(self.progress_at(now))
+            .unwrap_or_default()
+    }
+}
+
+/// The latest [`PlaybackState`], or `None` when nothing is playing on any
+/// device.
+pub static PLAYBACK_CHANNEL: PubSubChannel<
+    CriticalSectionRawMutex,
+    Option<PlaybackState>,
+    1,
+    4,
+    1,
+> = PubSubChannel::new();
+
+static POLL_NOW_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Makes [`poll_playback`] poll straight away - e.g. after sending a command
+/// which changes what is playing.
+pub fn poll_now() {
+    POLL_NOW_SIGNAL.signal(());
+}
+
+/// How long to wait before polling again after seeing `state`.
+fn poll_interval(state: &Option<PlaybackState>) -> Duration {
+    match state {
+        Some(state) if state.is_playing => {
+            // poll again right after the track ends, so the next one shows up on time
+            let remaining = state
+                .duration
+                .checked_sub(state.progress)
+                .unwrap_or_default();
+
+            PLAYING_INTERVAL.min(remaining + Duration::from_millis(500))
+        }
+        Some(_) => PAUSED_INTERVAL,
+        None => IDLE_INTERVAL,
+    }
+}
+
+/// Polls spotify forever, publishing every change on [`PLAYBACK_CHANNEL`].
+pub async fn poll_playback<T, D>(spotify: &mut Spotify<'_, '_, T, D>) -> !
+where
+    T: TcpConnect,
+    D: Dns,
+{
+    let publisher = PLAYBACK_CHANNEL.publisher().unwrap();
+
+    let mut last_state: Option<PlaybackState> = None;
+
+    loop {
+        let interval = match spotify.playback_state().await {
+            Ok(state) => {
+                let interval = poll_interval(&state);
+
+                // progress always changes while playing, so only the rest - and a seek,
+                // playing or paused - decides whether anyone needs to hear about it
+                let changed = match (&state, &last_state) {
+                    (Some(new), Some(old)) => {
+                        new.track != old.track
+                            || new.is_playing != old.is_playing
+                            || new.volume != old.volume
+                            || new
+                                .progress
+                                .as_secs()
+                                .abs_diff(old.progress_at(new.fetched_at).as_secs())
+                                > 2
+                    }
+                    (None, None) => false,
+                    _ => true,
+                };
+
+                if changed {
+                    debug!("Playback changed: {state:?}");
+                    publisher.publish_immediate(state.clone());
+                }
+
+                last_state = state;
+
+                interval
+            }
+            Err(TeenySpotifyError::RateLimited { retry_after }) => retry_after,
+            Err(e) => {
+                warn!("Failed to get playback state: {e:?}");
+                IDLE_INTERVAL
+            }
+        };
+
+        select(Timer::after(interval), POLL_NOW_SIGNAL.wait()).await;
+    }
+}
+
+/// Keeps [`PLAYBACK_CHANNEL`] up to date over the station interface.
+#[task]
+pub async fn playback_task(stack: &'static StaStack, mut rng: Rng) {
+    let client = StaClient::new(stack);
+
+    let mut rx_buf = [0; TLS_BUFFER_LEN];
+    let mut tx_buf = [0; TLS_BUFFER_LEN];
+
+    let (tcp_client, dns_socket) = client.ready().await;
+
+    let seed = ((rng.random() as u64) << u32::BITS) + rng.random() as u64;
+    let tls = TlsConfig::new(seed, &mut rx_buf, &mut tx_buf, TlsVerify::None);
+
+    let mut http = HttpClient::new_with_tls(&tcp_client, &dns_socket, tls);
+
+    poll_playback(&mut Spotify::new(&mut http)).await
+}
@@ -0,0 +1,996 @@
+use embedded_graphics::{pixelcolor::BinaryColor, prelude::*, primitives::Rectangle};
+
+/// The largest version supported - 57x57 modules, which is as big as still
+/// fits on a 64 pixel tall display.
+pub const MAX_VERSION: u8 = 10;
+
+/// The width of the light border around the code, in modules. The spec asks
+/// for 4, but 2 is plenty for phone cameras and leaves more room on a tiny
+/// display.
+pub const QUIET_ZONE: u32 = 2;
+
+const MAX_SIZE: usize = MAX_VERSION as usize * 4 + 17;
+const MAX_MODULES: usize = (MAX_SIZE * MAX_SIZE).div_ceil(8);
+
+/// Every codeword (data and error correction) of the largest version.
+const MAX_CODEWORDS: usize = raw_data_modules(MAX_VERSION) / 8;
+const MAX_BLOCKS: usize = 8;
+/// The longest a single block gets (version 9-L), plus the padding byte short
+/// blocks get while interleaving.
+const MAX_BLOCK_LEN: usize = 147;
+const MAX_ECC_LEN: usize = 30;
+
+const MODE_BYTE: u32 = 0b0100;
+
+/// Error correction codewords per block, by level then version.
+const ECC_CODEWORDS_PER_BLOCK: [[u8; MAX_VERSION as usize]; 4] = [
+    [7, 10, 15, 20, 26, 18, 20, 24, 30, 18],
+    [10, 16, 26, 18, 24, 16, 18, 22, 22, 26],
+    [13, 22, 18, 26, 18, 24, 18, 22, 20, 24],
+    [17, 28, 22, 16, 22, 28, 26, 26, 24, 28],
+];
+
+/// Error correction blocks, by level then version.
+const ECC_BLOCKS: [[u8; MAX_VERSION as usize]; 4] = [
+    [1, 1, 1, 1, 1, 2, 2, 2, 2, 4],
+    [1, 1, 1, 2, 2, 4, 4, 4, 5, 5],
+    [1, 1, 2, 2, 4, 4, 6, 6, 8, 8],
+    [1, 1, 2, 4, 4, 4, 5, 6, 8, 8],
+];
+
+// penalty weights used to pick a mask
+const PENALTY_N1: u32 = 3;
+const PENALTY_N2: u32 = 3;
+const PENALTY_N3: u32 = 40;
+const PENALTY_N4: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TeenyQrError {
+    /// The data doesn't fit in even the largest supported version.
+    DataTooLong,
+    /// The smallest code which fits the data doesn't fit in the area.
+    AreaTooSmall,
+}
+
+/// How much of the code can be damaged and still read back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum EccLevel {
+    /// ~7%
+    Low,
+    /// ~15%
+    Medium,
+    /// ~25%
+    Quartile,
+    /// ~30%
+    High,
+}
+
+impl EccLevel {
+    const ALL: [Self; 4] = [Self::Low, Self::Medium, Self::Quartile, Self::High];
+
+    const fn format_bits(self) -> u32 {
+        match self {
+            Self::Low => 1,
+            Self::Medium => 0,
+            Self::Quartile => 3,
+            Self::High => 2,
+        }
+    }
+}
+
+/// The number of modules left for data and error correction once all the
+/// fixed patterns are drawn.
+const fn raw_data_modules(version: u8) -> usize {
+    let version = version as usize;
+    let mut modules = (16 * version + 128) * version + 64;
+
+    if version >= 2 {
+        let alignments = version / 7 + 2;
+        modules -= (25 * alignments - 10) * alignments - 55;
+
+        if version >= 7 {
+            modules -= 36;
+        }
+    }
+
+    modules
+}
+
+fn ecc_codewords_per_block(version: u8, ecc: EccLevel) -> usize {
+    ECC_CODEWORDS_PER_BLOCK[ecc as usize][version as usize - 1] as usize
+}
+
+fn ecc_blocks(version: u8, ecc: EccLevel) -> usize {
+    ECC_BLOCKS[ecc as usize][version as usize - 1] as usize
+}
+
+/// How many bytes of data fit in `version` at `ecc`.
+fn data_codewords(version: u8, ecc: EccLevel) -> usize {
+    raw_data_modules(version) / 8 - ecc_codewords_per_block(version, ecc) * ecc_blocks(version, ecc)
+}
+
+fn char_count_bits(version: u8) -> usize {
+    if version < 10 {
+        8
+    } else {
+        16
+    }
+}
+
+/// Whether `len` bytes fit in `version` at `ecc`.
+fn fits(len: usize, version: u8, ecc: EccLevel) -> bool {
+    4 + char_count_bits(version) + len * 8 <= data_codewords(version, ecc) * 8
+}
+
+/// The smallest version `len` bytes fit in at `ecc`.
+fn min_version(len: usize, ecc: EccLevel) -> Option<u8> {
+    (1..=MAX_VERSION).find(|&version| fits(len, version, ecc))
+}
+
+fn gf_multiply(x: u8, y: u8) -> u8 {
+    let mut z: u8 = 0;
+
+    for i in (0..8).rev() {
+        z = (z << 1) ^ ((z >> 7) * 0x1d);
+        z ^= ((y >> i) & 1) * x;
+    }
+
+    z
+}
+
+/// The Reed-Solomon generator polynomial of the given degree, highest term
+/// first (without the leading 1).
+fn rs_divisor(degree: usize) -> [u8; MAX_ECC_LEN] {
+    let mut divisor = [0; MAX_ECC_LEN];
+    divisor[degree - 1] = 1;
+
+    let mut root = 1;
+
+    for _ in 0..degree {
+        for j in 0..degree {
+            divisor[j] = gf_multiply(divisor[j], root);
+
+            if j + 1 < degree {
+                divisor[j] ^= divisor[j + 1];
+            }
+        }
+
+        root = gf_multiply(root, 0x02);
+    }
+
+    divisor
+}
+
+fn rs_remainder(data: &[u8], divisor: &[u8], remainder: &mut [u8]) {
+    remainder.fill(0);
+
+    for &byte in data {
+        let factor = byte ^ remainder[0];
+
+        remainder.copy_within(1.., 0);
+        *remainder.last_mut().expect("ecc is never empty") = 0;
+
+        for (remainder, &divisor) in remainder.iter_mut().zip(divisor) {
+            *remainder ^= gf_multiply(divisor, factor);
+        }
+    }
+}
+
+/// Appends bits to a byte buffer, most significant first.
+struct BitWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl BitWriter<'_> {
+    fn push(&mut self, value: u32, bits: usize) {
+        for i in (0..bits).rev() {
+            if (value >> i) & 1 != 0 {
+                self.buf[self.len / 8] |= 0x80 >> (self.len % 8);
+            }
+
+            self.len += 1;
+        }
+    }
+}
+
+/// A square grid of bits.
+#[derive(Clone)]
+struct Grid {
+    size: usize,
+    bits: [u8; MAX_MODULES],
+}
+
+impl Grid {
+    const fn new(size: usize) -> Self {
+        Self {
+            size,
+            bits: [0; MAX_MODULES],
+        }
+    }
+
+    fn get(&self, x: usize, y: usize) -> bool {
+        let index = y * self.size + x;
+        self.bits[index / 8] & (1 << (index % 8)) != 0
+    }
+
+    fn set(&mut self, x: usize, y: usize, value: bool) {
+        let index = y * self.size + x;
+
+        if value {
+            self.bits[index / 8] |= 1 << (index % 8);
+        } else {
+            self.bits[index / 8] &= !(1 << (index % 8));
+        }
+    }
+}
+
+/// A QR code, in byte mode.
+#[derive(Clone)]
+pub struct QrCode {
+    version: u8,
+    ecc: EccLevel,
+    mask: u8,
+    modules: Grid,
+}
+
+impl QrCode {
+    /// Encodes `data` in the smallest version which fits, with as much error
+    /// correction as that version has room for.
+    pub fn new(data: &[u8]) -> Result<Self, TeenyQrError> {
+        let version = min_version(data.len(), EccLevel::Low).ok_or(TeenyQrError::DataTooLong)?;
+
+        Ok(Self::with_version(
+            data,
+            version,
+            Self::best_ecc(data.len(), version),
+            None,
+        ))
+    }
+
+    /// Encodes `data` as [`QrCode::new`] does, as long as the result (and its
+    /// quiet zone) fits in `area` with at least one pixel per module.
+    pub fn fit(data: &[u8], area: Size) -> Result<Self, TeenyQrError> {
+        let version = min_version(data.len(), EccLevel::Low).ok_or(TeenyQrError::DataTooLong)?;
+
+        if Self::size_of(version) + 2 * QUIET_ZONE > area.width.min(area.height) {
+            return Err(TeenyQrError::AreaTooSmall);
+        }
+
+        Ok(Self::with_version(
+            data,
+            version,
+            Self::best_ecc(data.len(), version),
+            None,
+        ))
+    }
+
+    /// Encodes `data` in exactly `version` at `ecc` - picking the best mask
+    /// unless one is given.
+    ///
+    /// # Panics
+    ///
+    /// If `data` doesn't fit, or `mask` isn't below 8.
+    pub fn with_version(data: &[u8], version: u8, ecc: EccLevel, mask: Option<u8>) -> Self {
+        assert!(
+            fits(data.len(), version, ecc),
+            "data should fit in the version"
+        );
+
+        let mut code = Self {
+            version,
+            ecc,
+            mask: 0,
+            modules: Grid::new(Self::size_of(version) as usize),
+        };
+
+        let mut functions = Grid::new(code.size() as usize);
+        code.draw_function_patterns(&mut functions);
+
+        let mut codewords = [0; MAX_CODEWORDS];
+        let codewords = code.codewords(data, &mut codewords);
+        code.draw_codewords(codewords, &functions);
+
+        let mask = mask.unwrap_or_else(|| {
+            (0..8)
+                .min_by_key(|&mask| {
+                    code.apply_mask(mask, &functions);
+                    code.draw_format_bits(mask, None);
+                    let penalty = code.penalty();
+                    // masks are their own inverse
+                    code.apply_mask(mask, &functions);
+                    penalty
+                })
+                .expect("there are 8 masks")
+        });
+
+        assert!(mask < 8, "there are only 8 masks");
+
+        code.mask = mask;
+        code.apply_mask(mask, &functions);
+        code.draw_format_bits(mask, None);
+
+        code
+    }
+
+    /// The highest error correction level which still fits `len` bytes in
+    /// `version`.
+    fn best_ecc(len: usize, version: u8) -> EccLevel {
+        EccLevel::ALL
+            .into_iter()
+            .rev()
+            .find(|&ecc| fits(len, version, ecc))
+            .unwrap_or(EccLevel::Low)
+    }
+
+    const fn size_of(version: u8) -> u32 {
+        version as u32 * 4 + 17
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn ecc(&self) -> EccLevel {
+        self.ecc
+    }
+
+    pub fn mask(&self) -> u8 {
+        self.mask
+    }
+
+    /// The width (and height) in modules, without the quiet zone.
+    pub fn size(&self) -> u32 {
+        Self::size_of(self.version)
+    }
+
+    /// Whether the module at `x`, `y` is dark - anything outside the code is
+    /// light.
+    pub fn get(&self, x: i32, y: i32) -> bool {
+        let size = self.size() as i32;
+
+        (0..size).contains(&x) && (0..size).contains(&y) && self.modules.get(x as usize, y as usize)
+    }
+
+    fn set_function(&mut self, functions: &mut Grid, x: usize, y: usize, dark: bool) {
+        self.modules.set(x, y, dark);
+        functions.set(x, y, true);
+    }
+
+    fn alignment_positions(&self) -> ([usize; 7], usize) {
+        let mut positions = [0; 7];
+
+        if self.version == 1 {
+            return (positions, 0);
+        }
+
+        let count = self.version as usize / 7 + 2;
+        let step = (self.version as usize * 8 + count * 3 + 5) / (count * 4 - 4) * 2;
+
+        positions[0] = 6;
+
+        for (i, position) in positions[1..count].iter_mut().rev().enumerate() {
+            *position = self.size() as usize - 7 - i * step;
+        }
+
+        (positions, count)
+    }
+
+    fn draw_function_patterns(&mut self, functions: &mut Grid) {
+        let size = self.size() as usize;
+
+        // timing patterns
+        for i in 0..size {
+            self.set_function(functions, 6, i, i % 2 == 0);
+            self.set_function(functions, i, 6, i % 2 == 0);
+        }
+
+        // finder patterns, with their separators
+        for (x, y) in [(3, 3), (size - 4, 3), (3, size - 4)] {
+            for dy in -4..=4i32 {
+                for dx in -4..=4i32 {
+                    let (xx, yy) = (x as i32 + dx, y as i32 + dy);
+
+                    if (0..size as i32).contains(&xx) && (0..size as i32).contains(&yy) {
+                        let distance = dx.abs().max(dy.abs());
+                        self.set_function(
+                            functions,
+                            xx as usize,
+                            yy as usize,
+                            distance != 2 && distance != 4,
+                        );
+                    }
+                }
+            }
+        }
+
+        // alignment patterns, except where they would overlap the finders
+        let (positions, count) = self.alignment_positions();
+
+        for i in 0..count {
+            for j in 0..count {
+                if (i == 0 && (j == 0 || j == count - 1)) || (i == count - 1 && j == 0) {
+                    continue;
+                }
+
+                for dy in -2..=2i32 {
+                    for dx in -2..=2i32 {
+                        self.set_function(
+                            functions,
+                            (positions[i] as i32 + dx) as usize,
+                            (positions[j] as i32 + dy) as usize,
+                            dx.abs().max(dy.abs()) != 1,
+                        );
+                    }
+                }
+            }
+        }
+
+        // reserve the format bits - the real ones are drawn with the mask
+        self.draw_format_bits(0, Some(functions));
+
+        if self.version >= 7 {
+            let mut remainder = self.version as u32;
+
+            for _ in 0..12 {
+                remainder = (remainder << 1) ^ ((remainder >> 11) * 0x1f25);
+            }
+
+            let bits = (self.version as u32) << 12 | remainder;
+
+            for i in 0..18 {
+                let dark = (bits >> i) & 1 != 0;
+                let a = size - 11 + i % 3;
+                let b = i / 3;
+
+                self.set_function(functions, a, b, dark);
+                self.set_function(functions, b, a, dark);
+            }
+        }
+    }
+
+    /// Draws both copies of the format bits (and the dark module), marking
+    /// them as function modules if `functions` is given.
+    fn draw_format_bits(&mut self, mask: u8, mut functions: Option<&mut Grid>) {
+        let data = self.ecc.format_bits() << 3 | mask as u32;
+        let mut remainder = data;
+
+        for _ in 0..10 {
+            remainder = (remainder << 1) ^ ((remainder >> 9) * 0x537);
+        }
+
+        let bits = (data << 10 | remainder) ^ 0x5412;
+        let bit = |i: usize| (bits >> i) & 1 != 0;
+
+        let size = self.size() as usize;
+
+        let mut set = |x: usize, y: usize, dark: bool| match functions.as_deref_mut() {
+            Some(functions) => self.set_function(functions, x, y, dark),
+            None => self.modules.set(x, y, dark),
+        };
+
+        // around the top left finder
+        for i in 0..=5 {
+            set(8, i, bit(i));
+        }
+        set(8, 7, bit(6));
+        set(8, 8, bit(7));
+        set(7, 8, bit(8));
+        for i in 9..15 {
+            set(14 - i, 8, bit(i));
+        }
+
+        // split between the other two finders
+        for i in 0..8 {
+            set(size - 1 - i, 8, bit(i));
+        }
+        for i in 8..15 {
+            set(8, size - 15 + i, bit(i));
+        }
+        set(8, size - 8, true);
+    }
+
+    /// The data with its padding and error correction, split into blocks and
+    /// interleaved.
+    fn codewords<'a>(&self, data: &[u8], buf: &'a mut [u8; MAX_CODEWORDS]) -> &'a [u8] {
+        let data_len = data_codewords(self.version, self.ecc);
+
+        let mut padded = [0; MAX_CODEWORDS];
+        let mut writer = BitWriter {
+            buf: &mut padded,
+            len: 0,
+        };
+
+        writer.push(MODE_BYTE, 4);
+        writer.push(data.len() as u32, char_count_bits(self.version));
+        for &byte in data {
+            writer.push(byte as u32, 8);
+        }
+
+        let capacity = data_len * 8;
+        writer.push(0, (capacity - writer.len).min(4));
+        writer.push(0, (8 - writer.len % 8) % 8);
+
+        for pad in [0xec, 0x11].into_iter().cycle() {
+            if writer.len >= capacity {
+                break;
+            }
+            writer.push(pad, 8);
+        }
+
+        let blocks = ecc_blocks(self.version, self.ecc);
+        let ecc_len = ecc_codewords_per_block(self.version, self.ecc);
+        let raw_len = raw_data_modules(self.version) / 8;
+        let short_blocks = blocks - raw_len % blocks;
+        let short_block_len = raw_len / blocks;
+        let short_data_len = short_block_len - ecc_len;
+
+        let divisor = rs_divisor(ecc_len);
+
+        // every block padded to the same length, so they can be read column by column
+        let mut split = [[0; MAX_BLOCK_LEN]; MAX_BLOCKS];
+        let mut offset = 0;
+
+        for (i, block) in split[..blocks].iter_mut().enumerate() {
+            let len = short_data_len + usize::from(i >= short_blocks);
+            let block_data = &padded[offset..offset + len];
+            offset += len;
+
+            block[..len].copy_from_slice(block_data);
+            rs_remainder(
+                block_data,
+                &divisor[..ecc_len],
+                &mut block[short_block_len + 1 - ecc_len..short_block_len + 1],
+            );
+        }
+
+        let mut len = 0;
+
+        for i in 0..=short_block_len {
+            for (j, block) in split[..blocks].iter().enumerate() {
+                // short blocks have one less data codeword
+                if i == short_data_len && j < short_blocks {
+                    continue;
+                }
+
+                buf[len] = block[i];
+                len += 1;
+            }
+        }
+
+        &buf[..len]
+    }
+
+    /// Fills every non-function module with `codewords`, in the zigzag order
+    /// from the bottom right.
+    fn draw_codewords(&mut self, codewords: &[u8], functions: &Grid) {
+        let size = self.size() as usize;
+        let mut i = 0;
+        let mut right = size - 1;
+
+        loop {
+            // skip the vertical timing pattern
+            if right == 6 {
+                right = 5;
+            }
+
+            for vertical in 0..size {
+                for j in 0..2 {
+                    let x = right - j;
+                    let upward = (right + 1) & 2 == 0;
+                    let y = if upward {
+                        size - 1 - vertical
+                    } else {
+                        vertical
+                    };
+
+                    if !functions.get(x, y) && i < codewords.len() * 8 {
+                        self.modules
+                            .set(x, y, (codewords[i / 8] >> (7 - i % 8)) & 1 != 0);
+                        i += 1;
+                    }
+                }
+            }
+
+            if right < 2 {
+                break;
+            }
+            right -= 2;
+        }
+    }
+
+    fn apply_mask(&mut self, mask: u8, functions: &Grid) {
+        let size = self.size() as usize;
+
+        for y in 0..size {
+            for x in 0..size {
+                let invert = match mask {
+                    0 => (x + y) % 2 == 0,
+                    1 => y % 2 == 0,
+                    2 => x % 3 == 0,
+                    3 => (x + y) % 3 == 0,
+                    4 => (x / 3 + y / 2) % 2 == 0,
+                    5 => x * y % 2 + x * y % 3 == 0,
+                    6 => (x * y % 2 + x * y % 3) % 2 == 0,
+                    _ => ((x + y) % 2 + x * y % 3) % 2 == 0,
+                };
+
+                if invert && !functions.get(x, y) {
+                    self.modules.set(x, y, !self.modules.get(x, y));
+                }
+            }
+        }
+    }
+
+    /// How hard the code is to read - long runs, blocks of one colour,
+    /// finder-like patterns and an uneven balance all count against it.
+    fn penalty(&self) -> u32 {
+        let size = self.size() as usize;
+        let mut penalty = 0;
+
+        for transpose in [false, true] {
+            for a in 0..size {
+                let mut run_dark = false;
+                let mut run_len = 0;
+                let mut history = [0; 7];
+
+                for b in 0..size {
+                    let dark = if transpose {
+                        self.modules.get(a, b)
+                    } else {
+                        self.modules.get(b, a)
+                    };
+
+                    if dark == run_dark {
+                        run_len += 1;
+
+                        if run_len == 5 {
+                            penalty += PENALTY_N1;
+                        } else if run_len > 5 {
+                            penalty += 1;
+                        }
+                    } else {
+                        self.add_run(&mut history, run_len);
+
+                        if !run_dark {
+                            penalty += Self::count_finder_patterns(&history) * PENALTY_N3;
+                        }
+
+                        run_dark = dark;
+                        run_len = 1;
+                    }
+                }
+
+                // finish with the light border past the edge
+                if run_dark {
+                    self.add_run(&mut history, run_len);
+                    run_len = 0;
+                }
+                self.add_run(&mut history, run_len + size);
+
+                penalty += Self::count_finder_patterns(&history) * PENALTY_N3;
+            }
+        }
+
+        for y in 0..size - 1 {
+            for x in 0..size - 1 {
+                let dark = self.modules.get(x, y);
+
+                if dark == self.modules.get(x + 1, y)
+                    && dark == self.modules.get(x, y + 1)
+                    && dark == self.modules.get(x + 1, y + 1)
+                {
+                    penalty += PENALTY_N2;
+                }
+            }
+        }
+
+        let total = size * size;
+        let dark = (0..size)
+            .flat_map(|y| (0..size).map(move |x| (x, y)))
+            .filter(|&(x, y)| self.modules.get(x, y))
+            .count();
+
+        // each 5% away from an even split
+        let k = (dark * 20).abs_diff(total * 10).div_ceil(total) - 1;
+        penalty += k as u32 * PENALTY_N4;
+
+        penalty
+    }
+
+    /// Pushes a run length onto the (newest first) history of runs.
+    fn add_run(&self, history: &mut [usize; 7], mut run_len: usize) {
+        // the first run carries on into the light border
+        if history[0] == 0 {
+            run_len += self.size() as usize;
+        }
+
+        history.copy_within(0..6, 1);
+        history[0] = run_len;
+    }
+
+    /// How many 1:1:3:1:1 finder-like patterns (with 4 light modules on one
+    /// side) end at the newest light run.
+    fn count_finder_patterns(history: &[usize; 7]) -> u32 {
+        let n = history[1];
+        let core =
+            n > 0 && history[2] == n && history[3] == n * 3 && history[4] == n && history[5] == n;
+
+        u32::from(core && history[0] >= n * 4 && history[6] >= n)
+            + u32::from(core && history[6] >= n * 4 && history[0] >= n)
+    }
+
+    /// Draws the code (with its quiet zone) as big as it fits, centered in
+    /// `area`. Dark modules are drawn [`BinaryColor::Off`], so it reads right
+    /// on a display which lights up `On` pixels.
+    pub fn draw<D>(&self, target: &mut D, area: Rectangle) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = BinaryColor>,
+    {
+        let modules = self.size() + 2 * QUIET_ZONE;
+        let scale = (area.size.width.min(area.size.height) / modules).max(1);
+        let side = modules * scale;
+
+        let top_left = area.top_left
+            + Point::new(
+                (area.size.width.saturating_sub(side) / 2) as i32,
+                (area.size.height.saturating_sub(side) / 2) as i32,
+            );
+
+        let quiet = QUIET_ZONE as i32;
+        let scale = scale as i32;
+
+        target.fill_contiguous(
+            &Rectangle::new(top_left, Size::new_equal(side)),
+            (0..side as i32).flat_map(|y| {
+                (0..side as i32).map(move |x| {
+                    if self.get(x / scale - quiet, y / scale - quiet) {
+                        BinaryColor::Off
+                    } else {
+                        BinaryColor::On
+                    }
+                })
+            }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_graphics::{geometry::OriginDimensions, Pixel};
+
+    use super::*;
+
+    /// Byte mode capacities at [`EccLevel::Low`], by version.
+    const CAPACITY: [usize; MAX_VERSION as usize] = [17, 32, 53, 78, 106, 134, 154, 192, 230, 271];
+
+    /// A monochrome framebuffer, lit everywhere nothing is drawn.
+    struct Canvas {
+        size: Size,
+        pixels: std::vec::Vec<BinaryColor>,
+    }
+
+    impl Canvas {
+        fn new(size: Size) -> Self {
+            Self {
+                size,
+                pixels: std::vec![BinaryColor::On; (size.width * size.height) as usize],
+            }
+        }
+
+        fn pixel(&self, x: u32, y: u32) -> BinaryColor {
+            self.pixels[(y * self.size.width + x) as usize]
+        }
+    }
+
+    impl OriginDimensions for Canvas {
+        fn size(&self) -> Size {
+            self.size
+        }
+    }
+
+    impl DrawTarget for Canvas {
+        type Color = BinaryColor;
+        type Error = core::convert::Infallible;
+
+        fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+        where
+            I: IntoIterator<Item = Pixel<Self::Color>>,
+        {
+            for Pixel(point, color) in pixels {
+                if let Ok((x, y)) = <(u32, u32)>::try_from(point) {
+                    if x < self.size.width && y < self.size.height {
+                        self.pixels[(y * self.size.width + x) as usize] = color;
+                    }
+                }
+            }
+
+            Ok(())
+        }
+    }
+
+    /// Draws `code` into `area` of a `canvas` sized canvas, and reads it back
+    /// as a phone would - returning the version, ECC format bits, mask and
+    /// contents it decodes to.
+    fn round_trip(
+        code: &QrCode,
+        canvas: Size,
+        area: Rectangle,
+    ) -> (usize, u16, u16, std::string::String) {
+        let mut canvas = Canvas::new(canvas);
+        code.draw(&mut canvas, area).unwrap();
+
+        // dark modules are drawn off
+        let mut image = rqrr::PreparedImage::prepare_from_greyscale(
+            canvas.size.width as usize,
+            canvas.size.height as usize,
+            |x, y| match canvas.pixel(x as u32, y as u32) {
+                BinaryColor::Off => 0,
+                BinaryColor::On => 255,
+            },
+        );
+
+        let grids = image.detect_grids();
+        assert_eq!(grids.len(), 1, "there should be exactly one code");
+
+        let (meta, contents) = grids[0].decode().expect("the code should decode");
+
+        (meta.version.0, meta.ecc_level, meta.mask, contents)
+    }
+
+    /// `len` bytes of printable ASCII, different for every length.
+    fn data(len: usize) -> std::vec::Vec<u8> {
+        (0..len)
+            .map(|i| b'!' + ((i * 7 + len) % 94) as u8)
+            .collect()
+    }
+
+    #[test]
+    fn reads_back_every_version_level_and_mask() {
+        for version in 1..=MAX_VERSION {
+            for ecc in EccLevel::ALL {
+                let len = (0..)
+                    .take_while(|&len| fits(len, version, ecc))
+                    .last()
+                    .unwrap();
+                let data = data(len);
+
+                // every mask comes up at every level, over the versions
+                for mask in [(version + ecc as u8) % 8, (version + ecc as u8 + 4) % 8] {
+                    let code = QrCode::with_version(&data, version, ecc, Some(mask));
+                    // the decoder needs a few pixels a module
+                    let side = (code.size() + 2 * QUIET_ZONE) * 3;
+
+                    let (read_version, read_ecc, read_mask, contents) = round_trip(
+                        &code,
+                        Size::new_equal(side + 8),
+                        Rectangle::new(Point::new_equal(4), Size::new_equal(side)),
+                    );
+
+                    assert_eq!(read_version, version as usize, "{version}-{ecc:?}");
+                    assert_eq!(read_ecc, ecc.format_bits() as u16, "{version}-{ecc:?}");
+                    assert_eq!(read_mask, mask as u16, "{version}-{ecc:?} mask {mask}");
+                    assert_eq!(contents.as_bytes(), data, "{version}-{ecc:?} mask {mask}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn draws_scaled_and_centered() {
+        let data = b"WIFI:S:teeny-setup;T:nopass;;";
+        let code = QrCode::new(data).unwrap();
+        let modules = code.size() + 2 * QUIET_ZONE;
+
+        // 3 pixels a module fit, with some left over on every side
+        let area = Rectangle::new(Point::new(5, 1), Size::new(128, modules * 3 + 2));
+        let (_, _, _, contents) = round_trip(&code, Size::new(140, 100), area);
+        assert_eq!(contents.as_bytes(), data);
+
+        let mut canvas = Canvas::new(Size::new(140, 100));
+        code.draw(&mut canvas, area).unwrap();
+
+        let left = 5 + (128 - modules * 3) / 2;
+        let top = 1 + 1;
+
+        // the top left finder, just inside the quiet zone
+        let finder = QUIET_ZONE * 3;
+        assert_eq!(canvas.pixel(left + finder, top + finder), BinaryColor::Off);
+        assert_eq!(
+            canvas.pixel(left + finder - 1, top + finder),
+            BinaryColor::On
+        );
+        assert_eq!(
+            canvas.pixel(left + finder, top + finder - 1),
+            BinaryColor::On
+        );
+    }
+
+    #[test]
+    fn fit_picks_the_smallest_version() {
+        let area = Size::new(128, 64);
+
+        for (version, &capacity) in (1..).zip(CAPACITY.iter()) {
+            assert_eq!(
+                QrCode::fit(&data(capacity), area).unwrap().version(),
+                version
+            );
+
+            if version < MAX_VERSION {
+                assert_eq!(
+                    QrCode::fit(&data(capacity + 1), area).unwrap().version(),
+                    version + 1
+                );
+            }
+        }
+
+        assert_eq!(
+            QrCode::fit(&data(CAPACITY[MAX_VERSION as usize - 1] + 1), area).err(),
+            Some(TeenyQrError::DataTooLong)
+        );
+    }
+
+    #[test]
+    fn fit_uses_the_spare_room_for_error_correction() {
+        let area = Size::new(128, 64);
+
+        // version 1 holds 7, 11, 14 and 17 bytes at H, Q, M and L
+        for (len, ecc) in [
+            (1, EccLevel::High),
+            (7, EccLevel::High),
+            (8, EccLevel::Quartile),
+            (11, EccLevel::Quartile),
+            (12, EccLevel::Medium),
+            (14, EccLevel::Medium),
+            (15, EccLevel::Low),
+            (17, EccLevel::Low),
+        ] {
+            let code = QrCode::fit(&data(len), area).unwrap();
+
+            assert_eq!(code.version(), 1, "{len} bytes");
+            assert_eq!(code.ecc(), ecc, "{len} bytes");
+
+            let side = code.size() + 2 * QUIET_ZONE;
+            let (_, read_ecc, _, contents) =
+                round_trip(&code, area, Rectangle::new(Point::zero(), area));
+
+            assert_eq!(read_ecc, ecc.format_bits() as u16, "{len} bytes");
+            assert_eq!(contents.as_bytes(), data(len), "{len} bytes at {side}");
+        }
+
+        // just past version 1 at L, version 2 has room for Q
+        let code = QrCode::fit(&data(18), area).unwrap();
+        assert_eq!((code.version(), code.ecc()), (2, EccLevel::Quartile));
+    }
+
+    #[test]
+    fn fit_needs_room_for_the_quiet_zone() {
+        // version 1 is 21 modules, plus the quiet zone on both sides
+        let side = 21 + 2 * QUIET_ZONE;
+        let data = data(17);
+
+        assert!(QrCode::fit(&data, Size::new_equal(side)).is_ok());
+        assert!(QrCode::fit(&data, Size::new(side, 64)).is_ok());
+
+        for area in [
+            Size::new_equal(side - 1),
+            Size::new(side - 1, 64),
+            Size::new(128, side - 1),
+        ] {
+            assert_eq!(
+                QrCode::fit(&data, area).err(),
+                Some(TeenyQrError::AreaTooSmall)
+            );
+        }
+
+        // the largest version (and its quiet zone) is 61 modules, which still
+        // fits on a 64 pixel tall display
+        let data = self::data(CAPACITY[MAX_VERSION as usize - 1]);
+        let area = Size::new(128, 64);
+        assert_eq!(QrCode::fit(&data, area).unwrap().version(), MAX_VERSION);
+        assert_eq!(
+            QrCode::fit(&data, Size::new(128, 60)).err(),
+            Some(TeenyQrError::AreaTooSmall)
+        );
+    }
+}
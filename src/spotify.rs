@@ -0,0 +1,470 @@
+use core::fmt::Write;
+
+use embedded_nal_async::{Dns, TcpConnect};
+use reqwless::{
+    client::HttpClient,
+    request::{Method, RequestBuilder},
+    response::Status,
+};
+
+use crate::{
+    auth,
+    json::{read_json, JsonParser, JsonPath, JsonValue, ReadJsonError, TeenyJsonError},
+    playback::{PlaybackState, TRACK_LEN},
+    prelude::*,
+};
+
+pub const SPOTIFY_API_URL: &str = "https://api.spotify.com";
+
+/// Device ids are 40 hex characters.
+pub const DEVICE_ID_LEN: usize = 40;
+pub const DEVICE_NAME_LEN: usize = 32;
+/// The most devices [`Spotify::devices`] keeps - any more are left off.
+pub const MAX_DEVICES: usize = 8;
+
+#[derive(Debug, Clone)]
+pub enum TeenySpotifyError {
+    Http(reqwless::Error),
+    /// 401 - the access token expired or was revoked.
+    Unauthorized,
+    /// 403 - the player endpoints only work for premium accounts.
+    PremiumRequired,
+    /// 404 - there is no device currently playing (or paused) to control.
+    NoActiveDevice,
+    /// 429 - too many requests, try again after `retry_after`.
+    RateLimited {
+        retry_after: Duration,
+    },
+    /// Any other unsuccessful response.
+    Status(Status),
+    Json(TeenyJsonError),
+    /// A request didn't fit in its fixed size buffer.
+    Capacity,
+}
+
+impl From<reqwless::Error> for TeenySpotifyError {
+    fn from(value: reqwless::Error) -> Self {
+        Self::Http(value)
+    }
+}
+
+impl From<ReadJsonError<reqwless::Error>> for TeenySpotifyError {
+    fn from(value: ReadJsonError<reqwless::Error>) -> Self {
+        match value {
+            ReadJsonError::Read(e) => Self::Http(e),
+            ReadJsonError::Json(e) => Self::Json(e),
+        }
+    }
+}
+
+impl From<core::fmt::Error> for TeenySpotifyError {
+    fn from(_: core::fmt::Error) -> Self {
+        Self::Capacity
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepeatState {
+    Track,
+    Context,
+    Off,
+}
+
+impl RepeatState {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Track => "track",
+            Self::Context => "context",
+            Self::Off => "off",
+        }
+    }
+}
+
+/// Something which can play spotify, from `/v1/me/player/devices`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Device {
+    pub id: String<DEVICE_ID_LEN>,
+    pub name: String<DEVICE_NAME_LEN>,
+    pub is_active: bool,
+    /// Restricted devices don't take commands from the web api.
+    pub is_restricted: bool,
+}
+
+pub type Devices = Vec<Device, MAX_DEVICES>;
+
+/// Adds a value from `/v1/me/player/devices` to the device it belongs to.
+fn collect_device(devices: &mut Devices, path: &JsonPath, value: JsonValue<'_>) {
+    if !path.matches("devices.*.*") {
+        return;
+    }
+
+    let Some(index) = path.index(1) else {
+        return;
+    };
+
+    // the first value of each device starts it - past `MAX_DEVICES` they're dropped
+    while devices.len() <= index {
+        if devices.push(Device::default()).is_err() {
+            return;
+        }
+    }
+
+    let device = &mut devices[index];
+
+    if path.matches("devices.*.id") {
+        device.id = value.truncated();
+    } else if path.matches("devices.*.name") {
+        device.name = value.truncated();
+    } else if path.matches("devices.*.is_active") {
+        device.is_active = value.as_bool().unwrap_or_default();
+    } else if path.matches("devices.*.is_restricted") {
+        device.is_restricted = value.as_bool().unwrap_or_default();
+    }
+}
+
+/// Leaves out the devices playback can't be moved to - restricted ones, and
+/// any without an id.
+fn playable(mut devices: Devices) -> Devices {
+    devices.retain(|device| !device.is_restricted && !device.id.is_empty());
+    devices
+}
+
+/// A client for spotify's `/v1/me/player` endpoints, authorised with whatever
+/// [`auth::access_token`] currently returns.
+pub struct Spotify<'a, 'c, T, D>
+where
+    T: TcpConnect + 'c,
+    D: Dns + 'c,
+{
+    client: &'a mut HttpClient<'c, T, D>,
+    api_url: &'static str,
+}
+
+impl<'a, 'c, T, D> Spotify<'a, 'c, T, D>
+where
+    T: TcpConnect + 'c,
+    D: Dns + 'c,
+{
+    pub fn new(client: &'a mut HttpClient<'c, T, D>) -> Self {
+        Self::with_api_url(client, SPOTIFY_API_URL)
+    }
+
+    /// Talks to `api_url` instead of the real spotify web api.
+    pub fn with_api_url(client: &'a mut HttpClient<'c, T, D>, api_url: &'static str) -> Self {
+        Self { client, api_url }
+    }
+
+    pub async fn play(&mut self) -> Result<(), TeenySpotifyError> {
+        self.send(Method::PUT, "/play", None).await
+    }
+
+    pub async fn pause(&mut self) -> Result<(), TeenySpotifyError> {
+        self.send(Method::PUT, "/pause", None).await
+    }
+
+    pub async fn next(&mut self) -> Result<(), TeenySpotifyError> {
+        self.send(Method::POST, "/next", None).await
+    }
+
+    pub async fn previous(&mut self) -> Result<(), TeenySpotifyError> {
+        self.send(Method::POST, "/previous", None).await
+    }
+
+    /// Sets the volume of the active device, clamped to 0-100%.
+    pub async fn set_volume(&mut self, percent: u8) -> Result<(), TeenySpotifyError> {
+        let mut path: String<32> = String::new();
+        write!(path, "/volume?volume_percent={}", percent.min(100))?;
+
+        self.send(Method::PUT, &path, None).await
+    }
+
+    /// Seeks to `position` in the current track.
+    pub async fn seek(&mut self, position: Duration) -> Result<(), TeenySpotifyError> {
+        let mut path: String<40> = String::new();
+        write!(path, "/seek?position_ms={}", position.as_millis())?;
+
+        self.send(Method::PUT, &path, None).await
+    }
+
+    pub async fn shuffle(&mut self, shuffle: bool) -> Result<(), TeenySpotifyError> {
+        let path = if shuffle {
+            "/shuffle?state=true"
+        } else {
+            "/shuffle?state=false"
+        };
+
+        self.send(Method::PUT, path, None).await
+    }
+
+    pub async fn repeat(&mut self, state: RepeatState) -> Result<(), TeenySpotifyError> {
+        let mut path: String<24> = String::new();
+        write!(path, "/repeat?state={}", state.as_str())?;
+
+        self.send(Method::PUT, &path, None).await
+    }
+
+    /// Moves playback to the device with the id `device_id`, starting it if
+    /// `play` is set.
+    pub async fn transfer_playback(
+        &mut self,
+        device_id: &str,
+        play: bool,
+    ) -> Result<(), TeenySpotifyError> {
+        let mut body: String<128> = String::new();
+        write!(body, r#"{{"device_ids":["{device_id}"],"play":{play}}}"#)?;
+
+        self.send(Method::PUT, "", Some(body.as_bytes())).await
+    }
+
+    /// The devices playback can be moved to with
+    /// [`Spotify::transfer_playback`].
+    pub async fn devices(&mut self) -> Result<Devices, TeenySpotifyError> {
+        let mut devices = Devices::new();
+
+        self.request(
+            Method::GET,
+            "/devices",
+            None,
+            Some(&mut |path: &JsonPath, value: JsonValue<'_>| {
+                collect_device(&mut devices, path, value)
+            }),
+        )
+        .await?;
+
+        Ok(playable(devices))
+    }
+
+    /// The state of the active device and whatever it is playing, or `None`
+    /// if nothing is.
+    ///
+    /// This is `/v1/me/player` rather than `/v1/me/player/currently-playing`,
+    /// as only the former includes the device's volume.
+    pub async fn playback_state(&mut self) -> Result<Option<PlaybackState>, TeenySpotifyError> {
+        let mut state = PlaybackState {
+            track: String::new(),
+            artist: String::new(),
+            album: String::new(),
+            album_id: String::new(),
+            art_url: String::new(),
+            progress: Duration::default(),
+            duration: Duration::default(),
+            is_playing: false,
+            volume: None,
+            fetched_at: Instant::now(),
+        };
+
+        let mut has_item = false;
+
+        let has_body = self
+            .request(
+                Method::GET,
+                "",
+                None,
+                Some(&mut |path: &JsonPath, value: JsonValue<'_>| {
+                    if path.matches("is_playing") {
+                        state.is_playing = value.as_bool().unwrap_or_default();
+                    } else if path.matches("progress_ms") {
+                        state.progress = Duration::from_millis(value.as_u64().unwrap_or_default());
+                    } else if path.matches("device.volume_percent") {
+                        state.volume = value.as_u64().map(|volume| volume.min(100) as u8);
+                    } else if path.matches("item.name") {
+                        has_item = true;
+                        state.track = value.truncated();
+                    } else if path.matches("item.duration_ms") {
+                        state.duration = Duration::from_millis(value.as_u64().unwrap_or_default());
+                    } else if path.matches("item.album.name") {
+                        state.album = value.truncated();
+                    } else if path.matches("item.album.id") {
+                        state.album_id = value.truncated();
+                    } else if path.matches("item.album.images.*.url") {
+                        // widest first, so the last one is the smallest
+                        state.art_url = value.truncated();
+                    } else if path.matches("item.artists.0.name") {
+                        state.artist = value.truncated();
+                    }
+                }),
+            )
+            .await?;
+
+        // 204 - no device is active
+        Ok((has_body && has_item).then_some(state))
+    }
+
+    /// Sends a request to `/v1/me/player{path}` and ignores the response body.
+    async fn send(
+        &mut self,
+        method: Method,
+        path: &str,
+        body: Option<&[u8]>,
+    ) -> Result<(), TeenySpotifyError> {
+        self.request(method, path, body, None).await?;
+
+        Ok(())
+    }
+
+    /// Sends a request to `/v1/me/player{path}`, turning any unsuccessful
+    /// response into a [`TeenySpotifyError`].
+    ///
+    /// The response body is streamed through a [`JsonParser`] into `on_value`
+    /// if one is given, and the returned bool is whether there was a body to
+    /// parse.
+    async fn request(
+        &mut self,
+        method: Method,
+        path: &str,
+        body: Option<&[u8]>,
+        on_value: Option<&mut dyn FnMut(&JsonPath, JsonValue<'_>)>,
+    ) -> Result<bool, TeenySpotifyError> {
+        let mut url: String<128> = String::new();
+        write!(url, "{}/v1/me/player{path}", self.api_url)?;
+
+        let token = auth::access_token().await;
+        let bearer = token.bearer();
+
+        let headers = [
+            ("User-Agent", "teeny/0.1.0"),
+            ("Accept", "application/json"),
+            ("Content-Type", "application/json"),
+            ("Authorization", bearer.as_str()),
+        ];
+
+        // only needs to fit the response headers, the body is streamed
+        let mut rx_buf = [0; 2048];
+
+        // spotify wants a `Content-Length` even on empty PUTs, so always send a body
+        let mut request = self
+            .client
+            .request(method, &url)
+            .await?
+            .headers(&headers)
+            .body(body.unwrap_or(&[]));
+
+        let response = request.send(&mut rx_buf).await?;
+
+        trace!("{url} responded with {:?}", response.status);
+
+        match response.status {
+            status if status.is_successful() => {
+                let Some(on_value) = on_value else {
+                    return Ok(false);
+                };
+
+                if response.content_length == Some(0) || status == Status::NoContent {
+                    return Ok(false);
+                }
+
+                let mut parser: JsonParser<TRACK_LEN> = JsonParser::new();
+
+                read_json(&mut response.body().reader(), &mut parser, on_value).await?;
+
+                Ok(true)
+            }
+            Status::Unauthorized => {
+                auth::invalidate().await;
+                Err(TeenySpotifyError::Unauthorized)
+            }
+            Status::Forbidden => Err(TeenySpotifyError::PremiumRequired),
+            Status::NotFound => Err(TeenySpotifyError::NoActiveDevice),
+            Status::TooManyRequests => {
+                let retry_after = response
+                    .headers()
+                    .find(|(name, _)| name.eq_ignore_ascii_case("retry-after"))
+                    .and_then(|(_, value)| core::str::from_utf8(value).ok())
+                    .and_then(|value| value.trim().parse().ok())
+                    .unwrap_or(1);
+
+                Err(TeenySpotifyError::RateLimited {
+                    retry_after: Duration::from_secs(retry_after),
+                })
+            }
+            status => Err(TeenySpotifyError::Status(status)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json::JsonParser;
+
+    const DEVICES: &[u8] = include_bytes!("../tests/fixtures/devices.json");
+
+    /// Reads `document` a `chunk` bytes at a time, as [`Spotify::devices`]
+    /// would.
+    fn read(document: &[u8], chunk: usize) -> Devices {
+        let mut parser: JsonParser<TRACK_LEN> = JsonParser::new();
+        let mut devices = Devices::new();
+
+        for chunk in document.chunks(chunk) {
+            parser
+                .feed(chunk, |path, value| {
+                    collect_device(&mut devices, path, value)
+                })
+                .unwrap();
+        }
+
+        parser.finish().unwrap();
+
+        playable(devices)
+    }
+
+    fn names(devices: &Devices) -> std::vec::Vec<&str> {
+        devices.iter().map(|device| device.name.as_str()).collect()
+    }
+
+    #[test]
+    fn reads_recorded_devices() {
+        let devices = read(DEVICES, DEVICES.len());
+
+        // the restricted tv and the device without an id are left out
+        assert_eq!(
+            names(&devices),
+            ["Kitchen", "Pixel 7", "Living Room Speaker"]
+        );
+
+        assert_eq!(devices[0].id, "c5a8b2f1e4d7a0c3b6e9f2a5d8c1b4e7f0a3d6c9");
+        assert!(devices[0].is_active);
+        assert!(!devices[1].is_active);
+
+        for chunk in [1, 7, 64] {
+            assert_eq!(read(DEVICES, chunk), devices, "{chunk} byte chunks");
+        }
+    }
+
+    #[test]
+    fn long_names_are_cut_short() {
+        let long = br#"{"devices":[{"id":"a","name":"A really very extremely long device name"}]}"#;
+
+        assert_eq!(
+            read(long, long.len())[0].name,
+            "A really very extremely long dev"
+        );
+    }
+
+    #[test]
+    fn keeps_the_first_max_devices() {
+        let mut document = std::string::String::from(r#"{"devices":["#);
+
+        for i in 0..MAX_DEVICES + 2 {
+            if i > 0 {
+                document.push(',');
+            }
+            document.push_str(&std::format!(r#"{{"id":"{i}","name":"Device {i}"}}"#));
+        }
+
+        document.push_str("]}");
+
+        let devices = read(document.as_bytes(), 16);
+
+        assert_eq!(devices.len(), MAX_DEVICES);
+        assert_eq!(
+            devices[MAX_DEVICES - 1].id,
+            std::format!("{}", MAX_DEVICES - 1).as_str()
+        );
+    }
+
+    #[test]
+    fn no_devices() {
+        assert!(read(br#"{"devices":[]}"#, 4).is_empty());
+    }
+}
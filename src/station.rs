@@ -0,0 +1,664 @@
+use embassy_futures::select::{select, select3, Either, Either3};
+use embassy_net::Ipv4Address;
+use esp_wifi::wifi::{
+    AccessPointConfiguration, AuthMethod, Configuration, WifiController, WifiEvent, WifiState,
+};
+
+use crate::{
+    net::{
+        ApPassword, ScannedNetwork, StaStack, WifiConfig, WifiConfigState, AP_SSID,
+        MAX_SCAN_RESULTS, SCAN_REQUEST_SIGNAL, SCAN_RESULTS_SIGNAL, WIFI_CONFIG_SIGNAL,
+    },
+    prelude::*,
+    storage::{SettingsMutex, WifiNetwork, MAX_NETWORKS},
+};
+
+/// How many attempts in a row can fail before we give up on the known
+/// networks and switch the radio to the setup access point alone.
+pub const MAX_FAILURES: u8 = 5;
+
+/// The delay after the first failed attempt - it doubles with every failure
+/// after that.
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(2 * 60);
+
+/// How often the known networks are tried again while provisioning, in case
+/// one of them has come back (e.g. after the router rebooted).
+const PROVISIONING_RETRY: Duration = Duration::from_secs(5 * 60);
+
+/// How often the signal is checked while connected, in case a known network
+/// with a stronger one is in range.
+const ROAM_INTERVAL: Duration = Duration::from_secs(2 * 60);
+
+/// How weak the signal (in dBm) has to get before we look for a stronger one.
+const ROAM_THRESHOLD: i8 = -70;
+
+/// How much stronger (in dB) another known network has to be to move to it -
+/// any less and we'd keep swapping between two that are about the same.
+const ROAM_MARGIN: i8 = 10;
+
+/// How long DHCP gets to give us an address after connecting, before the
+/// network counts as broken.
+const IP_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// The remembered networks, plus the one baked in at build time.
+const MAX_KNOWN_NETWORKS: usize = MAX_NETWORKS as usize + 1;
+
+/// What the station interface is up to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Looking for a known network in range.
+    Scanning,
+    Connecting {
+        ssid: String<32>,
+    },
+    /// Associated with the network, waiting for DHCP.
+    WaitingForIp {
+        ssid: String<32>,
+    },
+    Connected {
+        ssid: String<32>,
+        address: Ipv4Address,
+    },
+    /// Waiting a while before trying again.
+    Backoff {
+        failures: u8,
+        retry_at: Instant,
+    },
+    /// None of the known networks could be reached (or there are none) - the
+    /// radio is running the setup access point alone, until a network is
+    /// entered on the setup page or a known one comes back at `retry_at`.
+    Provisioning {
+        retry_at: Instant,
+    },
+}
+
+/// Every change in [`ConnectionState`], e.g. for showing on the display.
+pub static CONNECTION_CHANNEL: PubSubChannel<CriticalSectionRawMutex, ConnectionState, 1, 4, 1> =
+    PubSubChannel::new();
+
+pub(crate) fn ap_configuration(password: Option<&str>) -> AccessPointConfiguration {
+    AccessPointConfiguration {
+        ssid: AP_SSID
+            .try_into()
+            .expect("should be a valid access point SSID"),
+        auth_method: match password {
+            Some(_) => AuthMethod::WPA2Personal,
+            None => AuthMethod::None,
+        },
+        password: password
+            .and_then(|password| password.try_into().ok())
+            .unwrap_or_default(),
+        ..Default::default()
+    }
+}
+
+/// How long to wait after `failures` failed attempts in a row - exponential,
+/// with up to half of it randomised so several devices don't all retry in
+/// lockstep.
+pub fn backoff(failures: u8, rng: &mut Rng) -> Duration {
+    let delay = MIN_BACKOFF
+        .checked_mul(1 << failures.saturating_sub(1).min(16))
+        .map_or(MAX_BACKOFF, |delay| delay.min(MAX_BACKOFF));
+
+    let jitter = rng.random() as u64 % (delay.as_ticks() / 2 + 1);
+
+    delay - Duration::from_ticks(jitter)
+}
+
+pub async fn scan(
+    controller: &mut WifiController<'static>,
+) -> Vec<ScannedNetwork, MAX_SCAN_RESULTS> {
+    match controller.scan_n::<MAX_SCAN_RESULTS>().await {
+        Ok((access_points, _)) => access_points
+            .into_iter()
+            .filter(|access_point| !access_point.ssid.is_empty())
+            .map(|access_point| ScannedNetwork {
+                ssid: access_point.ssid,
+                strength: access_point.signal_strength,
+                secured: !matches!(access_point.auth_method, None | Some(AuthMethod::None)),
+            })
+            .collect(),
+        Err(e) => {
+            warn!("Failed to scan for networks: {e:?}");
+            Vec::new()
+        }
+    }
+}
+
+/// The known networks in `scanned`, best first - highest priority, then
+/// strongest signal.
+pub fn rank_networks<'a>(
+    known: &'a [WifiNetwork],
+    scanned: &[ScannedNetwork],
+) -> Vec<&'a WifiNetwork, MAX_KNOWN_NETWORKS> {
+    let mut candidates: Vec<(&WifiNetwork, i8), MAX_KNOWN_NETWORKS> = known
+        .iter()
+        .filter_map(|network| {
+            scanned
+                .iter()
+                .filter(|scanned| scanned.ssid == network.ssid)
+                .map(|scanned| scanned.strength)
+                .max()
+                .map(|strength| (network, strength))
+        })
+        .collect();
+
+    candidates.sort_unstable_by(|(a, a_strength), (b, b_strength)| {
+        b.priority.cmp(&a.priority).then(b_strength.cmp(a_strength))
+    });
+
+    candidates.into_iter().map(|(network, _)| network).collect()
+}
+
+/// The known network in `scanned` to move to from `current`, once its signal
+/// has dropped below [`ROAM_THRESHOLD`] - the strongest one that is at least
+/// [`ROAM_MARGIN`] stronger.
+pub fn roam_target<'a>(
+    known: &'a [WifiNetwork],
+    scanned: &[ScannedNetwork],
+    current: &str,
+) -> Option<&'a WifiNetwork> {
+    let strength = |ssid: &str| {
+        scanned
+            .iter()
+            .filter(|scanned| scanned.ssid == ssid)
+            .map(|scanned| scanned.strength)
+            .max()
+    };
+
+    // not seeing it at all is left to the disconnect that will follow
+    let current_strength = strength(current)?;
+
+    if current_strength >= ROAM_THRESHOLD {
+        return None;
+    }
+
+    known
+        .iter()
+        .filter(|network| network.ssid != current)
+        .filter_map(|network| Some((network, strength(&network.ssid)?)))
+        .filter(|(_, strength)| *strength >= current_strength.saturating_add(ROAM_MARGIN))
+        .max_by_key(|(_, strength)| *strength)
+        .map(|(network, _)| network)
+}
+
+/// Why [`StationManager::wait_for_retry`] returned.
+enum Wake {
+    /// Time to look for a network.
+    Retry,
+    /// Time to check the signal of the one we're connected to.
+    Roam,
+}
+
+/// Keeps the station interface connected to the best known network in range,
+/// moving to a stronger one when the signal gets weak and backing off (and
+/// eventually giving up) when none of them work.
+pub struct StationManager {
+    controller: WifiController<'static>,
+    rng: Rng,
+    stack: &'static StaStack,
+    wifi_config: WifiConfigState,
+    settings: &'static SettingsMutex,
+    ap_password: Option<ApPassword>,
+    networks: Vec<WifiNetwork, MAX_KNOWN_NETWORKS>,
+    state: ConnectionState,
+    failures: u8,
+    /// When to next check for a stronger network, while connected.
+    roam_at: Instant,
+}
+
+impl StationManager {
+    pub async fn new(
+        controller: WifiController<'static>,
+        rng: Rng,
+        stack: &'static StaStack,
+        wifi_config: WifiConfigState,
+        settings: &'static SettingsMutex,
+        ap_password: Option<ApPassword>,
+    ) -> Self {
+        let mut manager = Self {
+            controller,
+            rng,
+            stack,
+            wifi_config,
+            settings,
+            ap_password,
+            networks: Vec::new(),
+            state: ConnectionState::Scanning,
+            failures: 0,
+            roam_at: Instant::now(),
+        };
+
+        manager.load_networks().await;
+
+        manager
+    }
+
+    pub fn state(&self) -> &ConnectionState {
+        &self.state
+    }
+
+    async fn load_networks(&mut self) {
+        self.networks = self
+            .settings
+            .lock()
+            .await
+            .wifi_networks()
+            .into_iter()
+            .collect();
+
+        let build_time = WifiNetwork {
+            ssid: SSID.try_into().unwrap_or_default(),
+            password: PASSWORD.try_into().unwrap_or_default(),
+            priority: 0,
+        };
+
+        if !build_time.ssid.is_empty()
+            && !self
+                .networks
+                .iter()
+                .any(|network| network.ssid == build_time.ssid)
+        {
+            self.networks.push(build_time).ok();
+        }
+
+        debug!("Known networks: {}", self.networks.len());
+    }
+
+    fn set_state(&mut self, state: ConnectionState) {
+        debug!("Connection state: {state:?}");
+
+        self.state = state.clone();
+
+        CONNECTION_CHANNEL
+            .immediate_publisher()
+            .publish_immediate(state);
+    }
+
+    fn configure(&mut self, config: &WifiConfig) -> Result<(), esp_wifi::wifi::WifiError> {
+        self.controller.set_configuration(&Configuration::Mixed(
+            config.client_configuration(),
+            ap_configuration(self.ap_password.as_deref()),
+        ))
+    }
+
+    fn configure_access_point(&mut self) {
+        let config = Configuration::AccessPoint(ap_configuration(self.ap_password.as_deref()));
+
+        if let Err(e) = self.controller.set_configuration(&config) {
+            error!("Failed to switch to the access point alone: {e:?}");
+        }
+    }
+
+    /// Gives up on the known networks for a while, leaving the radio to the
+    /// setup access point.
+    fn provision(&mut self) {
+        self.configure_access_point();
+
+        self.set_state(ConnectionState::Provisioning {
+            retry_at: Instant::now() + PROVISIONING_RETRY,
+        });
+    }
+
+    /// Scans for networks - which needs the station interface, so it is
+    /// brought back for the scan while provisioning.
+    async fn scan(&mut self) -> Vec<ScannedNetwork, MAX_SCAN_RESULTS> {
+        let provisioning = matches!(self.state, ConnectionState::Provisioning { .. });
+
+        if provisioning {
+            if let Err(e) = self.configure(&WifiConfig::default()) {
+                error!("Failed to configure wifi for scanning: {e:?}");
+            }
+        }
+
+        let scanned = scan(&mut self.controller).await;
+
+        if provisioning {
+            self.configure_access_point();
+        }
+
+        scanned
+    }
+
+    async fn start(&mut self) {
+        if matches!(self.controller.is_started(), Ok(true)) {
+            return;
+        }
+
+        // an empty client configuration is fine until we know which network to use
+        self.configure(&WifiConfig::default()).unwrap();
+
+        info!("Starting wifi");
+        self.controller.start().await.unwrap();
+        info!("Wifi started!");
+    }
+
+    /// Tries to connect to `config`, leaving the state as [`Connected`] if it
+    /// worked.
+    ///
+    /// [`Connected`]: ConnectionState::Connected
+    async fn connect(&mut self, config: WifiConfig) -> bool {
+        self.set_state(ConnectionState::Connecting {
+            ssid: config.ssid.clone(),
+        });
+
+        if let Err(e) = self.configure(&config) {
+            error!("Failed to configure wifi: {e:?}");
+            return false;
+        }
+
+        if let Err(e) = self.controller.connect().await {
+            warn!("Failed to connect to {:?}: {e:?}", config.ssid);
+            return false;
+        }
+
+        debug!(
+            "Wifi connected to {:?}, waiting for an address...",
+            config.ssid
+        );
+
+        self.set_state(ConnectionState::WaitingForIp {
+            ssid: config.ssid.clone(),
+        });
+
+        let stack = self.stack;
+
+        let address = match embassy_time::with_timeout(IP_TIMEOUT, async {
+            loop {
+                if let Some(config) = stack.config_v4() {
+                    break config.address.address();
+                }
+                Timer::after(Duration::from_millis(500)).await;
+            }
+        })
+        .await
+        {
+            Ok(address) => address,
+            Err(_) => {
+                warn!("Didn't get an address from {:?} in time", config.ssid);
+
+                if let Err(e) = self.controller.disconnect().await {
+                    warn!("Failed to disconnect: {e:?}");
+                }
+
+                return false;
+            }
+        };
+
+        info!(
+            "Wifi connected to {:?} with address {address}!",
+            config.ssid
+        );
+
+        self.failures = 0;
+        self.roam_at = Instant::now() + ROAM_INTERVAL;
+        self.set_state(ConnectionState::Connected {
+            ssid: config.ssid.clone(),
+            address,
+        });
+        *self.wifi_config.0.lock().await = config;
+
+        true
+    }
+
+    /// Scans, then tries every known network in range, best first.
+    async fn connect_best(&mut self) -> bool {
+        if matches!(self.state, ConnectionState::Provisioning { .. }) {
+            // bring the station interface back to look for them
+            if let Err(e) = self.configure(&WifiConfig::default()) {
+                error!("Failed to configure wifi for scanning: {e:?}");
+            }
+        }
+
+        self.set_state(ConnectionState::Scanning);
+
+        let scanned = scan(&mut self.controller).await;
+
+        let candidates: Vec<WifiNetwork, MAX_KNOWN_NETWORKS> =
+            rank_networks(&self.networks, &scanned)
+                .into_iter()
+                .cloned()
+                .collect();
+
+        if candidates.is_empty() {
+            debug!("None of the known networks are in range");
+        }
+
+        for network in candidates {
+            if self.connect(network.into()).await {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Connects to a network entered on the setup page, remembering it if it
+    /// works.
+    async fn switch_to(&mut self, config: WifiConfig) {
+        info!("Switching to network {:?}", config.ssid);
+
+        if esp_wifi::wifi::get_wifi_state() == WifiState::StaConnected {
+            if let Err(e) = self.controller.disconnect().await {
+                warn!("Failed to disconnect: {e:?}");
+            }
+        }
+
+        if !self.connect(config.clone()).await {
+            self.failed();
+            return;
+        }
+
+        // newly added networks are preferred over the ones from before
+        let network = WifiNetwork {
+            priority: self
+                .networks
+                .iter()
+                .map(|network| network.priority)
+                .max()
+                .map_or(0, |priority| priority.saturating_add(1)),
+            ssid: config.ssid,
+            password: config.password,
+        };
+
+        if let Err(e) = self.settings.lock().await.add_wifi_network(&network) {
+            error!("Failed to save wifi network: {e:?}");
+        }
+
+        self.load_networks().await;
+    }
+
+    /// Moves to a stronger known network if the signal of this one has got
+    /// weak.
+    async fn roam(&mut self) {
+        self.roam_at = Instant::now() + ROAM_INTERVAL;
+
+        let ConnectionState::Connected { ssid, .. } = &self.state else {
+            return;
+        };
+
+        let scanned = scan(&mut self.controller).await;
+
+        let Some(network) = roam_target(&self.networks, &scanned, ssid).cloned() else {
+            return;
+        };
+
+        info!("Signal from {ssid:?} is weak, moving to {:?}", network.ssid);
+
+        if let Err(e) = self.controller.disconnect().await {
+            warn!("Failed to disconnect: {e:?}");
+        }
+
+        if !self.connect(network.into()).await && !self.connect_best().await {
+            self.failed();
+        }
+    }
+
+    fn failed(&mut self) {
+        self.failures = self.failures.saturating_add(1);
+
+        if self.failures >= MAX_FAILURES {
+            warn!("Couldn't connect to any known network, waiting to be set up");
+            self.provision();
+        } else {
+            let retry_at = Instant::now() + backoff(self.failures, &mut self.rng);
+
+            self.set_state(ConnectionState::Backoff {
+                failures: self.failures,
+                retry_at,
+            });
+        }
+    }
+
+    /// Waits until it is time to look for a network again - or, while
+    /// connected, to check for a stronger one.
+    async fn wait_for_retry(
+        controller: &mut WifiController<'static>,
+        state: &ConnectionState,
+        roam_at: Instant,
+    ) -> Wake {
+        match state {
+            ConnectionState::Connected { .. } => {
+                if esp_wifi::wifi::get_wifi_state() != WifiState::StaConnected {
+                    return Wake::Retry;
+                }
+
+                match select(
+                    controller.wait_for_event(WifiEvent::StaDisconnected),
+                    Timer::at(roam_at),
+                )
+                .await
+                {
+                    Either::First(()) => Wake::Retry,
+                    Either::Second(()) => Wake::Roam,
+                }
+            }
+            ConnectionState::Backoff { retry_at, .. }
+            | ConnectionState::Provisioning { retry_at } => {
+                Timer::at(*retry_at).await;
+                Wake::Retry
+            }
+            ConnectionState::Scanning
+            | ConnectionState::Connecting { .. }
+            | ConnectionState::WaitingForIp { .. } => Wake::Retry,
+        }
+    }
+
+    pub async fn run(&mut self) -> ! {
+        self.start().await;
+
+        loop {
+            match select3(
+                WIFI_CONFIG_SIGNAL.wait(),
+                SCAN_REQUEST_SIGNAL.wait(),
+                Self::wait_for_retry(&mut self.controller, &self.state, self.roam_at),
+            )
+            .await
+            {
+                Either3::First(config) => self.switch_to(config).await,
+                Either3::Second(()) => {
+                    debug!("Scanning for networks...");
+                    let scanned = self.scan().await;
+                    SCAN_RESULTS_SIGNAL.signal(scanned);
+                }
+                Either3::Third(Wake::Roam) => self.roam().await,
+                Either3::Third(Wake::Retry) => {
+                    if matches!(self.state, ConnectionState::Connected { .. }) {
+                        info!("Wifi disconnected");
+                        // reconnecting straight away doesn't count as a failure
+                        self.failures = 0;
+                    }
+
+                    if self.networks.is_empty() {
+                        // nothing to do until someone uses the setup page
+                        self.provision();
+                        continue;
+                    }
+
+                    if !self.connect_best().await {
+                        self.failed();
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(ssid: &str) -> WifiNetwork {
+        WifiNetwork {
+            ssid: ssid.try_into().unwrap(),
+            ..Default::default()
+        }
+    }
+
+    fn in_range(ssid: &str, strength: i8) -> ScannedNetwork {
+        ScannedNetwork {
+            ssid: ssid.try_into().unwrap(),
+            strength,
+            secured: true,
+        }
+    }
+
+    #[test]
+    fn stays_put_while_the_signal_is_good() {
+        let known = [network("home"), network("upstairs")];
+        let scanned = [in_range("home", ROAM_THRESHOLD), in_range("upstairs", -30)];
+
+        assert_eq!(roam_target(&known, &scanned, "home"), None);
+    }
+
+    #[test]
+    fn moves_to_the_strongest_known_network() {
+        let known = [network("home"), network("upstairs"), network("garage")];
+        let scanned = [
+            in_range("home", -80),
+            in_range("garage", -60),
+            in_range("upstairs", -50),
+            in_range("neighbours", -20),
+        ];
+
+        assert_eq!(roam_target(&known, &scanned, "home"), Some(&known[1]));
+    }
+
+    #[test]
+    fn only_moves_for_a_much_stronger_signal() {
+        let known = [network("home"), network("upstairs")];
+
+        let scanned = [
+            in_range("home", -80),
+            in_range("upstairs", -80 + ROAM_MARGIN - 1),
+        ];
+        assert_eq!(roam_target(&known, &scanned, "home"), None);
+
+        let scanned = [
+            in_range("home", -80),
+            in_range("upstairs", -80 + ROAM_MARGIN),
+        ];
+        assert_eq!(roam_target(&known, &scanned, "home"), Some(&known[1]));
+    }
+
+    #[test]
+    fn goes_by_the_strongest_access_point_of_each_network() {
+        let known = [network("home"), network("upstairs")];
+        let scanned = [
+            in_range("home", -90),
+            in_range("upstairs", -85),
+            in_range("home", -75),
+        ];
+
+        assert_eq!(roam_target(&known, &scanned, "home"), None);
+    }
+
+    #[test]
+    fn leaves_a_network_that_has_gone_to_the_reconnect() {
+        let known = [network("home"), network("upstairs")];
+        let scanned = [in_range("upstairs", -30)];
+
+        assert_eq!(roam_target(&known, &scanned, "home"), None);
+    }
+}
@@ -0,0 +1,395 @@
+//! What teeny as a whole is up to - from booting, through joining a network
+//! and logging in to spotify, to being ready to control playback - worked out
+//! from what the wifi and login tasks report, so it can be shown on the display
+//! rather than only over serial.
+
+use embassy_futures::select::{select3, Either3};
+use embassy_net::Ipv4Address;
+
+use crate::{
+    idle,
+    login::{LoginCode, LoginState, LOGIN_CHANNEL, LOGIN_URL_LEN},
+    prelude::*,
+    station::{ConnectionState, CONNECTION_CHANNEL},
+};
+
+/// Where the setup access point is in starting up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApState {
+    NotStarted,
+    Starting,
+    Up,
+}
+
+/// What teeny is up to, as shown on the status screen.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SystemState {
+    Booting,
+    StartingAp,
+    /// Waiting for someone to join the setup access point and pick a network.
+    SettingUp,
+    /// Looking for a known network, until `ssid` is the one being joined.
+    Connecting {
+        ssid: Option<String<32>>,
+    },
+    GotIp {
+        ssid: String<32>,
+        address: Ipv4Address,
+    },
+    /// Logging in to spotify - once the user has to do it themselves, `login`
+    /// is where, and the code to enter there.
+    Authorising {
+        login: Option<(String<LOGIN_URL_LEN>, LoginCode)>,
+    },
+    /// Connected and logged in - the player can be controlled.
+    Ready,
+    /// Something went wrong, and will be tried again - at `retry_at`, if it is
+    /// known.
+    Error {
+        reason: &'static str,
+        retry_at: Option<Instant>,
+    },
+}
+
+/// Everything the [`SystemState`] is worked out from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    ap: ApState,
+    connection: Option<ConnectionState>,
+    login: Option<LoginState>,
+}
+
+impl Default for Status {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Status {
+    pub const fn new() -> Self {
+        Self {
+            ap: ApState::NotStarted,
+            connection: None,
+            login: None,
+        }
+    }
+
+    pub fn set_ap(&mut self, ap: ApState) {
+        self.ap = ap;
+    }
+
+    pub fn set_connection(&mut self, connection: ConnectionState) {
+        self.connection = Some(connection);
+    }
+
+    pub fn set_login(&mut self, login: LoginState) {
+        self.login = Some(login);
+    }
+
+    /// The network comes first - logging in only matters once there is one, and
+    /// a login from before it dropped still counts once it is back.
+    pub fn state(&self) -> SystemState {
+        match self.ap {
+            ApState::NotStarted => return SystemState::Booting,
+            ApState::Starting => return SystemState::StartingAp,
+            ApState::Up => {}
+        }
+
+        let (ssid, address) = match &self.connection {
+            None | Some(ConnectionState::Scanning) => {
+                return SystemState::Connecting { ssid: None }
+            }
+            Some(ConnectionState::Connecting { ssid } | ConnectionState::WaitingForIp { ssid }) => {
+                return SystemState::Connecting {
+                    ssid: Some(ssid.clone()),
+                }
+            }
+            Some(ConnectionState::Backoff { retry_at, .. }) => {
+                return SystemState::Error {
+                    reason: "Couldn't join wifi",
+                    retry_at: Some(*retry_at),
+                }
+            }
+            Some(ConnectionState::Provisioning { .. }) => return SystemState::SettingUp,
+            Some(ConnectionState::Connected { ssid, address }) => (ssid, address),
+        };
+
+        match &self.login {
+            None => SystemState::GotIp {
+                ssid: ssid.clone(),
+                address: *address,
+            },
+            Some(LoginState::Authorising) => SystemState::Authorising { login: None },
+            Some(LoginState::Required { url, code }) => SystemState::Authorising {
+                login: Some((url.clone(), code.clone())),
+            },
+            Some(LoginState::Failed) => SystemState::Error {
+                reason: "Spotify login failed",
+                retry_at: None,
+            },
+            Some(LoginState::LoggedIn) => SystemState::Ready,
+        }
+    }
+}
+
+/// Every change in [`SystemState`], e.g. for the status screen.
+pub static SYSTEM_STATE_CHANNEL: PubSubChannel<CriticalSectionRawMutex, SystemState, 1, 4, 1> =
+    PubSubChannel::new();
+
+static AP_SIGNAL: Signal<CriticalSectionRawMutex, ApState> = Signal::new();
+
+/// Reports how the setup access point is getting on - the wifi and login tasks
+/// report through their own channels.
+pub fn set_ap(ap: ApState) {
+    AP_SIGNAL.signal(ap);
+}
+
+/// Keeps [`SYSTEM_STATE_CHANNEL`] up to date with everything that goes into
+/// the [`SystemState`] - waking the display for every change, so nothing goes
+/// wrong unseen.
+#[task]
+pub async fn status_task() {
+    let mut connection = CONNECTION_CHANNEL.subscriber().unwrap();
+    let mut login = LOGIN_CHANNEL.subscriber().unwrap();
+
+    let publisher = SYSTEM_STATE_CHANNEL.immediate_publisher();
+
+    let mut status = Status::new();
+    let mut state = status.state();
+
+    publisher.publish_immediate(state.clone());
+
+    loop {
+        match select3(
+            AP_SIGNAL.wait(),
+            connection.next_message_pure(),
+            login.next_message_pure(),
+        )
+        .await
+        {
+            Either3::First(ap) => status.set_ap(ap),
+            Either3::Second(connection) => status.set_connection(connection),
+            Either3::Third(login) => status.set_login(login),
+        }
+
+        let new_state = status.state();
+
+        if new_state != state {
+            info!("Status: {new_state:?}");
+
+            state = new_state;
+            publisher.publish_immediate(state.clone());
+            idle::wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: Ipv4Address = Ipv4Address::new(192, 168, 1, 20);
+
+    fn ssid() -> String<32> {
+        String::try_from("home").unwrap()
+    }
+
+    fn url() -> String<LOGIN_URL_LEN> {
+        String::try_from("http://192.168.1.20/login").unwrap()
+    }
+
+    fn code() -> LoginCode {
+        LoginCode(String::try_from("k7mx3p").unwrap())
+    }
+
+    fn required() -> LoginState {
+        LoginState::Required {
+            url: url(),
+            code: code(),
+        }
+    }
+
+    fn connected() -> ConnectionState {
+        ConnectionState::Connected {
+            ssid: ssid(),
+            address: ADDRESS,
+        }
+    }
+
+    fn status(
+        ap: ApState,
+        connection: Option<ConnectionState>,
+        login: Option<LoginState>,
+    ) -> SystemState {
+        let mut status = Status::new();
+
+        status.set_ap(ap);
+
+        if let Some(connection) = connection {
+            status.set_connection(connection);
+        }
+
+        if let Some(login) = login {
+            status.set_login(login);
+        }
+
+        status.state()
+    }
+
+    #[test]
+    fn starts_out_booting() {
+        assert_eq!(Status::new().state(), SystemState::Booting);
+    }
+
+    #[test]
+    fn waits_for_the_access_point_first() {
+        for (ap, expected) in [
+            (ApState::NotStarted, SystemState::Booting),
+            (ApState::Starting, SystemState::StartingAp),
+        ] {
+            assert_eq!(status(ap, None, None), expected);
+            assert_eq!(
+                status(ap, Some(connected()), Some(LoginState::LoggedIn)),
+                expected
+            );
+            assert_eq!(
+                status(
+                    ap,
+                    Some(ConnectionState::Backoff {
+                        failures: 1,
+                        retry_at: Instant::from_secs(5),
+                    }),
+                    Some(LoginState::Failed)
+                ),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn follows_the_connection() {
+        let retry_at = Instant::from_secs(5);
+
+        for (connection, expected) in [
+            (None, SystemState::Connecting { ssid: None }),
+            (
+                Some(ConnectionState::Scanning),
+                SystemState::Connecting { ssid: None },
+            ),
+            (
+                Some(ConnectionState::Connecting { ssid: ssid() }),
+                SystemState::Connecting { ssid: Some(ssid()) },
+            ),
+            (
+                Some(ConnectionState::WaitingForIp { ssid: ssid() }),
+                SystemState::Connecting { ssid: Some(ssid()) },
+            ),
+            (
+                Some(ConnectionState::Backoff {
+                    failures: 2,
+                    retry_at,
+                }),
+                SystemState::Error {
+                    reason: "Couldn't join wifi",
+                    retry_at: Some(retry_at),
+                },
+            ),
+            (
+                Some(ConnectionState::Provisioning { retry_at }),
+                SystemState::SettingUp,
+            ),
+            (
+                Some(connected()),
+                SystemState::GotIp {
+                    ssid: ssid(),
+                    address: ADDRESS,
+                },
+            ),
+        ] {
+            assert_eq!(status(ApState::Up, connection, None), expected);
+        }
+    }
+
+    #[test]
+    fn follows_the_login_once_connected() {
+        for (login, expected) in [
+            (
+                LoginState::Authorising,
+                SystemState::Authorising { login: None },
+            ),
+            (
+                required(),
+                SystemState::Authorising {
+                    login: Some((url(), code())),
+                },
+            ),
+            (
+                LoginState::Failed,
+                SystemState::Error {
+                    reason: "Spotify login failed",
+                    retry_at: None,
+                },
+            ),
+            (LoginState::LoggedIn, SystemState::Ready),
+        ] {
+            assert_eq!(
+                status(ApState::Up, Some(connected()), Some(login)),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn wifi_errors_win_over_login_errors() {
+        let retry_at = Instant::from_secs(5);
+
+        assert_eq!(
+            status(
+                ApState::Up,
+                Some(ConnectionState::Backoff {
+                    failures: 1,
+                    retry_at,
+                }),
+                Some(LoginState::Failed)
+            ),
+            SystemState::Error {
+                reason: "Couldn't join wifi",
+                retry_at: Some(retry_at),
+            }
+        );
+
+        assert_eq!(
+            status(
+                ApState::Up,
+                Some(ConnectionState::Provisioning { retry_at }),
+                Some(LoginState::Failed)
+            ),
+            SystemState::SettingUp
+        );
+
+        assert_eq!(
+            status(
+                ApState::Up,
+                Some(ConnectionState::Scanning),
+                Some(required())
+            ),
+            SystemState::Connecting { ssid: None }
+        );
+    }
+
+    #[test]
+    fn a_login_outlasts_the_network_dropping() {
+        let mut status = Status::new();
+        status.set_ap(ApState::Up);
+        status.set_connection(connected());
+        status.set_login(LoginState::LoggedIn);
+
+        assert_eq!(status.state(), SystemState::Ready);
+
+        status.set_connection(ConnectionState::Scanning);
+        assert_eq!(status.state(), SystemState::Connecting { ssid: None });
+
+        status.set_connection(connected());
+        assert_eq!(status.state(), SystemState::Ready);
+    }
+}
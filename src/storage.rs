@@ -0,0 +1,1262 @@
+//! A small key/value store for settings, kept in flash so they survive a
+//! reflash of the firmware.
+//!
+//! Records are appended to a log spread over several flash sectors. The newest
+//! record for a key wins, and when the current sector fills up the log moves
+//! on to the next (always erased) sector, copying over anything still live in
+//! the oldest one before erasing it - so every sector gets erased in turn,
+//! rather than the same one over and over.
+
+use embedded_storage::nor_flash::{NorFlash, NorFlashError, NorFlashErrorKind};
+
+use crate::{
+    display::driver::{Controller, DisplayConfig, PanelSize, Rotation},
+    prelude::*,
+};
+
+/// Start of the `nvs` partition in the default partition table - we don't use
+/// esp-idf's nvs, so it is free for us.
+pub const FLASH_OFFSET: u32 = 0x9000;
+/// The `nvs` partition is 24KiB long.
+pub const FLASH_SECTORS: u32 = 6;
+
+/// Marks the start of a sector which is part of the store ("TENY").
+const MAGIC: u32 = 0x5445_4e59;
+
+/// Magic, then the sequence number of the sector.
+const SECTOR_HEADER_LEN: u32 = 8;
+/// Key, flags, length, then a crc of all of them and the data.
+const RECORD_HEADER_LEN: u32 = 8;
+
+/// The key of the erased space after the last record in a sector.
+const ERASED: u8 = 0xff;
+/// Flag for a record which removes its key, rather than setting it.
+const TOMBSTONE: u8 = 0x01;
+
+/// The largest value which can be stored.
+pub const MAX_VALUE_LEN: usize = 256;
+
+/// The largest `READ_SIZE`/`WRITE_SIZE` supported, and the size of the chunks
+/// data is copied in.
+const CHUNK_LEN: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TeenyStorageError {
+    Flash(NorFlashErrorKind),
+    /// There is too much live data to fit another record.
+    Full,
+    /// The record is larger than a sector could ever hold.
+    TooLarge,
+    /// The record didn't fit in the buffer it was read into.
+    BufferTooSmall,
+}
+
+impl<E: NorFlashError> From<E> for TeenyStorageError {
+    fn from(value: E) -> Self {
+        Self::Flash(value.kind())
+    }
+}
+
+/// A standard crc32 (as used by ethernet and zip).
+pub fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    !crc
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RecordHeader {
+    key: u8,
+    flags: u8,
+    len: u16,
+    crc: u32,
+}
+
+impl RecordHeader {
+    fn from_bytes(bytes: [u8; RECORD_HEADER_LEN as usize]) -> Self {
+        Self {
+            key: bytes[0],
+            flags: bytes[1],
+            len: u16::from_le_bytes([bytes[2], bytes[3]]),
+            crc: u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+        }
+    }
+
+    fn to_bytes(self) -> [u8; RECORD_HEADER_LEN as usize] {
+        let [len_0, len_1] = self.len.to_le_bytes();
+        let [crc_0, crc_1, crc_2, crc_3] = self.crc.to_le_bytes();
+
+        [
+            self.key, self.flags, len_0, len_1, crc_0, crc_1, crc_2, crc_3,
+        ]
+    }
+
+    fn crc_of(key: u8, flags: u8, data: &[u8]) -> u32 {
+        crc32(crc32(0, &[key, flags]), data)
+    }
+
+    fn is_tombstone(&self) -> bool {
+        self.flags & TOMBSTONE != 0
+    }
+}
+
+/// Where the newest record for a key is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Location {
+    sector: u32,
+    offset: u32,
+}
+
+/// A log structured key/value store over any [`NorFlash`] - the real flash
+/// on device, or an in-memory stand-in on the host.
+pub struct FlashStore<F: NorFlash> {
+    flash: F,
+    /// Address of the first sector.
+    start: u32,
+    sectors: u32,
+    /// The sector new records are appended to.
+    active: u32,
+    /// Where in the active sector the next record goes.
+    next_offset: u32,
+    /// The sequence number of the active sector.
+    sequence: u32,
+}
+
+impl<F: NorFlash> FlashStore<F> {
+    const SECTOR_LEN: u32 = F::ERASE_SIZE as u32;
+    const ALIGN: u32 = if F::READ_SIZE > F::WRITE_SIZE {
+        F::READ_SIZE as u32
+    } else {
+        F::WRITE_SIZE as u32
+    };
+
+    /// Opens the store in the `sectors` sectors starting at `start`,
+    /// formatting them if they don't hold one yet and finishing off anything
+    /// interrupted by a power cut.
+    pub fn mount(flash: F, start: u32, sectors: u32) -> Result<Self, TeenyStorageError> {
+        assert!(sectors >= 2, "the store needs a spare sector");
+        assert!(Self::ALIGN as usize <= CHUNK_LEN && CHUNK_LEN % Self::ALIGN as usize == 0);
+
+        let mut store = Self {
+            flash,
+            start,
+            sectors,
+            active: 0,
+            next_offset: SECTOR_HEADER_LEN,
+            sequence: 0,
+        };
+
+        let mut newest = None;
+
+        for sector in 0..sectors {
+            if let Some(sequence) = store.sector_sequence(sector)? {
+                if newest.map_or(true, |(_, newest)| sequence > newest) {
+                    newest = Some((sector, sequence));
+                }
+            }
+        }
+
+        let Some((active, sequence)) = newest else {
+            info!("No settings found in flash, formatting");
+            return store.format();
+        };
+
+        store.active = active;
+        store.sequence = sequence;
+        store.next_offset = store.end_of_log(active)?;
+
+        // the sector after the active one should be the spare - if it isn't, we lost
+        // power part way through moving on to the active sector
+        let spare = store.next_sector(active);
+        if store.sector_sequence(spare)?.is_some() {
+            warn!("Finishing an interrupted settings sector swap");
+            store.reclaim(spare)?;
+        } else if !store.is_blank(spare)? {
+            store.erase(spare)?;
+        }
+
+        debug!(
+            "Mounted settings store, sector {} at offset {}",
+            store.active, store.next_offset
+        );
+
+        Ok(store)
+    }
+
+    /// Erases the whole store.
+    pub fn format(mut self) -> Result<Self, TeenyStorageError> {
+        for sector in 0..self.sectors {
+            self.erase(sector)?;
+        }
+
+        self.active = 0;
+        self.sequence = 1;
+        self.write_sector_header(0, 1)?;
+        self.next_offset = SECTOR_HEADER_LEN;
+
+        Ok(self)
+    }
+
+    /// Gives back the flash the store was using.
+    pub fn release(self) -> F {
+        self.flash
+    }
+
+    /// Reads the value of `key` into `buf`, returning how long it is - or
+    /// `None` if it has no value.
+    pub fn read(&mut self, key: u8, buf: &mut [u8]) -> Result<Option<usize>, TeenyStorageError> {
+        let Some((location, header)) = self.find(key)? else {
+            return Ok(None);
+        };
+
+        if header.is_tombstone() {
+            return Ok(None);
+        }
+
+        let len = header.len as usize;
+        let buf = buf
+            .get_mut(..len)
+            .ok_or(TeenyStorageError::BufferTooSmall)?;
+
+        self.read_bytes(location.data_address(self), buf)?;
+
+        Ok(Some(len))
+    }
+
+    /// Sets the value of `key`.
+    pub fn write(&mut self, key: u8, value: &[u8]) -> Result<(), TeenyStorageError> {
+        self.append(key, 0, value)
+    }
+
+    /// Removes the value of `key`, if it has one.
+    pub fn remove(&mut self, key: u8) -> Result<(), TeenyStorageError> {
+        match self.find(key)? {
+            Some((_, header)) if !header.is_tombstone() => self.append(key, TOMBSTONE, &[]),
+            _ => Ok(()),
+        }
+    }
+
+    fn append(&mut self, key: u8, flags: u8, value: &[u8]) -> Result<(), TeenyStorageError> {
+        assert_ne!(key, ERASED, "0xff is reserved");
+
+        if value.len() > MAX_VALUE_LEN {
+            return Err(TeenyStorageError::TooLarge);
+        }
+
+        let needed = Self::record_len(value.len());
+
+        if self.next_offset + needed > Self::SECTOR_LEN {
+            self.rotate()?;
+
+            if self.next_offset + needed > Self::SECTOR_LEN {
+                return Err(TeenyStorageError::Full);
+            }
+        }
+
+        self.write_record(key, flags, value)
+    }
+
+    /// Writes a record at the end of the active sector, which must have room
+    /// for it.
+    fn write_record(&mut self, key: u8, flags: u8, value: &[u8]) -> Result<(), TeenyStorageError> {
+        let address = self.sector_address(self.active) + self.next_offset;
+
+        let header = RecordHeader {
+            key,
+            flags,
+            len: value.len() as u16,
+            crc: RecordHeader::crc_of(key, flags, value),
+        };
+
+        // header first, so a record cut short by a power cut still has its length
+        // known and can be skipped over - its data won't match the crc
+        self.flash.write(address, &header.to_bytes())?;
+
+        let mut chunk = [ERASED; CHUNK_LEN];
+        for (i, part) in value.chunks(CHUNK_LEN).enumerate() {
+            let len = Self::align(part.len() as u32) as usize;
+            chunk[..part.len()].copy_from_slice(part);
+            chunk[part.len()..].fill(ERASED);
+
+            self.flash.write(
+                address + RECORD_HEADER_LEN + (i * CHUNK_LEN) as u32,
+                &chunk[..len],
+            )?;
+        }
+
+        self.next_offset += Self::record_len(value.len());
+
+        Ok(())
+    }
+
+    /// Moves the log on to the spare sector, and makes the oldest sector the
+    /// new spare.
+    fn rotate(&mut self) -> Result<(), TeenyStorageError> {
+        let next = self.next_sector(self.active);
+
+        self.sequence += 1;
+        self.write_sector_header(next, self.sequence)?;
+
+        self.active = next;
+        self.next_offset = SECTOR_HEADER_LEN;
+
+        debug!("Settings moved on to sector {next}");
+
+        self.reclaim(self.next_sector(next))
+    }
+
+    /// Copies every record in `sector` which is still the newest for its key
+    /// into the active sector, then erases `sector`.
+    fn reclaim(&mut self, sector: u32) -> Result<(), TeenyStorageError> {
+        let mut offset = SECTOR_HEADER_LEN;
+
+        while let Some(header) = self.record_at(sector, offset)? {
+            let location = Location { sector, offset };
+
+            let is_newest =
+                matches!(self.find(header.key)?, Some((newest, _)) if newest == location);
+
+            if is_newest && !header.is_tombstone() && self.is_intact(location, header)? {
+                let mut value = [0; MAX_VALUE_LEN];
+                let value = value
+                    .get_mut(..header.len as usize)
+                    .ok_or(TeenyStorageError::TooLarge)?;
+
+                self.read_bytes(location.data_address(self), value)?;
+
+                if self.next_offset + Self::record_len(value.len()) > Self::SECTOR_LEN {
+                    return Err(TeenyStorageError::Full);
+                }
+
+                self.write_record(header.key, header.flags, value)?;
+            }
+
+            offset += Self::record_len(header.len as usize);
+        }
+
+        self.erase(sector)
+    }
+
+    /// The newest intact record for `key`.
+    fn find(&mut self, key: u8) -> Result<Option<(Location, RecordHeader)>, TeenyStorageError> {
+        let mut newest = None;
+
+        // oldest sector first, so later records replace earlier ones
+        for i in 1..=self.sectors {
+            let sector = (self.active + i) % self.sectors;
+
+            if self.sector_sequence(sector)?.is_none() {
+                continue;
+            }
+
+            let mut offset = SECTOR_HEADER_LEN;
+
+            while let Some(header) = self.record_at(sector, offset)? {
+                let location = Location { sector, offset };
+
+                if header.key == key && self.is_intact(location, header)? {
+                    newest = Some((location, header));
+                }
+
+                offset += Self::record_len(header.len as usize);
+            }
+        }
+
+        Ok(newest)
+    }
+
+    /// The header of the record at `offset` in `sector`, or `None` if the log
+    /// in that sector ends there.
+    fn record_at(
+        &mut self,
+        sector: u32,
+        offset: u32,
+    ) -> Result<Option<RecordHeader>, TeenyStorageError> {
+        if offset + RECORD_HEADER_LEN > Self::SECTOR_LEN {
+            return Ok(None);
+        }
+
+        let mut bytes = [0; RECORD_HEADER_LEN as usize];
+        self.read_bytes(self.sector_address(sector) + offset, &mut bytes)?;
+
+        let header = RecordHeader::from_bytes(bytes);
+
+        // a header which runs off the end of the sector was only half written
+        if header.key == ERASED || offset + Self::record_len(header.len as usize) > Self::SECTOR_LEN
+        {
+            return Ok(None);
+        }
+
+        Ok(Some(header))
+    }
+
+    /// Whether the record's data matches its crc.
+    fn is_intact(
+        &mut self,
+        location: Location,
+        header: RecordHeader,
+    ) -> Result<bool, TeenyStorageError> {
+        let mut crc = crc32(0, &[header.key, header.flags]);
+        let mut chunk = [0; CHUNK_LEN];
+        let mut address = location.data_address(self);
+        let mut remaining = header.len as usize;
+
+        while remaining > 0 {
+            let len = remaining.min(CHUNK_LEN);
+            self.read_bytes(address, &mut chunk[..len])?;
+            crc = crc32(crc, &chunk[..len]);
+
+            address += len as u32;
+            remaining -= len;
+        }
+
+        Ok(crc == header.crc)
+    }
+
+    /// Where the log in `sector` ends.
+    fn end_of_log(&mut self, sector: u32) -> Result<u32, TeenyStorageError> {
+        let mut offset = SECTOR_HEADER_LEN;
+
+        while let Some(header) = self.record_at(sector, offset)? {
+            offset += Self::record_len(header.len as usize);
+        }
+
+        // anything written after a broken header can't be found, so start again
+        // in a fresh sector rather than writing over it
+        let mut key = [0; 4];
+        if offset + RECORD_HEADER_LEN <= Self::SECTOR_LEN {
+            self.read_bytes(self.sector_address(sector) + offset, &mut key)?;
+
+            if key != [ERASED; 4] {
+                return Ok(Self::SECTOR_LEN);
+            }
+        }
+
+        Ok(offset)
+    }
+
+    fn sector_sequence(&mut self, sector: u32) -> Result<Option<u32>, TeenyStorageError> {
+        let mut header = [0; SECTOR_HEADER_LEN as usize];
+        self.read_bytes(self.sector_address(sector), &mut header)?;
+
+        let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let sequence = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+
+        Ok((magic == MAGIC && sequence != u32::MAX).then_some(sequence))
+    }
+
+    fn write_sector_header(&mut self, sector: u32, sequence: u32) -> Result<(), TeenyStorageError> {
+        let mut header = [0; SECTOR_HEADER_LEN as usize];
+        header[..4].copy_from_slice(&MAGIC.to_le_bytes());
+        header[4..].copy_from_slice(&sequence.to_le_bytes());
+
+        self.flash.write(self.sector_address(sector), &header)?;
+
+        Ok(())
+    }
+
+    fn is_blank(&mut self, sector: u32) -> Result<bool, TeenyStorageError> {
+        let mut chunk = [0; CHUNK_LEN];
+
+        for offset in (0..Self::SECTOR_LEN).step_by(CHUNK_LEN) {
+            self.read_bytes(self.sector_address(sector) + offset, &mut chunk)?;
+
+            if chunk != [ERASED; CHUNK_LEN] {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn erase(&mut self, sector: u32) -> Result<(), TeenyStorageError> {
+        let address = self.sector_address(sector);
+
+        self.flash.erase(address, address + Self::SECTOR_LEN)?;
+
+        Ok(())
+    }
+
+    /// Reads `buf.len()` bytes from the (aligned) `address`, whatever the
+    /// flash's `READ_SIZE`.
+    fn read_bytes(&mut self, address: u32, buf: &mut [u8]) -> Result<(), TeenyStorageError> {
+        let mut chunk = [0; CHUNK_LEN];
+
+        for (i, part) in buf.chunks_mut(CHUNK_LEN).enumerate() {
+            let len = Self::align(part.len() as u32) as usize;
+
+            self.flash
+                .read(address + (i * CHUNK_LEN) as u32, &mut chunk[..len])?;
+
+            part.copy_from_slice(&chunk[..part.len()]);
+        }
+
+        Ok(())
+    }
+
+    fn next_sector(&self, sector: u32) -> u32 {
+        (sector + 1) % self.sectors
+    }
+
+    fn sector_address(&self, sector: u32) -> u32 {
+        self.start + sector * Self::SECTOR_LEN
+    }
+
+    const fn align(len: u32) -> u32 {
+        len.div_ceil(Self::ALIGN) * Self::ALIGN
+    }
+
+    const fn record_len(len: usize) -> u32 {
+        RECORD_HEADER_LEN + Self::align(len as u32)
+    }
+}
+
+impl Location {
+    fn data_address<F: NorFlash>(&self, store: &FlashStore<F>) -> u32 {
+        store.sector_address(self.sector) + self.offset + RECORD_HEADER_LEN
+    }
+}
+
+/// What each setting is stored under.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    RefreshToken,
+    PotCalibration,
+    Preferences,
+    /// The password for the setup access point.
+    ApPassword,
+    /// The panel fitted, if it isn't the one the board was built with.
+    Display,
+    /// One of up to [`MAX_NETWORKS`] known wifi networks.
+    WifiNetwork(u8),
+}
+
+/// How many wifi networks can be remembered.
+pub const MAX_NETWORKS: u8 = 8;
+
+impl Key {
+    pub const fn id(&self) -> u8 {
+        match self {
+            Self::RefreshToken => 0x01,
+            Self::PotCalibration => 0x02,
+            Self::Preferences => 0x03,
+            Self::ApPassword => 0x04,
+            Self::Display => 0x05,
+            Self::WifiNetwork(index) => 0x10 + *index,
+        }
+    }
+}
+
+/// A value which can be kept in the [`Settings`].
+pub trait Record: Sized {
+    /// Writes the record into `buf`, returning how many bytes it took - `None`
+    /// if it didn't fit.
+    fn encode(&self, buf: &mut [u8]) -> Option<usize>;
+
+    /// Reads back a record written by [`Record::encode`] - `None` if it is
+    /// malformed.
+    fn decode(buf: &[u8]) -> Option<Self>;
+}
+
+/// Writes values one after the other into a buffer.
+pub struct Encoder<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> Encoder<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, len: 0 }
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) -> Option<&mut Self> {
+        self.buf
+            .get_mut(self.len..self.len + bytes.len())?
+            .copy_from_slice(bytes);
+        self.len += bytes.len();
+        Some(self)
+    }
+
+    pub fn u8(&mut self, value: u8) -> Option<&mut Self> {
+        self.bytes(&[value])
+    }
+
+    pub fn u16(&mut self, value: u16) -> Option<&mut Self> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    /// A string of up to 255 bytes, prefixed by its length.
+    pub fn str(&mut self, value: &str) -> Option<&mut Self> {
+        self.u8(value.len().try_into().ok()?)?
+            .bytes(value.as_bytes())
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// Reads back what an [`Encoder`] wrote.
+pub struct Decoder<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if len > self.buf.len() {
+            return None;
+        }
+
+        let (bytes, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Some(bytes)
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Option<u16> {
+        let bytes = self.bytes(2)?;
+        Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn str<const N: usize>(&mut self) -> Option<String<N>> {
+        let len = self.u8()? as usize;
+        let bytes = self.bytes(len)?;
+        core::str::from_utf8(bytes).ok()?.try_into().ok()
+    }
+
+    /// Whether everything has been read - records written by older firmware
+    /// end early, and newer fields should fall back to their defaults.
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+}
+
+/// A wifi network to connect to.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct WifiNetwork {
+    pub ssid: String<32>,
+    pub password: String<64>,
+    /// Higher is preferred when several known networks are in range.
+    pub priority: u8,
+}
+
+impl Record for WifiNetwork {
+    fn encode(&self, buf: &mut [u8]) -> Option<usize> {
+        Some(
+            Encoder::new(buf)
+                .str(&self.ssid)?
+                .str(&self.password)?
+                .u8(self.priority)?
+                .len(),
+        )
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let mut decoder = Decoder::new(buf);
+
+        Some(Self {
+            ssid: decoder.str()?,
+            password: decoder.str()?,
+            priority: decoder.u8()?,
+        })
+    }
+}
+
+/// The raw adc readings at either end of the potentiometer's travel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PotCalibration {
+    pub min: u16,
+    pub max: u16,
+}
+
+impl Default for PotCalibration {
+    fn default() -> Self {
+        Self { min: 0, max: 2754 }
+    }
+}
+
+impl Record for PotCalibration {
+    fn encode(&self, buf: &mut [u8]) -> Option<usize> {
+        Some(Encoder::new(buf).u16(self.min)?.u16(self.max)?.len())
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let mut decoder = Decoder::new(buf);
+
+        Some(Self {
+            min: decoder.u16()?,
+            max: decoder.u16()?,
+        })
+    }
+}
+
+/// User interface preferences.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Preferences {
+    /// Display contrast, 0-255.
+    pub brightness: u8,
+    /// How long without any input (or change in playback) before the display
+    /// dims, in seconds - `0` for never.
+    pub dim_after_secs: u16,
+    /// How long without any input before the display switches off, in seconds
+    /// - `0` for never.
+    pub off_after_secs: u16,
+    /// Whether everything on the display is moved around by a pixel every so
+    /// often, so nothing stays lit in the same place long enough to burn in.
+    pub pixel_shift: bool,
+}
+
+impl Default for Preferences {
+    fn default() -> Self {
+        Self {
+            brightness: 0xff,
+            dim_after_secs: 30,
+            off_after_secs: 300,
+            pixel_shift: true,
+        }
+    }
+}
+
+impl Record for Preferences {
+    fn encode(&self, buf: &mut [u8]) -> Option<usize> {
+        Some(
+            Encoder::new(buf)
+                .u8(self.brightness)?
+                .u16(self.dim_after_secs)?
+                .u16(self.off_after_secs)?
+                .u8(self.pixel_shift as u8)?
+                .len(),
+        )
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let mut decoder = Decoder::new(buf);
+        let defaults = Self::default();
+
+        // anything missing was saved before it existed
+        Some(Self {
+            brightness: decoder.u8().unwrap_or(defaults.brightness),
+            dim_after_secs: decoder.u16().unwrap_or(defaults.dim_after_secs),
+            off_after_secs: decoder.u16().unwrap_or(defaults.off_after_secs),
+            pixel_shift: decoder
+                .u8()
+                .map_or(defaults.pixel_shift, |shift| shift != 0),
+        })
+    }
+}
+
+impl Record for DisplayConfig {
+    fn encode(&self, buf: &mut [u8]) -> Option<usize> {
+        let controller = match self.controller {
+            Controller::Ssd1306 => 0,
+            Controller::Sh1106 => 1,
+        };
+        let size = match self.size {
+            PanelSize::Size128x64 => 0,
+            PanelSize::Size128x32 => 1,
+            PanelSize::Size72x40 => 2,
+        };
+        let rotation = match self.rotation {
+            Rotation::Rotate0 => 0,
+            Rotation::Rotate90 => 1,
+            Rotation::Rotate180 => 2,
+            Rotation::Rotate270 => 3,
+        };
+
+        Some(
+            Encoder::new(buf)
+                .u8(controller)?
+                .u8(size)?
+                .u8(rotation)?
+                .u8(self.address)?
+                .len(),
+        )
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let mut decoder = Decoder::new(buf);
+
+        Some(Self {
+            controller: match decoder.u8()? {
+                0 => Controller::Ssd1306,
+                1 => Controller::Sh1106,
+                _ => return None,
+            },
+            size: match decoder.u8()? {
+                0 => PanelSize::Size128x64,
+                1 => PanelSize::Size128x32,
+                2 => PanelSize::Size72x40,
+                _ => return None,
+            },
+            rotation: match decoder.u8()? {
+                0 => Rotation::Rotate0,
+                1 => Rotation::Rotate90,
+                2 => Rotation::Rotate180,
+                3 => Rotation::Rotate270,
+                _ => return None,
+            },
+            address: decoder.u8()?,
+        })
+    }
+}
+
+impl<const N: usize> Record for String<N> {
+    fn encode(&self, buf: &mut [u8]) -> Option<usize> {
+        Some(Encoder::new(buf).bytes(self.as_bytes())?.len())
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        core::str::from_utf8(buf).ok()?.try_into().ok()
+    }
+}
+
+/// Typed access to the [`FlashStore`].
+pub struct Settings<F: NorFlash> {
+    store: FlashStore<F>,
+}
+
+impl<F: NorFlash> Settings<F> {
+    pub fn new(store: FlashStore<F>) -> Self {
+        Self { store }
+    }
+
+    /// The value stored under `key`, or `None` if there isn't one (or it can't
+    /// be read).
+    pub fn load<R: Record>(&mut self, key: Key) -> Result<Option<R>, TeenyStorageError> {
+        let mut buf = [0; MAX_VALUE_LEN];
+
+        let Some(len) = self.store.read(key.id(), &mut buf)? else {
+            return Ok(None);
+        };
+
+        let record = R::decode(&buf[..len]);
+
+        if record.is_none() {
+            warn!("Stored {key:?} is malformed, ignoring it");
+        }
+
+        Ok(record)
+    }
+
+    /// Like [`Settings::load`], but falls back to the default if there is no
+    /// (readable) value.
+    pub fn load_or_default<R: Record + Default>(&mut self, key: Key) -> R {
+        match self.load(key) {
+            Ok(Some(record)) => record,
+            Ok(None) => R::default(),
+            Err(e) => {
+                warn!("Failed to load {key:?}: {e:?}");
+                R::default()
+            }
+        }
+    }
+
+    pub fn save<R: Record>(&mut self, key: Key, record: &R) -> Result<(), TeenyStorageError> {
+        let mut buf = [0; MAX_VALUE_LEN];
+
+        let len = record.encode(&mut buf).ok_or(TeenyStorageError::TooLarge)?;
+
+        self.store.write(key.id(), &buf[..len])
+    }
+
+    pub fn remove(&mut self, key: Key) -> Result<(), TeenyStorageError> {
+        self.store.remove(key.id())
+    }
+
+    /// Every remembered wifi network, in the order they are stored.
+    pub fn wifi_networks(&mut self) -> Vec<WifiNetwork, { MAX_NETWORKS as usize }> {
+        (0..MAX_NETWORKS)
+            .filter_map(|index| self.load(Key::WifiNetwork(index)).ok().flatten())
+            .collect()
+    }
+
+    /// Remembers `network`, replacing any network with the same ssid - or the
+    /// lowest priority one if there is no room left.
+    pub fn add_wifi_network(&mut self, network: &WifiNetwork) -> Result<(), TeenyStorageError> {
+        let mut free = None;
+        let mut lowest: Option<(u8, u8)> = None;
+
+        for index in 0..MAX_NETWORKS {
+            match self.load::<WifiNetwork>(Key::WifiNetwork(index))? {
+                Some(known) if known.ssid == network.ssid => {
+                    return self.save(Key::WifiNetwork(index), network);
+                }
+                Some(known) => {
+                    if lowest.map_or(true, |(_, priority)| known.priority < priority) {
+                        lowest = Some((index, known.priority));
+                    }
+                }
+                None => {
+                    free.get_or_insert(index);
+                }
+            }
+        }
+
+        let index = free
+            .or(lowest.map(|(index, _)| index))
+            .expect("MAX_NETWORKS is not 0");
+
+        self.save(Key::WifiNetwork(index), network)
+    }
+
+    /// Forgets the network called `ssid`, if it is known.
+    pub fn remove_wifi_network(&mut self, ssid: &str) -> Result<(), TeenyStorageError> {
+        for index in 0..MAX_NETWORKS {
+            if let Some(known) = self.load::<WifiNetwork>(Key::WifiNetwork(index))? {
+                if known.ssid == ssid {
+                    self.remove(Key::WifiNetwork(index))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub type FlashSettings = Settings<esp_storage::FlashStorage>;
+
+pub type SettingsMutex = Mutex<CriticalSectionRawMutex, FlashSettings>;
+
+pub static SETTINGS: StaticCell<SettingsMutex> = StaticCell::new();
+
+/// Mounts the settings stored in the `nvs` partition.
+pub fn init_settings() -> &'static SettingsMutex {
+    let store = FlashStore::mount(
+        esp_storage::FlashStorage::new(),
+        FLASH_OFFSET,
+        FLASH_SECTORS,
+    )
+    .expect("flash should be readable and writable");
+
+    SETTINGS.init(Mutex::new(Settings::new(store)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{vec, vec::Vec};
+
+    use embedded_storage::nor_flash::{ErrorType, NorFlashErrorKind, ReadNorFlash};
+
+    use super::*;
+
+    const SECTOR_LEN: usize = 4096;
+
+    /// NOR flash in ram - writes can only clear bits, and can be made to fail
+    /// part way through, as if the power was cut.
+    struct MemFlash {
+        data: Vec<u8>,
+        erases: Vec<u32>,
+        /// How many more writes and erases go through - the one after is cut
+        /// off half way, and every one after that fails.
+        ops_left: Option<usize>,
+    }
+
+    #[derive(Debug)]
+    struct PowerCut;
+
+    impl NorFlashError for PowerCut {
+        fn kind(&self) -> NorFlashErrorKind {
+            NorFlashErrorKind::Other
+        }
+    }
+
+    impl MemFlash {
+        fn new(sectors: usize) -> Self {
+            Self {
+                data: vec![ERASED; sectors * SECTOR_LEN],
+                erases: vec![0; sectors],
+                ops_left: None,
+            }
+        }
+
+        /// How much of an operation on `len` bytes gets done before the power
+        /// goes.
+        fn allowance(&mut self, len: usize) -> Result<usize, PowerCut> {
+            match &mut self.ops_left {
+                None => Ok(len),
+                Some(0) => {
+                    self.ops_left = Some(usize::MAX);
+                    Ok(len / 2)
+                }
+                Some(usize::MAX) => Err(PowerCut),
+                Some(left) => {
+                    *left -= 1;
+                    Ok(len)
+                }
+            }
+        }
+
+        fn restore_power(&mut self) {
+            self.ops_left = None;
+        }
+    }
+
+    impl ErrorType for MemFlash {
+        type Error = PowerCut;
+    }
+
+    impl ReadNorFlash for MemFlash {
+        const READ_SIZE: usize = 4;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), PowerCut> {
+            let offset = offset as usize;
+            assert!(offset % Self::READ_SIZE == 0 && bytes.len() % Self::READ_SIZE == 0);
+
+            bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.data.len()
+        }
+    }
+
+    impl NorFlash for MemFlash {
+        const WRITE_SIZE: usize = 4;
+        const ERASE_SIZE: usize = SECTOR_LEN;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), PowerCut> {
+            let (from, to) = (from as usize, to as usize);
+            assert!(from % SECTOR_LEN == 0 && to % SECTOR_LEN == 0);
+
+            let len = self.allowance(to - from)?;
+            self.erases[from / SECTOR_LEN] += 1;
+            self.data[from..from + len].fill(ERASED);
+
+            if len < to - from {
+                return Err(PowerCut);
+            }
+
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), PowerCut> {
+            let offset = offset as usize;
+            assert!(offset % Self::WRITE_SIZE == 0 && bytes.len() % Self::WRITE_SIZE == 0);
+
+            let len = self.allowance(bytes.len())?;
+
+            for (stored, byte) in self.data[offset..].iter_mut().zip(&bytes[..len]) {
+                assert_eq!(*stored, ERASED, "writing to unerased flash at {offset}");
+                *stored &= byte;
+            }
+
+            if len < bytes.len() {
+                return Err(PowerCut);
+            }
+
+            Ok(())
+        }
+    }
+
+    fn mount(flash: MemFlash) -> FlashStore<MemFlash> {
+        let sectors = (flash.capacity() / SECTOR_LEN) as u32;
+
+        FlashStore::mount(flash, 0, sectors).unwrap()
+    }
+
+    fn value(store: &mut FlashStore<MemFlash>, key: u8) -> Option<Vec<u8>> {
+        let mut buf = [0; MAX_VALUE_LEN];
+        let len = store.read(key, &mut buf).unwrap()?;
+
+        Some(buf[..len].to_vec())
+    }
+
+    #[test]
+    fn values_survive_remounting() {
+        let mut store = mount(MemFlash::new(3));
+
+        assert_eq!(value(&mut store, 1), None);
+
+        store.write(1, b"hello").unwrap();
+        store.write(2, b"world").unwrap();
+        store.write(1, b"hi").unwrap();
+        store.write(3, &[]).unwrap();
+
+        let mut store = mount(store.release());
+
+        assert_eq!(value(&mut store, 1).as_deref(), Some(&b"hi"[..]));
+        assert_eq!(value(&mut store, 2).as_deref(), Some(&b"world"[..]));
+        assert_eq!(value(&mut store, 3).as_deref(), Some(&[][..]));
+
+        let mut small = [0; 1];
+        assert!(matches!(
+            store.read(2, &mut small),
+            Err(TeenyStorageError::BufferTooSmall)
+        ));
+        assert!(matches!(
+            store.write(4, &[0; MAX_VALUE_LEN + 1]),
+            Err(TeenyStorageError::TooLarge)
+        ));
+    }
+
+    #[test]
+    fn tombstones_remove_values_for_good() {
+        let mut store = mount(MemFlash::new(3));
+
+        store.write(1, b"gone soon").unwrap();
+        store.write(2, b"staying").unwrap();
+        store.remove(1).unwrap();
+        // removing it again doesn't need another tombstone
+        let end = store.next_offset;
+        store.remove(1).unwrap();
+        assert_eq!(store.next_offset, end);
+
+        let mut store = mount(store.release());
+        assert_eq!(value(&mut store, 1), None);
+
+        // nor does the old value come back once the log has gone round every sector
+        for i in 0..200u8 {
+            store.write(3, &[i; 64]).unwrap();
+        }
+
+        assert_eq!(value(&mut store, 1), None);
+        assert_eq!(value(&mut store, 2).as_deref(), Some(&b"staying"[..]));
+    }
+
+    #[test]
+    fn rotating_keeps_the_newest_values_and_levels_wear() {
+        let mut store = mount(MemFlash::new(4));
+
+        for i in 0..5000u32 {
+            store.write((i % 5) as u8, &i.to_le_bytes()).unwrap();
+
+            if i % 7 == 0 {
+                store.write(9, &[i as u8; 200]).unwrap();
+            }
+        }
+
+        let check = |store: &mut FlashStore<MemFlash>| {
+            for key in 0..5u8 {
+                let newest = 5000 - 5 + key as u32;
+                assert_eq!(value(store, key), Some(newest.to_le_bytes().to_vec()));
+            }
+
+            assert_eq!(value(store, 9), Some(vec![4998u32 as u8; 200]));
+        };
+
+        check(&mut store);
+
+        let mut store = mount(store.release());
+        check(&mut store);
+
+        let flash = store.release();
+        let (least, most) = (
+            flash.erases.iter().min().unwrap(),
+            flash.erases.iter().max().unwrap(),
+        );
+
+        assert!(*least > 0);
+        assert!(most - least <= 1, "uneven wear: {:?}", flash.erases);
+    }
+
+    #[test]
+    fn corrupt_records_are_skipped() {
+        let mut store = mount(MemFlash::new(2));
+
+        store.write(1, b"older").unwrap();
+        let newer = store.next_offset as usize;
+        store.write(1, b"newer").unwrap();
+        store.write(2, b"after").unwrap();
+
+        let mut flash = store.release();
+        // clearing bits, as a bad write would
+        flash.data[newer + RECORD_HEADER_LEN as usize] &= 0x0f;
+
+        let mut store = mount(flash);
+
+        assert_eq!(value(&mut store, 1).as_deref(), Some(&b"older"[..]));
+        assert_eq!(value(&mut store, 2).as_deref(), Some(&b"after"[..]));
+    }
+
+    #[test]
+    fn remounts_after_a_power_cut_at_any_point() {
+        const KEYS: u8 = 4;
+        // enough to fill a sector and move on to the next
+        const WRITES: u32 = 60;
+
+        let mut cut = 0;
+
+        loop {
+            let mut store = mount(MemFlash::new(3));
+
+            for key in 0..KEYS {
+                store.write(key, &[key; 100]).unwrap();
+            }
+
+            store.flash.ops_left = Some(cut);
+
+            let mut saved: [u8; KEYS as usize] = core::array::from_fn(|key| key as u8);
+            let mut in_flight = None;
+            let mut finished = true;
+
+            for i in 0..WRITES {
+                let (key, fill) = ((i % KEYS as u32) as u8, (i % 250) as u8 + 10);
+
+                if store.write(key, &[fill; 100]).is_err() {
+                    in_flight = Some((key, fill));
+                    finished = false;
+                    break;
+                }
+
+                saved[key as usize] = fill;
+            }
+
+            let mut flash = store.release();
+            flash.restore_power();
+
+            let mut store = mount(flash);
+
+            for key in 0..KEYS {
+                let stored = value(&mut store, key).expect("a key was lost");
+                let fill = stored[0];
+
+                assert_eq!(stored, [fill; 100], "cut {cut} mixed up key {key}");
+                assert!(
+                    fill == saved[key as usize] || in_flight == Some((key, fill)),
+                    "cut {cut} left key {key} at {fill}"
+                );
+            }
+
+            // and it carries on working, through another rotation
+            for i in 0..40u8 {
+                store.write(i % KEYS, &[i; 100]).unwrap();
+            }
+
+            let mut store = mount(store.release());
+            assert_eq!(value(&mut store, 3), Some(vec![39; 100]));
+
+            if finished {
+                break;
+            }
+
+            cut += 1;
+        }
+
+        // every write and erase of the run was cut short at some point
+        assert!(cut > WRITES as usize);
+    }
+}